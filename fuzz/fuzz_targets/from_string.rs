@@ -0,0 +1,26 @@
+//! Feeds arbitrary Discord message contents to `Message::from_string`, with every codec.
+//!
+//! It must only ever return errors. Runs offline, from the seeds in `fuzz/corpus/from_string`:
+//!
+//! cargo +nightly fuzz run from_string
+
+#![no_main]
+
+use discraft::codec::Codec;
+use discraft::message::Message;
+use discraft::partitioning::Aggregator;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let _ = Message::from_string(text);
+
+    // `Message::from_string` only uses the codec of the run (hex by default).
+    for codec in [Codec::Base64, Codec::Base85, Codec::Base32768] {
+        if let Ok(messages) = Aggregator::disaggregate(text, codec) {
+            for message in messages {
+                // What was decoded can be encoded again.
+                let _ = Message::from_string(message.to_string());
+            }
+        }
+    }
+});
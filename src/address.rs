@@ -0,0 +1,174 @@
+//! Everything to find the Minecraft server to connect to.
+//!
+//! Like the Minecraft client, a hostname given without a port is first looked up as a
+//! `_minecraft._tcp` SRV record, so that the same address works in Discraft and in the game.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, warn};
+use tokio::net::{lookup_host, TcpStream};
+
+/// The port of a Minecraft server when none is given.
+pub const DEFAULT_PORT: u16 = 25565;
+
+/// The service looked up in the SRV records of a hostname.
+const SRV_SERVICE: &str = "_minecraft._tcp";
+
+/// A host and port found in a SRV record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct SrvTarget {
+    priority: u16,
+    weight: u16,
+    host: String,
+    port: u16,
+}
+
+/// Sorts the SRV targets in the order they must be tried: lowest priority first, then highest
+/// weight.
+fn sort_targets(targets: &mut [SrvTarget]) {
+    targets.sort_by(|a, b| {
+        a.priority
+            .cmp(&b.priority)
+            .then_with(|| b.weight.cmp(&a.weight))
+    });
+}
+
+/// Parses an IP address literal. (IPv6 addresses may be in brackets: `[::1]`)
+fn parse_ip(host: &str) -> Option<IpAddr> {
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    host.parse().ok()
+}
+
+/// Looks up the `_minecraft._tcp` SRV records of a hostname.
+///
+/// Returns the targets in the order they must be tried, or nothing if there is no record.
+async fn lookup_srv(host: &str) -> Vec<SrvTarget> {
+    let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+        Ok(resolver) => resolver,
+        Err(err) => {
+            warn!("Failed to read the system DNS configuration: {err}");
+            return Vec::new();
+        }
+    };
+
+    let lookup = match resolver.srv_lookup(format!("{SRV_SERVICE}.{host}.")).await {
+        Ok(lookup) => lookup,
+        Err(err) => {
+            debug!("No SRV record for {host}: {err}");
+            return Vec::new();
+        }
+    };
+
+    let mut targets: Vec<SrvTarget> = lookup
+        .iter()
+        .map(|srv| SrvTarget {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            host: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            port: srv.port(),
+        })
+        .collect();
+    sort_targets(&mut targets);
+    targets
+}
+
+/// Resolves the address of the Minecraft server into the socket addresses to try, in order.
+///
+/// If no port is given, the SRV records of a hostname are used first, then `DEFAULT_PORT`.
+pub async fn resolve(host: &str, port: Option<u16>) -> io::Result<Vec<SocketAddr>> {
+    if let Some(ip) = parse_ip(host) {
+        return Ok(vec![SocketAddr::new(ip, port.unwrap_or(DEFAULT_PORT))]);
+    }
+
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+
+    if port.is_none() {
+        for target in lookup_srv(host).await {
+            debug!("SRV record of {host}: {}:{}", target.host, target.port);
+            match lookup_host((target.host.as_str(), target.port)).await {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(err) => warn!("Failed to resolve {}: {err}", target.host),
+            }
+        }
+    }
+
+    if addrs.is_empty() {
+        addrs.extend(lookup_host((host, port.unwrap_or(DEFAULT_PORT))).await?);
+    }
+
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} has no address"),
+        ));
+    }
+
+    Ok(addrs)
+}
+
+/// Connects to the first reachable address of the Minecraft server.
+///
+/// The address is resolved again on every call, so that a server changing its address is followed.
+pub async fn connect(host: &str, port: Option<u16>) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut last_err: Option<io::Error> = None;
+
+    for addr in resolve(host, port).await? {
+        match TcpStream::connect(addr).await {
+            Ok(socket) => return Ok((socket, addr)),
+            Err(err) => {
+                debug!("Failed to connect to {addr}: {err}");
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotFound)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(priority: u16, weight: u16, host: &str) -> SrvTarget {
+        SrvTarget {
+            priority,
+            weight,
+            host: host.to_string(),
+            port: DEFAULT_PORT,
+        }
+    }
+
+    #[test]
+    fn test_sort_targets() {
+        let mut targets = vec![
+            target(20, 0, "backup"),
+            target(10, 5, "light"),
+            target(10, 60, "heavy"),
+        ];
+        sort_targets(&mut targets);
+
+        let hosts: Vec<&str> = targets.iter().map(|t| t.host.as_str()).collect();
+        assert_eq!(hosts, ["heavy", "light", "backup"]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ip_literals() {
+        assert_eq!(
+            resolve("127.0.0.1", None).await.unwrap(),
+            ["127.0.0.1:25565".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolve("[::1]", Some(25566)).await.unwrap(),
+            ["[::1]:25566".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(
+            resolve("::1", Some(1)).await.unwrap(),
+            ["[::1]:1".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
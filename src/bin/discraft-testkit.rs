@@ -0,0 +1,136 @@
+//! Fake Minecraft server and client, to check a tunnel end to end. (see `discraft::testkit`)
+//!
+//! Run the server behind the server side, then the client against the client side:
+//!
+//! ```sh
+//! discraft-testkit server --listen 127.0.0.1:25566
+//! discraft server -a 127.0.0.1 -p 25566 ...
+//! discraft client -l 127.0.0.1:25565 ...
+//! discraft-testkit client --connect 127.0.0.1:25565
+//! ```
+
+use std::net::SocketAddr;
+
+use clap::{Args, Parser, Subcommand};
+use discraft::logging;
+use discraft::testkit::{self, Settings};
+use tokio::net::TcpListener;
+
+#[derive(Parser)]
+#[command(name = "discraft-testkit")]
+#[command(about = "Fake Minecraft endpoints checking that a tunnel delivers every byte", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run the fake Minecraft server, streaming the packets to every player
+    Server {
+        /// The address to listen on
+        #[arg(short, long, value_name = "IP:PORT", default_value = "127.0.0.1:25566")]
+        listen: SocketAddr,
+
+        #[command(flatten)]
+        stream: StreamArgs,
+    },
+
+    /// Join through the client side, and check every packet streamed
+    Client {
+        /// The address of the client side
+        #[arg(short, long, value_name = "IP:PORT", default_value = "127.0.0.1:25565")]
+        connect: SocketAddr,
+
+        /// Do not get the status first
+        #[arg(long)]
+        no_status: bool,
+
+        #[command(flatten)]
+        stream: StreamArgs,
+    },
+}
+
+/// What is streamed: the same for both tools.
+#[derive(Args)]
+struct StreamArgs {
+    /// The protocol version spoken
+    #[arg(long, default_value_t = Settings::default().protocol)]
+    protocol: i32,
+
+    /// How many packets are streamed
+    #[arg(long, default_value_t = Settings::default().packets)]
+    packets: u32,
+
+    /// The size of the packets, in bytes
+    #[arg(long, default_value_t = Settings::default().packet_size)]
+    packet_size: usize,
+
+    /// The compression threshold (no compression if not given)
+    #[arg(long, value_name = "BYTES")]
+    compression: Option<usize>,
+
+    /// What the streamed bytes are derived from
+    #[arg(long, default_value_t = Settings::default().seed)]
+    seed: u64,
+}
+
+impl From<StreamArgs> for Settings {
+    fn from(args: StreamArgs) -> Self {
+        Settings {
+            protocol: args.protocol,
+            packets: args.packets,
+            packet_size: args.packet_size,
+            compression: args.compression,
+            seed: args.seed,
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    logging::init_logger();
+
+    match Cli::parse().command {
+        Command::Server { listen, stream } => {
+            let listener = match TcpListener::bind(listen).await {
+                Ok(listener) => listener,
+                Err(err) => {
+                    eprintln!("Failed to listen on {listen}: {err}");
+                    std::process::exit(1);
+                }
+            };
+            println!("Fake Minecraft server listening on {listen}");
+            if let Err(err) = testkit::serve(listener, stream.into()).await {
+                eprintln!("Fake Minecraft server failed: {err}");
+                std::process::exit(1);
+            }
+        }
+        Command::Client {
+            connect,
+            no_status,
+            stream,
+        } => {
+            let settings: Settings = stream.into();
+            if !no_status {
+                match testkit::status(connect, settings).await {
+                    Ok((status, ping)) => println!("Status in {ping:.2?}: {status}"),
+                    Err(err) => println!("Failed to get the status: {err}"),
+                }
+            }
+
+            match testkit::join(connect, settings).await {
+                Ok(report) => {
+                    println!("{report}");
+                    if !report.is_ok(&settings) {
+                        std::process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Failed to join: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+}
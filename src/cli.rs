@@ -1,13 +1,34 @@
-use clap::{Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+
+use crate::codec::Codec;
+use crate::compression::Compression;
+use crate::crypto::FrameMode;
+use crate::forward::{self, End, Forward, Framing, Protocol, Target};
 
 #[derive(Parser)]
 #[command(name = "Discraft")]
 #[command(author = "Urpagin")]
 #[command(version = "0.1")]
-#[command(about = "Play a Minecraft server through Discord", long_about = None)]
+#[command(about = "Play a Minecraft server, or reach any TCP service, through Discord", long_about = None)]
 pub struct Args {
+    /// The TOML configuration file (the options given on the command line override it)
+    #[arg(long, global = true, value_name = "FILE", env = "DISCRAFT_CONFIG")]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
-    pub mode: Mode,
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[command(flatten)]
+    Run(Mode),
+
+    /// Check the configuration file without connecting to anything
+    CheckConfig,
 }
 
 #[derive(Subcommand, PartialEq, Clone)]
@@ -16,34 +37,238 @@ pub enum Mode {
     Server {
         /// The Minecraft server address/IP
         #[arg(short, long)]
-        address: String,
+        address: Option<String>,
 
-        /// The Minecraft server port
-        #[arg(short, long, default_value_t = 25565)]
-        port: u16,
+        /// The Minecraft server port (from the `_minecraft._tcp` SRV record of the address if not
+        /// given, else 25565)
+        #[arg(short, long)]
+        port: Option<u16>,
 
         /// The Discord bot token
-        #[arg(short, long)]
-        token: String,
+        #[arg(short, long, env = "DISCRAFT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
 
         /// The Discord guild ID
         #[arg(short, long)]
-        guild_id: u64,
+        guild_id: Option<u64>,
+
+        /// The ID of a Discord channel to send the messages to (repeatable)
+        #[arg(long = "channel", value_name = "CHANNEL_ID")]
+        channels: Vec<u64>,
+
+        /// How the payloads are encoded into Discord messages (the same on both sides)
+        #[arg(short, long, value_enum, default_value_t = Codec::Hex)]
+        codec: Codec,
+
+        /// How the data is compressed before being encoded
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compression: Compression,
+
+        /// How the data read from the sockets is cut into messages (`minecraft` sends whole
+        /// Minecraft packets only, and answers the Keep Alives locally: the same on both sides)
+        #[arg(long, value_enum, default_value_t = Framing::Raw)]
+        framing: Framing,
+
+        /// The secret shared by both sides to encrypt the tunnel (unencrypted if not given)
+        #[arg(long, env = "DISCRAFT_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// How the frames are sealed with the secret
+        #[arg(long, value_enum, default_value_t = FrameMode::Encrypt)]
+        frame_mode: FrameMode,
+
+        /// The user ID of the peer's bot, the only author whose messages are read (repeatable;
+        /// anyone in the guild if not given)
+        #[arg(long = "peer-bot", value_name = "USER_ID")]
+        peer_bots: Vec<u64>,
+
+        /// The base URL of the Discord REST API (e.g. a local stand-in for testing; the real
+        /// Discord if not given)
+        #[arg(long, value_name = "URL", env = "DISCRAFT_API_URL")]
+        api_url: Option<String>,
     },
 
     /// Run as the client-side
     Client {
+        /// The address the Minecraft clients connect to (repeatable, e.g. `[::]:25565` for IPv6)
+        #[arg(short, long, value_name = "IP:PORT", default_value = "0.0.0.0:25565")]
+        listen: Vec<SocketAddr>,
+
         /// The Discord bot token
+        #[arg(short, long, env = "DISCRAFT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+
+        /// The Discord guild ID
         #[arg(short, long)]
-        token: String,
+        guild_id: Option<u64>,
+
+        /// The ID of a Discord channel to send the messages to (repeatable)
+        #[arg(long = "channel", value_name = "CHANNEL_ID")]
+        channels: Vec<u64>,
+
+        /// How the payloads are encoded into Discord messages (the same on both sides)
+        #[arg(short, long, value_enum, default_value_t = Codec::Hex)]
+        codec: Codec,
+
+        /// How the data is compressed before being encoded
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compression: Compression,
+
+        /// How the data read from the sockets is cut into messages (`minecraft` sends whole
+        /// Minecraft packets only, and answers the Keep Alives locally: the same on both sides)
+        #[arg(long, value_enum, default_value_t = Framing::Raw)]
+        framing: Framing,
+
+        /// The secret shared by both sides to encrypt the tunnel (unencrypted if not given)
+        #[arg(long, env = "DISCRAFT_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// How the frames are sealed with the secret
+        #[arg(long, value_enum, default_value_t = FrameMode::Encrypt)]
+        frame_mode: FrameMode,
+
+        /// The user ID of the peer's bot, the only author whose messages are read (repeatable;
+        /// anyone in the guild if not given)
+        #[arg(long = "peer-bot", value_name = "USER_ID")]
+        peer_bots: Vec<u64>,
+
+        /// The base URL of the Discord REST API (e.g. a local stand-in for testing; the real
+        /// Discord if not given)
+        #[arg(long, value_name = "URL", env = "DISCRAFT_API_URL")]
+        api_url: Option<String>,
+    },
+
+    /// Run one end of generic TCP tunnels (the forwards are in the configuration file)
+    Tunnel {
+        /// Which end of the forwards runs here
+        #[arg(short, long, value_enum)]
+        end: End,
+
+        /// The Discord bot token
+        #[arg(short, long, env = "DISCRAFT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
 
         /// The Discord guild ID
         #[arg(short, long)]
-        guild_id: u64,
+        guild_id: Option<u64>,
+
+        /// How the payloads are encoded into Discord messages (the same on both sides)
+        #[arg(short, long, value_enum, default_value_t = Codec::Hex)]
+        codec: Codec,
+
+        /// How the data is compressed before being encoded
+        #[arg(long, value_enum, default_value_t = Compression::None)]
+        compression: Compression,
+
+        /// The secret shared by both sides to encrypt the tunnel (unencrypted if not given)
+        #[arg(long, env = "DISCRAFT_SECRET", hide_env_values = true)]
+        secret: Option<String>,
+
+        /// How the frames are sealed with the secret
+        #[arg(long, value_enum, default_value_t = FrameMode::Encrypt)]
+        frame_mode: FrameMode,
+
+        /// The user ID of the peer's bot, the only author whose messages are read (repeatable;
+        /// anyone in the guild if not given)
+        #[arg(long = "peer-bot", value_name = "USER_ID")]
+        peer_bots: Vec<u64>,
+
+        /// The base URL of the Discord REST API (e.g. a local stand-in for testing; the real
+        /// Discord if not given)
+        #[arg(long, value_name = "URL", env = "DISCRAFT_API_URL")]
+        api_url: Option<String>,
+
+        /// The forwards, from the `forwards` of the configuration file
+        #[arg(skip)]
+        forwards: Vec<Forward>,
     },
 }
 
-/// Returns a usable args struct
-pub fn parse() -> Args {
-    Args::parse()
+impl Mode {
+    /// The Discord bot token. (always set once the configuration is loaded)
+    pub fn token(&self) -> &str {
+        match self {
+            Mode::Server { token, .. }
+            | Mode::Client { token, .. }
+            | Mode::Tunnel { token, .. } => token.as_deref().unwrap_or_default(),
+        }
+    }
+
+    /// The Discord guild ID. (always set once the configuration is loaded)
+    pub fn guild_id(&self) -> u64 {
+        match self {
+            Mode::Server { guild_id, .. }
+            | Mode::Client { guild_id, .. }
+            | Mode::Tunnel { guild_id, .. } => guild_id.unwrap_or_default(),
+        }
+    }
+
+    /// The base URL of the Discord REST API, if not the real Discord's.
+    pub fn api_url(&self) -> Option<&str> {
+        match self {
+            Mode::Server { api_url, .. }
+            | Mode::Client { api_url, .. }
+            | Mode::Tunnel { api_url, .. } => api_url.as_deref(),
+        }
+    }
+
+    /// Returns true if this side connects to the targets, and so receives the serverbound
+    /// messages.
+    pub fn is_server(&self) -> bool {
+        match self {
+            Mode::Server { .. } => true,
+            Mode::Client { .. } => false,
+            Mode::Tunnel { end, .. } => *end == End::Exit,
+        }
+    }
+
+    /// The forwards to run. (a single one to the Minecraft server, unless in tunnel mode)
+    pub fn forwards(&self) -> Vec<Forward> {
+        match self {
+            Mode::Server {
+                address,
+                port,
+                channels,
+                framing,
+                ..
+            } => vec![Forward {
+                name: forward::MINECRAFT.to_string(),
+                protocol: Protocol::Tcp,
+                framing: *framing,
+                listen: Vec::new(),
+                target: address.clone().map(|host| Target { host, port: *port }),
+                channels: channels.clone(),
+            }],
+            Mode::Client {
+                listen,
+                channels,
+                framing,
+                ..
+            } => vec![Forward {
+                name: forward::MINECRAFT.to_string(),
+                protocol: Protocol::Tcp,
+                framing: *framing,
+                listen: listen.clone(),
+                target: None,
+                channels: channels.clone(),
+            }],
+            Mode::Tunnel { forwards, .. } => forwards.clone(),
+        }
+    }
+}
+
+/// Returns a usable args struct, along with the matches telling where each value comes from.
+pub fn parse() -> (Args, ArgMatches) {
+    parse_from(std::env::args_os()).unwrap_or_else(|err| err.exit())
+}
+
+/// Same as `parse()`, with the given command line.
+pub fn parse_from<I, T>(args: I) -> Result<(Args, ArgMatches), clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let matches = Args::command().try_get_matches_from(args)?;
+    let args = Args::from_arg_matches(&matches)?;
+    Ok((args, matches))
 }
@@ -0,0 +1,372 @@
+//! Everything to encode the payload of a `Message` into text, which Discord can carry.
+//!
+//! Each codec trades density for safety differently:
+//!
+//! - hex: 2 characters per byte.
+//! - base64: 4 characters per 3 bytes.
+//! - base85: 5 characters per 4 bytes. (RFC 1924 alphabet)
+//! - base32768: 15 bits per character, in the style of base32768. (CJK and Hangul characters)
+//!
+//! Discord limits messages in characters, not bytes, so the non-ASCII base32768 is by far the
+//! densest.
+//!
+//! Both sides of the tunnel must use the same codec.
+
+use base64::Engine;
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::message::MessageError;
+
+/// Encodes bytes into text and back.
+///
+/// A codec may not be able to decode any slice of its output (e.g. base64 groups characters by
+/// 4), so `Message`s are always split bytewise, before encoding: see `max_decoded_len`.
+pub trait PayloadCodec: Sync {
+    /// Encodes the bytes into text.
+    fn encode(&self, data: &[u8]) -> String;
+
+    /// Decodes text produced by `encode`.
+    fn decode(&self, text: &str) -> Result<Vec<u8>, MessageError>;
+
+    /// Returns the number of characters `encode` produces for `bytes` bytes.
+    fn encoded_len(&self, bytes: usize) -> usize;
+
+    /// Returns the maximum number of bytes whose encoding fits in `chars` characters.
+    fn max_decoded_len(&self, chars: usize) -> usize;
+}
+
+/// 2 hex digits (uppercase) per byte.
+pub struct Hex;
+
+impl PayloadCodec for Hex {
+    fn encode(&self, data: &[u8]) -> String {
+        hex::encode_upper(data)
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, MessageError> {
+        hex::decode(text).map_err(|e| MessageError::Decode(format!("Failed to decode hex: {e}")))
+    }
+
+    fn encoded_len(&self, bytes: usize) -> usize {
+        bytes * 2
+    }
+
+    fn max_decoded_len(&self, chars: usize) -> usize {
+        chars / 2
+    }
+}
+
+/// Standard base64, without padding.
+pub struct Base64;
+
+impl PayloadCodec for Base64 {
+    fn encode(&self, data: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(data)
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, MessageError> {
+        base64::engine::general_purpose::STANDARD_NO_PAD
+            .decode(text)
+            .map_err(|e| MessageError::Decode(format!("Failed to decode base64: {e}")))
+    }
+
+    fn encoded_len(&self, bytes: usize) -> usize {
+        // A trailing group of n bytes takes n + 1 characters.
+        let trailing: usize = bytes % 3;
+        bytes / 3 * 4 + if trailing == 0 { 0 } else { trailing + 1 }
+    }
+
+    fn max_decoded_len(&self, chars: usize) -> usize {
+        chars / 4 * 3 + (chars % 4).saturating_sub(1)
+    }
+}
+
+/// Base85 with the RFC 1924 alphabet.
+pub struct Base85;
+
+impl Base85 {
+    const ALPHABET: &'static [u8; 85] =
+        b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+    /// The digit the `base85` crate pads a trailing group with when decoding.
+    const PADDING_DIGIT: u64 = 126;
+
+    /// Checks that no group of 5 characters is worth more than 32 bits.
+    ///
+    /// The `base85` crate does not: it overflows, which panics in debug builds. (e.g. "~~~~~")
+    fn check_groups(text: &str) -> Result<(), MessageError> {
+        for group in text.as_bytes().chunks(5) {
+            let mut value: u64 = 0;
+            for i in 0..5 {
+                let digit: u64 =
+                    match group.get(i) {
+                        Some(c) => Self::ALPHABET.iter().position(|a| a == c).ok_or(
+                            MessageError::Decode(format!(
+                                "Failed to decode base85: invalid character {c:#04X}"
+                            )),
+                        )? as u64,
+                        None => Self::PADDING_DIGIT,
+                    };
+                value = value * 85 + digit;
+            }
+
+            if value > u32::MAX as u64 {
+                return Err(MessageError::Decode(
+                    "Failed to decode base85: group is out of range".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PayloadCodec for Base85 {
+    fn encode(&self, data: &[u8]) -> String {
+        base85::encode(data)
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, MessageError> {
+        Self::check_groups(text)?;
+        base85::decode(text)
+            .map_err(|e| MessageError::Decode(format!("Failed to decode base85: {e}")))
+    }
+
+    fn encoded_len(&self, bytes: usize) -> usize {
+        // A trailing group of n bytes takes n + 1 characters.
+        let trailing: usize = bytes % 4;
+        bytes / 4 * 5 + if trailing == 0 { 0 } else { trailing + 1 }
+    }
+
+    fn max_decoded_len(&self, chars: usize) -> usize {
+        chars / 5 * 4 + (chars % 5).saturating_sub(1)
+    }
+}
+
+/// 15 bits per character, taken from blocks of the Basic Multilingual Plane which Discord leaves
+/// alone. (no combining, whitespace or control characters)
+///
+/// The last character may carry 7 bits or less: it is then taken from a separate, smaller
+/// alphabet, so the decoder knows how many bits are padding.
+pub struct Base32768;
+
+impl Base32768 {
+    /// The ranges of the main alphabet (32768 characters), in order: (first code point, length)
+    const MAIN_RANGES: [(u32, u32); 3] = [
+        // CJK Unified Ideographs.
+        (0x4E00, 20992),
+        // Hangul Syllables.
+        (0xAC00, 11172),
+        // CJK Unified Ideographs Extension A.
+        (0x3400, 604),
+    ];
+
+    /// The first code point of the tail alphabet (128 characters), right after the main alphabet
+    /// in the CJK Unified Ideographs Extension A.
+    const TAIL_START: u32 = 0x3660;
+
+    /// Number of bits carried by a character of the main alphabet.
+    const MAIN_BITS: u32 = 15;
+
+    /// Number of bits carried by a character of the tail alphabet.
+    const TAIL_BITS: u32 = 7;
+
+    fn main_char(mut index: u32) -> char {
+        for (start, len) in Self::MAIN_RANGES {
+            if index < len {
+                return char::from_u32(start + index).expect("The alphabet only has valid chars.");
+            }
+            index -= len;
+        }
+        unreachable!("Index out of the alphabet.")
+    }
+
+    fn main_index(c: char) -> Option<u32> {
+        let code: u32 = c as u32;
+        let mut offset: u32 = 0;
+        for (start, len) in Self::MAIN_RANGES {
+            if (start..start + len).contains(&code) {
+                return Some(offset + code - start);
+            }
+            offset += len;
+        }
+        None
+    }
+
+    fn tail_index(c: char) -> Option<u32> {
+        let code: u32 = c as u32;
+        (Self::TAIL_START..Self::TAIL_START + (1 << Self::TAIL_BITS))
+            .contains(&code)
+            .then(|| code - Self::TAIL_START)
+    }
+}
+
+impl PayloadCodec for Base32768 {
+    fn encode(&self, data: &[u8]) -> String {
+        let mut text = String::with_capacity(self.encoded_len(data.len()) * 3);
+        let mut buffer: u32 = 0;
+        let mut bits: u32 = 0;
+
+        for &byte in data {
+            buffer = (buffer << 8) | byte as u32;
+            bits += 8;
+            if bits >= Self::MAIN_BITS {
+                bits -= Self::MAIN_BITS;
+                text.push(Self::main_char(buffer >> bits));
+                buffer &= (1 << bits) - 1;
+            }
+        }
+
+        // Pads the remaining bits with zeros.
+        if bits > Self::TAIL_BITS {
+            text.push(Self::main_char(buffer << (Self::MAIN_BITS - bits)));
+        } else if bits > 0 {
+            let index: u32 = buffer << (Self::TAIL_BITS - bits);
+            text.push(char::from_u32(Self::TAIL_START + index).expect("Valid tail char."));
+        }
+
+        text
+    }
+
+    fn decode(&self, text: &str) -> Result<Vec<u8>, MessageError> {
+        let mut data: Vec<u8> = Vec::with_capacity(self.max_decoded_len(text.len() / 3));
+        let mut buffer: u32 = 0;
+        let mut bits: u32 = 0;
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            let (index, width) = if let Some(index) = Self::main_index(c) {
+                (index, Self::MAIN_BITS)
+            } else if let Some(index) = Self::tail_index(c) {
+                if chars.peek().is_some() {
+                    return Err(MessageError::Decode(
+                        "Failed to decode base32768: tail character before the end.".to_string(),
+                    ));
+                }
+                (index, Self::TAIL_BITS)
+            } else {
+                return Err(MessageError::Decode(format!(
+                    "Failed to decode base32768: invalid character {c:?}."
+                )));
+            };
+
+            buffer = (buffer << width) | index;
+            bits += width;
+            while bits >= 8 {
+                bits -= 8;
+                data.push((buffer >> bits) as u8);
+                buffer &= (1 << bits) - 1;
+            }
+        }
+
+        // The remaining bits are padding.
+        Ok(data)
+    }
+
+    fn encoded_len(&self, bytes: usize) -> usize {
+        (bytes * 8).div_ceil(Self::MAIN_BITS as usize)
+    }
+
+    fn max_decoded_len(&self, chars: usize) -> usize {
+        chars * Self::MAIN_BITS as usize / 8
+    }
+}
+
+/// The codecs that can be chosen from the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Codec {
+    #[default]
+    Hex,
+    Base64,
+    Base85,
+    Base32768,
+}
+
+impl Codec {
+    /// Returns the implementation of the codec.
+    pub fn get(self) -> &'static dyn PayloadCodec {
+        match self {
+            Codec::Hex => &Hex,
+            Codec::Base64 => &Base64,
+            Codec::Base85 => &Base85,
+            Codec::Base32768 => &Base32768,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, RngCore};
+
+    const CODECS: [Codec; 4] = [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768];
+
+    #[test]
+    fn test_round_trip_and_encoded_len() {
+        for codec in CODECS.map(Codec::get) {
+            for len in 0..64 {
+                let mut data = vec![0; len];
+                rand::rng().fill_bytes(&mut data);
+
+                let text = codec.encode(&data);
+                assert_eq!(text.chars().count(), codec.encoded_len(len));
+                assert_eq!(codec.decode(&text).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_max_decoded_len_fits() {
+        for codec in CODECS.map(Codec::get) {
+            for chars in 0..200 {
+                let bytes = codec.max_decoded_len(chars);
+                assert!(codec.encoded_len(bytes) <= chars);
+                // It is the maximum.
+                assert!(codec.encoded_len(bytes + 1) > chars);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        assert!(Codec::Hex.get().decode("ABC").is_err());
+        assert!(Codec::Hex.get().decode("ZZ").is_err());
+        assert!(Codec::Base64.get().decode("A").is_err());
+        assert!(Codec::Base64.get().decode("A=A=").is_err());
+        assert!(Codec::Base85.get().decode("\"\"").is_err());
+        // Worth more than 32 bits.
+        assert!(Codec::Base85.get().decode("~~~~~").is_err());
+        assert!(Codec::Base85.get().decode("00000~~").is_err());
+        assert!(Codec::Base32768.get().decode("A").is_err());
+        // A tail character can only be the last one.
+        assert!(Codec::Base32768.get().decode("\u{3660}\u{4E00}").is_err());
+    }
+
+    #[test]
+    fn test_denser_than_hex() {
+        let len: usize = rand::rng().random_range(100..1000);
+        let hex = Codec::Hex.get().encoded_len(len);
+        assert!(Codec::Base64.get().encoded_len(len) < hex);
+        assert!(Codec::Base85.get().encoded_len(len) < Codec::Base64.get().encoded_len(len));
+        assert!(Codec::Base32768.get().encoded_len(len) < Codec::Base85.get().encoded_len(len));
+    }
+
+    #[test]
+    fn test_base32768_alphabet() {
+        // Every index maps to a distinct char, and back.
+        let mut seen = std::collections::HashSet::new();
+        for index in 0..1 << Base32768::MAIN_BITS {
+            let c = Base32768::main_char(index);
+            assert_eq!(Base32768::main_index(c), Some(index));
+            assert_eq!(Base32768::tail_index(c), None);
+            assert!(seen.insert(c));
+        }
+        for index in 0..1 << Base32768::TAIL_BITS {
+            let c = char::from_u32(Base32768::TAIL_START + index).unwrap();
+            assert_eq!(Base32768::main_index(c), None);
+            assert_eq!(Base32768::tail_index(c), Some(index));
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! Everything to compress the payload of data `Message`s before encoding.
+//!
+//! Minecraft traffic before login (and of offline-mode servers) is not encrypted, and compresses
+//! very well.
+//!
+//! A compressed frame has its own kind (`MessageKind::Deflated`), so the receiver knows whether to
+//! decompress it, whatever its own settings.
+
+use std::io::{Read, Write};
+
+use clap::ValueEnum;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use log::{debug, error};
+use serde::Deserialize;
+
+use crate::message::{Message, MessageError, MessageKind};
+
+/// Maximum size of a decompressed payload.
+///
+/// The sender never compresses more than the reads of one flush, so anything bigger is not from a
+/// well-behaved peer. (e.g. a decompression bomb)
+pub const MAX_DECOMPRESSED_LEN: usize = 1024 * 1024;
+
+/// The compressions that can be chosen from the command line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    /// Sends the payloads as they are.
+    #[default]
+    None,
+    /// Deflate (RFC 1951), without any header.
+    Deflate,
+}
+
+impl Compression {
+    /// Compresses a data `Message` into a `MessageKind::Deflated` one.
+    ///
+    /// The message is returned as-is if it is not data, or if compressing does not make it smaller.
+    pub fn compress(self, message: Message) -> Message {
+        if self == Compression::None || message.kind != MessageKind::Data {
+            return message;
+        }
+
+        let original_len: usize = message.payload().len();
+        let compressed: Vec<u8> = match deflate(message.payload()) {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                error!("Failed to compress the payload: {err}");
+                return message;
+            }
+        };
+
+        debug!(
+            "Compressed {original_len} bytes into {} bytes (ratio: {:.2})",
+            compressed.len(),
+            original_len as f64 / compressed.len().max(1) as f64
+        );
+
+        if compressed.len() >= original_len {
+            return message;
+        }
+
+        Message::new(
+            MessageKind::Deflated,
+            message.direction,
+            message.session,
+            message.seq,
+            message.part,
+            compressed,
+        )
+        .with_codec(message.codec())
+    }
+}
+
+/// Decompresses a `MessageKind::Deflated` message back into a data `Message`.
+///
+/// Any other message is returned as-is.
+pub fn decompress(message: Message) -> Result<Message, MessageError> {
+    if message.kind != MessageKind::Deflated {
+        return Ok(message);
+    }
+
+    let payload: Vec<u8> = inflate(message.payload())?;
+    Ok(Message::new(
+        MessageKind::Data,
+        message.direction,
+        message.session,
+        message.seq,
+        message.part,
+        payload,
+    )
+    .with_codec(message.codec()))
+}
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, MessageError> {
+    let mut decompressed: Vec<u8> = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_DECOMPRESSED_LEN as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|e| MessageError::Decode(format!("Failed to decompress the payload: {e}")))?;
+
+    if decompressed.len() > MAX_DECOMPRESSED_LEN {
+        return Err(MessageError::Decode(format!(
+            "Decompressed payload is bigger than {MAX_DECOMPRESSED_LEN} bytes."
+        )));
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageDirection;
+    use crate::session::SessionId;
+    use rand::RngCore;
+
+    fn data(payload: &[u8]) -> Message {
+        Message::from_bytes(payload, MessageDirection::Serverbound, SessionId::new(3))
+    }
+
+    #[test]
+    fn test_compress_round_trip() {
+        let payload: Vec<u8> = b"minecraft:overworld ".repeat(100);
+        let part = crate::partitioning::Part::new(1, 1).unwrap();
+        let message = Message::new(
+            MessageKind::Data,
+            MessageDirection::Serverbound,
+            SessionId::new(3),
+            12,
+            part,
+            payload.clone(),
+        );
+
+        let compressed = Compression::Deflate.compress(message);
+        assert_eq!(compressed.kind, MessageKind::Deflated);
+        assert_eq!(compressed.seq, 12);
+        assert!(compressed.payload().len() < payload.len());
+        assert!(!compressed.is_control());
+
+        let decompressed = decompress(compressed).unwrap();
+        assert_eq!(decompressed.kind, MessageKind::Data);
+        assert_eq!(decompressed.seq, 12);
+        assert_eq!(decompressed.payload(), &payload[..]);
+    }
+
+    #[test]
+    fn test_compress_keeps_incompressible() {
+        let mut payload = vec![0; 256];
+        rand::rng().fill_bytes(&mut payload);
+
+        let message = Compression::Deflate.compress(data(&payload));
+        assert_eq!(message.kind, MessageKind::Data);
+        assert_eq!(message.payload(), &payload[..]);
+
+        let message = Compression::None.compress(data(&[0; 256]));
+        assert_eq!(message.kind, MessageKind::Data);
+    }
+
+    #[test]
+    fn test_decompress_invalid_and_bomb() {
+        let part = crate::partitioning::Part::new(1, 1).unwrap();
+        let deflated = |payload: Vec<u8>| {
+            Message::new(
+                MessageKind::Deflated,
+                MessageDirection::Clientbound,
+                SessionId::new(1),
+                1,
+                part,
+                payload,
+            )
+        };
+
+        assert!(decompress(deflated(vec![0xFF; 16])).is_err());
+
+        let bomb = deflate(&vec![0; MAX_DECOMPRESSED_LEN + 1]).unwrap();
+        assert!(decompress(deflated(bomb)).is_err());
+    }
+}
@@ -0,0 +1,751 @@
+//! Everything to load the settings from a TOML configuration file.
+//!
+//! Every option of the command line can also be set in the file, except the ones that only make
+//! sense on the command line. The command line always wins: the file only fills in what was not
+//! given there. Some settings (the timeouts) are only in the file.
+//!
+//! ```toml
+//! token_file = "/run/secrets/discraft_token"  # or `token = "..."`, or `token_env = "VAR"`
+//! guild_id = 123456789012345678
+//! channels = [123456789012345679, 123456789012345680]
+//! codec = "base85"
+//! compression = "deflate"
+//! framing = "minecraft"  # not for the tunnel mode: each forward has its own
+//! secret_file = "/run/secrets/discraft_secret"  # or `secret = "..."`
+//! peer_bots = [123456789012345681]
+//! # api_url = "http://127.0.0.1:8080"  # a stand-in for the Discord API (e.g. for testing)
+//!
+//! [server]
+//! address = "mc.example.com"
+//!
+//! [client]
+//! listen = ["0.0.0.0:25565", "[::1]:25565"]
+//!
+//! [timeouts]
+//! reassembly_secs = 30
+//!
+//! # Only for the tunnel mode
+//! [[forwards]]
+//! name = "ssh"
+//! listen = ["127.0.0.1:2222"]
+//! target = "build-box:22"
+//! channels = [123456789012345682]
+//!
+//! [[forwards]]
+//! name = "creative"
+//! framing = "minecraft"
+//! listen = ["127.0.0.1:25566"]
+//! target = "creative.example.com:25565"
+//! channels = [123456789012345684]
+//!
+//! [[forwards]]
+//! name = "geyser"
+//! protocol = "udp"
+//! listen = ["0.0.0.0:19132"]
+//! target = "127.0.0.1:19132"
+//! channels = [123456789012345683]
+//! ```
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use log::{info, warn};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::cli;
+use crate::codec::Codec;
+use crate::compression::Compression;
+use crate::crypto::FrameMode;
+use crate::forward::{self, Forward, Framing};
+
+/// The file read when `check-config` is not given any.
+pub const DEFAULT_PATH: &str = "discraft.toml";
+
+/// The file the channel IDs used to be read from, still read if no channel is configured.
+const LEGACY_CHANNEL_IDS_PATH: &str = "channel_ids.txt";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+
+    #[error("Invalid configuration file: {0}")]
+    Parse(#[from] toml::de::Error),
+
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// The content of a configuration file. Everything is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The Discord bot token.
+    pub token: Option<String>,
+    /// A file containing the Discord bot token.
+    pub token_file: Option<PathBuf>,
+    /// The environment variable containing the Discord bot token.
+    pub token_env: Option<String>,
+    pub guild_id: Option<u64>,
+    #[serde(default)]
+    pub channels: Vec<u64>,
+    pub codec: Option<Codec>,
+    pub compression: Option<Compression>,
+    /// How the server and client sides cut the data into messages. (see `forward::Framing`)
+    pub framing: Option<Framing>,
+    pub secret: Option<String>,
+    /// A file containing the secret.
+    pub secret_file: Option<PathBuf>,
+    pub frame_mode: Option<FrameMode>,
+    #[serde(default)]
+    pub peer_bots: Vec<u64>,
+    /// The base URL of the Discord REST API. (the real Discord's if not set)
+    pub api_url: Option<String>,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    #[serde(default)]
+    pub timeouts: Timeouts,
+    /// The forwards of the tunnel mode. (see `forward`)
+    #[serde(default)]
+    pub forwards: Vec<Forward>,
+}
+
+/// The settings only used on the server side.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub address: Option<String>,
+    pub port: Option<u16>,
+}
+
+/// The settings only used on the client side.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+}
+
+/// How long to wait for things, in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// How long the parts of a partitioned message are kept waiting for the others.
+    pub reassembly_secs: u64,
+    /// How long a closed session waits for the peer to acknowledge its last messages.
+    pub linger_secs: u64,
+    /// How long the server side tries to connect to the Minecraft server.
+    pub connect_secs: u64,
+    /// How long a UDP session lives without any datagram, in either direction.
+    pub udp_idle_secs: u64,
+    /// How often the server side sends the status of the Minecraft server to the client side.
+    /// (see `status`)
+    pub status_refresh_secs: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            reassembly_secs: 30,
+            linger_secs: 30,
+            connect_secs: 10,
+            udp_idle_secs: 60,
+            status_refresh_secs: 30,
+        }
+    }
+}
+
+/// The timeouts of this run.
+static CURRENT_TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+impl Timeouts {
+    /// Chooses the timeouts of this run. Can only be done once.
+    pub fn init(self) {
+        if CURRENT_TIMEOUTS.set(self).is_err() {
+            warn!("The timeouts were already chosen. Ignored {self:?}.");
+        }
+    }
+
+    /// Returns the timeouts of this run. (the defaults if none were chosen)
+    pub fn current() -> Timeouts {
+        CURRENT_TIMEOUTS.get().copied().unwrap_or_default()
+    }
+
+    pub fn reassembly(&self) -> Duration {
+        Duration::from_secs(self.reassembly_secs)
+    }
+
+    pub fn linger(&self) -> Duration {
+        Duration::from_secs(self.linger_secs)
+    }
+
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect_secs)
+    }
+
+    pub fn udp_idle(&self) -> Duration {
+        Duration::from_secs(self.udp_idle_secs)
+    }
+
+    pub fn status_refresh(&self) -> Duration {
+        Duration::from_secs(self.status_refresh_secs)
+    }
+
+    fn problems(&self) -> Vec<String> {
+        [
+            ("reassembly_secs", self.reassembly_secs),
+            ("linger_secs", self.linger_secs),
+            ("connect_secs", self.connect_secs),
+            ("udp_idle_secs", self.udp_idle_secs),
+            ("status_refresh_secs", self.status_refresh_secs),
+        ]
+        .into_iter()
+        .filter(|(_, secs)| *secs == 0)
+        .map(|(name, _)| format!("timeouts.{name} must be at least 1"))
+        .collect()
+    }
+}
+
+/// Reads a file holding a secret value. (e.g. a token) Surrounding whitespace is ignored.
+fn read_secret_file(path: &Path) -> Result<String, ConfigError> {
+    let content: String =
+        fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    let content: &str = content.trim();
+    if content.is_empty() {
+        return Err(ConfigError::Invalid(format!("{} is empty", path.display())));
+    }
+    Ok(content.to_string())
+}
+
+/// Parses a list of channel IDs, one per line. Blank lines and `#` comments are ignored.
+pub fn parse_channel_ids(text: &str) -> Result<Vec<u64>, ConfigError> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse()
+                .map_err(|_| ConfigError::Invalid(format!("{line:?} is not a channel ID")))
+        })
+        .collect()
+}
+
+/// Returns true if the argument was given on the command line. (or in its environment variable)
+fn is_given(matches: &ArgMatches, id: &str) -> bool {
+    matches!(
+        matches.value_source(id),
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+impl Config {
+    /// Reads and parses a configuration file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content: String =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        Self::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// Returns the token, from whichever of `token`, `token_file` and `token_env` is set.
+    pub fn token(&self) -> Result<Option<String>, ConfigError> {
+        match (&self.token, &self.token_file, &self.token_env) {
+            (None, None, None) => Ok(None),
+            (Some(token), None, None) => Ok(Some(token.clone())),
+            (None, Some(path), None) => read_secret_file(path).map(Some),
+            (None, None, Some(var)) => std::env::var(var).map(Some).map_err(|_| {
+                ConfigError::Invalid(format!("the environment variable {var} is not set"))
+            }),
+            _ => Err(ConfigError::Invalid(
+                "only one of token, token_file and token_env can be set".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the secret, from whichever of `secret` and `secret_file` is set.
+    pub fn secret(&self) -> Result<Option<String>, ConfigError> {
+        match (&self.secret, &self.secret_file) {
+            (None, None) => Ok(None),
+            (Some(secret), None) => Ok(Some(secret.clone())),
+            (None, Some(path)) => read_secret_file(path).map(Some),
+            (Some(_), Some(_)) => Err(ConfigError::Invalid(
+                "only one of secret and secret_file can be set".to_string(),
+            )),
+        }
+    }
+
+    /// Lists what is wrong in the file, without connecting to anything.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems: Vec<String> = Vec::new();
+
+        for result in [self.token(), self.secret()] {
+            if let Err(err) = result {
+                problems.push(err.to_string());
+            }
+        }
+        if matches!(self.secret.as_deref(), Some("")) {
+            problems.push("the secret is empty".to_string());
+        }
+        if self.guild_id == Some(0) {
+            problems.push("guild_id cannot be 0".to_string());
+        }
+        if self.channels.contains(&0) {
+            problems.push("channels cannot contain 0".to_string());
+        }
+        if self.peer_bots.contains(&0) {
+            problems.push("peer_bots cannot contain 0".to_string());
+        }
+        if let Some(url) = &self.api_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("api_url {url:?} is not an HTTP(S) URL"));
+            }
+        }
+        if matches!(self.server.address.as_deref(), Some("")) {
+            problems.push("server.address is empty".to_string());
+        }
+        problems.extend(self.timeouts.problems());
+        problems.extend(forward::problems(&self.forwards, None));
+
+        problems
+    }
+
+    /// Fills in the options of the side that were not given on the command line.
+    ///
+    /// `matches` are the matches of the side's subcommand.
+    pub fn apply(&self, mode: &mut cli::Mode, matches: &ArgMatches) -> Result<(), ConfigError> {
+        match mode {
+            cli::Mode::Server {
+                address,
+                port,
+                channels,
+                framing,
+                ..
+            } => {
+                if let Some(value) = self.framing.filter(|_| !is_given(matches, "framing")) {
+                    *framing = value;
+                }
+                if address.is_none() {
+                    address.clone_from(&self.server.address);
+                }
+                if port.is_none() {
+                    *port = self.server.port;
+                }
+                if channels.is_empty() {
+                    channels.clone_from(&self.channels);
+                }
+            }
+            cli::Mode::Client {
+                listen,
+                channels,
+                framing,
+                ..
+            } => {
+                if let Some(value) = self.framing.filter(|_| !is_given(matches, "framing")) {
+                    *framing = value;
+                }
+                if !is_given(matches, "listen") && !self.client.listen.is_empty() {
+                    listen.clone_from(&self.client.listen);
+                }
+                if channels.is_empty() {
+                    channels.clone_from(&self.channels);
+                }
+            }
+            cli::Mode::Tunnel { forwards, .. } => forwards.clone_from(&self.forwards),
+        }
+
+        match mode {
+            cli::Mode::Server {
+                token,
+                guild_id,
+                codec,
+                compression,
+                secret,
+                frame_mode,
+                peer_bots,
+                api_url,
+                ..
+            }
+            | cli::Mode::Client {
+                token,
+                guild_id,
+                codec,
+                compression,
+                secret,
+                frame_mode,
+                peer_bots,
+                api_url,
+                ..
+            }
+            | cli::Mode::Tunnel {
+                token,
+                guild_id,
+                codec,
+                compression,
+                secret,
+                frame_mode,
+                peer_bots,
+                api_url,
+                ..
+            } => {
+                if token.is_none() {
+                    *token = self.token()?;
+                }
+                if guild_id.is_none() {
+                    *guild_id = self.guild_id;
+                }
+                if let Some(value) = self.codec.filter(|_| !is_given(matches, "codec")) {
+                    *codec = value;
+                }
+                if let Some(value) = self
+                    .compression
+                    .filter(|_| !is_given(matches, "compression"))
+                {
+                    *compression = value;
+                }
+                if secret.is_none() {
+                    *secret = self.secret()?;
+                }
+                if let Some(value) = self.frame_mode.filter(|_| !is_given(matches, "frame_mode")) {
+                    *frame_mode = value;
+                }
+                if peer_bots.is_empty() {
+                    peer_bots.clone_from(&self.peer_bots);
+                }
+                if api_url.is_none() {
+                    api_url.clone_from(&self.api_url);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that a side has everything it needs to run.
+fn check_mode(mode: &cli::Mode) -> Result<(), ConfigError> {
+    let missing = |what: &str, flag: &str, key: &str| {
+        Err(ConfigError::Invalid(format!(
+            "no {what} given ({flag}, or `{key}` in the configuration file)"
+        )))
+    };
+
+    if let cli::Mode::Server { address: None, .. } = mode {
+        return missing("server address", "--address", "server.address");
+    }
+    if mode.token().is_empty() {
+        return missing("Discord bot token", "--token", "token");
+    }
+    if mode.guild_id() == 0 {
+        return missing("Discord guild ID", "--guild-id", "guild_id");
+    }
+
+    match mode {
+        cli::Mode::Tunnel { end, forwards, .. } => {
+            if forwards.is_empty() {
+                return Err(ConfigError::Invalid(
+                    "no forward given (`forwards` in the configuration file)".to_string(),
+                ));
+            }
+            let problems: Vec<String> = forward::problems(forwards, Some(*end));
+            if !problems.is_empty() {
+                return Err(ConfigError::Invalid(problems.join(", ")));
+            }
+        }
+        _ if mode.forwards().iter().any(|f| f.channels.is_empty()) => {
+            return missing("Discord channel", "--channel", "channels");
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Builds the settings of the side to run from the command line and the configuration file.
+///
+/// The timeouts of the configuration file are chosen for this run.
+pub fn load_mode(
+    mut mode: cli::Mode,
+    config_path: Option<&Path>,
+    matches: &ArgMatches,
+) -> Result<cli::Mode, ConfigError> {
+    let config: Config = match config_path {
+        Some(path) => {
+            info!("Reading the configuration file {}", path.display());
+            Config::load(path)?
+        }
+        None => Config::default(),
+    };
+
+    let problems: Vec<String> = config.problems();
+    if !problems.is_empty() {
+        return Err(ConfigError::Invalid(problems.join(", ")));
+    }
+
+    let side_matches: &ArgMatches = matches
+        .subcommand()
+        .map(|(_, side_matches)| side_matches)
+        .unwrap_or(matches);
+    config.apply(&mut mode, side_matches)?;
+
+    if let cli::Mode::Server { channels, .. } | cli::Mode::Client { channels, .. } = &mut mode {
+        if channels.is_empty() && Path::new(LEGACY_CHANNEL_IDS_PATH).exists() {
+            warn!("Reading the channel IDs from {LEGACY_CHANNEL_IDS_PATH}. Set `channels` in the configuration file instead.");
+            let content: String = fs::read_to_string(LEGACY_CHANNEL_IDS_PATH)
+                .map_err(|e| ConfigError::Read(PathBuf::from(LEGACY_CHANNEL_IDS_PATH), e))?;
+            *channels = parse_channel_ids(&content)?;
+        }
+    }
+
+    check_mode(&mode)?;
+    config.timeouts.init();
+
+    Ok(mode)
+}
+
+/// Checks a configuration file for the `check-config` subcommand. Returns true if it is valid.
+///
+/// The report is printed to the standard output.
+pub fn check_config(path: &Path) -> bool {
+    let config: Config = match Config::load(path) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("{}: {err}", path.display());
+            return false;
+        }
+    };
+
+    let problems: Vec<String> = config.problems();
+    for problem in &problems {
+        println!("{}: {problem}", path.display());
+    }
+
+    // What is not in the file has to be given on the command line.
+    let mut missing: Vec<&str> = Vec::new();
+    if config.token.is_none() && config.token_file.is_none() && config.token_env.is_none() {
+        missing.push("--token");
+    }
+    if config.guild_id.is_none() {
+        missing.push("--guild-id");
+    }
+    // A file with forwards is for the tunnel mode, which needs neither.
+    if config.forwards.is_empty() {
+        if config.channels.is_empty() {
+            missing.push("--channel");
+        }
+        if config.server.address.is_none() {
+            missing.push("--address (server side)");
+        }
+    }
+    if !missing.is_empty() {
+        println!(
+            "Not in the file, to give on the command line: {}",
+            missing.join(", ")
+        );
+    }
+
+    if problems.is_empty() {
+        println!("{}: OK", path.display());
+    }
+    problems.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode_from(args: &[&str], config: &str) -> Result<cli::Mode, ConfigError> {
+        let (args, matches) = cli::parse_from(args).unwrap();
+        let cli::Command::Run(mut mode) = args.command else {
+            panic!("not a side");
+        };
+        let side_matches = matches.subcommand().unwrap().1;
+        Config::parse(config)?.apply(&mut mode, side_matches)?;
+        check_mode(&mode)?;
+        Ok(mode)
+    }
+
+    const CONFIG: &str = r#"
+        token = "file-token"
+        guild_id = 42
+        channels = [1, 2]
+        codec = "base32768"
+        frame_mode = "sign"
+        framing = "minecraft"
+
+        [server]
+        address = "mc.example.com"
+        port = 25566
+
+        [client]
+        listen = ["[::1]:25565", "127.0.0.1:25565"]
+
+        [timeouts]
+        linger_secs = 5
+    "#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(CONFIG).unwrap();
+        assert_eq!(config.codec, Some(Codec::Base32768));
+        assert_eq!(config.frame_mode, Some(FrameMode::Sign));
+        assert_eq!(config.client.listen.len(), 2);
+        assert_eq!(config.timeouts.linger(), Duration::from_secs(5));
+        assert_eq!(config.timeouts.reassembly(), Duration::from_secs(30));
+        assert!(config.problems().is_empty());
+
+        assert!(Config::parse("tokn = \"typo\"").is_err());
+        assert!(Config::parse("codec = \"base16\"").is_err());
+        assert!(Config::parse("[client]\nlisten = [\"localhost\"]").is_err());
+        assert!(!Config::parse("api_url = \"discord.com\"")
+            .unwrap()
+            .problems()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_command_line_overrides_file() {
+        let mode = mode_from(&["discraft", "server"], CONFIG).unwrap();
+        assert_eq!(mode.token(), "file-token");
+        assert_eq!(mode.guild_id(), 42);
+        assert_eq!(mode.forwards()[0].channels, [1, 2]);
+        assert_eq!(mode.forwards()[0].framing, Framing::Minecraft);
+        let cli::Mode::Server {
+            address,
+            port,
+            codec,
+            ..
+        } = &mode
+        else {
+            panic!("not the server side");
+        };
+        assert_eq!(address.as_deref(), Some("mc.example.com"));
+        assert_eq!(*port, Some(25566));
+        assert_eq!(*codec, Codec::Base32768);
+
+        let mode = mode_from(
+            &[
+                "discraft",
+                "client",
+                "-t",
+                "cli-token",
+                "-c",
+                "hex",
+                "-l",
+                "0.0.0.0:1",
+            ],
+            CONFIG,
+        )
+        .unwrap();
+        assert_eq!(mode.token(), "cli-token");
+        let cli::Mode::Client { listen, codec, .. } = &mode else {
+            panic!("not the client side");
+        };
+        assert_eq!(*listen, ["0.0.0.0:1".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(*codec, Codec::Hex);
+        assert_eq!(mode.forwards()[0].framing, Framing::Minecraft);
+
+        // The default listen address is replaced by the file's.
+        let mode = mode_from(&["discraft", "client"], CONFIG).unwrap();
+        let cli::Mode::Client { listen, .. } = &mode else {
+            panic!("not the client side");
+        };
+        assert_eq!(listen.len(), 2);
+    }
+
+    #[test]
+    fn test_tunnel_forwards() {
+        let config = r#"
+            token = "t"
+            guild_id = 1
+
+            [[forwards]]
+            name = "ssh"
+            listen = ["127.0.0.1:2222"]
+            target = "build-box:22"
+            channels = [10]
+
+            [[forwards]]
+            name = "rcon"
+            target = "127.0.0.1:25575"
+            channels = [11, 12]
+        "#;
+
+        let mode = mode_from(&["discraft", "tunnel", "--end", "exit"], config).unwrap();
+        assert!(mode.is_server());
+        let forwards = mode.forwards();
+        assert_eq!(forwards.len(), 2);
+        assert_eq!(forwards[1].target.as_ref().unwrap().port, Some(25575));
+
+        // The rcon forward has nowhere to listen on.
+        assert!(mode_from(&["discraft", "tunnel", "--end", "entry"], config).is_err());
+        assert!(mode_from(
+            &["discraft", "tunnel", "--end", "exit"],
+            "token = \"t\"\nguild_id = 1"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_missing_settings() {
+        // `cli::Mode` is not `Debug`, so that its secrets never end up in the logs.
+        let err = mode_from(&["discraft", "client"], "guild_id = 1\nchannels = [1]")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("--token"));
+
+        let err = mode_from(
+            &["discraft", "server", "-t", "t", "-g", "1"],
+            "channels = [1]",
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("--address"));
+
+        assert!(mode_from(
+            &["discraft", "client", "-t", "t", "-g", "1", "--channel", "3"],
+            ""
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_token_sources() {
+        let path = std::env::temp_dir().join(format!("discraft-token-{}", std::process::id()));
+        fs::write(&path, "  secret-token\n").unwrap();
+
+        let config = Config {
+            token_file: Some(path.clone()),
+            ..Config::default()
+        };
+        assert_eq!(config.token().unwrap().as_deref(), Some("secret-token"));
+        fs::remove_file(&path).unwrap();
+        assert!(config.token().is_err());
+
+        let config = Config {
+            token: Some(String::from("a")),
+            token_env: Some(String::from("DISCRAFT_TEST_TOKEN")),
+            ..Config::default()
+        };
+        assert!(config.token().is_err());
+        assert!(!config.problems().is_empty());
+    }
+
+    #[test]
+    fn test_parse_channel_ids() {
+        assert_eq!(
+            parse_channel_ids("1\n\n  2  \n# comment\n3\n").unwrap(),
+            [1, 2, 3]
+        );
+        assert!(parse_channel_ids("1\nchannel\n").is_err());
+    }
+}
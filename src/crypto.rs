@@ -0,0 +1,692 @@
+//! Everything to encrypt and authenticate the frames posted to Discord.
+//!
+//! Anyone in the guild can read (and write in) the tunnel's channels. With a secret shared by both
+//! sides, every frame is sealed in one of two ways:
+//!
+//! - encrypt: the payload is encrypted with ChaCha20-Poly1305, and the header is authenticated. A
+//!   frame cannot be read, forged, altered, or moved to another session.
+//!   `[envelope (24 bytes)][ciphertext][tag (16 bytes)]`
+//! - sign: the header and the payload are signed with HMAC-SHA256. (truncated to 16 bytes) A frame
+//!   can be read, but not forged, altered, or moved to another session.
+//!   `[envelope (24 bytes)][payload][tag (16 bytes)]`
+//!
+//! Each side draws a run at startup, and the two sides of a forward tell each other theirs. (see
+//! `Link`) The envelope holds the run of the sender, the run of the receiver, and a counter. The
+//! key of a frame is derived (HKDF-SHA256) from the secret, the direction and both runs, and the
+//! counter is its nonce: each frame sent (including each retransmission) has its own.
+//!
+//! A frame is only accepted from the current run of the peer, for our current run. So the frames
+//! of the sessions of a previous run are rejected, whichever side restarted, and within a run the
+//! receiver remembers the recent counters: a frame posted twice is dropped. (replay protection)
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use clap::ValueEnum;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::Deserialize;
+use sha2::Sha256;
+
+use crate::message::{Control, Message, MessageDirection, MessageError, MessageKind};
+
+/// Size of a run in the envelope.
+const RUN_LEN: usize = 8;
+
+/// Size of the envelope prepended to the ciphertext: the run of the sender, the run of the
+/// receiver, and the counter.
+const ENVELOPE_LEN: usize = 2 * RUN_LEN + 8;
+
+/// Size of the authentication tag appended to the payload. (Poly1305, or truncated HMAC-SHA256)
+const TAG_LEN: usize = 16;
+
+/// Number of bytes sealing adds to a payload.
+pub const OVERHEAD: usize = ENVELOPE_LEN + TAG_LEN;
+
+/// How many frames older than the newest one are still accepted. (they may arrive out of order,
+/// e.g. through different channels)
+const REPLAY_WINDOW: u64 = 1024;
+
+/// How often HELLOs are sent while the run of the peer is not known, and answered at most when
+/// they name another run than ours.
+pub const HELLO_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How the frames are sealed, when a secret is given.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrameMode {
+    /// Encrypts and authenticates the frames. (ChaCha20-Poly1305)
+    #[default]
+    Encrypt,
+    /// Only authenticates the frames: they stay readable in Discord. (HMAC-SHA256)
+    Sign,
+}
+
+impl FrameMode {
+    /// Separates the keys of each mode, and of Discraft from other uses of the same secret.
+    fn key_context(self) -> &'static [u8] {
+        match self {
+            FrameMode::Encrypt => b"discraft frame key v2",
+            FrameMode::Sign => b"discraft frame signing key v2",
+        }
+    }
+}
+
+/// The key sealing the frames, for each `FrameMode`.
+enum FrameKey {
+    Encrypt(ChaCha20Poly1305),
+    Sign(Hmac<Sha256>),
+}
+
+/// The nonces already received from one peer, as a sliding window.
+#[derive(Default)]
+struct ReplayWindow {
+    highest: u64,
+    seen: BTreeSet<u64>,
+}
+
+impl ReplayWindow {
+    /// Records the counter. Returns false if it was already received, or is too old to tell.
+    fn accept(&mut self, counter: u64) -> bool {
+        if counter < self.highest.saturating_sub(REPLAY_WINDOW) || !self.seen.insert(counter) {
+            return false;
+        }
+
+        if counter > self.highest {
+            self.highest = counter;
+            let oldest: u64 = self.highest.saturating_sub(REPLAY_WINDOW);
+            self.seen = self.seen.split_off(&oldest);
+        }
+        true
+    }
+}
+
+/// What we know of the run of the peer.
+#[derive(Default)]
+struct Peer {
+    /// The current run of the peer. (0 until a HELLO tells it)
+    run: u64,
+    /// The counters received from it.
+    received: ReplayWindow,
+    /// Its previous runs, which are never adopted again.
+    retired: HashSet<u64>,
+}
+
+/// The runs of both sides of a forward, and the cipher sealing its frames.
+///
+/// Both sides send `Hello { run, peer_run: 0 }` until they know the run of the peer. A HELLO
+/// naming our run could only be sealed since we started: its run is adopted, and answered so
+/// that the peer adopts ours too. A HELLO naming another run (from a peer that does not know us,
+/// or replayed) is answered with ours, at most every `HELLO_INTERVAL` for each run.
+///
+/// Without a cipher, the frames are sent and received as they are, and no HELLO is sent.
+#[derive(Default)]
+pub struct Link {
+    /// The cipher of this run, if a secret was given.
+    cipher: Option<Arc<FrameCipher>>,
+    peer: Mutex<Peer>,
+    /// When the HELLOs of each run naming another run were last answered. (within the interval)
+    answered_at: Mutex<HashMap<u64, Instant>>,
+}
+
+impl Link {
+    pub fn new(cipher: Option<Arc<FrameCipher>>) -> Self {
+        Self {
+            cipher,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the current run of the peer, if a HELLO told it.
+    pub fn peer_run(&self) -> Option<u64> {
+        let run: u64 = self.peer().run;
+        (run != 0).then_some(run)
+    }
+
+    /// Returns the number of bytes sealing adds to a payload. (0 if sealing is disabled)
+    pub fn overhead(&self) -> usize {
+        if self.cipher.is_some() {
+            OVERHEAD
+        } else {
+            0
+        }
+    }
+
+    /// Seals the message for the peer if sealing is enabled.
+    pub fn seal(&self, message: Message) -> Result<Message, MessageError> {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(self, &message),
+            None => Ok(message),
+        }
+    }
+
+    /// Opens the message received from the peer if sealing is enabled.
+    pub fn open(&self, message: Message) -> Result<Message, MessageError> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(self, &message),
+            None => Ok(message),
+        }
+    }
+
+    /// Returns the HELLO to send, if sealing is enabled and the run of the peer is not known yet.
+    pub fn hello(&self) -> Option<Control> {
+        let cipher: &FrameCipher = self.cipher.as_deref()?;
+        self.peer_run().is_none().then_some(Control::Hello {
+            run: cipher.run,
+            peer_run: 0,
+        })
+    }
+
+    /// Takes a HELLO received from the peer. Returns the HELLO to answer with, if any.
+    pub fn on_hello(&self, run: u64, peer_run: u64) -> Option<Control> {
+        self.on_hello_at(run, peer_run, Instant::now())
+    }
+
+    fn on_hello_at(&self, run: u64, peer_run: u64, now: Instant) -> Option<Control> {
+        let cipher: &FrameCipher = self.cipher.as_deref()?;
+        let answer = Control::Hello {
+            run: cipher.run,
+            peer_run: run,
+        };
+
+        if peer_run == cipher.run {
+            let mut peer = self.peer();
+            if peer.run == run || peer.retired.contains(&run) {
+                return None;
+            }
+            let previous: u64 = std::mem::replace(&mut peer.run, run);
+            if previous != 0 {
+                peer.retired.insert(previous);
+            }
+            peer.received = ReplayWindow::default();
+            info!("The peer is now at run {run:016x}");
+            return Some(answer);
+        }
+
+        let mut answered_at = lock(&self.answered_at);
+        answered_at.retain(|_, answered_at| now < *answered_at + HELLO_INTERVAL);
+        if answered_at.contains_key(&run) {
+            return None;
+        }
+        answered_at.insert(run, now);
+        Some(answer)
+    }
+
+    fn peer(&self) -> MutexGuard<'_, Peer> {
+        lock(&self.peer)
+    }
+}
+
+/// Seals and opens the payloads of frames with keys derived from the shared secret.
+pub struct FrameCipher {
+    mode: FrameMode,
+    /// Derives the key of each pair of runs, in each direction.
+    keys: Hkdf<Sha256>,
+    /// Our run, drawn at startup. (never 0)
+    run: u64,
+    /// Number of frames sealed so far.
+    counter: AtomicU64,
+}
+
+impl FrameCipher {
+    pub fn new(secret: &str, mode: FrameMode) -> Self {
+        Self {
+            mode,
+            keys: Hkdf::new(Some(mode.key_context()), secret.as_bytes()),
+            run: rand::random_range(1..=u64::MAX),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns our run.
+    pub fn run(&self) -> u64 {
+        self.run
+    }
+
+    /// Derives the key of the frames going in the direction, from the run of the sender to the
+    /// run of the receiver.
+    fn key(&self, direction: MessageDirection, sender_run: u64, receiver_run: u64) -> FrameKey {
+        let direction: &[u8] = match direction {
+            MessageDirection::Serverbound => b"serverbound",
+            MessageDirection::Clientbound => b"clientbound",
+        };
+        let mut key = [0; 32];
+        self.keys
+            .expand_multi_info(
+                &[
+                    direction,
+                    &sender_run.to_be_bytes(),
+                    &receiver_run.to_be_bytes(),
+                ],
+                &mut key,
+            )
+            .expect("HKDF-SHA256 derives 32 bytes.");
+
+        match self.mode {
+            FrameMode::Encrypt => FrameKey::Encrypt(ChaCha20Poly1305::new(Key::from_slice(&key))),
+            FrameMode::Sign => FrameKey::Sign(
+                <Hmac<Sha256> as Mac>::new_from_slice(&key)
+                    .expect("HMAC accepts keys of any size."),
+            ),
+        }
+    }
+
+    /// The nonce of the counter. (the key already tells the runs)
+    fn nonce(counter: &[u8; 8]) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(counter);
+        nonce
+    }
+
+    /// Signs the header, the envelope and the payload, in an unambiguous way.
+    fn sign(mac: &Hmac<Sha256>, header: &str, envelope: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = mac.clone();
+        mac.update(&(header.len() as u64).to_be_bytes());
+        mac.update(header.as_bytes());
+        mac.update(envelope);
+        mac.update(payload);
+        mac
+    }
+
+    /// Encrypts (or signs) the payload of the message for the run of the peer of the link, and
+    /// authenticates its header.
+    ///
+    /// HELLOs are sealed for the run they name, which may not be adopted (yet).
+    fn seal(&self, link: &Link, message: &Message) -> Result<Message, MessageError> {
+        let receiver_run: u64 = match message.kind {
+            MessageKind::Hello => match Control::try_from(message)? {
+                Control::Hello { peer_run, .. } => peer_run,
+                _ => unreachable!("A HELLO message holds a HELLO"),
+            },
+            _ => link.peer_run().ok_or(MessageError::Authentication(
+                "the run of the peer is not known yet",
+            ))?,
+        };
+
+        let counter: u64 = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut envelope = [0; ENVELOPE_LEN];
+        envelope[..RUN_LEN].copy_from_slice(&self.run.to_be_bytes());
+        envelope[RUN_LEN..2 * RUN_LEN].copy_from_slice(&receiver_run.to_be_bytes());
+        envelope[2 * RUN_LEN..].copy_from_slice(&counter.to_be_bytes());
+
+        let header: String = message.header_string();
+        let mut sealed: Vec<u8> = Vec::with_capacity(message.payload().len() + OVERHEAD);
+        sealed.extend_from_slice(&envelope);
+        match self.key(message.direction, self.run, receiver_run) {
+            FrameKey::Encrypt(cipher) => {
+                let ciphertext: Vec<u8> = cipher
+                    .encrypt(
+                        Nonce::from_slice(&Self::nonce(&counter.to_be_bytes())),
+                        Payload {
+                            msg: message.payload(),
+                            aad: header.as_bytes(),
+                        },
+                    )
+                    .map_err(|_| MessageError::Authentication("failed to encrypt the payload"))?;
+                sealed.extend_from_slice(&ciphertext);
+            }
+            FrameKey::Sign(mac) => {
+                let tag = Self::sign(&mac, &header, &envelope, message.payload()).finalize();
+                sealed.extend_from_slice(message.payload());
+                sealed.extend_from_slice(&tag.into_bytes()[..TAG_LEN]);
+            }
+        }
+
+        Ok(message.make_part(sealed, message.part))
+    }
+
+    /// Decrypts the payload of the message, if it is authentic, was sealed by the current run of
+    /// the peer of the link for our run, and was not received before.
+    ///
+    /// HELLOs only have to be authentic and sealed for our run, or for none: they tell the run
+    /// of the peer. (see `Link`)
+    fn open(&self, link: &Link, message: &Message) -> Result<Message, MessageError> {
+        let (envelope, body) = message
+            .payload()
+            .split_first_chunk::<ENVELOPE_LEN>()
+            .ok_or(MessageError::Authentication("the envelope is missing"))?;
+        let (sender_run, rest) = envelope.split_at(RUN_LEN);
+        let (receiver_run, counter) = rest.split_at(RUN_LEN);
+        let sender_run = u64::from_be_bytes(sender_run.try_into().expect("8 bytes"));
+        let receiver_run = u64::from_be_bytes(receiver_run.try_into().expect("8 bytes"));
+        let counter: &[u8; 8] = counter.try_into().expect("8 bytes");
+
+        let header: String = message.header_string();
+        let payload: Vec<u8> = match self.key(message.direction, sender_run, receiver_run) {
+            FrameKey::Encrypt(cipher) => cipher
+                .decrypt(
+                    Nonce::from_slice(&Self::nonce(counter)),
+                    Payload {
+                        msg: body,
+                        aad: header.as_bytes(),
+                    },
+                )
+                .map_err(|_| MessageError::Authentication("invalid tag"))?,
+            FrameKey::Sign(mac) => {
+                let (payload, tag) = body
+                    .split_last_chunk::<TAG_LEN>()
+                    .ok_or(MessageError::Authentication("the tag is missing"))?;
+                Self::sign(&mac, &header, envelope, payload)
+                    .verify_truncated_left(tag)
+                    .map_err(|_| MessageError::Authentication("invalid tag"))?;
+                payload.to_vec()
+            }
+        };
+        let opened: Message = message.make_part(payload, message.part);
+
+        // Only authentic frames get here: forged frames cannot fill the window.
+        if message.kind == MessageKind::Hello {
+            if receiver_run != self.run && receiver_run != 0 {
+                return Err(MessageError::Authentication("HELLO for a previous run"));
+            }
+            let told = Control::Hello {
+                run: sender_run,
+                peer_run: receiver_run,
+            };
+            if Control::try_from(&opened)? != told {
+                return Err(MessageError::Authentication(
+                    "HELLO not matching its envelope",
+                ));
+            }
+            return Ok(opened);
+        }
+
+        if receiver_run != self.run {
+            return Err(MessageError::Authentication("sealed for a previous run"));
+        }
+        let mut peer = link.peer();
+        if sender_run != peer.run {
+            return Err(MessageError::Authentication(
+                "sealed by another run of the peer",
+            ));
+        }
+        if !peer.received.accept(u64::from_be_bytes(*counter)) {
+            return Err(MessageError::Authentication("replayed frame"));
+        }
+
+        Ok(opened)
+    }
+}
+
+/// Locks the mutex, even if a thread panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::partitioning::Part;
+    use crate::session::SessionId;
+
+    fn message(payload: &[u8]) -> Message {
+        Message::new(
+            MessageKind::Data,
+            MessageDirection::Serverbound,
+            SessionId::new(9),
+            4,
+            Part::new(2, 3).unwrap(),
+            payload.to_vec(),
+        )
+    }
+
+    const MODES: [FrameMode; 2] = [FrameMode::Encrypt, FrameMode::Sign];
+
+    /// A link with a fresh run.
+    fn link(secret: &str, mode: FrameMode) -> Link {
+        Link::new(Some(Arc::new(FrameCipher::new(secret, mode))))
+    }
+
+    /// Returns our run on the link.
+    fn run(link: &Link) -> u64 {
+        link.cipher.as_ref().unwrap().run()
+    }
+
+    /// A sender and a receiver knowing the runs of each other.
+    struct Pair {
+        sender: Link,
+        receiver: Link,
+    }
+
+    impl Pair {
+        fn new(secret: &str, mode: FrameMode) -> Self {
+            let pair = Self {
+                sender: link(secret, mode),
+                receiver: link(secret, mode),
+            };
+            handshake(&pair.sender, &pair.receiver);
+            pair
+        }
+
+        fn seal(&self, message: &Message) -> Message {
+            self.sender.seal(message.clone()).unwrap()
+        }
+
+        fn open(&self, message: &Message) -> Result<Message, MessageError> {
+            self.receiver.open(message.clone())
+        }
+    }
+
+    /// Passes the HELLOs between both sides until neither has anything to say.
+    /// Returns how many were passed.
+    fn handshake(a: &Link, b: &Link) -> usize {
+        let mut passed: usize = 0;
+        let mut pending: Vec<(Control, bool)> = vec![(a.hello().unwrap(), true)];
+        while let Some((hello, from_a)) = pending.pop() {
+            passed += 1;
+            let Control::Hello { run, peer_run } = hello else {
+                panic!("not a HELLO");
+            };
+            let (to, from) = if from_a { (b, a) } else { (a, b) };
+            // Sealed and opened, as it would be through Discord.
+            let sealed = from
+                .seal(Message::from_control(
+                    &hello,
+                    MessageDirection::Serverbound,
+                    SessionId::FORWARD,
+                ))
+                .unwrap();
+            assert_eq!(Control::try_from(&to.open(sealed).unwrap()).unwrap(), hello);
+            if let Some(answer) = to.on_hello(run, peer_run) {
+                pending.push((answer, !from_a));
+            }
+        }
+        passed
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        for mode in MODES {
+            let pair = Pair::new("hunter2", mode);
+            let original = message(b"login start");
+
+            let sealed = pair.seal(&original);
+            assert_eq!(sealed.payload().len(), original.payload().len() + OVERHEAD);
+            assert_eq!(sealed.header_string(), original.header_string());
+            let body = &sealed.payload()[ENVELOPE_LEN..sealed.payload().len() - TAG_LEN];
+            // Only signing leaves the payload readable.
+            assert_eq!(body == original.payload(), mode == FrameMode::Sign);
+
+            let opened = pair.open(&sealed).unwrap();
+            assert_eq!(opened.payload(), original.payload());
+            assert_eq!(opened.seq, 4);
+            assert_eq!(opened.part.current(), 2);
+
+            // Each frame has its own nonce.
+            let resealed = pair.seal(&original);
+            assert_ne!(resealed.payload(), sealed.payload());
+            assert!(pair.open(&resealed).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_without_cipher() {
+        let link = Link::new(None);
+        let original = message(b"login start");
+
+        assert_eq!(link.overhead(), 0);
+        assert_eq!(link.seal(original.clone()).unwrap(), original);
+        assert_eq!(link.open(original.clone()).unwrap(), original);
+        assert_eq!(link.hello(), None);
+        assert_eq!(link.on_hello(7, 0), None);
+    }
+
+    #[test]
+    fn test_handshake() {
+        let a = link("hunter2", FrameMode::Encrypt);
+        let b = link("hunter2", FrameMode::Encrypt);
+
+        // Nothing is sealed before knowing the peer.
+        assert!(a.seal(message(b"early")).is_err());
+
+        // HELLO, HELLO naming it, HELLO naming it back (a adopts b), and once more (b adopts a).
+        assert_eq!(handshake(&a, &b), 4);
+        assert_eq!(a.peer_run(), Some(run(&b)));
+        assert_eq!(b.peer_run(), Some(run(&a)));
+        assert_eq!(a.hello(), None);
+
+        // The restarted side tells its new run, and the other adopts it.
+        let b = link("hunter2", FrameMode::Encrypt);
+        assert_eq!(handshake(&b, &a), 4);
+        assert_eq!(a.peer_run(), Some(run(&b)));
+    }
+
+    #[test]
+    fn test_hello_answers_are_rate_limited() {
+        let link = link("hunter2", FrameMode::Encrypt);
+        let now = Instant::now();
+
+        // Replayed HELLOs of an old run of the peer are answered, but not every time.
+        assert!(link.on_hello_at(7, 0, now).is_some());
+        assert!(link.on_hello_at(7, 0, now).is_none());
+        assert!(link.on_hello_at(7, 3, now).is_none());
+        assert!(link.on_hello_at(7, 0, now + HELLO_INTERVAL).is_some());
+        // They do not hold back the HELLOs of another run.
+        assert!(link.on_hello_at(9, 0, now).is_some());
+        assert_eq!(link.peer_run(), None);
+
+        // Once retired, a run is never adopted again.
+        assert!(link.on_hello_at(7, run(&link), now).is_some());
+        assert!(link.on_hello_at(8, run(&link), now).is_some());
+        assert!(link.on_hello_at(7, run(&link), now).is_none());
+        assert_eq!(link.peer_run(), Some(8));
+    }
+
+    #[test]
+    fn test_open_rejects_forgeries() {
+        for mode in MODES {
+            let pair = Pair::new("hunter2", mode);
+            let sealed = pair.seal(&message(b"chat"));
+
+            // Another secret, or another mode.
+            let other = Pair::new("hunter3", mode);
+            assert!(other.open(&sealed).is_err());
+            for other in MODES.into_iter().filter(|&other| other != mode) {
+                let other = FrameCipher::new("hunter2", other);
+                assert!(other.open(&pair.receiver, &sealed).is_err());
+            }
+
+            // An altered envelope, payload, or tag.
+            for index in [
+                0,
+                RUN_LEN,
+                ENVELOPE_LEN - 1,
+                ENVELOPE_LEN,
+                sealed.payload().len() - 1,
+            ] {
+                let mut payload = sealed.payload().to_vec();
+                payload[index] ^= 1;
+                assert!(pair.open(&sealed.make_part(payload, sealed.part)).is_err());
+            }
+
+            // An altered header.
+            let mut moved = sealed.clone();
+            moved.session = SessionId::new(10);
+            assert!(pair
+                .open(&moved.make_part(sealed.payload(), sealed.part))
+                .is_err());
+
+            // Too short to hold an envelope.
+            assert!(pair.open(&message(b"short")).is_err());
+
+            // The original is still fine.
+            assert!(pair.open(&sealed).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_open_rejects_replays() {
+        for mode in MODES {
+            let pair = Pair::new("hunter2", mode);
+
+            let first = pair.seal(&message(b"a"));
+            let second = pair.seal(&message(b"b"));
+
+            // Out of order is fine, twice is not.
+            assert!(pair.open(&second).is_ok());
+            assert!(pair.open(&first).is_ok());
+            assert!(pair.open(&first).is_err());
+            assert!(pair.open(&second).is_err());
+        }
+    }
+
+    #[test]
+    fn test_open_rejects_replays_after_restart() {
+        for mode in MODES {
+            let pair = Pair::new("hunter2", mode);
+            let sealed = pair.seal(&message(b"login start"));
+            assert!(pair.open(&sealed).is_ok());
+
+            // The receiver restarted: a fresh run, whose replay window is empty.
+            let receiver = link("hunter2", mode);
+            assert!(receiver.open(sealed.clone()).is_err());
+            // Even once it knows the sender again.
+            handshake(&receiver, &pair.sender);
+            assert!(receiver.open(sealed.clone()).is_err());
+
+            // The sender restarted: the frames of its previous run are rejected.
+            let sender = link("hunter2", mode);
+            handshake(&sender, &pair.receiver);
+            assert!(pair.open(&sealed).is_err());
+            let fresh = sender.seal(message(b"login start")).unwrap();
+            assert!(pair.open(&fresh).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_hello_for_another_run_is_rejected() {
+        let pair = Pair::new("hunter2", FrameMode::Encrypt);
+        let hello = Control::Hello {
+            run: run(&pair.sender),
+            peer_run: 5,
+        };
+        let sealed = pair
+            .sender
+            .seal(Message::from_control(
+                &hello,
+                MessageDirection::Serverbound,
+                SessionId::FORWARD,
+            ))
+            .unwrap();
+        assert!(pair.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(0));
+        assert!(window.accept(REPLAY_WINDOW * 2));
+        // Too old to tell.
+        assert!(!window.accept(1));
+        assert!(window.accept(REPLAY_WINDOW + 1));
+        assert!(!window.accept(REPLAY_WINDOW + 1));
+        assert!(window.seen.len() <= REPLAY_WINDOW as usize + 1);
+    }
+}
@@ -1,22 +1,35 @@
-use std::fs::{File, OpenOptions};
-use std::io::prelude::*;
-
-use std::io::{self, BufRead};
-use std::sync::Arc;
-use std::time::Instant;
-
-use crate::partitioning::Partitioner;
-use crate::{cli, message, CURRENT_SIDE};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::codec::Codec;
+use crate::config::Timeouts;
+use crate::partitioning::{Aggregator, Partitioner};
+use crate::scheduler::Scheduler;
+use crate::session::SessionId;
+use crate::transport::{Bucket, Frame, Transport, TransportError};
+use crate::{cli, crypto, message};
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::{debug, error, info, warn};
-use serenity::all::{ChannelId, CreateMessage, Http, UserId};
+use serenity::all::{
+    ChannelId, ClientBuilder, CreateAllowedMentions, CreateMessage, Http, HttpBuilder, Ready,
+    UserId,
+};
 use serenity::async_trait;
+use serenity::http::{LightMethod, Request, Route, StatusCode};
 use serenity::model::channel;
 use serenity::prelude::*;
 use tokio::sync::{broadcast, mpsc};
+use tokio::time::interval;
 
 pub struct DiscordBot {
     client: Arc<tokio::sync::Mutex<Client>>,
     http: Arc<Http>,
+    /// Sends the messages, whose rate limits are handled by the `Scheduler`.
+    rest: reqwest::Client,
+    /// The contents of the messages of the peer, from the handler.
+    frames: tokio::sync::Mutex<mpsc::Receiver<Frame>>,
 }
 
 impl DiscordBot {
@@ -24,31 +37,39 @@ impl DiscordBot {
     // (for more information, go to the aggregation code)
     pub const MAX_MESSAGE_LENGTH_ALLOWED: usize = 1900;
 
-    pub async fn new(
-        side: cli::Mode,
-        message_tx: mpsc::Sender<message::Message>,
-        stop_tx: broadcast::Sender<()>,
-    ) -> Self {
-        // Launch cache cleanup async task (cleanup every X seconds)
-        cache::cleanup_task().await;
+    /// Maximum number of queued messages aggregated together.
+    const MAX_BATCH_SIZE: usize = 64;
+
+    /// How many channels a message is tried on before being dropped.
+    const MAX_SEND_ATTEMPTS: u32 = 3;
+
+    /// Queueing delays above this are logged as warnings.
+    const SLOW_QUEUE_DELAY: Duration = Duration::from_secs(1);
 
+    pub async fn new(side: cli::Mode) -> Self {
         // Set gateway intents, which decides what events the bot will be notified about
         let intents = GatewayIntents::GUILD_MESSAGES
             | GatewayIntents::DIRECT_MESSAGES
             | GatewayIntents::MESSAGE_CONTENT;
 
-        // Get the token from Server or Client.
-        let token: &String = match &side {
-            cli::Mode::Server { token, .. } | cli::Mode::Client { token, .. } => token,
-        };
+        let mut http = HttpBuilder::new(side.token());
+        if let Some(api_url) = side.api_url() {
+            info!("Using the Discord API at {api_url}");
+            // The ratelimiter of serenity always talks to the real Discord.
+            // (the messages are sent without it anyway, see `send`)
+            http = http.proxy(api_url).ratelimiter_disabled(true);
+        }
 
         // Create a new instance of the Client, logging in as a bot.
-        let client = Client::builder(token, intents)
-            .event_handler(Handler {
-                message_tx,
-                stop_tx,
-                side,
-            })
+        let (frame_tx, frame_rx) = mpsc::channel::<Frame>(64);
+        let handler = Handler {
+            frame_tx,
+            guild_id: side.guild_id(),
+            peer_bots: peer_bots(&side).to_vec(),
+            bot_id: OnceLock::new(),
+        };
+        let client = ClientBuilder::new_with_http(http.build(), intents)
+            .event_handler(handler)
             .await
             .expect("Failed to create client");
 
@@ -59,6 +80,8 @@ impl DiscordBot {
         Self {
             client: Arc::new(Mutex::new(client)),
             http,
+            rest: reqwest::Client::new(),
+            frames: tokio::sync::Mutex::new(frame_rx),
         }
     }
 
@@ -73,68 +96,127 @@ impl DiscordBot {
 
         info!("Discord bot started");
     }
+}
 
-    /// Infinite loop that listens on the receiver and sends the message to Discord channel
-    /// as soon as a message is received.
-    pub async fn handle_write_discord(
-        &self,
-        rx: mpsc::Receiver<message::Message>,
-        stop_tx: broadcast::Sender<()>,
-        channel_ids: &[u64],
-    ) {
-        let mut stop_rx = stop_tx.subscribe();
+impl Transport for DiscordBot {
+    async fn send(&self, channel: u64, content: String) -> Result<Option<Bucket>, TransportError> {
+        // The payload may look like a mention. (e.g. base85 has '@', '<' and '>')
+        let discord_message = CreateMessage::new()
+            .content(content)
+            .allowed_mentions(CreateAllowedMentions::new());
+        let body = serde_json::to_vec(&discord_message)
+            .map_err(|err| TransportError::Send(err.to_string()))?;
+
+        // Sent by hand rather than with `send_message`, whose errors drop the rate limit headers.
+        let route = Route::ChannelMessages {
+            channel_id: ChannelId::new(channel),
+        };
+        let response = Request::new(route, LightMethod::Post)
+            .body(Some(body))
+            .build(&self.rest, self.http.token(), self.http.proxy.as_deref())
+            .map_err(|err| TransportError::Send(err.to_string()))?
+            .send()
+            .await
+            .map_err(|err| TransportError::Send(err.to_string()))?;
 
-        tokio::select! {
-            _ = self.handle_write_discord_offload(rx, stop_tx, channel_ids) => {}
-            _ = stop_rx.recv() => { debug!("Received stop signal"); return; }
+        let status = response.status();
+        let headers = response.headers().clone();
+        if status.is_success() {
+            return Ok(rate_limit::bucket(&headers));
+        }
+
+        let body = response.text().await.unwrap_or_default();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            Err(rate_limit::rate_limited(&headers, &body))
+        } else {
+            Err(TransportError::Send(format!("{status}: {body}")))
         }
     }
 
-    async fn handle_write_discord_offload(
-        &self,
-        mut rx: mpsc::Receiver<message::Message>,
-        stop_tx: broadcast::Sender<()>,
-        channel_ids: &[u64],
-    ) {
-        info!("Listening for messages to SEND to Discord");
-        let channels = channel_ids
-            .iter()
-            .map(|id| ChannelId::new(*id))
-            .collect::<Vec<ChannelId>>();
-
-        // Channel index counter that will rotate.
-        // u128 so that we are sure it will never overflow
-        let mut counter: u128 = 0;
-
-        // Listen infinitely
-        loop {
-            match rx.recv().await {
+    async fn receive(&self) -> Option<Frame> {
+        self.frames.lock().await.recv().await
+    }
+}
+
+/// Infinite loop that listens on the receiver and sends the message to Discord channel
+/// as soon as a message is received.
+///
+/// The messages are encoded with the codec, and sealed for the peer of the link.
+pub async fn handle_write_discord<T: Transport>(
+    transport: &T,
+    rx: mpsc::Receiver<message::Message>,
+    stop_tx: broadcast::Sender<()>,
+    channel_ids: &[u64],
+    link: &crypto::Link,
+    codec: Codec,
+) {
+    let mut stop_rx = stop_tx.subscribe();
+
+    tokio::select! {
+        _ = handle_write_discord_offload(transport, rx, stop_tx, channel_ids, link, codec) => {}
+        _ = stop_rx.recv() => { debug!("Received stop signal"); }
+    }
+}
+
+async fn handle_write_discord_offload<T: Transport>(
+    transport: &T,
+    mut rx: mpsc::Receiver<message::Message>,
+    stop_tx: broadcast::Sender<()>,
+    channel_ids: &[u64],
+    link: &crypto::Link,
+    codec: Codec,
+) {
+    info!("Listening for messages to SEND to Discord");
+
+    let mut scheduler = Scheduler::new(channel_ids);
+    // The contents waiting for a channel, oldest first.
+    let mut waiting: VecDeque<Outgoing> = VecDeque::new();
+    // At most one per channel.
+    let mut sending = FuturesUnordered::new();
+
+    // Listen infinitely
+    loop {
+        // Every channel ready takes the next content.
+        let mut wake_at: Option<Instant> = None;
+        while !waiting.is_empty() {
+            let now = Instant::now();
+            match scheduler.next(now) {
+                Some((channel, ready_at)) if ready_at <= now => {
+                    scheduler.on_start(channel);
+                    let outgoing = waiting.pop_front().expect("A content is waiting");
+                    sending.push(send_outgoing(transport, channel, outgoing));
+                }
+                Some((_, ready_at)) => {
+                    wake_at = Some(ready_at);
+                    break;
+                }
+                // Every channel is sending.
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            // The rest of the backlog stays in the queue, where the sessions feel it.
+            received = rx.recv(), if waiting.len() < channel_ids.len() => match received {
                 Some(received_message) => {
                     debug!("Received a message to SEND to Discord");
-
-                    match make_partitions(received_message) {
-                        Ok(partitions) => {
-                            for msg in partitions {
-                                let rotated_idx = (counter % channels.len() as u128) as usize;
-                                let channel = channels[rotated_idx];
-                                counter += 1;
-
-                                if let Err(err) =
-                                    channel.send_message(&self.http, msg.clone()).await
-                                {
-                                    warn!("Failed to send message to Discord channel: {err}");
-                                    warn!("Message info: {msg:?}");
-                                } else {
-                                    debug!("SENT A MESSAGE TO DISCORD");
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            error!("Failed to partition message: {err}. Sending stop signal...");
-                            stop_tx.send(()).unwrap();
-                            return;
+                    let dequeued_at = Instant::now();
+
+                    // The messages queued meanwhile (of any session) are sent along with it,
+                    // aggregated into as few Discord messages as possible.
+                    let mut batch: Vec<message::Message> = vec![received_message];
+                    while batch.len() < DiscordBot::MAX_BATCH_SIZE {
+                        match rx.try_recv() {
+                            Ok(message) => batch.push(message),
+                            Err(_) => break,
                         }
                     }
+
+                    waiting.extend(make_contents(batch, link, codec).into_iter().map(|content| Outgoing {
+                        content,
+                        attempts: 0,
+                        dequeued_at,
+                    }));
                 }
                 None => {
                     error!("Received None (channel closed): exiting the function");
@@ -142,43 +224,251 @@ impl DiscordBot {
                     debug!("Channel closed (None received): broadcast stop signal");
                     return;
                 }
-            }
+            },
+            Some((channel, outgoing, result)) = sending.next(), if !sending.is_empty() => {
+                let backlog: usize = rx.len() + waiting.len();
+                match result {
+                    Ok(bucket) => {
+                        scheduler.on_sent(channel, bucket, Instant::now());
+                        let delay = outgoing.dequeued_at.elapsed();
+                        if delay > DiscordBot::SLOW_QUEUE_DELAY {
+                            warn!("Message sent to Discord channel {channel} after waiting {delay:.2?} ({backlog} more queued)");
+                        } else {
+                            debug!("SENT A MESSAGE TO DISCORD channel {channel} after waiting {delay:.2?} ({backlog} more queued)");
+                        }
+                    }
+                    Err(err) => {
+                        scheduler.on_failed(channel, &err, Instant::now());
+                        warn!(
+                            "Failed to send message to Discord channel {channel} (attempt {}/{}): {err}",
+                            outgoing.attempts,
+                            DiscordBot::MAX_SEND_ATTEMPTS
+                        );
+                        if outgoing.attempts < DiscordBot::MAX_SEND_ATTEMPTS {
+                            // Tried again first, on the channel that takes it soonest.
+                            waiting.push_front(outgoing);
+                        } else {
+                            warn!("Gave up sending a message to Discord. Its sequenced frames will be retransmitted.");
+                            warn!("Message info: {:?}", outgoing.content);
+                        }
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(wake_at.unwrap_or_else(Instant::now).into()), if wake_at.is_some() => {}
         }
     }
 }
 
-/// Logs all packet parts into a file.
-fn debug_logging_parts(message: &message::Message) -> () {
-    let part: &str = &message.part.to_string();
-    let side: &str = match CURRENT_SIDE.get().unwrap() {
-        cli::Mode::Server { .. } => "server",
-        cli::Mode::Client { .. } => "client",
-    };
+/// The content of a Discord message to send.
+struct Outgoing {
+    content: String,
+    /// How many times it was sent. (or tried to be)
+    /// It is dropped after `DiscordBot::MAX_SEND_ATTEMPTS`: its sequenced frames will be
+    /// retransmitted.
+    attempts: u32,
+    /// When its messages left the queue.
+    dequeued_at: Instant,
+}
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .append(true)
-        .open(format!("{side}_packets_logging_part.txt"))
-        .unwrap();
+/// Sends the content to the channel, counting the attempt.
+async fn send_outgoing<T: Transport>(
+    transport: &T,
+    channel: u64,
+    mut outgoing: Outgoing,
+) -> (u64, Outgoing, Result<Option<Bucket>, TransportError>) {
+    outgoing.attempts += 1;
+    let result = transport.send(channel, outgoing.content.clone()).await;
+    (channel, outgoing, result)
+}
 
-    if let Err(e) = writeln!(file, "{part}") {
-        error!("Failed to write to file: {e}");
+/// Encodes the messages with the codec, partitions those that are too big to be sent to Discord
+/// as one, seals every part for the peer of the link (if encryption is enabled), then aggregates
+/// all the parts into the contents of as few Discord messages as possible.
+///
+/// A message that cannot be partitioned (or sealed) is dropped, the others are still sent.
+fn make_contents(
+    messages: Vec<message::Message>,
+    link: &crypto::Link,
+    codec: Codec,
+) -> Vec<String> {
+    let mut parts: Vec<message::Message> = Vec::with_capacity(messages.len());
+    for message in messages {
+        let session = message.session;
+        let message = if message.codec() == codec {
+            message
+        } else {
+            message.with_codec(codec)
+        };
+        let partitions: Result<Vec<message::Message>, _> = Partitioner::partition_with_overhead(
+            message,
+            DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED,
+            link.overhead(),
+        )
+        .and_then(|partitions| {
+            partitions
+                .into_iter()
+                .map(|partition| link.seal(partition))
+                .collect()
+        });
+        match partitions {
+            Ok(partitions) => parts.extend(partitions),
+            // Only the message's session is affected, the others keep going.
+            Err(err) => {
+                error!(
+                    "Failed to partition or seal message of session {session}: {err}. Dropped it."
+                )
+            }
+        }
+    }
+
+    match Aggregator::aggregate(&parts) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!(
+                "Failed to aggregate {} messages: {err}. Dropped them.",
+                parts.len()
+            );
+            Vec::new()
+        }
     }
 }
 
-/// Partitions the received message if it's too big to be sent to Discord as one.
-fn make_partitions(message: message::Message) -> Result<Vec<CreateMessage>, message::MessageError> {
-    let message_string: &str = message.to_string();
-    if message_string.len() <= DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED {
-        Ok(vec![CreateMessage::new().content(message_string)])
-    } else {
-        let partitions = Partitioner::partition(message, DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED)?;
-        let result = partitions
-            .iter()
-            .map(|m| CreateMessage::new().content(m.to_string()))
-            .collect();
+/// Reading the rate limits told by Discord in the headers of its responses.
+mod rate_limit {
+    use std::time::Duration;
+
+    use reqwest::header::HeaderMap;
+
+    use crate::transport::{Bucket, TransportError};
+
+    /// How many more messages the bucket takes.
+    const REMAINING: &str = "X-RateLimit-Remaining";
+    /// In how many seconds (with decimals) the bucket refills.
+    const RESET_AFTER: &str = "X-RateLimit-Reset-After";
+    /// Set on the 429s of the global rate limit.
+    const GLOBAL: &str = "X-RateLimit-Global";
+    /// `global`, `user` or `shared`, on the 429s.
+    const SCOPE: &str = "X-RateLimit-Scope";
+    /// In how many seconds to retry, on the 429s.
+    const RETRY_AFTER: &str = "Retry-After";
+
+    /// Returns the bucket of the channel told by the headers of a message sent to it.
+    pub fn bucket(headers: &HeaderMap) -> Option<Bucket> {
+        Some(Bucket {
+            remaining: header(headers, REMAINING)?.parse().ok()?,
+            reset_after: seconds(header(headers, RESET_AFTER)?)?,
+        })
+    }
 
-        Ok(result)
+    /// Returns the error of a 429, with how long to wait as told by its headers (or its body).
+    ///
+    /// A 429 telling nothing is a plain failure.
+    pub fn rate_limited(headers: &HeaderMap, body: &str) -> TransportError {
+        let body: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+        let retry_after: Option<Duration> = header(headers, RETRY_AFTER)
+            .or_else(|| header(headers, RESET_AFTER))
+            .and_then(seconds)
+            .or_else(|| Duration::try_from_secs_f64(body["retry_after"].as_f64()?).ok());
+        let global: bool = header(headers, GLOBAL).is_some_and(|global| global != "false")
+            || header(headers, SCOPE) == Some("global")
+            || body["global"].as_bool() == Some(true);
+
+        match retry_after {
+            Some(retry_after) => TransportError::RateLimited {
+                retry_after,
+                global,
+            },
+            None => TransportError::Send("Rate limited, without telling for how long".to_string()),
+        }
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+        headers.get(name)?.to_str().ok()
+    }
+
+    fn seconds(value: &str) -> Option<Duration> {
+        Duration::try_from_secs_f64(value.trim().parse().ok()?).ok()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use reqwest::header::{HeaderName, HeaderValue};
+
+        fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+            pairs
+                .iter()
+                .map(|&(name, value)| {
+                    (
+                        HeaderName::from_static(name),
+                        HeaderValue::from_static(value),
+                    )
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_bucket() {
+            let told = headers(&[
+                ("x-ratelimit-limit", "5"),
+                ("x-ratelimit-remaining", "2"),
+                ("x-ratelimit-reset-after", "1.25"),
+            ]);
+            assert_eq!(
+                bucket(&told),
+                Some(Bucket {
+                    remaining: 2,
+                    reset_after: Duration::from_millis(1250),
+                })
+            );
+            assert_eq!(bucket(&headers(&[("x-ratelimit-remaining", "2")])), None);
+            assert_eq!(bucket(&HeaderMap::new()), None);
+        }
+
+        #[test]
+        fn test_rate_limited() {
+            let limited = |pairs, body| match rate_limited(&headers(pairs), body) {
+                TransportError::RateLimited {
+                    retry_after,
+                    global,
+                } => Some((retry_after, global)),
+                _ => None,
+            };
+
+            // Retry-After first, then the reset of the bucket, then the body.
+            assert_eq!(
+                limited(
+                    &[("retry-after", "3"), ("x-ratelimit-reset-after", "2.5")],
+                    ""
+                ),
+                Some((Duration::from_secs(3), false))
+            );
+            assert_eq!(
+                limited(&[("x-ratelimit-reset-after", "2.5")], ""),
+                Some((Duration::from_millis(2500), false))
+            );
+            assert_eq!(
+                limited(&[], r#"{"retry_after": 0.5, "global": false}"#),
+                Some((Duration::from_millis(500), false))
+            );
+
+            // Global.
+            assert_eq!(
+                limited(&[("retry-after", "1"), ("x-ratelimit-global", "true")], ""),
+                Some((Duration::from_secs(1), true))
+            );
+            assert_eq!(
+                limited(&[("retry-after", "1"), ("x-ratelimit-scope", "global")], ""),
+                Some((Duration::from_secs(1), true))
+            );
+            assert_eq!(
+                limited(&[], r#"{"retry_after": 1, "global": true}"#),
+                Some((Duration::from_secs(1), true))
+            );
+
+            // Told nothing.
+            assert_eq!(limited(&[], "Too Many Requests"), None);
+        }
     }
 }
 
@@ -186,253 +476,806 @@ fn make_partitions(message: message::Message) -> Result<Vec<CreateMessage>, mess
 mod cache {
     use dashmap::DashMap;
     use log::{debug, warn};
-    use serenity::futures::lock::Mutex;
+    use std::collections::BTreeMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::{Duration, Instant};
 
-    use crate::message;
+    use crate::message::{Message, MessageError};
+    use crate::partitioning::Partitioner;
+    use crate::session::SessionId;
+
+    /// Maximum memory taken by the cached parts. (about 64 full sessions' worth of
+    /// retransmissions)
+    pub const MAX_CACHED_BYTES: usize = 16 * 1024 * 1024;
+
+    /// The parts received so far of a partitioned message.
+    struct PartialMessage {
+        /// Indexed by `current`. Only what was received takes memory.
+        parts: BTreeMap<usize, Message>,
+        /// The number of parts of the message.
+        total: usize,
+        /// Memory taken by `parts`. (see `Reassembler::size_of`)
+        bytes: usize,
+        /// When the last part was received.
+        updated_at: Instant,
+    }
 
-    /// Stale entries are purged after 30 seconds
-    pub const MESSAGE_EXPIRATION: Duration = Duration::from_secs(30);
+    /// Puts the partitioned messages back together.
+    ///
+    /// The parts of a message share the session and sequence number of the message, which is
+    /// what identifies it. They can be received in any order, and interleaved with the parts of
+    /// other messages.
+    ///
+    /// The cached parts take at most `max_bytes`: anyone able to post in the channels could send
+    /// parts of messages that are never completed.
+    pub struct Reassembler {
+        partials: DashMap<(SessionId, u32), PartialMessage>,
+        expiration: Duration,
+        max_bytes: usize,
+        /// Memory taken by all the cached parts.
+        cached_bytes: AtomicUsize,
+    }
 
-    type MessageParts = Vec<message::Message>;
-    type MessageCache = DashMap<u128, (MessageParts, Instant)>;
+    impl Reassembler {
+        pub fn new(expiration: Duration, max_bytes: usize) -> Self {
+            Self {
+                partials: DashMap::new(),
+                expiration,
+                max_bytes,
+                cached_bytes: AtomicUsize::new(0),
+            }
+        }
 
-    lazy_static::lazy_static! {
-        pub static ref MESSAGE_CACHE: MessageCache = DashMap::new();
-        pub static ref CURRENT_KEY: Mutex<u128> = Mutex::new(0);
-        //pub static ref KEY_COUNTER: Mutex<u128> = Mutex::new(0);
-    }
+        /// Approximates the memory taken by a cached part.
+        fn size_of(message: &Message) -> usize {
+            std::mem::size_of::<Message>() + message.payload().len() + message.to_string().len()
+        }
 
-    /// Clean up stale entries continually
-    pub async fn cleanup_task() {
-        debug!("Started cleanup task for message cache");
+        /// Accounts for `bytes` more of cached parts. Returns false if there is no room for them.
+        fn reserve(&self, bytes: usize) -> bool {
+            self.cached_bytes
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |cached| {
+                    cached
+                        .checked_add(bytes)
+                        .filter(|&cached| cached <= self.max_bytes)
+                })
+                .is_ok()
+        }
 
-        tokio::spawn(async move {
-            loop {
-                // Cleanup every 30 seconds
-                tokio::time::sleep(Duration::from_secs(30)).await;
+        /// Accounts for `bytes` less of cached parts.
+        fn release(&self, bytes: usize) {
+            self.cached_bytes.fetch_sub(bytes, Ordering::Relaxed);
+        }
 
-                let now = Instant::now();
-                let len_before: usize = MESSAGE_CACHE.len();
-                MESSAGE_CACHE.retain(|_, (_, timestamp)| {
-                    now.duration_since(*timestamp) < MESSAGE_EXPIRATION
-                });
+        /// Caches the part, or merges all the parts of its message if it was the last missing.
+        ///
+        /// Returns `Ok(None)` while parts are missing. Duplicated parts are dropped.
+        pub fn push(&self, message: Message) -> Result<Option<Message>, MessageError> {
+            self.push_at(message, Instant::now())
+        }
 
-                let len_after: usize = MESSAGE_CACHE.len();
+        fn push_at(&self, message: Message, now: Instant) -> Result<Option<Message>, MessageError> {
+            let total: usize = message.part.total();
+            if total == 1 {
+                return Ok(Some(message));
+            }
 
-                warn!(
-                    "PURGED {} STALE MESSAGES FROM CACHE",
-                    len_before - len_after
+            let key = (message.session, message.seq);
+            let current: usize = message.part.current();
+            let size: usize = Self::size_of(&message);
+
+            // Holding the entry locks the key until the end of the function.
+            let mut partial = self.partials.entry(key).or_insert_with(|| PartialMessage {
+                parts: BTreeMap::new(),
+                total,
+                bytes: 0,
+                updated_at: now,
+            });
+
+            if partial.total != total {
+                return Err(MessageError::Merging(
+                    "part total differs from the other parts of the message",
+                ));
+            }
+            if partial.parts.contains_key(&current) {
+                debug!(
+                    "Dropped duplicate part {current}/{total} of message #{} (session {})",
+                    key.1, key.0
                 );
+                return Ok(None);
+            }
+            if !self.reserve(size) {
+                let empty: bool = partial.parts.is_empty();
+                drop(partial);
+                if empty {
+                    self.partials.remove(&key);
+                }
+                return Err(MessageError::Merging("the reassembly cache is full"));
+            }
+
+            partial.parts.insert(current, message);
+            partial.bytes += size;
+            partial.updated_at = now;
+
+            if partial.parts.len() < total {
+                return Ok(None);
+            }
+
+            // All the parts are here.
+            let parts: Vec<Message> = std::mem::take(&mut partial.parts).into_values().collect();
+            self.release(partial.bytes);
+            drop(partial);
+            self.partials.remove(&key);
+
+            Partitioner::merge(&parts).map(Some)
+        }
+
+        /// Drops the messages whose parts stopped coming. Returns how many were dropped.
+        pub fn purge_expired(&self) -> usize {
+            self.purge_expired_at(Instant::now())
+        }
+
+        fn purge_expired_at(&self, now: Instant) -> usize {
+            let len_before: usize = self.partials.len();
+            self.partials.retain(|(session, seq), partial| {
+                let expired = now.duration_since(partial.updated_at) >= self.expiration;
+                if expired {
+                    warn!(
+                        "Dropped message #{seq} of session {session}: only {}/{} parts received",
+                        partial.parts.len(),
+                        partial.total
+                    );
+                    self.release(partial.bytes);
+                }
+                !expired
+            });
+
+            len_before - self.partials.len()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::message::MessageDirection;
+
+        const MESSAGE_EXPIRATION: Duration = Duration::from_secs(30);
+
+        /// Partitions a message with the given seq into parts of 16 payload bytes.
+        fn partitioned(session: u32, seq: u32, payload: &[u8]) -> Vec<Message> {
+            let message = Message::from_bytes(
+                payload,
+                MessageDirection::Clientbound,
+                SessionId::new(session),
+            )
+            .with_seq(seq);
+            let limit = message.get_header_size() + 32;
+            Partitioner::partition(message, limit).unwrap()
+        }
+
+        #[test]
+        fn test_single_part_is_returned() {
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, MAX_CACHED_BYTES);
+            let message = partitioned(1, 1, b"small").remove(0);
+            assert_eq!(reassembler.push(message.clone()).unwrap(), Some(message));
+        }
+
+        #[test]
+        fn test_interleaved_out_of_order_parts() {
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, MAX_CACHED_BYTES);
+            let payload_a: Vec<u8> = (0..70).collect();
+            let payload_b: Vec<u8> = (100..150).collect();
+            let payload_c: Vec<u8> = (200..240).collect();
+            let parts_a = partitioned(1, 1, &payload_a); // 5 parts
+            let parts_b = partitioned(1, 2, &payload_b); // 4 parts, same session
+            let parts_c = partitioned(2, 1, &payload_c); // 3 parts, same seq, other session
+            assert_eq!(parts_a.len(), 5);
+
+            // Interleave the parts of the three messages, each in reverse order.
+            let mut queues = [parts_a, parts_b, parts_c];
+            let mut merged: Vec<Message> = Vec::new();
+            while queues.iter().any(|q| !q.is_empty()) {
+                for queue in queues.iter_mut() {
+                    if let Some(part) = queue.pop() {
+                        merged.extend(reassembler.push(part).unwrap());
+                    }
+                }
+            }
+
+            assert_eq!(merged.len(), 3);
+            let find = |session: u32, seq: u32| {
+                merged
+                    .iter()
+                    .find(|m| m.session == SessionId::new(session) && m.seq == seq)
+                    .unwrap()
+                    .payload()
+                    .to_vec()
+            };
+            assert_eq!(find(1, 1), payload_a);
+            assert_eq!(find(1, 2), payload_b);
+            assert_eq!(find(2, 1), payload_c);
+            assert_eq!(reassembler.partials.len(), 0);
+        }
+
+        #[test]
+        fn test_duplicated_parts_are_dropped() {
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, MAX_CACHED_BYTES);
+            let payload: Vec<u8> = (0..48).collect();
+            let parts = partitioned(1, 7, &payload);
+            assert_eq!(parts.len(), 3);
+
+            assert_eq!(reassembler.push(parts[1].clone()).unwrap(), None);
+            assert_eq!(reassembler.push(parts[1].clone()).unwrap(), None);
+            assert_eq!(reassembler.push(parts[0].clone()).unwrap(), None);
+            let merged = reassembler.push(parts[2].clone()).unwrap().unwrap();
+            assert_eq!(merged.payload(), &payload[..]);
+            assert_eq!(merged.seq, 7);
+        }
+
+        #[test]
+        fn test_mismatched_total_is_an_error() {
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, MAX_CACHED_BYTES);
+            let three_parts = partitioned(1, 1, &[0; 48]);
+            let two_parts = partitioned(1, 1, &[0; 32]);
+
+            assert_eq!(reassembler.push(three_parts[0].clone()).unwrap(), None);
+            assert!(reassembler.push(two_parts[1].clone()).is_err());
+        }
+
+        #[test]
+        fn test_incomplete_messages_expire() {
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, MAX_CACHED_BYTES);
+            let start = Instant::now();
+            let old = partitioned(1, 1, &[0; 48]);
+            let recent = partitioned(1, 2, &[0; 48]);
+
+            reassembler.push_at(old[0].clone(), start).unwrap();
+            reassembler
+                .push_at(recent[0].clone(), start + MESSAGE_EXPIRATION / 2)
+                .unwrap();
+
+            assert_eq!(reassembler.purge_expired_at(start + MESSAGE_EXPIRATION), 1);
+            assert_eq!(reassembler.partials.len(), 1);
+            assert!(reassembler.partials.contains_key(&(SessionId::new(1), 2)));
+        }
+
+        #[test]
+        fn test_memory_is_capped() {
+            let parts = partitioned(1, 1, &[0; 48]);
+            let size = Reassembler::size_of(&parts[0]);
+            // Room for 2 parts.
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, size * 2 + size / 2);
+
+            assert_eq!(reassembler.push(parts[0].clone()).unwrap(), None);
+            assert_eq!(reassembler.push(parts[1].clone()).unwrap(), None);
+            let other = partitioned(1, 2, &[0; 48]);
+            assert!(reassembler.push(other[0].clone()).is_err());
+            // The rejected part did not leave an empty entry behind.
+            assert_eq!(reassembler.partials.len(), 1);
+
+            // Neither does the last part of the first message.
+            assert!(reassembler.push(parts[2].clone()).is_err());
+
+            // Completing a message frees its memory.
+            let reassembler = Reassembler::new(MESSAGE_EXPIRATION, size * 3);
+            for part in &parts[..2] {
+                assert_eq!(reassembler.push(part.clone()).unwrap(), None);
             }
+            assert!(reassembler.push(parts[2].clone()).unwrap().is_some());
+            assert_eq!(reassembler.cached_bytes.load(Ordering::Relaxed), 0);
+            assert_eq!(reassembler.push(other[0].clone()).unwrap(), None);
+
+            // And so does purging it.
+            assert_eq!(
+                reassembler.purge_expired_at(Instant::now() + MESSAGE_EXPIRATION),
+                1
+            );
+            assert_eq!(reassembler.cached_bytes.load(Ordering::Relaxed), 0);
+        }
+    }
+}
+
+/// Where the messages received from Discord go: to the forward owning the channel they were
+/// received in.
+#[derive(Default)]
+pub struct Routes {
+    forwards: Vec<ForwardRoute>,
+}
+
+/// The queues of a forward.
+struct ForwardRoute {
+    channels: Vec<u64>,
+    /// Gets the messages received in the channels.
+    tx: mpsc::Sender<message::Message>,
+    /// Sends the messages to the channels. (see `handle_write_discord`)
+    writer_tx: mpsc::Sender<message::Message>,
+    /// Opens the messages received in the channels.
+    link: Arc<crypto::Link>,
+}
+
+impl Routes {
+    /// Sends the messages received in these channels to `tx`, once opened with the link, and
+    /// the answers to them (PONGs and HELLOs) to `writer_tx`.
+    pub fn add(
+        &mut self,
+        channels: &[u64],
+        tx: mpsc::Sender<message::Message>,
+        writer_tx: mpsc::Sender<message::Message>,
+        link: Arc<crypto::Link>,
+    ) {
+        self.forwards.push(ForwardRoute {
+            channels: channels.to_vec(),
+            tx,
+            writer_tx,
+            link,
         });
     }
+
+    /// Returns the forward of the channel.
+    ///
+    /// With a single forward, it does not matter which channel they were received in.
+    fn get(&self, channel: u64) -> Option<&ForwardRoute> {
+        match self.forwards.as_slice() {
+            [forward] => Some(forward),
+            forwards => forwards
+                .iter()
+                .find(|forward| forward.channels.contains(&channel)),
+        }
+    }
 }
 
 /// Structure that will implement the handler that will receive all new Discord messages.
+///
+/// The messages of the peer are given to `DiscordBot::receive`.
 struct Handler {
-    message_tx: mpsc::Sender<message::Message>,
-    stop_tx: broadcast::Sender<()>,
-    side: cli::Mode,
+    frame_tx: mpsc::Sender<Frame>,
+    /// The guild whose messages are read.
+    guild_id: u64,
+    /// The user IDs of the peer's bots. (empty if anyone is allowed)
+    peer_bots: Vec<u64>,
+    /// Our own user ID, to ignore our messages. Told by the READY event.
+    bot_id: OnceLock<UserId>,
 }
 
 #[async_trait]
 impl EventHandler for Handler {
-    async fn message(&self, ctx: Context, msg: channel::Message) {
+    async fn ready(&self, _ctx: Context, ready: Ready) {
+        info!(
+            "Connected to Discord as {} ({})",
+            ready.user.name, ready.user.id
+        );
+        // Sent again on each reconnection, with the same user.
+        let _ = self.bot_id.set(ready.user.id);
+    }
+
+    async fn message(&self, _ctx: Context, msg: channel::Message) {
+        // Until READY, we cannot tell our messages from the peer's.
+        let Some(&bot_id) = self.bot_id.get() else {
+            warn!(
+                "Skipped a message of channel {}: not ready yet",
+                msg.channel_id
+            );
+            return;
+        };
+
         // Exclude messages sent by us
-        if msg.author.id == get_bot_id(ctx).await {
+        if msg.author.id == bot_id {
             return;
         }
 
         // Exclude all messages from other guilds
-        if msg.guild_id.unwrap_or_default() != get_discord_guild_id() {
+        if msg.guild_id.unwrap_or_default() != self.guild_id {
+            return;
+        }
+
+        // Exclude the messages of anyone but the peer's bot
+        if !is_peer_bot(&self.peer_bots, msg.author.id) {
+            let count: u64 = Rejection::UnknownAuthor.count();
+            warn!(
+                "Rejected a message from {} ({}): not a peer bot. ({count} so far)",
+                msg.author.name, msg.author.id
+            );
             return;
         }
 
-        // Will be parsed and sent to the mpsc::Sender
-        let message_content: String = msg.content;
+        let frame = Frame {
+            channel: msg.channel_id.get(),
+            content: msg.content,
+        };
+        if let Err(err) = self.frame_tx.send(frame).await {
+            warn!("Failed to enqueue message from Discord: {err}");
+        }
+    }
+}
 
-        match message::Message::from_string(&message_content) {
-            Ok(messages) => {
-                for message in messages {
-                    if message::Message::is_halt_message(&message) {
-                        info!("RECEIVED DISCORD HALT MESSAGE");
-                        self.stop_tx.send(()).unwrap();
-                        debug!("Send stop signal");
+/// Reads the frames of the peer, and sends the messages they carry to the forward owning their
+/// channel, until the transport is closed.
+///
+/// `is_server` tells which direction the messages for us go in: the others are skipped. The
+/// payloads are decoded with the codec.
+pub async fn handle_read_discord<T: Transport>(
+    transport: Arc<T>,
+    routes: Routes,
+    is_server: bool,
+    codec: Codec,
+) {
+    info!("Listening for messages RECEIVED from Discord");
+    let reassembler =
+        cache::Reassembler::new(Timeouts::current().reassembly(), cache::MAX_CACHED_BYTES);
+
+    // Cleanup as often as entries expire
+    let mut cleanup = interval(Timeouts::current().reassembly());
+
+    // Until the peer of each forward is known. (see `crypto::Link`)
+    let mut hello = interval(crypto::HELLO_INTERVAL);
+    let direction = if is_server {
+        message::MessageDirection::Clientbound
+    } else {
+        message::MessageDirection::Serverbound
+    };
+
+    loop {
+        tokio::select! {
+            frame = transport.receive() => match frame {
+                Some(frame) => handle_frame(&routes, &reassembler, is_server, codec, frame).await,
+                None => {
+                    warn!("The transport is closed. Stopped reading Discord messages.");
+                    return;
+                }
+            },
+            _ = cleanup.tick() => {
+                let purged: usize = reassembler.purge_expired();
+                if purged > 0 {
+                    warn!("PURGED {purged} STALE MESSAGES FROM CACHE");
+                }
+            }
+            _ = hello.tick() => {
+                for forward in &routes.forwards {
+                    let Some(hello) = forward.link.hello() else {
+                        continue;
+                    };
+                    let hello = message::Message::from_control(&hello, direction, SessionId::FORWARD);
+                    if let Err(err) = forward.writer_tx.try_send(hello) {
+                        warn!("Failed to send HELLO: {err}");
                     }
+                }
+            }
+        }
+    }
+}
 
-                    let current_side: &cli::Mode = &self.side;
-                    let message_side: &message::MessageDirection = &message.direction;
+/// Sends the messages of a frame to their forward.
+async fn handle_frame(
+    routes: &Routes,
+    reassembler: &cache::Reassembler,
+    is_server: bool,
+    codec: Codec,
+    frame: Frame,
+) {
+    // The messages of channels that are not ours are not for us
+    let Some(forward) = routes.get(frame.channel) else {
+        debug!(
+            "Ignored a message of channel {}: no forward uses it",
+            frame.channel
+        );
+        return;
+    };
 
-                    // Return if the message direction does not correspond with our side.
-                    if !message_direction_matches_side(current_side, message_side) {
-                        return;
+    match Aggregator::disaggregate(&frame.content, codec) {
+        Ok(messages) => {
+            for message in messages {
+                // Skip the message if its direction does not correspond with our side.
+                if !message_direction_matches_side(is_server, &message.direction) {
+                    continue;
+                }
+
+                // From here, the message is for us :
+
+                let message = match forward.link.open(message) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        let count: u64 = Rejection::Unauthenticated.count();
+                        warn!(
+                            "Rejected a frame of channel {}: {err}. ({count} so far)",
+                            frame.channel
+                        );
+                        continue;
                     }
+                };
 
-                    // From here, the message is for us :
-
-                    match cache_or_merge_message(message.clone()).await {
-                        Ok(maybe_message) => {
-                            if let Some(merged_message) = maybe_message {
-                                // Send message to tx
-                                if let Err(err) = self.message_tx.send(merged_message).await {
-                                    warn!("Failed to enqueue message from Discord: {err}");
-                                }
-                                debug!(
-                                    "ENQUEUED DISCORD MESSAGE TO TCP CHANNEL. {}/{}",
-                                    message.part.current(),
-                                    message.part.total()
-                                )
-                            } else {
-                                debug!(
-                                    "CACHING DISCORD RECEIVED MESSAGE. {}/{}",
-                                    message.part.current(),
-                                    message.part.total()
-                                );
-                            }
-                        }
-                        Err(err) => {
-                            error!("Failed to cache or merge message: {err}");
-                            return;
+                // Control frames are never partitioned, they skip the cache.
+                if message.is_control() {
+                    handle_control(forward, message).await;
+                    continue;
+                }
+
+                let current: usize = message.part.current();
+                let total: usize = message.part.total();
+                match reassembler.push(message) {
+                    Ok(Some(merged_message)) => {
+                        // Send message to tx
+                        if let Err(err) = forward.tx.send(merged_message).await {
+                            warn!("Failed to enqueue message from Discord: {err}");
                         }
+                        debug!("ENQUEUED DISCORD MESSAGE TO TCP CHANNEL. {current}/{total}")
                     }
+                    Ok(None) => debug!("CACHING DISCORD RECEIVED MESSAGE. {current}/{total}"),
+                    Err(err) => error!("Failed to cache or merge message: {err}"),
                 }
             }
-            Err(err) => {
-                warn!("Failed to decode Discord message (from_string()): {err}");
+        }
+        Err(err) => {
+            warn!("Failed to decode Discord message: {err}");
+        }
+    }
+}
+
+/// Reacts to a control frame received from Discord.
+///
+/// PINGs and HELLOs are answered right away, along with the other messages of the forward.
+/// Other frames are for the session.
+async fn handle_control(forward: &ForwardRoute, message: message::Message) {
+    let control = match message::Control::try_from(&message) {
+        Ok(control) => control,
+        Err(err) => {
+            warn!(
+                "Invalid control frame of session {}: {err}",
+                message.session
+            );
+            return;
+        }
+    };
+
+    match control {
+        message::Control::Ping(nonce) => {
+            let pong = message::Message::from_control(
+                &message::Control::Pong(nonce),
+                message.direction.opposite(),
+                message.session,
+            );
+            // The frames received meanwhile must not wait for the writer: a PING that cannot
+            // be answered now times out like a lost one.
+            if let Err(err) = forward.writer_tx.try_send(pong) {
+                warn!(
+                    "Failed to answer PING of session {}: {err}",
+                    message.session
+                );
             }
+            return;
+        }
+        message::Control::Hello { run, peer_run } => {
+            debug!("RECEIVED DISCORD HELLO FRAME: run {run:016x}, ours {peer_run:016x}");
+            let Some(answer) = forward.link.on_hello(run, peer_run) else {
+                return;
+            };
+            let answer = message::Message::from_control(
+                &answer,
+                message.direction.opposite(),
+                message.session,
+            );
+            if let Err(err) = forward.writer_tx.try_send(answer) {
+                warn!("Failed to answer HELLO: {err}");
+            }
+            return;
+        }
+        message::Control::Open => {
+            info!("RECEIVED DISCORD OPEN FRAME (session {})", message.session)
         }
+        message::Control::Close { ref reason } => info!(
+            "RECEIVED DISCORD CLOSE FRAME (session {}): {reason}",
+            message.session
+        ),
+        message::Control::Error { code, ref text } => warn!(
+            "RECEIVED DISCORD ERROR FRAME (session {}): [{code}] {text}",
+            message.session
+        ),
+        message::Control::Pong(nonce) => {
+            debug!(
+                "RECEIVED DISCORD PONG FRAME (session {}): {nonce}",
+                message.session
+            )
+        }
+        message::Control::Ack { next_seq, window } => {
+            debug!(
+                "RECEIVED DISCORD ACK FRAME (session {}): up to #{next_seq}, window {window}B",
+                message.session
+            )
+        }
+    }
+
+    if let Err(err) = forward.tx.send(message).await {
+        warn!("Failed to enqueue control frame from Discord: {err}");
     }
 }
 
 /// Checks if we should account for the received Discord message.
 fn message_direction_matches_side(
-    current_side: &cli::Mode,
+    is_server: bool,
     message_side: &message::MessageDirection,
 ) -> bool {
-    let is_server: bool = matches!(current_side, cli::Mode::Server { .. });
     let is_serverbound: bool = matches!(message_side, message::MessageDirection::Serverbound);
     is_server == is_serverbound
 }
 
-/// Caches the message
-/// Or, merges the cache to make one message.
-///
-/// If the function returns Ok(None), we should receive more messages to make for the
-/// merged message with all parts.
-async fn cache_or_merge_message(
-    message: message::Message,
-) -> Result<Option<message::Message>, message::MessageError> {
-    if message.part.total() == 1 {
-        debug!("Got 1/1. Returned message.");
-        return Ok(Some(message));
-    }
-
-    let now = Instant::now();
-    let messages = (vec![message.clone()], now);
-    let mut current_key_guard = cache::CURRENT_KEY.lock().await;
-
-    if cache::MESSAGE_CACHE.is_empty() {
-        cache::MESSAGE_CACHE.insert(*current_key_guard, messages);
-        debug!("MESSAGE_CACHE was empty. Inserted message. Returned Ok(None)");
-        return Ok(None);
-    }
-
-    let cached_messages = cache::MESSAGE_CACHE
-        .get(&current_key_guard)
-        .ok_or(message::MessageError::Merging("unknown key in cache"))?
-        .clone();
-
-    let last_cached_message: &message::Message = cached_messages
-        .0
-        .last()
-        .ok_or(message::MessageError::Merging("expected message, got None"))?;
-
-    // We have different total parts (e.g., last is 2/5 and we are 2/10)
-    if last_cached_message.part.total() != message.part.total() {
-        *current_key_guard += 1;
-        cache::MESSAGE_CACHE.insert(*current_key_guard, messages);
-        error!(
-        "Got different total parts between received and cached. Incrementing key, inserting new message into cache. Returned Ok(Some(message))"
-            );
-
-        return Ok(None);
-    }
-
-    // We are the next part (e.g., last is 1/5 and we are 2/5)
-    if last_cached_message.part.current() == message.part.current() - 1 {
-        cache::MESSAGE_CACHE.insert(*current_key_guard, messages);
-        debug!(
-            "Got next part. Returning Ok(None). {}/{}",
-            last_cached_message.part.current(),
-            message.part.current()
-        );
-        return Ok(None);
-
-        //*current_key_guard += 1;
-        //debug!("Finished merging partitions as I got the last part. Returning merged.");
-        //return Ok(Some(message::Message::merge_partitions(&series)?));
+/// Returns the user IDs of the peer's bots. (empty if anyone is allowed)
+fn peer_bots(side: &cli::Mode) -> &[u64] {
+    match side {
+        cli::Mode::Server { peer_bots, .. }
+        | cli::Mode::Client { peer_bots, .. }
+        | cli::Mode::Tunnel { peer_bots, .. } => peer_bots,
     }
+}
 
-    // We are the last part (e.g., last is 4/5 and we are 5/5)
-    if last_cached_message.part.total() == message.part.current() {
-        *current_key_guard += 1;
+/// Returns true if the messages of the author must be read.
+fn is_peer_bot(peer_bots: &[u64], author: UserId) -> bool {
+    peer_bots.is_empty() || peer_bots.contains(&author.get())
+}
 
-        let mut series = cached_messages.0.clone();
-        series.push(message);
+/// Why a Discord message, or one of its frames, was rejected.
+#[derive(Clone, Copy, Debug)]
+enum Rejection {
+    /// The author is not one of the peer's bots.
+    UnknownAuthor,
+    /// The frame could not be authenticated. (forged, altered or replayed)
+    Unauthenticated,
+}
 
-        debug!("Got last part. Returning merged message");
+/// The number of rejections so far, for each `Rejection`.
+static REJECTIONS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
 
-        return Ok(Some(Partitioner::merge(&series)?));
+impl Rejection {
+    /// Counts one more rejection. Returns the number of rejections of this kind so far.
+    fn count(self) -> u64 {
+        REJECTIONS[self as usize].fetch_add(1, Ordering::Relaxed) + 1
     }
-
-    error!("Reached end of merge_from_cache(). Returned Err");
-    Err(message::MessageError::Merging(
-        "Unexpected logic flow :shrug:",
-    ))
 }
 
-/// Returns a vec of u64 of each line from a file.
-pub fn read_channel_ids_file(filepath: &str) -> Vec<u64> {
-    // Open the file
-    let file = File::open(filepath).expect("Failed to open Discord channel IDs file");
+#[cfg(test)]
+mod tests {
 
-    // Create a buffered reader
-    let reader = io::BufReader::new(file);
-    let mut channel_ids: Vec<u64> = Vec::new();
+    use super::*;
 
-    for line in reader.lines() {
-        let line = line.expect("Failed to read line");
-        channel_ids.push(line.parse().expect("Failed to parse line as a channel ID"));
+    use crate::codec::Codec;
+    use crate::compression::Compression;
+    use crate::crypto::FrameMode;
+    use crate::forward::{End, Framing};
+    use crate::session::SessionId;
+
+    #[test]
+    fn test_is_peer_bot() {
+        let author = UserId::new(1234);
+        assert!(is_peer_bot(&[], author));
+        assert!(is_peer_bot(&[1, 1234], author));
+        assert!(!is_peer_bot(&[1, 2], author));
     }
 
-    channel_ids
-}
+    #[test]
+    fn test_rejection_count() {
+        let before = Rejection::UnknownAuthor.count();
+        assert!(Rejection::UnknownAuthor.count() > before);
+    }
 
-/// A lazy-initialized value because in the handler, we need the value of the botID to ignore our
-/// messages, so we'll query it once and reuse it for the rest of the program's lifetime.
-static BOT_ID: tokio::sync::OnceCell<UserId> = tokio::sync::OnceCell::const_new();
+    #[test]
+    fn test_message_direction_matches_side() {
+        let server = cli::Mode::Server {
+            address: Some(String::from("127.0.0.1")),
+            port: Some(25565),
+            token: None,
+            guild_id: None,
+            channels: Vec::new(),
+            codec: Codec::Hex,
+            compression: Compression::None,
+            framing: Framing::Raw,
+            secret: None,
+            frame_mode: FrameMode::Encrypt,
+            peer_bots: Vec::new(),
+            api_url: None,
+        };
+        let client = cli::Mode::Client {
+            listen: vec!["0.0.0.0:25565".parse().unwrap()],
+            token: None,
+            guild_id: None,
+            channels: Vec::new(),
+            codec: Codec::Hex,
+            compression: Compression::None,
+            framing: Framing::Raw,
+            secret: None,
+            frame_mode: FrameMode::Encrypt,
+            peer_bots: Vec::new(),
+            api_url: None,
+        };
 
-/// lazy-initialized Discord Guild ID.
-static DISCORD_GUILD_ID: once_cell::sync::OnceCell<String> = once_cell::sync::OnceCell::new();
+        let serverbound = message::MessageDirection::Serverbound;
+        let clientbound = message::MessageDirection::Clientbound;
+        assert!(message_direction_matches_side(
+            server.is_server(),
+            &serverbound
+        ));
+        assert!(!message_direction_matches_side(
+            server.is_server(),
+            &clientbound
+        ));
+        assert!(message_direction_matches_side(
+            client.is_server(),
+            &clientbound
+        ));
+        assert!(!message_direction_matches_side(
+            client.is_server(),
+            &serverbound
+        ));
+
+        let exit = cli::Mode::Tunnel {
+            end: End::Exit,
+            token: None,
+            guild_id: None,
+            codec: Codec::Hex,
+            compression: Compression::None,
+            secret: None,
+            frame_mode: FrameMode::Encrypt,
+            peer_bots: Vec::new(),
+            api_url: None,
+            forwards: Vec::new(),
+        };
+        assert!(message_direction_matches_side(
+            exit.is_server(),
+            &serverbound
+        ));
+        assert!(!message_direction_matches_side(
+            exit.is_server(),
+            &clientbound
+        ));
+    }
 
-/// Fetches the current running bot UserId and stores it into a static variable for later use.
-async fn get_bot_id(ctx: Context) -> UserId {
-    // Initialize the value if not already initialized
-    *BOT_ID
-        .get_or_init(|| async { ctx.http.get_current_user().await.unwrap().id })
-        .await
-}
+    #[test]
+    fn test_routes() {
+        let (ssh_tx, _ssh_rx) = mpsc::channel(1);
+        let (rcon_tx, _rcon_rx) = mpsc::channel(1);
+        let (writer_tx, _writer_rx) = mpsc::channel(1);
+
+        let mut routes = Routes::default();
+        let link = Arc::new(crypto::Link::new(None));
+        routes.add(
+            &[1, 2],
+            ssh_tx.clone(),
+            writer_tx.clone(),
+            Arc::clone(&link),
+        );
+        // A single forward gets the messages of every channel.
+        assert!(routes.get(3).unwrap().tx.same_channel(&ssh_tx));
 
-/// Reads the Discord bot token from a .env file and initializes the static var above.
-pub fn get_discord_guild_id() -> u64 {
-    match CURRENT_SIDE.get().unwrap() {
-        cli::Mode::Server { guild_id, .. } | cli::Mode::Client { guild_id, .. } => *guild_id,
+        routes.add(&[3], rcon_tx.clone(), writer_tx, link);
+        assert!(routes.get(2).unwrap().tx.same_channel(&ssh_tx));
+        assert!(routes.get(3).unwrap().tx.same_channel(&rcon_tx));
+        assert!(routes.get(4).is_none());
     }
-}
 
-#[cfg(test)]
-mod tests {
+    #[tokio::test]
+    async fn test_ping_is_answered_through_the_writer() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let (writer_tx, mut writer_rx) = mpsc::channel(1);
+        let forward = ForwardRoute {
+            channels: vec![1],
+            tx,
+            writer_tx,
+            link: Arc::new(crypto::Link::new(None)),
+        };
 
-    use super::*;
+        let session = SessionId::new(7);
+        let ping = message::Message::from_control(
+            &message::Control::Ping(42),
+            message::MessageDirection::Serverbound,
+            session,
+        );
+        handle_control(&forward, ping).await;
+
+        let pong = writer_rx.try_recv().unwrap();
+        assert_eq!(pong.direction, message::MessageDirection::Clientbound);
+        assert_eq!(pong.session, session);
+        assert_eq!(
+            message::Control::try_from(&pong).unwrap(),
+            message::Control::Pong(42)
+        );
+        // Not for the session.
+        assert!(rx.try_recv().is_err());
+    }
 }
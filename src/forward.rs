@@ -0,0 +1,237 @@
+//! Everything to describe the forwards of the tunnel mode.
+//!
+//! A forward carries the connections made to a local address to a remote `host:port`, through
+//! its own Discord channels. The entry end listens, the exit end connects: both ends read the same
+//! forwards (usually from the same configuration file) and match them by their channels.
+//!
+//! The server and client modes are a single forward to a Minecraft server.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// The name of the forward of the server and client modes.
+pub const MINECRAFT: &str = "minecraft";
+
+/// Which end of the forwards runs in this process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum End {
+    /// Listens for the connections to forward. (like the client side)
+    Entry,
+    /// Connects to the targets of the forwards. (like the server side)
+    Exit,
+}
+
+/// What a forward carries.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// A byte stream.
+    #[default]
+    Tcp,
+    /// Datagrams, each one sent as a message of its own. (see `udp`)
+    Udp,
+}
+
+/// How the stream read from the sockets of a forward is cut into messages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// Whatever was read, every 100 ms.
+    #[default]
+    Raw,
+    /// Whole Minecraft packets only, as long as they can be read. (see `minecraft`) The server
+    /// list pings are also answered on the client side (see `status`), and the Keep Alives on both
+    /// sides: it must be set on both. The player is told why the tunnel failed, if it does.
+    Minecraft,
+}
+
+/// Where the exit end of a forward connects to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Target {
+    /// A hostname or an IP address. (IPv6 addresses are in brackets)
+    pub host: String,
+    /// Only the Minecraft server can be given without a port. (see `address::resolve`)
+    pub port: Option<u16>,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    /// Parses `host:port`, `[ipv6]:port` or `host`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = if s.starts_with('[') {
+            match s.split_once("]:") {
+                Some((host, port)) => (format!("{host}]"), Some(port)),
+                None => (s.to_string(), None),
+            }
+        } else {
+            match s.matches(':').count() {
+                0 => (s.to_string(), None),
+                1 => {
+                    let (host, port) = s.split_once(':').unwrap_or_default();
+                    (host.to_string(), Some(port))
+                }
+                _ => return Err(format!("{s:?}: IPv6 addresses must be in brackets")),
+            }
+        };
+
+        if host.is_empty() || host == "[]" {
+            return Err(format!("{s:?} has no host"));
+        }
+        let port: Option<u16> = port
+            .map(|port| {
+                port.parse()
+                    .map_err(|_| format!("{s:?} has an invalid port"))
+            })
+            .transpose()?;
+
+        Ok(Target { host, port })
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}:{port}", self.host),
+            None => write!(f, "{}", self.host),
+        }
+    }
+}
+
+/// A local address forwarded to a remote one.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Forward {
+    /// Shows up in the logs.
+    pub name: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub framing: Framing,
+    /// Where the entry end listens.
+    #[serde(default)]
+    pub listen: Vec<SocketAddr>,
+    /// Where the exit end connects to.
+    pub target: Option<Target>,
+    /// The Discord channels of this forward only.
+    pub channels: Vec<u64>,
+}
+
+/// Lists what is wrong in the forwards, for the given end. (or for both ends if `None`)
+pub fn problems(forwards: &[Forward], end: Option<End>) -> Vec<String> {
+    let mut problems: Vec<String> = Vec::new();
+    let mut names: HashSet<&str> = HashSet::new();
+    let mut channels: HashSet<u64> = HashSet::new();
+
+    for forward in forwards {
+        let name: &str = &forward.name;
+        if name.is_empty() {
+            problems.push("a forward has no name".to_string());
+        } else if !names.insert(name) {
+            problems.push(format!("there are several forwards named {name}"));
+        }
+
+        if forward.channels.is_empty() {
+            problems.push(format!("forward {name} has no channel"));
+        }
+        for channel in &forward.channels {
+            if *channel == 0 || !channels.insert(*channel) {
+                problems.push(format!(
+                    "channel {channel} of forward {name} is invalid or used by another forward"
+                ));
+            }
+        }
+
+        if end != Some(End::Exit) && forward.listen.is_empty() {
+            problems.push(format!("forward {name} has no listen address"));
+        }
+        if forward.protocol == Protocol::Udp && forward.framing != Framing::Raw {
+            problems.push(format!(
+                "forward {name} frames the packets of UDP datagrams"
+            ));
+        }
+        match &forward.target {
+            Some(Target { port: None, .. }) => {
+                problems.push(format!("the target of forward {name} has no port"))
+            }
+            None if end != Some(End::Entry) => {
+                problems.push(format!("forward {name} has no target"))
+            }
+            _ => {}
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forward(name: &str, channels: &[u64]) -> Forward {
+        Forward {
+            name: name.to_string(),
+            protocol: Protocol::Tcp,
+            framing: Framing::Raw,
+            listen: vec!["127.0.0.1:2222".parse().unwrap()],
+            target: Some("build-box:22".parse().unwrap()),
+            channels: channels.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let target: Target = "build-box:22".parse().unwrap();
+        assert_eq!(target.host, "build-box");
+        assert_eq!(target.port, Some(22));
+        assert_eq!(target.to_string(), "build-box:22");
+
+        let target: Target = "[::1]:25575".parse().unwrap();
+        assert_eq!(target.host, "[::1]");
+        assert_eq!(target.port, Some(25575));
+
+        let target: Target = "mc.example.com".parse().unwrap();
+        assert_eq!(target.port, None);
+
+        for invalid in ["", ":22", "[]:22", "::1", "box:port", "box:65536"] {
+            assert!(invalid.parse::<Target>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        let forwards = vec![forward("ssh", &[1, 2]), forward("rcon", &[3])];
+        assert!(problems(&forwards, None).is_empty());
+
+        let forwards = vec![forward("ssh", &[1, 2]), forward("ssh", &[2])];
+        assert_eq!(problems(&forwards, None).len(), 2);
+
+        let mut no_listen = forward("dynmap", &[4]);
+        no_listen.listen.clear();
+        assert!(problems(&[no_listen.clone()], Some(End::Exit)).is_empty());
+        assert_eq!(problems(&[no_listen], Some(End::Entry)).len(), 1);
+
+        let mut no_port = forward("dynmap", &[4]);
+        no_port.target = Some("map.example.com".parse().unwrap());
+        assert_eq!(problems(&[no_port], Some(End::Exit)).len(), 1);
+
+        let mut framed_udp = forward("geyser", &[5]);
+        framed_udp.protocol = Protocol::Udp;
+        framed_udp.framing = Framing::Minecraft;
+        assert_eq!(problems(&[framed_udp], None).len(), 1);
+    }
+}
@@ -0,0 +1,44 @@
+//! Play a Minecraft server through Discord.
+//!
+//! The binary (`main.rs`) wires these modules together. They live in a library so that they can
+//! also be used by the fuzz targets (see `fuzz/`) and by the `discraft-testkit` binary. (see
+//! `testkit`)
+//!
+//! The test doubles (`mock_discord`, `simulator` and `testkit`) are only built for the tests and
+//! with the `testkit` feature: `cargo run --features testkit --bin discraft-testkit`.
+
+pub mod address;
+pub mod cli;
+pub mod codec;
+pub mod compression;
+pub mod config;
+pub mod crypto;
+pub mod discord;
+pub mod forward;
+pub mod logging;
+pub mod message;
+pub mod minecraft;
+#[cfg(any(test, feature = "testkit"))]
+pub mod mock_discord;
+pub mod partitioning;
+pub mod reliability;
+pub mod scheduler;
+pub mod sequencing;
+pub mod session;
+#[cfg(any(test, feature = "testkit"))]
+pub mod simulator;
+pub mod sockets;
+pub mod status;
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+pub mod transport;
+pub mod tunnel;
+pub mod udp;
+
+use std::sync::OnceLock;
+
+/// Which side we are running on
+///
+/// Client: MC Client <-> us <-> Discord
+/// Server: Discord <-> us <-> MC Server
+pub static CURRENT_SIDE: OnceLock<cli::Mode> = OnceLock::new();
@@ -1,62 +1,51 @@
-mod cli;
-mod discord;
-mod logging;
-mod message;
-mod partitioning;
-mod sockets;
-
+use discraft::config;
+use discraft::{cli, crypto, discord, logging, tunnel, CURRENT_SIDE};
 use log::debug;
 use log::error;
 use log::info;
 use log::warn;
 use std::error::Error;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::sync::OnceLock;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
 use tokio::sync::broadcast;
-use tokio::sync::mpsc;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::Mutex;
-
-/// Which side we are running on
-///
-/// Client: MC Client <-> us <-> Discord
-/// Server: Discord <-> us <-> MC Server
-pub static CURRENT_SIDE: OnceLock<cli::Mode> = OnceLock::new();
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Init logging
     logging::init_logger();
 
+    let (args, matches) = cli::parse();
+    let mode: cli::Mode = match args.command {
+        cli::Command::CheckConfig => {
+            let path = args
+                .config
+                .unwrap_or_else(|| PathBuf::from(config::DEFAULT_PATH));
+            if !config::check_config(&path) {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        cli::Command::Run(mode) => config::load_mode(mode, args.config.as_deref(), &matches)?,
+    };
+
     // Init the current side (client or server)
-    init_side();
+    let settings: tunnel::Settings = init_side(mode);
 
     // Channel that is meant to signal to stop listening (TCP and Discord)
-    // when there is a disconnection for example.
-    // It should stop all awaiting async tasks.
+    // when the Discord bot dies for example.
+    // It is forwarded to every session's own stop signal.
     let (stop_tx, _) = broadcast::channel::<()>(16);
 
-    // Start the Discord bot
-    let (discord_tx, discord_rx) = mpsc::channel::<message::Message>(64);
-    let discord_rx = Arc::new(Mutex::new(discord_rx)); // Wrap receiver in Arc<Mutex>
-
-    let bot: Arc<discord::DiscordBot> = init_discord_bot(discord_tx, stop_tx.clone()).await;
+    // Start the Discord bot. Each forward receives the messages of its own channels.
+    let bot: Arc<discord::DiscordBot> = init_discord_bot(stop_tx.clone()).await;
 
-    match CURRENT_SIDE.get().unwrap() {
-        cli::Mode::Server { .. } => server(stop_tx, bot, discord_rx).await,
-        cli::Mode::Client { .. } => client(stop_tx, bot, discord_rx).await,
-    }
+    let side: &cli::Mode = CURRENT_SIDE.get().unwrap();
+    tunnel::run(bot, side.forwards(), side.is_server(), stop_tx, settings).await
 }
 
-async fn init_discord_bot(
-    sender: mpsc::Sender<message::Message>,
-    stop_tx: broadcast::Sender<()>,
-) -> Arc<discord::DiscordBot> {
+async fn init_discord_bot(stop_tx: broadcast::Sender<()>) -> Arc<discord::DiscordBot> {
     let current_side = CURRENT_SIDE.get().unwrap().clone();
-    let bot = Arc::new(discord::DiscordBot::new(current_side, sender, stop_tx.clone()).await);
+    let bot = Arc::new(discord::DiscordBot::new(current_side).await);
 
     let bot_clone = Arc::clone(&bot);
     tokio::spawn(async move {
@@ -64,7 +53,7 @@ async fn init_discord_bot(
         bot_clone.start().await;
 
         error!("Bot exited. Broadcasting stop signal");
-        stop_tx.send(()).unwrap();
+        let _ = stop_tx.send(());
     });
 
     info!("Discord bot started");
@@ -73,170 +62,57 @@ async fn init_discord_bot(
 }
 
 /// Initializes the current side on which the program will run
-fn init_side() {
-    CURRENT_SIDE.get_or_init(|| cli::parse().mode);
+///
+/// Returns how the frames are written.
+fn init_side(mode: cli::Mode) -> tunnel::Settings {
+    CURRENT_SIDE.get_or_init(|| mode);
+
+    let settings = match CURRENT_SIDE.get().unwrap() {
+        cli::Mode::Server {
+            codec,
+            compression,
+            secret,
+            frame_mode,
+            peer_bots,
+            ..
+        }
+        | cli::Mode::Client {
+            codec,
+            compression,
+            secret,
+            frame_mode,
+            peer_bots,
+            ..
+        }
+        | cli::Mode::Tunnel {
+            codec,
+            compression,
+            secret,
+            frame_mode,
+            peer_bots,
+            ..
+        } => {
+            if secret.is_none() {
+                warn!("No secret given: the tunnel is NOT encrypted nor authenticated.");
+            }
+            if peer_bots.is_empty() {
+                warn!("No peer bot given: the messages of anyone in the guild are read.");
+            }
+            tunnel::Settings {
+                codec: *codec,
+                compression: *compression,
+                cipher: secret
+                    .as_deref()
+                    .map(|secret| Arc::new(crypto::FrameCipher::new(secret, *frame_mode))),
+            }
+        }
+    };
 
     match CURRENT_SIDE.get().unwrap() {
         cli::Mode::Server { .. } => info!("[ SERVER SIDE RUNNING ]\n"),
         cli::Mode::Client { .. } => info!("[ CLIENT SIDE RUNNING ]\n"),
+        cli::Mode::Tunnel { end, .. } => info!("[ TUNNEL {end:?} RUNNING ]\n"),
     }
-}
-
-/// Client-side logic
-async fn client(
-    stop_tx: broadcast::Sender<()>,
-    bot: Arc<discord::DiscordBot>,
-    discord_rx: Arc<Mutex<Receiver<message::Message>>>,
-) -> Result<(), Box<dyn Error>> {
-    const LISTENING_ADDR: &str = "0.0.0.0";
-    const LISTENING_PORT: u16 = 25565;
-
-    let listener = TcpListener::bind(format!("{LISTENING_ADDR}:{LISTENING_PORT}")).await?;
-
-    let mut conn_counter: u64 = 0;
-
-    loop {
-        info!("Listening on {LISTENING_ADDR}:{LISTENING_PORT}...");
-
-        let (socket, addr) = listener.accept().await?;
-        info!("Connected to client #{conn_counter}: {addr}");
-        conn_counter += 1;
-
-        // Split to socket in two OWNED parts so that we can use the socket through two functions.
-        let (read_half, write_half) = socket.into_split();
-
-        // MC Client -> Discord channels
-        let (tcp_tx, tcp_rx) = mpsc::channel::<message::Message>(64);
-
-        // Receives TCP packets from the MC Client.
-        let tcp_tx_clone = tcp_tx.clone();
-        let stop_tx_clone = stop_tx.clone();
-        let handle_receive_tcp = tokio::spawn(async move {
-            debug!("Inside the handle_receive_socket async task");
-
-            sockets::handle_receive_socket(
-                read_half,
-                tcp_tx_clone,
-                stop_tx_clone,
-                message::MessageDirection::Serverbound,
-            )
-            .await;
-        });
 
-        // Send MC Client packets to Discord
-        let channel_ids: Vec<u64> = discord::read_channel_ids_file("channel_ids.txt");
-        debug!("Discord channel IDs: {channel_ids:#?}");
-
-        let bot_clone = Arc::clone(&bot);
-        let stop_tx_clone2 = stop_tx.clone();
-        let handle_write_discord = tokio::spawn(async move {
-            debug!("Inside the handle_write_discord async task");
-            bot_clone
-                .handle_write_discord(tcp_rx, stop_tx_clone2, &channel_ids)
-                .await;
-        });
-
-        // Sends received Discord messages to the MC Server through TCP.
-        let stop_tx_clone3 = stop_tx.clone();
-        let discord_rx_clone = Arc::clone(&discord_rx);
-        let handle_write_tcp = tokio::spawn(async move {
-            sockets::handle_channel_to_socket(write_half, discord_rx_clone, stop_tx_clone3).await;
-        });
-
-        if let Err(err) =
-            tokio::try_join!(handle_receive_tcp, handle_write_discord, handle_write_tcp)
-        {
-            error!("Error in one of the connection tasks: {:?}", err);
-        }
-
-        info!("--- CONNECTION CLOSED ---");
-    }
-}
-
-const SERVER_ADDRESS: &str = "127.0.0.1";
-const SERVER_PORT: u16 = 25566;
-
-/// Server-side logic
-async fn server(
-    stop_tx: broadcast::Sender<()>,
-    bot: Arc<discord::DiscordBot>,
-    discord_rx: Arc<Mutex<Receiver<message::Message>>>,
-) -> Result<(), Box<dyn Error>> {
-    let mut conn_counter: u64 = 0;
-
-    loop {
-        // Listen for a message that's serverbound (directed to us)
-        let discord_msg: message::Message = {
-            let mut rx_guard = discord_rx.lock().await;
-            match rx_guard.recv().await {
-                Some(msg) => msg,
-                None => {
-                    warn!("Error receiving discord message from closed mpsc channel, got None");
-                    continue;
-                }
-            }
-        };
-
-        info!("Connecting to the server... {SERVER_ADDRESS}:{SERVER_PORT:}");
-        // Connect to the server
-        let mut socket = TcpStream::connect(format!("{SERVER_ADDRESS}:{SERVER_PORT}")).await?;
-        info!("Connected.");
-
-        // Send the first message
-        if let Err(err) = socket.write_all(discord_msg.payload()).await {
-            error!("Failed to send first packet to MC Server: {err}");
-            continue;
-        }
-
-        // Split to socket in two OWNED parts so that we can use the socket through two functions.
-        let (read_half, write_half) = socket.into_split();
-        info!("Connection #{conn_counter} established with {SERVER_ADDRESS}:{SERVER_PORT}");
-        conn_counter += 1;
-
-        // Sends received Discord messages to the MC Server through TCP.
-        let stop_tx_clone3 = stop_tx.clone();
-        let discord_rx_clone = Arc::clone(&discord_rx);
-        let handle_write_tcp = tokio::spawn(async move {
-            sockets::handle_channel_to_socket(write_half, discord_rx_clone, stop_tx_clone3).await;
-        });
-
-        // MC Client -> Discord channels
-        let (tcp_tx, tcp_rx) = mpsc::channel::<message::Message>(64);
-
-        // Receives TCP packets from the MC Server.
-        let tcp_tx_clone = tcp_tx.clone();
-        let stop_tx_clone = stop_tx.clone();
-        let handle_receive_tcp = tokio::spawn(async move {
-            debug!("Inside the handle_receive_socket async task");
-
-            sockets::handle_receive_socket(
-                read_half,
-                tcp_tx_clone,
-                stop_tx_clone,
-                message::MessageDirection::Clientbound,
-            )
-            .await;
-        });
-
-        // Send MC Client packets to Discord
-        let channel_ids: Vec<u64> = discord::read_channel_ids_file("channel_ids.txt");
-        debug!("Discord channel IDs: {channel_ids:#?}");
-
-        let bot_clone = Arc::clone(&bot);
-        let stop_tx_clone2 = stop_tx.clone();
-        let handle_write_discord = tokio::spawn(async move {
-            debug!("Inside the handle_write_discord async task");
-            bot_clone
-                .handle_write_discord(tcp_rx, stop_tx_clone2, &channel_ids)
-                .await;
-        });
-
-        if let Err(err) =
-            tokio::try_join!(handle_receive_tcp, handle_write_discord, handle_write_tcp)
-        {
-            error!("Error in one of the connection tasks: {:?}", err);
-        }
-
-        info!("--- CONNECTION CLOSED ---");
-    }
+    settings
 }
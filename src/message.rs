@@ -3,10 +3,11 @@
 
 use std::fmt::Debug;
 
-use base64::Engine;
 use thiserror::Error;
 
+use crate::codec::Codec;
 use crate::partitioning::{self, Aggregator, Part};
+use crate::session::SessionId;
 
 #[derive(Debug, Error)]
 pub enum MessageError {
@@ -24,6 +25,21 @@ pub enum MessageError {
 
     #[error("Merging error: {0}")]
     Merging(&'static str),
+
+    #[error("Invalid session: {0}")]
+    Session(&'static str),
+
+    #[error("Invalid kind: {0}")]
+    Kind(&'static str),
+
+    #[error("Invalid control frame: {0}")]
+    Control(&'static str),
+
+    #[error("Invalid sequence number: {0}")]
+    Sequence(&'static str),
+
+    #[error("Authentication failed: {0}")]
+    Authentication(&'static str),
 }
 
 /// An attribute specifying who should account for the packet.
@@ -52,6 +68,14 @@ impl MessageDirection {
     pub fn from_string(text: &str) -> Result<MessageDirection, MessageError> {
         MessageDirection::try_from(text)
     }
+
+    /// Returns the direction going back to the sender.
+    pub fn opposite(self) -> MessageDirection {
+        match self {
+            MessageDirection::Clientbound => MessageDirection::Serverbound,
+            MessageDirection::Serverbound => MessageDirection::Clientbound,
+        }
+    }
 }
 
 impl TryFrom<&str> for MessageDirection {
@@ -68,9 +92,224 @@ impl TryFrom<&str> for MessageDirection {
     }
 }
 
+/// What a `Message` carries.
+///
+/// Either Minecraft data, or a control frame driving the session. (see `Control`)
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MessageKind {
+    Data,
+    // Data compressed with deflate.
+    Deflated,
+    Open,
+    Close,
+    Error,
+    Ping,
+    Pong,
+    Ack,
+    // The status of the Minecraft server, in JSON. (see `status`)
+    Status,
+    Hello,
+}
+
+impl MessageKind {
+    /// Encodes the kind to String (a letter followed by a space)
+    pub fn to_string(self) -> &'static str {
+        match self {
+            MessageKind::Data => "D ",
+            MessageKind::Deflated => "F ",
+            MessageKind::Open => "O ",
+            MessageKind::Close => "C ",
+            MessageKind::Error => "E ",
+            MessageKind::Ping => "P ",
+            MessageKind::Pong => "Q ",
+            MessageKind::Ack => "A ",
+            MessageKind::Status => "S ",
+            MessageKind::Hello => "H ",
+        }
+    }
+
+    /// Decodes the first kind from text
+    pub fn from_string(text: &str) -> Result<MessageKind, MessageError> {
+        MessageKind::try_from(text)
+    }
+
+    /// Returns the length of the encoded (to String) `MessageKind`.
+    pub const fn get_standard_string_length() -> usize {
+        2
+    }
+
+    /// Returns true if messages of this kind are numbered and delivered in order.
+    ///
+    /// PING, PONG and ACK are not: they must not wait behind missing data. Neither are STATUS and
+    /// HELLO, which are not part of any session.
+    pub fn is_sequenced(self) -> bool {
+        !matches!(
+            self,
+            MessageKind::Ping
+                | MessageKind::Pong
+                | MessageKind::Ack
+                | MessageKind::Status
+                | MessageKind::Hello
+        )
+    }
+}
+
+impl TryFrom<&str> for MessageKind {
+    type Error = MessageError;
+
+    fn try_from(value: &str) -> Result<Self, MessageError> {
+        [
+            MessageKind::Data,
+            MessageKind::Deflated,
+            MessageKind::Open,
+            MessageKind::Close,
+            MessageKind::Error,
+            MessageKind::Ping,
+            MessageKind::Pong,
+            MessageKind::Ack,
+            MessageKind::Status,
+            MessageKind::Hello,
+        ]
+        .into_iter()
+        .find(|kind| value.starts_with(kind.to_string()))
+        .ok_or(MessageError::Kind("unknown kind"))
+    }
+}
+
+/// A control frame, which drives a session instead of carrying Minecraft data.
+///
+/// It travels as the payload of a `Message` of the matching `MessageKind`:
+///
+/// - OPEN: empty.
+/// - CLOSE: the reason in UTF-8.
+/// - ERROR: the code as a big-endian u16, then the text in UTF-8.
+/// - PING/PONG: the nonce as a big-endian u64.
+/// - ACK: the next expected sequence number as a big-endian u32, then the window as a big-endian
+///   u32.
+/// - HELLO: the run of the sender as a big-endian u64, then the run of the receiver as a
+///   big-endian u64.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Control {
+    /// A new TCP connection was accepted on the client side.
+    Open,
+    /// The session is over. Nothing has to be sent back.
+    Close { reason: String },
+    /// The session failed. Nothing has to be sent back.
+    Error { code: u16, text: String },
+    /// Asks the peer for a `Pong` with the same nonce.
+    Ping(u64),
+    /// Answers a `Ping`.
+    Pong(u64),
+    /// Acknowledges every sequenced message numbered below `next_seq`. (cumulative)
+    /// The peer may have `window` bytes of messages in flight after it: that's what we can
+    /// still buffer.
+    Ack { next_seq: u32, window: u32 },
+    /// Tells the peer our `run`, and the run we know of it. (0 if none) The frames are sealed for
+    /// the runs of both sides. (see `crypto`)
+    Hello { run: u64, peer_run: u64 },
+}
+
+impl Control {
+    /// The server side could not connect to the Minecraft server.
+    pub const ERROR_UNREACHABLE: u16 = 1;
+    /// Reading or writing a TCP socket failed.
+    pub const ERROR_SOCKET: u16 = 2;
+    /// The Discord side of the tunnel failed.
+    pub const ERROR_DISCORD: u16 = 3;
+    /// A message of the session could not be decoded. (e.g. decompressed)
+    pub const ERROR_DECODE: u16 = 4;
+
+    /// Returns the kind of `Message` carrying this control frame.
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Control::Open => MessageKind::Open,
+            Control::Close { .. } => MessageKind::Close,
+            Control::Error { .. } => MessageKind::Error,
+            Control::Ping(_) => MessageKind::Ping,
+            Control::Pong(_) => MessageKind::Pong,
+            Control::Ack { .. } => MessageKind::Ack,
+            Control::Hello { .. } => MessageKind::Hello,
+        }
+    }
+
+    /// Encodes the fields of the control frame into a payload.
+    fn to_payload(&self) -> Vec<u8> {
+        match self {
+            Control::Open => Vec::new(),
+            Control::Close { reason } => reason.as_bytes().to_vec(),
+            Control::Error { code, text } => [&code.to_be_bytes(), text.as_bytes()].concat(),
+            Control::Ping(nonce) | Control::Pong(nonce) => nonce.to_be_bytes().to_vec(),
+            Control::Ack { next_seq, window } => {
+                [&next_seq.to_be_bytes()[..], &window.to_be_bytes()].concat()
+            }
+            Control::Hello { run, peer_run } => {
+                [&run.to_be_bytes()[..], &peer_run.to_be_bytes()].concat()
+            }
+        }
+    }
+}
+
+impl TryFrom<&Message> for Control {
+    type Error = MessageError;
+
+    fn try_from(message: &Message) -> Result<Self, MessageError> {
+        let payload: &[u8] = message.payload();
+        let text = |bytes: &[u8]| {
+            String::from_utf8(bytes.to_vec())
+                .map_err(|_| MessageError::Control("text is not valid UTF-8"))
+        };
+        let nonce = |bytes: &[u8]| {
+            bytes
+                .try_into()
+                .map(u64::from_be_bytes)
+                .map_err(|_| MessageError::Control("nonce must be 8 bytes"))
+        };
+
+        match message.kind {
+            MessageKind::Data | MessageKind::Deflated | MessageKind::Status => {
+                Err(MessageError::Control("data is not a control frame"))
+            }
+            MessageKind::Open => Ok(Control::Open),
+            MessageKind::Close => Ok(Control::Close {
+                reason: text(payload)?,
+            }),
+            MessageKind::Error => {
+                let (code, rest) = payload
+                    .split_first_chunk::<2>()
+                    .ok_or(MessageError::Control("error code is missing"))?;
+                Ok(Control::Error {
+                    code: u16::from_be_bytes(*code),
+                    text: text(rest)?,
+                })
+            }
+            MessageKind::Ping => Ok(Control::Ping(nonce(payload)?)),
+            MessageKind::Pong => Ok(Control::Pong(nonce(payload)?)),
+            MessageKind::Ack => {
+                let bytes: [u8; 8] = payload
+                    .try_into()
+                    .map_err(|_| MessageError::Control("ACK must be 8 bytes"))?;
+                Ok(Control::Ack {
+                    next_seq: u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                    window: u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+                })
+            }
+            MessageKind::Hello => {
+                let bytes: [u8; 16] = payload
+                    .try_into()
+                    .map_err(|_| MessageError::Control("HELLO must be 16 bytes"))?;
+                let (run, peer_run) = bytes.split_at(8);
+                Ok(Control::Hello {
+                    run: u64::from_be_bytes(run.try_into().expect("8 bytes")),
+                    peer_run: u64::from_be_bytes(peer_run.try_into().expect("8 bytes")),
+                })
+            }
+        }
+    }
+}
+
 /// Represents a Message in this application.
 /// That can be instantiated from strings and bytes.
-/// Message layout [length, direction, part, payload]
+/// Message layout [length, direction, session, kind, seq, part, payload]
 ///
 /// # Length
 ///
@@ -82,71 +321,153 @@ pub struct Message {
     pub length: String,
     // Either clientbound, or serverbound.
     pub direction: MessageDirection,
+    // The TCP connection this message belongs to.
+    pub session: SessionId,
+    // Either data, or a control frame.
+    pub kind: MessageKind,
+    // The position of the message in its session and direction. (0 if not sequenced)
+    // All the parts of a partitioned message share it.
+    pub seq: u32,
     // X/Y to partition messages into smaller ones. (e.g. 2/5)
     pub part: partitioning::Part,
 
     // The actual bytes of data. The payload.
     payload: Vec<u8>,
 
+    // How the payload is encoded into text.
+    codec: Codec,
+
     // The full message as a String. Ready to be sent to Discord.
     text: String,
 }
 
-const HALT_DATA: &[u8; 37] = &[
-    3, 4, 4, 0, 1, 1, 1, 1, 0, 0, 0, 0, 127, 127, 127, 127, 127, 127, 9, 9, 9, 9, 9, 9, 9, 9, 9, 9,
-    9, 9, 9, 9, 9, 0, 1, 1, 100,
-];
-
 impl Message {
     pub const LENGTH_DELIMITER: char = '~';
 
-    /// Returs either true of false the input message is a halt message.
-    pub fn is_halt_message(message: &Message) -> bool {
-        let control = Self::make_halt_message(message.direction);
-        control == *message
-    }
+    /// Length of the encoded sequence number. (8 hex digits and a space)
+    pub const SEQ_STRING_LENGTH: usize = 9;
 
     /// Returns the size of the header AS A STRING.
     pub fn get_header_size(&self) -> usize {
-        self.length.len() + self.direction.to_string().len() + Part::get_standard_string_length()
+        self.length.len()
+            + self.direction.to_string().len()
+            + SessionId::get_standard_string_length()
+            + MessageKind::get_standard_string_length()
+            + Self::SEQ_STRING_LENGTH
+            + Part::get_standard_string_length()
     }
 
-    /// Returns a standart halt message.
-    pub fn make_halt_message(direction: MessageDirection) -> Self {
-        let part = Part::new(1, 1).unwrap();
-        let message = Self::make_string(&direction, &part, HALT_DATA);
+    /// Constructs a Message object from all its fields. The payload is encoded with the default
+    /// codec. (see `with_codec`)
+    pub fn new(
+        kind: MessageKind,
+        direction: MessageDirection,
+        session: SessionId,
+        seq: u32,
+        part: Part,
+        payload: Vec<u8>,
+    ) -> Self {
+        let mut message = Self {
+            length: String::new(),
+            direction,
+            session,
+            kind,
+            seq,
+            part,
+            payload,
+            codec: Codec::default(),
+            text: String::new(),
+        };
 
-        Self::from_string(message.0 + &message.1)
-            .expect("Failed to make halt message. (II)")
-            .iter()
-            .next()
-            .expect("Failed to make halt message. (III)")
-            .clone()
+        message.render();
+        message
     }
 
-    // Constructs a Message object from an array of bytes and a direction.
-    pub fn from_bytes<T: AsRef<[u8]>>(data: T, direction: MessageDirection) -> Self {
-        let data: &[u8] = data.as_ref();
-        let part = Part::new(1, 1).unwrap();
+    /// Returns the same Message, with the payload encoded by the codec.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self.render();
+        self
+    }
 
-        let (length, text) = Self::make_string(&direction, &part, data);
+    /// Returns the codec encoding the payload.
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
 
-        Self {
-            length: length.clone(),
+    /// Returns the same Message, numbered with the sequence number.
+    pub fn with_seq(mut self, seq: u32) -> Self {
+        self.seq = seq;
+        self.render();
+        self
+    }
+
+    /// Makes the string representation of the message from its fields.
+    fn render(&mut self) {
+        let (length, text) = self.make_string();
+        self.text = length.clone() + &text;
+        self.length = length;
+    }
+
+    // Constructs a data Message object from an array of bytes, a direction and a session.
+    pub fn from_bytes<T: AsRef<[u8]>>(
+        data: T,
+        direction: MessageDirection,
+        session: SessionId,
+    ) -> Self {
+        Self::new(
+            MessageKind::Data,
+            direction,
+            session,
+            0,
+            Part::new(1, 1).unwrap(),
+            data.as_ref().to_vec(),
+        )
+    }
+
+    // Constructs a Message object carrying a control frame.
+    pub fn from_control(
+        control: &Control,
+        direction: MessageDirection,
+        session: SessionId,
+    ) -> Self {
+        Self::new(
+            control.kind(),
             direction,
+            session,
+            0,
+            Part::new(1, 1).unwrap(),
+            control.to_payload(),
+        )
+    }
+
+    // Constructs a part of this Message, with the same header but the given part and data.
+    pub fn make_part<T: AsRef<[u8]>>(&self, data: T, part: Part) -> Self {
+        let mut message = Self {
+            length: String::new(),
             part,
-            payload: data.to_vec(),
-            text: length + &text,
-        }
+            payload: data.as_ref().to_vec(),
+            text: String::new(),
+            ..*self
+        };
+
+        message.render();
+        message
     }
 
     // Constructs a Message object from a string.
     // Parses the direction from the string.
     //
     // Can return multiple messages if the string is an aggregate of messages.
+    // The payloads must be encoded with the default codec. (see `Aggregator::disaggregate`)
     pub fn from_string<T: AsRef<str>>(message: T) -> Result<Vec<Self>, MessageError> {
         // Use the parsing function from Aggregator.
-        Aggregator::disaggregate(message.as_ref())
+        Aggregator::disaggregate(message.as_ref(), Codec::default())
+    }
+
+    /// Returns the number of characters of the Message as a String.
+    pub fn char_count(&self) -> usize {
+        self.text.chars().count()
     }
 
     // Returns an array of bytes of the Message.
@@ -154,52 +475,12 @@ impl Message {
         &self.payload
     }
 
-    // TODO: (IF HEX ENCODING) Make sure no nibbles are allowed, only full bytes.
-
-    /// Converts bytes to string representation
-    pub fn payload_bytes_to_string(data: &[u8]) -> String {
-        println!("payload_bytes_to_string() input: {data:?}");
-        //base85::encode(data)
-        //general_purpose::STANDARD.encode(data)
-        // base64::Engine::encode(&self, input)
-        data.iter()
-            .map(|byte| format!("{byte:02X}"))
-            .collect::<Vec<String>>()
-            .join("")
-    }
-
-    /// Converts a string to an array of bytes
-    pub fn payload_string_to_bytes(string: &str) -> Result<Vec<u8>, MessageError> {
-        //base85::decode(string).map_err(|_| MessageError::Decode("Failed to decode base85 string"))
-        // general_purpose::STANDARD
-        //     .decode(string)
-        //     .map_err(|_| MessageError::Decode("Failed to decode base85 string"))
-
-        //debug!("In hex_to_bytes(). string={string}");
-
-        // TERRIBLE PERFORMANCE todo
-        // let mut buffer = String::with_capacity(string.len() + string.len() / 4 + 1);
-        // for (idx, char) in string.char_indices() {
-        //     buffer += &char.to_string();
-        //     if (idx + 1) % 2 == 0 {
-        //         buffer += " ";
-        //     }
-        // }
-        let modulo = string.len() % 2 == 0;
-        if modulo {
-        } else {
-        }
-        hex::decode(string.replace(" ", ""))
-            .map_err(|e| MessageError::Decode(format!("Failed to decode hex: {e}")))
-
-        //
-        // println!("payload_string_to_bytes() input: {string:?}");
-        // let res: Vec<u8> = string
-        //     .split_whitespace()
-        //     .filter_map(|hex| u8::from_str_radix(hex, 16).ok())
-        //     .collect();
-        //
-        // Ok(res)
+    /// Returns true if the Message carries a control frame rather than data.
+    pub fn is_control(&self) -> bool {
+        !matches!(
+            self.kind,
+            MessageKind::Data | MessageKind::Deflated | MessageKind::Status
+        )
     }
 
     /// Makes the string representation of the message.
@@ -210,18 +491,17 @@ impl Message {
     ///
     /// So to build the complete packet, just flatten the tuple into a String, and send it's ready
     /// to be sent to Discord.
-    pub fn make_string(
-        direction: &MessageDirection,
-        part: &Part,
-        payload: &[u8],
-    ) -> (String, String) {
-        let mut message_str_except_length = String::with_capacity(100);
-        message_str_except_length.push_str(direction.to_string());
-        message_str_except_length.push_str(&part.to_string());
-        message_str_except_length.push_str(&Self::payload_bytes_to_string(payload));
+    pub fn make_string(&self) -> (String, String) {
+        let codec = self.codec.get();
+        let mut message_str_except_length =
+            String::with_capacity(100 + codec.encoded_len(self.payload.len()));
+        message_str_except_length.push_str(&self.header_string());
+        message_str_except_length.push_str(&codec.encode(&self.payload));
 
         // With length excluded.
-        let length: usize = message_str_except_length.len();
+        // In characters, not bytes: Discord limits the length of messages in characters, and the
+        // payload may not be ASCII.
+        let length: usize = message_str_except_length.chars().count();
         (
             // Length string
             length.to_string() + &Self::LENGTH_DELIMITER.to_string(),
@@ -230,6 +510,39 @@ impl Message {
         )
     }
 
+    /// Makes the string representation of the header, except the length.
+    /// (direction, session, kind, seq and part)
+    pub fn header_string(&self) -> String {
+        let mut header = String::with_capacity(32);
+        header.push_str(self.direction.to_string());
+        header.push_str(&self.session.to_string());
+        header.push_str(self.kind.to_string());
+        header.push_str(&Self::seq_to_string(self.seq));
+        header.push_str(&self.part.to_string());
+        header
+    }
+
+    /// Encodes a sequence number into 8 hex digits followed by a space.
+    pub fn seq_to_string(seq: u32) -> String {
+        format!("{seq:08X} ")
+    }
+
+    /// Decodes the first sequence number from text.
+    pub fn seq_from_string(text: &str) -> Result<u32, MessageError> {
+        let text = text
+            .get(..Self::SEQ_STRING_LENGTH)
+            .ok_or(MessageError::Sequence("sequence number string too small"))?;
+
+        if !text.ends_with(' ') {
+            return Err(MessageError::Sequence(
+                "sequence number string is not delimited",
+            ));
+        }
+
+        u32::from_str_radix(text.trim_end(), 16)
+            .map_err(|_| MessageError::Sequence("failed to parse the sequence number as hex"))
+    }
+
     // Returns the string representation from Message.
     // Ready to be sent to Discord.
     pub fn to_string(&self) -> &str {
@@ -239,14 +552,12 @@ impl Message {
 
 impl PartialEq for Message {
     fn eq(&self, other: &Self) -> bool {
-        if self.direction == other.direction
+        self.direction == other.direction
+            && self.session == other.session
+            && self.kind == other.kind
+            && self.seq == other.seq
             && self.payload == other.payload
             && self.to_string() == other.to_string()
-        {
-            true
-        } else {
-            false
-        }
     }
 }
 
@@ -303,17 +614,19 @@ mod tests {
     fn test_payload_conversion() {
         // Test that encoding and then decoding recovers the original bytes.
         let original_bytes = vec![104, 101, 108, 108, 111]; // "hello"
-        let encoded = Message::payload_bytes_to_string(&original_bytes);
-        let decoded = Message::payload_string_to_bytes(&encoded).unwrap();
+        let encoded = Codec::Hex.get().encode(&original_bytes);
+        let decoded = Codec::Hex.get().decode(&encoded).unwrap();
         assert_eq!(original_bytes, decoded);
     }
 
     #[test]
     fn test_make_string_length() {
         let direction = MessageDirection::Clientbound;
+        let session = SessionId::new(42);
         let part = Part::new(1, 1).unwrap();
         let payload = b"test payload";
-        let (length_str, msg_body) = Message::make_string(&direction, &part, payload);
+        let message = Message::from_bytes(payload, direction, session);
+        let (length_str, msg_body) = message.make_string();
 
         // The length string should contain the length and the delimiter.
         let mut parts = length_str.split(Message::LENGTH_DELIMITER);
@@ -325,43 +638,168 @@ mod tests {
 
         // The message body should begin with the header and contain the partition and encoded payload.
         assert!(msg_body.starts_with(MessageDirection::Clientbound.to_string()));
+        assert!(msg_body.contains(&session.to_string()));
+        assert!(msg_body.contains(MessageKind::Data.to_string()));
         assert!(msg_body.contains(&part.to_string()));
-        let encoded_payload = Message::payload_bytes_to_string(payload);
+        let encoded_payload = Codec::Hex.get().encode(payload);
         assert!(msg_body.contains(&encoded_payload));
     }
 
+    #[test]
+    fn test_make_string_length_counts_chars() {
+        let message = Message::from_bytes(
+            b"test payload",
+            MessageDirection::Clientbound,
+            SessionId::new(42),
+        )
+        .with_codec(Codec::Base32768);
+        let (length_str, msg_body) = message.make_string();
+        let len_number = length_str
+            .trim_end_matches(Message::LENGTH_DELIMITER)
+            .parse::<usize>()
+            .unwrap();
+
+        assert_eq!(len_number, msg_body.chars().count());
+        assert!(len_number < msg_body.len());
+    }
+
     #[test]
     fn test_from_bytes() {
         let direction = MessageDirection::Serverbound;
+        let session = SessionId::new(7);
         let payload = b"sample payload";
-        let message = Message::from_bytes(payload, direction);
+        let message = Message::from_bytes(payload, direction, session);
 
-        // Verify the direction and payload.
+        // Verify the direction, session and payload.
         assert_eq!(message.direction, direction);
+        assert_eq!(message.session, session);
         assert_eq!(message.payload(), payload);
 
         // Ensure the complete message string contains the proper header and encoded payload.
         let header = direction.to_string();
         assert!(message.text.contains(header));
-        let encoded_payload = Message::payload_bytes_to_string(payload);
+        let encoded_payload = Codec::Hex.get().encode(payload);
         assert!(message.text.contains(&encoded_payload));
     }
 
     #[test]
-    fn test_halt_message() {
-        let halt_msg = Message::make_halt_message(MessageDirection::Clientbound);
+    fn test_control_round_trip() {
+        let controls = [
+            Control::Open,
+            Control::Close {
+                reason: String::from("connection closed by the Minecraft client"),
+            },
+            Control::Error {
+                code: Control::ERROR_UNREACHABLE,
+                text: String::from("connection refused"),
+            },
+            Control::Ping(0xDEAD_BEEF),
+            Control::Pong(u64::MAX),
+            Control::Ack {
+                next_seq: 42,
+                window: 128 * 1024,
+            },
+            Control::Hello {
+                run: 7,
+                peer_run: 0,
+            },
+        ];
+
+        for control in controls {
+            let message =
+                Message::from_control(&control, MessageDirection::Serverbound, SessionId::new(1));
+            assert!(message.is_control());
+            assert_eq!(message.kind, control.kind());
+
+            // Through the text representation, as if it was received from Discord.
+            let parsed = Message::from_string(message.to_string()).unwrap();
+            assert_eq!(parsed.len(), 1);
+            assert_eq!(Control::try_from(&parsed[0]).unwrap(), control);
+        }
+    }
+
+    #[test]
+    fn test_data_is_not_control() {
+        // Even a payload that looks like an encoded control frame is plain data.
+        let close = Control::Close {
+            reason: String::from("bye"),
+        };
+        let msg = Message::from_bytes(
+            close.to_payload(),
+            MessageDirection::Clientbound,
+            SessionId::new(1),
+        );
+        assert!(!msg.is_control());
+        assert!(Control::try_from(&msg).is_err());
+    }
+
+    #[test]
+    fn test_control_invalid_payload() {
+        let direction = MessageDirection::Clientbound;
+        let session = SessionId::new(1);
+        let part = Part::new(1, 1).unwrap();
+
+        // Nonce too small
+        let ping = Message::new(MessageKind::Ping, direction, session, 0, part, vec![1, 2]);
+        assert!(Control::try_from(&ping).is_err());
+
+        // Missing error code
+        let error = Message::new(MessageKind::Error, direction, session, 0, part, vec![1]);
+        assert!(Control::try_from(&error).is_err());
+
+        // Invalid UTF-8 reason
+        let close = Message::new(MessageKind::Close, direction, session, 0, part, vec![0xFF]);
+        assert!(Control::try_from(&close).is_err());
+
+        // Window missing
+        let ack = Message::new(MessageKind::Ack, direction, session, 0, part, vec![0; 4]);
+        assert!(Control::try_from(&ack).is_err());
+    }
+
+    #[test]
+    fn test_seq_round_trip() {
+        let message = Message::from_bytes(b"seq", MessageDirection::Serverbound, SessionId::new(2))
+            .with_seq(0xABCD);
+        assert_eq!(message.seq, 0xABCD);
+
+        let parsed = Message::from_string(message.to_string()).unwrap();
+        assert_eq!(parsed[0].seq, 0xABCD);
+        assert_eq!(parsed[0], message);
 
-        // Check that the halt message is recognized.
-        assert!(Message::is_halt_message(&halt_msg));
+        assert!(Message::seq_from_string("0000ABCD").is_err());
+        assert!(Message::seq_from_string("0000ABCZ ").is_err());
+    }
+
+    #[test]
+    fn test_ping_pong_ack_are_not_sequenced() {
+        assert!(MessageKind::Data.is_sequenced());
+        assert!(MessageKind::Deflated.is_sequenced());
+        assert!(MessageKind::Open.is_sequenced());
+        assert!(MessageKind::Close.is_sequenced());
+        assert!(MessageKind::Error.is_sequenced());
+        assert!(!MessageKind::Ping.is_sequenced());
+        assert!(!MessageKind::Pong.is_sequenced());
+        assert!(!MessageKind::Ack.is_sequenced());
+        assert!(!MessageKind::Status.is_sequenced());
+    }
+
+    #[test]
+    fn test_message_kind_from_string() {
+        assert_eq!(
+            MessageKind::from_string("C 01/01 ").unwrap(),
+            MessageKind::Close
+        );
+        assert!(MessageKind::from_string("Z 01/01 ").is_err());
+        assert!(MessageKind::from_string("C").is_err());
     }
 
     #[test]
     fn test_from_string_aggregation() {
         // Construct a valid message string using make_string.
         let direction = MessageDirection::Clientbound;
-        let part = Part::new(1, 1).unwrap();
+        let session = SessionId::new(0xCAFE);
         let payload = b"aggregated message";
-        let (length_str, msg_body) = Message::make_string(&direction, &part, payload);
+        let (length_str, msg_body) = Message::from_bytes(payload, direction, session).make_string();
         let full_message = format!("{}{}", length_str, msg_body);
 
         // Use the Aggregator to disaggregate the message.
@@ -371,6 +809,7 @@ mod tests {
         // Validate that the parsed message has the expected direction and payload.
         let message = &messages[0];
         assert_eq!(message.direction, direction);
+        assert_eq!(message.session, session);
         assert_eq!(message.payload(), payload);
     }
 }
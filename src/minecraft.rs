@@ -0,0 +1,1168 @@
+//! Everything to understand the Minecraft protocol, as much as the tunnel needs to.
+//!
+//! A Minecraft connection is a stream of packets, each one prefixed with its length as a VarInt.
+//! Once the server sends Set Compression, the rest of a packet starts with the length of the
+//! decompressed data (0 if the packet is not compressed), but the length prefix stays: the packets
+//! can always be cut out of the stream. Once the encryption is enabled (online-mode servers),
+//! nothing can be read anymore, and the stream goes through as it is.
+//!
+//! As long as the packets can be read, the Keep Alives do not go through the tunnel: the side of
+//! the server answers them right away, and the side of the player sends its own. Otherwise, the
+//! delay of Discord would get the player kicked. Both sides of the tunnel must cut the packets for
+//! it. (the `minecraft` framing)
+//!
+//! See <https://minecraft.wiki/w/Java_Edition_protocol>.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use log::{debug, trace, warn};
+use thiserror::Error;
+
+use crate::message::MessageDirection;
+
+/// Maximum length of a VarInt.
+const MAX_VARINT_LEN: usize = 5;
+
+/// Maximum length of the length prefix of a packet.
+const MAX_LENGTH_PREFIX_LEN: usize = 3;
+
+/// The biggest packet, without its length prefix. (the biggest length a 3-byte VarInt can hold)
+pub const MAX_PACKET_LEN: usize = (1 << 21) - 1;
+
+/// The first byte of the server list ping of the clients older than 1.7, which is not a VarInt.
+pub const LEGACY_PING: u8 = 0xFE;
+
+/// How often the side of the player sends it a Keep Alive. (the vanilla servers send one every 15
+/// seconds)
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The length of the ID of a Keep Alive, its only field. (a long since 1.12.2)
+const KEEP_ALIVE_LEN: usize = 8;
+
+/// How many Keep Alives sent to the player can wait for their answer. The older ones are
+/// forgotten.
+const MAX_KEEP_ALIVES: usize = 8;
+
+/// The longest reason given to a disconnected player, in characters. The rest is cut.
+const MAX_REASON_LEN: usize = 1000;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MinecraftError {
+    #[error("VarInt is longer than {0} bytes")]
+    VarIntTooLong(usize),
+
+    #[error("Invalid packet length: {0}")]
+    InvalidLength(i32),
+
+    #[error("Truncated packet")]
+    Truncated,
+
+    #[error("Invalid packet: {0}")]
+    Invalid(String),
+
+    #[error("Legacy (pre-1.7) packet")]
+    Legacy,
+}
+
+/// Reads a VarInt of at most `max_len` bytes at the start of `data`.
+///
+/// Returns the value and the length of the VarInt, or `None` if more bytes are needed.
+pub fn read_varint(data: &[u8], max_len: usize) -> Result<Option<(i32, usize)>, MinecraftError> {
+    let mut value: u32 = 0;
+    for (i, byte) in data.iter().take(max_len).enumerate() {
+        value |= ((byte & 0x7F) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value as i32, i + 1)));
+        }
+    }
+
+    if data.len() >= max_len {
+        Err(MinecraftError::VarIntTooLong(max_len))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Appends `value` as a VarInt.
+pub fn write_varint(value: i32, out: &mut Vec<u8>) {
+    let mut value = value as u32;
+    loop {
+        if value & !0x7F == 0 {
+            out.push(value as u8);
+            return;
+        }
+        out.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+/// Appends `value` as a string: its length in bytes as a VarInt, then its UTF-8 bytes.
+pub fn write_string(value: &str, out: &mut Vec<u8>) {
+    write_varint(value.len() as i32, out);
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Reads the fields of a packet, one after the other.
+pub struct Fields<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// The bytes not read yet.
+    pub fn rest(&self) -> &'a [u8] {
+        self.data
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MinecraftError> {
+        if self.data.len() < len {
+            return Err(MinecraftError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn varint(&mut self) -> Result<i32, MinecraftError> {
+        let (value, len) =
+            read_varint(self.data, MAX_VARINT_LEN)?.ok_or(MinecraftError::Truncated)?;
+        self.data = &self.data[len..];
+        Ok(value)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, MinecraftError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn string(&mut self) -> Result<String, MinecraftError> {
+        let len: i32 = self.varint()?;
+        let len: usize = usize::try_from(len)
+            .map_err(|_| MinecraftError::Invalid(format!("negative string length {len}")))?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| MinecraftError::Invalid("string is not UTF-8".to_string()))
+    }
+}
+
+/// A packet, decompressed, without its length prefix.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    pub id: i32,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new<T: Into<Vec<u8>>>(id: i32, body: T) -> Self {
+        Self {
+            id,
+            body: body.into(),
+        }
+    }
+
+    fn data(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(MAX_VARINT_LEN + self.body.len());
+        write_varint(self.id, &mut data);
+        data.extend_from_slice(&self.body);
+        data
+    }
+
+    /// Makes the packet as it is sent before the compression is enabled, with its length prefix.
+    pub fn to_frame(&self) -> Vec<u8> {
+        with_length_prefix(&self.data())
+    }
+
+    /// Makes the packet as it is sent once the compression is enabled, with its length prefix.
+    ///
+    /// The packet is compressed only if it is at least `threshold` bytes long.
+    pub fn to_compressed_frame(&self, threshold: usize) -> Vec<u8> {
+        let data: Vec<u8> = self.data();
+        let mut rest: Vec<u8> = Vec::new();
+        if data.len() < threshold {
+            write_varint(0, &mut rest);
+            rest.extend_from_slice(&data);
+        } else {
+            write_varint(data.len() as i32, &mut rest);
+            let mut zlib = ZlibEncoder::new(rest, flate2::Compression::default());
+            // Writing to a Vec cannot fail.
+            let _ = zlib.write_all(&data);
+            rest = zlib.finish().unwrap_or_default();
+        }
+        with_length_prefix(&rest)
+    }
+
+    /// Parses a packet cut out of the stream. (with its length prefix)
+    ///
+    /// `compressed` tells whether the compression was enabled when the packet was sent.
+    pub fn parse(frame: &[u8], compressed: bool) -> Result<Packet, MinecraftError> {
+        let data: Vec<u8> = uncompressed_data(frame, compressed, MAX_PACKET_LEN)?;
+        let mut fields = Fields::new(&data);
+        let id: i32 = fields.varint()?;
+        Ok(Packet {
+            id,
+            body: fields.rest().to_vec(),
+        })
+    }
+
+    /// Reads only the ID of a packet cut out of the stream. (with its length prefix)
+    pub fn peek_id(frame: &[u8], compressed: bool) -> Result<i32, MinecraftError> {
+        let data: Vec<u8> = uncompressed_data(frame, compressed, MAX_VARINT_LEN)?;
+        Fields::new(&data).varint()
+    }
+}
+
+fn with_length_prefix(data: &[u8]) -> Vec<u8> {
+    let mut frame: Vec<u8> = Vec::with_capacity(MAX_LENGTH_PREFIX_LEN + data.len());
+    write_varint(data.len() as i32, &mut frame);
+    frame.extend_from_slice(data);
+    frame
+}
+
+/// Makes a JSON text component of plain text, as a string.
+fn json_text(text: &str) -> Vec<u8> {
+    let mut json = String::from("{\"text\":\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push_str("\"}");
+
+    let mut out: Vec<u8> = Vec::new();
+    write_string(&json, &mut out);
+    out
+}
+
+/// Makes an NBT text component of plain text: a string tag, without a name. (1.20.3+)
+fn nbt_text(text: &str) -> Vec<u8> {
+    // Java's modified UTF-8: each UTF-16 unit is encoded on its own, and NUL on 2 bytes.
+    let mut bytes: Vec<u8> = Vec::with_capacity(text.len());
+    for unit in text.encode_utf16() {
+        if (0x01..0x80).contains(&unit) {
+            bytes.push(unit as u8);
+        } else if unit < 0x800 {
+            bytes.extend_from_slice(&[0xC0 | (unit >> 6) as u8, 0x80 | (unit & 0x3F) as u8]);
+        } else {
+            bytes.extend_from_slice(&[
+                0xE0 | (unit >> 12) as u8,
+                0x80 | ((unit >> 6) & 0x3F) as u8,
+                0x80 | (unit & 0x3F) as u8,
+            ]);
+        }
+    }
+
+    let mut out: Vec<u8> = vec![ids::NBT_STRING];
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(&bytes);
+    out
+}
+
+/// Returns the ID and data of a packet, decompressed, keeping at most `limit` bytes.
+fn uncompressed_data(
+    frame: &[u8],
+    compressed: bool,
+    limit: usize,
+) -> Result<Vec<u8>, MinecraftError> {
+    let mut fields = Fields::new(frame);
+    fields.varint()?;
+
+    if !compressed {
+        return Ok(fields.rest()[..fields.rest().len().min(limit)].to_vec());
+    }
+
+    let data_len: i32 = fields.varint()?;
+    if data_len == 0 {
+        return Ok(fields.rest()[..fields.rest().len().min(limit)].to_vec());
+    }
+    if data_len < 0 || data_len as usize > MAX_PACKET_LEN * 4 {
+        return Err(MinecraftError::InvalidLength(data_len));
+    }
+
+    let mut data: Vec<u8> = Vec::new();
+    ZlibDecoder::new(fields.rest())
+        .take(limit.min(data_len as usize) as u64)
+        .read_to_end(&mut data)
+        .map_err(|e| MinecraftError::Invalid(format!("failed to decompress: {e}")))?;
+    Ok(data)
+}
+
+/// What is buffered by a `Framer`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Chunk {
+    /// A whole packet, with its length prefix.
+    Packet(Vec<u8>),
+    /// The rest of a packet whose start was given away. (see `Framer::take_pending`)
+    Tail(Vec<u8>),
+}
+
+/// Cuts the packets out of one direction of the stream.
+#[derive(Debug, Default)]
+pub struct Framer {
+    buffer: Vec<u8>,
+    /// The bytes of a packet given away before it was whole, that are still to come.
+    tail: usize,
+}
+
+impl Framer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Takes the next whole packet out of the buffer, or `None` if more bytes are needed.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk>, MinecraftError> {
+        if self.tail > 0 {
+            let len: usize = self.tail.min(self.buffer.len());
+            if len == 0 {
+                return Ok(None);
+            }
+            self.tail -= len;
+            return Ok(Some(Chunk::Tail(self.buffer.drain(..len).collect())));
+        }
+
+        let Some((length, prefix_len)) = read_varint(&self.buffer, MAX_LENGTH_PREFIX_LEN)? else {
+            return Ok(None);
+        };
+        if length <= 0 || length as usize > MAX_PACKET_LEN {
+            return Err(MinecraftError::InvalidLength(length));
+        }
+
+        let frame_len: usize = prefix_len + length as usize;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+        Ok(Some(Chunk::Packet(
+            self.buffer.drain(..frame_len).collect(),
+        )))
+    }
+
+    /// How many bytes are waiting for the rest of their packet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Whether the next byte starts a packet.
+    pub fn is_at_boundary(&self) -> bool {
+        self.buffer.is_empty() && self.tail == 0
+    }
+
+    /// Gives away the start of a packet that is not whole yet. Its rest is given away as a
+    /// `Chunk::Tail` once it comes.
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        if let Ok(Some((length, prefix_len))) = read_varint(&self.buffer, MAX_LENGTH_PREFIX_LEN) {
+            let frame_len: usize = prefix_len + length.max(0) as usize;
+            self.tail = frame_len.saturating_sub(self.buffer.len());
+        }
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+/// The first packet of a connection, which tells what the client wants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Handshake {
+    pub protocol: i32,
+    /// The address the client connected to, as typed by the player.
+    pub address: String,
+    pub port: u16,
+    pub next_state: State,
+}
+
+impl Handshake {
+    pub const ID: i32 = 0x00;
+
+    /// The protocol version to give when it is not known. (e.g. to get the status)
+    pub const ANY_PROTOCOL: i32 = -1;
+
+    pub fn parse(packet: &Packet) -> Result<Handshake, MinecraftError> {
+        if packet.id != Self::ID {
+            return Err(MinecraftError::Invalid(format!(
+                "packet {:#04X} is not a handshake",
+                packet.id
+            )));
+        }
+
+        let mut fields = Fields::new(&packet.body);
+        let protocol: i32 = fields.varint()?;
+        let address: String = fields.string()?;
+        let port: u16 = fields.u16()?;
+        let next_state: State = match fields.varint()? {
+            1 => State::Status,
+            // 3 is a transfer from another server (1.20.5+)
+            2 | 3 => State::Login,
+            next => {
+                return Err(MinecraftError::Invalid(format!(
+                    "unknown next state {next} in handshake"
+                )))
+            }
+        };
+
+        Ok(Handshake {
+            protocol,
+            address,
+            port,
+            next_state,
+        })
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut body: Vec<u8> = Vec::new();
+        write_varint(self.protocol, &mut body);
+        write_string(&self.address, &mut body);
+        body.extend_from_slice(&self.port.to_be_bytes());
+        write_varint(
+            if self.next_state == State::Status {
+                1
+            } else {
+                2
+            },
+            &mut body,
+        );
+        Packet::new(Self::ID, body)
+    }
+}
+
+/// The state of a connection, which tells what its packets mean.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Handshaking,
+    Status,
+    Login,
+    /// After the login, and whenever the server configures the client again. (1.20.2+)
+    Configuration,
+    Play,
+}
+
+/// The packet IDs the tracker looks for.
+pub mod ids {
+    pub const ENCRYPTION_RESPONSE: i32 = 0x01;
+    pub const LOGIN_SUCCESS: i32 = 0x02;
+    pub const SET_COMPRESSION: i32 = 0x03;
+    /// Serverbound, in the login state. (1.20.2+)
+    pub const LOGIN_ACKNOWLEDGED: i32 = 0x03;
+    /// Clientbound, in the login state.
+    pub const LOGIN_DISCONNECT: i32 = 0x00;
+
+    /// The type of an NBT string tag.
+    pub const NBT_STRING: u8 = 0x08;
+
+    /// The first protocol version with the text components in NBT instead of JSON, out of the
+    /// login state. (1.20.3)
+    pub const NBT_TEXT_PROTOCOL: i32 = 765;
+
+    /// The first protocol version with the configuration state. (1.20.2)
+    pub const CONFIGURATION_PROTOCOL: i32 = 764;
+
+    /// The ID of the serverbound packet acknowledging the end of the configuration.
+    pub fn finish_configuration(protocol: i32) -> i32 {
+        if protocol >= 766 {
+            0x03
+        } else {
+            0x02
+        }
+    }
+
+    /// The ID of the serverbound packet acknowledging the start of a configuration, in the play
+    /// state.
+    pub fn acknowledge_configuration(protocol: i32) -> Option<i32> {
+        match protocol {
+            764..=765 => Some(0x0B),
+            766..=767 => Some(0x0C),
+            768..=769 => Some(0x0E),
+            _ => None,
+        }
+    }
+
+    /// The ID of the clientbound Disconnect, in the configuration state.
+    pub fn configuration_disconnect(protocol: i32) -> i32 {
+        if protocol >= 766 {
+            0x02
+        } else {
+            0x01
+        }
+    }
+
+    /// The ID of the clientbound Disconnect, in the play state.
+    pub fn play_disconnect(protocol: i32) -> Option<i32> {
+        match protocol {
+            340 => Some(0x1A),
+            754 => Some(0x19),
+            755..=758 => Some(0x1A),
+            759 => Some(0x17),
+            760 => Some(0x19),
+            761 => Some(0x17),
+            762..=763 => Some(0x1A),
+            764..=765 => Some(0x1B),
+            766..=769 => Some(0x1D),
+            _ => None,
+        }
+    }
+
+    /// The IDs of the clientbound and serverbound Keep Alives, in the play state.
+    ///
+    /// They move with every version: the Keep Alives are only proxied for the versions below.
+    pub fn keep_alive(protocol: i32) -> Option<(i32, i32)> {
+        match protocol {
+            340 => Some((0x1F, 0x0B)),       // 1.12.2
+            754 => Some((0x1F, 0x10)),       // 1.16.5
+            755..=758 => Some((0x21, 0x0F)), // 1.17 - 1.18.2
+            759 => Some((0x1E, 0x11)),       // 1.19
+            760 => Some((0x20, 0x12)),       // 1.19.1 - 1.19.2
+            761 => Some((0x1F, 0x11)),       // 1.19.3
+            762..=763 => Some((0x23, 0x12)), // 1.19.4 - 1.20.1
+            764 => Some((0x24, 0x14)),       // 1.20.2
+            765 => Some((0x24, 0x15)),       // 1.20.3 - 1.20.4
+            766..=767 => Some((0x26, 0x18)), // 1.20.5 - 1.21.1
+            768..=769 => Some((0x27, 0x1A)), // 1.21.2 - 1.21.4
+            _ => None,
+        }
+    }
+}
+
+/// Follows a Minecraft connection from both of its directions, to cut its packets out of the
+/// stream.
+///
+/// One side of the tunnel reads one direction from the socket, and writes the other one to it:
+/// both must be fed to the same tracker.
+///
+/// The tracker also makes up packets to write back to the socket: the answers to the Keep Alives
+/// of the server, or the Keep Alives sent to the player.
+#[derive(Debug)]
+pub struct Tracker {
+    state: State,
+    /// The protocol version of the client, from the handshake.
+    protocol: i32,
+    /// The compression threshold, once the compression is enabled.
+    compression: Option<usize>,
+    /// The packets cannot be read anymore: the stream goes through as it is.
+    opaque: bool,
+    serverbound: Framer,
+    clientbound: Framer,
+    /// The packets made up by the tracker, waiting to be written to the socket.
+    local: Vec<u8>,
+    /// The IDs of the Keep Alives sent to the player, waiting for their answer.
+    keep_alives: VecDeque<Vec<u8>>,
+    /// When the last Keep Alive was sent to the player.
+    last_keep_alive: Option<Instant>,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self {
+            state: State::Handshaking,
+            protocol: 0,
+            compression: None,
+            opaque: false,
+            serverbound: Framer::default(),
+            clientbound: Framer::default(),
+            local: Vec::new(),
+            keep_alives: VecDeque::new(),
+            last_keep_alive: None,
+        }
+    }
+}
+
+impl Tracker {
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// The protocol version of the client, once the handshake went through.
+    pub fn protocol(&self) -> i32 {
+        self.protocol
+    }
+
+    /// Whether the packets are compressed. (after Set Compression)
+    pub fn is_compressed(&self) -> bool {
+        self.compression.is_some()
+    }
+
+    /// Whether the packets cannot be read anymore. (after the encryption is enabled)
+    pub fn is_opaque(&self) -> bool {
+        self.opaque
+    }
+
+    fn framer(&mut self, direction: MessageDirection) -> &mut Framer {
+        match direction {
+            MessageDirection::Serverbound => &mut self.serverbound,
+            MessageDirection::Clientbound => &mut self.clientbound,
+        }
+    }
+
+    /// Feeds the bytes going in `direction`.
+    ///
+    /// Returns the ones that can be sent: whole packets only, unless the stream cannot be read
+    /// anymore. The start of the next packet stays in the tracker until the rest of it comes.
+    ///
+    /// The Keep Alives are not returned: the ones of the server are answered by the tracker, and
+    /// the answers of the player to the tracker's own are dropped. (see `take_local`)
+    pub fn feed(&mut self, direction: MessageDirection, data: &[u8]) -> Vec<u8> {
+        self.cut(direction, data, true)
+    }
+
+    fn cut(&mut self, direction: MessageDirection, data: &[u8], proxy: bool) -> Vec<u8> {
+        if self.opaque {
+            let mut ready: Vec<u8> = self.framer(direction).take_pending();
+            ready.extend_from_slice(data);
+            return ready;
+        }
+
+        if self.state == State::Handshaking
+            && direction == MessageDirection::Serverbound
+            && self.serverbound.pending() == 0
+            && data.first() == Some(&LEGACY_PING)
+        {
+            self.give_up(MinecraftError::Legacy);
+            return data.to_vec();
+        }
+
+        self.framer(direction).push(data);
+        let mut ready: Vec<u8> = Vec::new();
+        while !self.opaque {
+            match self.framer(direction).next_chunk() {
+                Ok(Some(Chunk::Packet(frame))) => {
+                    self.observe(direction, &frame);
+                    if !(proxy && self.proxy_keep_alive(direction, &frame)) {
+                        ready.extend_from_slice(&frame);
+                    }
+                }
+                Ok(Some(Chunk::Tail(tail))) => ready.extend_from_slice(&tail),
+                Ok(None) => break,
+                Err(err) => self.give_up(err),
+            }
+        }
+
+        // The rest cannot be read: it is given away as it is.
+        if self.opaque {
+            ready.extend_from_slice(&self.framer(direction).take_pending());
+        }
+        ready
+    }
+
+    /// Feeds the bytes going in `direction` when they are sent anyway. (see `feed`)
+    pub fn observe_stream(&mut self, direction: MessageDirection, data: &[u8]) {
+        if !self.opaque {
+            self.cut(direction, data, false);
+        }
+    }
+
+    /// How many bytes going in `direction` are waiting for the rest of their packet.
+    pub fn pending(&self, direction: MessageDirection) -> usize {
+        match direction {
+            MessageDirection::Serverbound => self.serverbound.pending(),
+            MessageDirection::Clientbound => self.clientbound.pending(),
+        }
+    }
+
+    /// Gives away the start of the packet going in `direction` that is not whole yet. (see
+    /// `Framer::take_pending`)
+    pub fn take_pending(&mut self, direction: MessageDirection) -> Vec<u8> {
+        self.framer(direction).take_pending()
+    }
+
+    /// Takes the packets made up by the tracker, to write to the socket the packets going in
+    /// `direction` are written to.
+    ///
+    /// Nothing is taken while a packet is written halfway: they would end up in the middle of it.
+    pub fn take_local(&mut self, direction: MessageDirection) -> Vec<u8> {
+        if self.opaque {
+            // The end of the packets written cannot be told anymore.
+            self.local.clear();
+        }
+        if !self.framer(direction).is_at_boundary() {
+            return Vec::new();
+        }
+        std::mem::take(&mut self.local)
+    }
+
+    /// Sends a Keep Alive to the player if it is time to. (on the side of the player, see
+    /// `take_local`)
+    pub fn poll_keep_alive(&mut self, now: Instant) {
+        let keep_alive: Option<(i32, i32)> =
+            ids::keep_alive(self.protocol).filter(|_| !self.opaque && self.state == State::Play);
+        let Some((clientbound, _)) = keep_alive else {
+            self.last_keep_alive = None;
+            return;
+        };
+
+        match self.last_keep_alive {
+            // The first one is sent after a whole interval.
+            None => self.last_keep_alive = Some(now),
+            Some(last) if now.duration_since(last) >= KEEP_ALIVE_INTERVAL => {
+                let id: Vec<u8> = rand::random::<u64>().to_be_bytes().to_vec();
+                trace!("Sending Keep Alive {id:02X?} to the player");
+                self.push_local(&Packet::new(clientbound, id.clone()));
+                if self.keep_alives.len() == MAX_KEEP_ALIVES {
+                    self.keep_alives.pop_front();
+                }
+                self.keep_alives.push_back(id);
+                self.last_keep_alive = Some(now);
+            }
+            Some(_) => {}
+        }
+    }
+
+    /// Answers a Keep Alive of the server, or drops the answer of the player to a Keep Alive of
+    /// the tracker.
+    ///
+    /// Returns whether the packet must not go through the tunnel.
+    fn proxy_keep_alive(&mut self, direction: MessageDirection, frame: &[u8]) -> bool {
+        if self.state != State::Play {
+            return false;
+        }
+        let Some((clientbound, serverbound)) = ids::keep_alive(self.protocol) else {
+            return false;
+        };
+        let id: i32 = match direction {
+            MessageDirection::Clientbound => clientbound,
+            MessageDirection::Serverbound => serverbound,
+        };
+        if Packet::peek_id(frame, self.is_compressed()) != Ok(id) {
+            return false;
+        }
+        let packet: Packet = match Packet::parse(frame, self.is_compressed()) {
+            Ok(packet) if packet.body.len() == KEEP_ALIVE_LEN => packet,
+            _ => return false,
+        };
+
+        match direction {
+            MessageDirection::Clientbound => {
+                trace!("Answering Keep Alive {:02X?} of the server", packet.body);
+                self.push_local(&Packet::new(serverbound, packet.body));
+                true
+            }
+            MessageDirection::Serverbound => {
+                match self.keep_alives.iter().position(|id| *id == packet.body) {
+                    Some(index) => {
+                        self.keep_alives.remove(index);
+                        true
+                    }
+                    // An answer to the server itself: the peer does not proxy the Keep Alives.
+                    None => false,
+                }
+            }
+        }
+    }
+
+    /// Makes the packet disconnecting the player with `reason`, to write to the player before
+    /// closing the socket.
+    ///
+    /// Returns `None` if the player cannot be told: out of the login, configuration and play
+    /// states, once the packets cannot be read, in the middle of a clientbound packet, or in the
+    /// play state of an unknown version.
+    pub fn disconnect(&self, reason: &str) -> Option<Vec<u8>> {
+        if self.opaque || !self.clientbound.is_at_boundary() {
+            return None;
+        }
+
+        let reason: String = reason.chars().take(MAX_REASON_LEN).collect();
+        let text: Vec<u8> = if self.protocol >= ids::NBT_TEXT_PROTOCOL {
+            nbt_text(&reason)
+        } else {
+            json_text(&reason)
+        };
+        let packet: Packet = match self.state {
+            // Always in JSON.
+            State::Login => Packet::new(ids::LOGIN_DISCONNECT, json_text(&reason)),
+            State::Configuration => Packet::new(ids::configuration_disconnect(self.protocol), text),
+            State::Play => Packet::new(ids::play_disconnect(self.protocol)?, text),
+            State::Handshaking | State::Status => return None,
+        };
+        Some(self.frame(&packet))
+    }
+
+    /// Makes a packet as it is sent now. (compressed or not)
+    fn frame(&self, packet: &Packet) -> Vec<u8> {
+        match self.compression {
+            Some(threshold) => packet.to_compressed_frame(threshold),
+            None => packet.to_frame(),
+        }
+    }
+
+    fn push_local(&mut self, packet: &Packet) {
+        let frame: Vec<u8> = self.frame(packet);
+        self.local.extend_from_slice(&frame);
+    }
+
+    fn give_up(&mut self, reason: MinecraftError) {
+        warn!(
+            "Cannot read the Minecraft packets anymore ({reason}). Forwarding the stream as it is."
+        );
+        self.opaque = true;
+    }
+
+    /// Updates the state of the connection with a packet cut out of the stream.
+    fn observe(&mut self, direction: MessageDirection, frame: &[u8]) {
+        let id: Option<i32> = Packet::peek_id(frame, self.is_compressed()).ok();
+        trace!(
+            "{direction:?} packet {id:02X?} [{}B] ({:?})",
+            frame.len(),
+            self.state
+        );
+
+        match (self.state, direction, id) {
+            (State::Handshaking, MessageDirection::Serverbound, Some(Handshake::ID)) => {
+                match Packet::parse(frame, false).and_then(|packet| Handshake::parse(&packet)) {
+                    Ok(handshake) => {
+                        debug!(
+                            "Minecraft handshake: protocol {}, {:?}",
+                            handshake.protocol, handshake.next_state
+                        );
+                        self.protocol = handshake.protocol;
+                        self.state = handshake.next_state;
+                    }
+                    Err(err) => self.give_up(err),
+                }
+            }
+            (State::Login, MessageDirection::Serverbound, Some(ids::ENCRYPTION_RESPONSE)) => {
+                debug!("The Minecraft connection is now encrypted");
+                self.opaque = true;
+            }
+            (State::Login, MessageDirection::Clientbound, Some(ids::SET_COMPRESSION)) => {
+                match Packet::parse(frame, self.is_compressed())
+                    .and_then(|packet| Fields::new(&packet.body).varint())
+                {
+                    Ok(threshold) => {
+                        debug!(
+                            "The Minecraft connection is now compressed (threshold {threshold})"
+                        );
+                        self.compression = usize::try_from(threshold).ok();
+                    }
+                    Err(err) => self.give_up(err),
+                }
+            }
+            // From 1.20.2, the client acknowledges it first.
+            (State::Login, MessageDirection::Clientbound, Some(ids::LOGIN_SUCCESS))
+                if self.protocol < ids::CONFIGURATION_PROTOCOL =>
+            {
+                self.state = State::Play;
+            }
+            (State::Login, MessageDirection::Serverbound, Some(ids::LOGIN_ACKNOWLEDGED))
+                if self.protocol >= ids::CONFIGURATION_PROTOCOL =>
+            {
+                self.state = State::Configuration;
+            }
+            (State::Configuration, MessageDirection::Serverbound, Some(id))
+                if id == ids::finish_configuration(self.protocol) =>
+            {
+                self.state = State::Play;
+            }
+            (State::Play, MessageDirection::Serverbound, Some(id))
+                if Some(id) == ids::acknowledge_configuration(self.protocol) =>
+            {
+                self.state = State::Configuration;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    fn frame(id: i32, body: &[u8]) -> Vec<u8> {
+        Packet::new(id, body).to_frame()
+    }
+
+    fn compressed(id: i32, body: &[u8]) -> Vec<u8> {
+        Packet::new(id, body).to_compressed_frame(256)
+    }
+
+    fn handshake(next_state: State) -> Vec<u8> {
+        Handshake {
+            protocol: 767,
+            address: "localhost".to_string(),
+            port: 25565,
+            next_state,
+        }
+        .to_packet()
+        .to_frame()
+    }
+
+    #[test]
+    fn test_varint() {
+        for (value, bytes) in [
+            (0, vec![0x00]),
+            (127, vec![0x7F]),
+            (128, vec![0x80, 0x01]),
+            (25565, vec![0xDD, 0xC7, 0x01]),
+            (-1, vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F]),
+        ] {
+            let mut out: Vec<u8> = Vec::new();
+            write_varint(value, &mut out);
+            assert_eq!(out, bytes);
+            assert_eq!(
+                read_varint(&bytes, MAX_VARINT_LEN),
+                Ok(Some((value, bytes.len())))
+            );
+        }
+
+        assert_eq!(read_varint(&[0x80], MAX_VARINT_LEN), Ok(None));
+        assert_eq!(
+            read_varint(&[0x80, 0x80, 0x80, 0x01], MAX_LENGTH_PREFIX_LEN),
+            Err(MinecraftError::VarIntTooLong(3))
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let frame = handshake(State::Status);
+        assert_eq!(frame[..3], [frame.len() as u8 - 1, 0x00, 0xFF]);
+        let packet = Packet::parse(&frame, false).unwrap();
+        let parsed = Handshake::parse(&packet).unwrap();
+        assert_eq!(parsed.address, "localhost");
+        assert_eq!(parsed.next_state, State::Status);
+
+        let not_a_handshake = Packet::new(0x01, [0; 8]);
+        assert!(Handshake::parse(&not_a_handshake).is_err());
+    }
+
+    #[test]
+    fn test_framer() {
+        let first = frame(0x01, b"hello");
+        let second = frame(0x02, &[7; 300]);
+        let stream: Vec<u8> = [first.clone(), second.clone()].concat();
+
+        let mut framer = Framer::default();
+        framer.push(&stream[..3]);
+        assert_eq!(framer.next_chunk(), Ok(None));
+        framer.push(&stream[3..first.len() + 2]);
+        assert_eq!(framer.next_chunk(), Ok(Some(Chunk::Packet(first.clone()))));
+        assert_eq!(framer.next_chunk(), Ok(None));
+
+        // The start of a packet given away, then its rest.
+        assert_eq!(framer.take_pending(), second[..2]);
+        framer.push(&second[2..]);
+        assert_eq!(
+            framer.next_chunk(),
+            Ok(Some(Chunk::Tail(second[2..].to_vec())))
+        );
+        // The packets after it are whole again.
+        framer.push(&first);
+        assert_eq!(framer.next_chunk(), Ok(Some(Chunk::Packet(first))));
+
+        let mut framer = Framer::default();
+        framer.push(&[0x00]);
+        assert_eq!(framer.next_chunk(), Err(MinecraftError::InvalidLength(0)));
+    }
+
+    #[test]
+    fn test_compressed_packet() {
+        let data = [vec![0x26], vec![42; 500]].concat();
+        let mut zlib = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        zlib.write_all(&data).unwrap();
+        let compressed = zlib.finish().unwrap();
+
+        let mut body: Vec<u8> = Vec::new();
+        write_varint(data.len() as i32, &mut body);
+        body.extend_from_slice(&compressed);
+        let mut frame: Vec<u8> = Vec::new();
+        write_varint(body.len() as i32, &mut frame);
+        frame.extend_from_slice(&body);
+
+        assert_eq!(Packet::peek_id(&frame, true), Ok(0x26));
+        let packet = Packet::parse(&frame, true).unwrap();
+        assert_eq!(packet.id, 0x26);
+        assert_eq!(packet.body, vec![42; 500]);
+
+        // Below the threshold: not compressed, but with a data length of 0.
+        let small = [vec![0x04, 0x00, 0x26], vec![1, 2]].concat();
+        assert_eq!(Packet::parse(&small, true).unwrap().body, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut tracker = Tracker::default();
+        let handshake = handshake(State::Login);
+        let login_start = frame(0x00, b"\x05Steve");
+
+        // Cut in the middle of the login start: only the handshake can be sent.
+        let stream = [handshake.clone(), login_start.clone()].concat();
+        let ready = tracker.feed(
+            MessageDirection::Serverbound,
+            &stream[..handshake.len() + 3],
+        );
+        assert_eq!(ready, handshake);
+        assert_eq!(tracker.state(), State::Login);
+        assert_eq!(tracker.protocol(), 767);
+        assert_eq!(tracker.pending(MessageDirection::Serverbound), 3);
+        let ready = tracker.feed(
+            MessageDirection::Serverbound,
+            &stream[handshake.len() + 3..],
+        );
+        assert_eq!(ready, login_start);
+
+        // The server enables the compression, then the login succeeds.
+        let mut threshold: Vec<u8> = Vec::new();
+        write_varint(256, &mut threshold);
+        tracker.observe_stream(
+            MessageDirection::Clientbound,
+            &frame(ids::SET_COMPRESSION, &threshold),
+        );
+        assert!(tracker.is_compressed());
+        tracker.observe_stream(
+            MessageDirection::Clientbound,
+            &[0x04, 0x00, ids::LOGIN_SUCCESS as u8, 0x00, 0x00],
+        );
+        assert_eq!(tracker.state(), State::Login);
+
+        // The client acknowledges it, then gets configured.
+        tracker.feed(
+            MessageDirection::Serverbound,
+            &compressed(ids::LOGIN_ACKNOWLEDGED, &[]),
+        );
+        assert_eq!(tracker.state(), State::Configuration);
+        tracker.feed(MessageDirection::Serverbound, &compressed(0x03, &[]));
+        assert_eq!(tracker.state(), State::Play);
+        assert!(!tracker.is_opaque());
+
+        // Until the server configures it again.
+        tracker.feed(MessageDirection::Serverbound, &compressed(0x0C, &[]));
+        assert_eq!(tracker.state(), State::Configuration);
+    }
+
+    /// A tracker of a 1.21.1 connection in the play state, with the compression enabled.
+    fn playing() -> Tracker {
+        let mut tracker = Tracker::default();
+        tracker.feed(MessageDirection::Serverbound, &handshake(State::Login));
+        let mut threshold: Vec<u8> = Vec::new();
+        write_varint(256, &mut threshold);
+        tracker.observe_stream(
+            MessageDirection::Clientbound,
+            &frame(ids::SET_COMPRESSION, &threshold),
+        );
+        tracker.observe_stream(
+            MessageDirection::Clientbound,
+            &compressed(ids::LOGIN_SUCCESS, &[]),
+        );
+        tracker.feed(
+            MessageDirection::Serverbound,
+            &[
+                compressed(ids::LOGIN_ACKNOWLEDGED, &[]),
+                compressed(0x03, &[]),
+            ]
+            .concat(),
+        );
+        assert_eq!(tracker.state(), State::Play);
+        tracker
+    }
+
+    #[test]
+    fn test_keep_alive_answered() {
+        // The side of the server reads the clientbound packets.
+        let mut tracker = playing();
+        let keep_alive = compressed(0x26, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let chat = compressed(0x6C, b"hi");
+        let stream = [keep_alive, chat.clone()].concat();
+        assert_eq!(tracker.feed(MessageDirection::Clientbound, &stream), chat);
+
+        // The answer waits for the packet written halfway to the server.
+        let movement = compressed(0x1A, &[0; 33]);
+        tracker.observe_stream(MessageDirection::Serverbound, &movement[..10]);
+        assert!(tracker.take_local(MessageDirection::Serverbound).is_empty());
+        tracker.observe_stream(MessageDirection::Serverbound, &movement[10..]);
+        assert_eq!(
+            tracker.take_local(MessageDirection::Serverbound),
+            compressed(0x18, &[1, 2, 3, 4, 5, 6, 7, 8])
+        );
+        assert!(tracker.take_local(MessageDirection::Serverbound).is_empty());
+
+        // Not a Keep Alive: a long is expected.
+        let odd = compressed(0x26, &[1, 2, 3]);
+        assert_eq!(tracker.feed(MessageDirection::Clientbound, &odd), odd);
+    }
+
+    #[test]
+    fn test_keep_alive_sent() {
+        // The side of the player reads the serverbound packets.
+        let mut tracker = playing();
+        let start = Instant::now();
+        tracker.poll_keep_alive(start);
+        tracker.poll_keep_alive(start + KEEP_ALIVE_INTERVAL / 2);
+        assert!(tracker.take_local(MessageDirection::Clientbound).is_empty());
+
+        tracker.poll_keep_alive(start + KEEP_ALIVE_INTERVAL);
+        let local = tracker.take_local(MessageDirection::Clientbound);
+        let packet = Packet::parse(&local, true).unwrap();
+        assert_eq!(packet.id, 0x26);
+        assert_eq!(packet.body.len(), KEEP_ALIVE_LEN);
+
+        // The answer of the player is dropped, the answers to the server go through.
+        let answer = compressed(0x18, &packet.body);
+        assert!(tracker
+            .feed(MessageDirection::Serverbound, &answer)
+            .is_empty());
+        assert_eq!(tracker.feed(MessageDirection::Serverbound, &answer), answer);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut tracker = Tracker::default();
+        assert_eq!(tracker.disconnect("down"), None);
+
+        // The login disconnect is always in JSON.
+        tracker.feed(MessageDirection::Serverbound, &handshake(State::Login));
+        let packet = Packet::parse(&tracker.disconnect("\"down\"").unwrap(), false).unwrap();
+        assert_eq!(packet.id, ids::LOGIN_DISCONNECT);
+        assert_eq!(
+            Fields::new(&packet.body).string().unwrap(),
+            r#"{"text":"\"down\""}"#
+        );
+
+        // In NBT in the play state of 1.21.1, and compressed.
+        let tracker = playing();
+        let packet = Packet::parse(&tracker.disconnect("down\0").unwrap(), true).unwrap();
+        assert_eq!(packet.id, 0x1D);
+        assert_eq!(
+            packet.body,
+            [0x08, 0x00, 0x06, b'd', b'o', b'w', b'n', 0xC0, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_compressed_frame() {
+        let packet = Packet::new(0x26, vec![42; 500]);
+        let frame = packet.to_compressed_frame(256);
+        assert!(frame.len() < 100);
+        assert_eq!(Packet::parse(&frame, true), Ok(packet));
+
+        let small = Packet::new(0x26, [1, 2]);
+        assert_eq!(small.to_compressed_frame(256), [0x04, 0x00, 0x26, 1, 2]);
+    }
+
+    #[test]
+    fn test_tracker_encryption() {
+        let mut tracker = Tracker::default();
+        tracker.feed(MessageDirection::Serverbound, &handshake(State::Login));
+
+        // Whatever follows the encryption response is sent as it is.
+        let response = frame(ids::ENCRYPTION_RESPONSE, &[1; 20]);
+        let stream = [response.clone(), vec![0xFF; 10]].concat();
+        assert_eq!(tracker.feed(MessageDirection::Serverbound, &stream), stream);
+        assert!(tracker.is_opaque());
+        assert_eq!(
+            tracker.feed(MessageDirection::Clientbound, &[0x80]),
+            vec![0x80]
+        );
+    }
+
+    #[test]
+    fn test_tracker_legacy_ping() {
+        let mut tracker = Tracker::default();
+        let ping = [LEGACY_PING, 0x01, 0xFA];
+        assert_eq!(tracker.feed(MessageDirection::Serverbound, &ping), ping);
+        assert!(tracker.is_opaque());
+    }
+}
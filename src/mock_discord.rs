@@ -0,0 +1,715 @@
+//! A stand-in for Discord on localhost, to run the bots without network access. (e.g. in the
+//! end-to-end tests)
+//!
+//! It speaks just enough of the REST API (`GET /gateway`, `GET /users/@me` and
+//! `POST /channels/{id}/messages`) and of the gateway (HELLO, IDENTIFY, READY, MESSAGE_CREATE and
+//! the heartbeats) for `DiscordBot` to log in, send its messages, and receive the messages of the
+//! other bots. A side is pointed at it with `--api-url`.
+//!
+//! Every bot is in the guild, and can post in any channel. The bots are told apart by their
+//! token: each new token is a new user.
+//!
+//! Optionally, the posts are rate limited like Discord does: each bot has a bucket in each
+//! channel, told in the `X-RateLimit-*` headers, and posting to an empty bucket gets a 429.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+/// How often the bots are asked to send a heartbeat.
+const HEARTBEAT_INTERVAL_MS: u64 = 41_250;
+
+/// The dispatches not yet sent to a slow bot. Older ones are lost, as with Discord.
+const MAX_PENDING_DISPATCHES: usize = 1024;
+
+/// The first user and message ID. (IDs are never 0)
+const FIRST_ID: u64 = 1000;
+
+/// A message posted through the REST API.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PostedMessage {
+    pub channel: u64,
+    /// The user ID of the bot that posted it.
+    pub author: u64,
+    pub content: String,
+}
+
+/// How many messages each bot may post in each channel, per period.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub messages: u32,
+    pub per: Duration,
+}
+
+/// What is left of the rate limit of a bot in a channel.
+struct Bucket {
+    remaining: u32,
+    reset_at: Instant,
+}
+
+/// The answer to a REST request.
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Value,
+}
+
+impl Reply {
+    fn new(status: u16, body: Value) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body,
+        }
+    }
+}
+
+/// What the REST API and the gateway share.
+struct State {
+    guild_id: u64,
+    gateway_url: String,
+    /// The user ID of each token.
+    users: Mutex<HashMap<String, u64>>,
+    next_id: AtomicU64,
+    messages: Mutex<Vec<PostedMessage>>,
+    /// The MESSAGE_CREATE events, for every gateway connection.
+    dispatches: broadcast::Sender<Value>,
+    /// The number of gateway connections that received READY.
+    sessions: watch::Sender<usize>,
+    /// None if the bots post as fast as they want.
+    rate_limit: Option<RateLimit>,
+    /// The bucket of each bot (user ID) in each channel.
+    buckets: Mutex<HashMap<(u64, u64), Bucket>>,
+    /// The number of posts answered with a 429.
+    rate_limited: AtomicU64,
+}
+
+/// The mock Discord, serving until dropped.
+pub struct MockDiscord {
+    state: Arc<State>,
+    api_addr: SocketAddr,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl MockDiscord {
+    /// Starts serving the REST API and the gateway on random local ports. The bots are all in
+    /// the guild `guild_id`.
+    pub async fn start(guild_id: u64) -> std::io::Result<Self> {
+        Self::start_with(guild_id, None).await
+    }
+
+    /// Same as `start`, the posts of each bot in each channel being rate limited.
+    pub async fn start_rate_limited(guild_id: u64, rate_limit: RateLimit) -> std::io::Result<Self> {
+        Self::start_with(guild_id, Some(rate_limit)).await
+    }
+
+    async fn start_with(guild_id: u64, rate_limit: Option<RateLimit>) -> std::io::Result<Self> {
+        let api_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let gateway_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let api_addr: SocketAddr = api_listener.local_addr()?;
+
+        let state = Arc::new(State {
+            guild_id,
+            gateway_url: format!("ws://{}", gateway_listener.local_addr()?),
+            users: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(FIRST_ID),
+            messages: Mutex::new(Vec::new()),
+            dispatches: broadcast::channel(MAX_PENDING_DISPATCHES).0,
+            sessions: watch::channel(0).0,
+            rate_limit,
+            buckets: Mutex::new(HashMap::new()),
+            rate_limited: AtomicU64::new(0),
+        });
+
+        let tasks = vec![
+            tokio::spawn(serve(api_listener, Arc::clone(&state), handle_http)),
+            tokio::spawn(serve(gateway_listener, Arc::clone(&state), handle_gateway)),
+        ];
+
+        Ok(Self {
+            state,
+            api_addr,
+            tasks,
+        })
+    }
+
+    /// The base URL to give to `--api-url`.
+    pub fn api_url(&self) -> String {
+        format!("http://{}", self.api_addr)
+    }
+
+    /// The user ID of the bot with this token.
+    pub fn user_id(&self, token: &str) -> u64 {
+        self.state.user_id(token)
+    }
+
+    /// The messages posted so far, in order.
+    pub fn messages(&self) -> Vec<PostedMessage> {
+        self.state.messages.lock().unwrap().clone()
+    }
+
+    /// The number of posts answered with a 429 so far.
+    pub fn rate_limited(&self) -> u64 {
+        self.state.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Waits until `count` bots are logged in to the gateway, and so receive the messages.
+    pub async fn wait_for_sessions(&self, count: usize) {
+        let mut sessions = self.state.sessions.subscribe();
+        // Fails only if the state is dropped, which it is not while borrowed.
+        let _ = sessions.wait_for(|&sessions| sessions >= count).await;
+    }
+}
+
+impl Drop for MockDiscord {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl State {
+    fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Returns the user ID of the token, a new one if the token was never seen.
+    fn user_id(&self, token: &str) -> u64 {
+        let token: &str = token.strip_prefix("Bot ").unwrap_or(token);
+        let mut users = self.users.lock().unwrap();
+        match users.get(token) {
+            Some(&id) => id,
+            None => {
+                let id: u64 = self.new_id();
+                users.insert(token.to_string(), id);
+                id
+            }
+        }
+    }
+
+    /// Answers a REST request.
+    fn respond(&self, method: &str, path: &str, token: Option<&str>, body: &[u8]) -> Reply {
+        let path: &str = path.split('?').next().unwrap_or_default();
+        let Some(path) = path.strip_prefix("/api/v10") else {
+            return not_found();
+        };
+
+        match (method, path) {
+            ("GET", "/gateway") => return Reply::new(200, json!({ "url": self.gateway_url })),
+            ("GET", "/gateway/bot") => {
+                return Reply::new(
+                    200,
+                    json!({
+                        "url": self.gateway_url,
+                        "shards": 1,
+                        "session_start_limit": {
+                            "total": 1000,
+                            "remaining": 1000,
+                            "reset_after": 0,
+                            "max_concurrency": 1,
+                        },
+                    }),
+                )
+            }
+            _ => {}
+        }
+
+        let Some(token) = token else {
+            return Reply::new(401, json!({ "message": "401: Unauthorized", "code": 0 }));
+        };
+        let user_id: u64 = self.user_id(token);
+
+        if method == "GET" && path == "/users/@me" {
+            return Reply::new(200, user(user_id));
+        }
+        let channel: Option<u64> = path
+            .strip_prefix("/channels/")
+            .and_then(|rest| rest.strip_suffix("/messages"))
+            .and_then(|channel| channel.parse().ok());
+        match (method, channel) {
+            ("POST", Some(channel)) => self.post_message(user_id, channel, body),
+            _ => not_found(),
+        }
+    }
+
+    /// Records the message, and dispatches it to every bot. (the author included, as Discord
+    /// does)
+    fn post_message(&self, author: u64, channel: u64, body: &[u8]) -> Reply {
+        let headers = match self.take(author, channel) {
+            Ok(headers) => headers,
+            Err(too_many) => return too_many,
+        };
+        let content: Option<String> = serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|body| body["content"].as_str().map(str::to_string));
+        let Some(content) = content else {
+            return Reply::new(
+                400,
+                json!({ "message": "Cannot send an empty message", "code": 50006 }),
+            );
+        };
+
+        let mut message = json!({
+            "id": self.new_id().to_string(),
+            "channel_id": channel.to_string(),
+            "author": user(author),
+            "content": content,
+            "timestamp": "2024-01-01T00:00:00.000000+00:00",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        });
+        self.messages.lock().unwrap().push(PostedMessage {
+            channel,
+            author,
+            content,
+        });
+
+        // Only the gateway events tell the guild.
+        let mut dispatch: Value = message.clone();
+        dispatch["guild_id"] = json!(self.guild_id.to_string());
+        // No bot logged in yet is not an error.
+        let _ = self.dispatches.send(dispatch);
+
+        message["guild_id"] = Value::Null;
+        Reply {
+            status: 200,
+            headers,
+            body: message,
+        }
+    }
+
+    /// Takes a post from the bucket of the author in the channel. Returns the headers telling
+    /// what is left of it, or the 429 if it is empty.
+    fn take(&self, author: u64, channel: u64) -> Result<Vec<(&'static str, String)>, Reply> {
+        let Some(limit) = self.rate_limit else {
+            return Ok(Vec::new());
+        };
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry((author, channel)).or_insert(Bucket {
+            remaining: limit.messages,
+            reset_at: now + limit.per,
+        });
+        if bucket.reset_at <= now {
+            bucket.remaining = limit.messages;
+            bucket.reset_at = now + limit.per;
+        }
+        let reset_after: f64 = (bucket.reset_at - now).as_secs_f64();
+
+        if bucket.remaining == 0 {
+            self.rate_limited.fetch_add(1, Ordering::Relaxed);
+            return Err(Reply {
+                status: 429,
+                headers: vec![
+                    ("Retry-After", reset_after.ceil().to_string()),
+                    ("X-RateLimit-Reset-After", format!("{reset_after:.3}")),
+                    ("X-RateLimit-Scope", "user".to_string()),
+                ],
+                body: json!({
+                    "message": "You are being rate limited.",
+                    "retry_after": reset_after,
+                    "global": false,
+                }),
+            });
+        }
+
+        bucket.remaining -= 1;
+        Ok(vec![
+            ("X-RateLimit-Limit", limit.messages.to_string()),
+            ("X-RateLimit-Remaining", bucket.remaining.to_string()),
+            ("X-RateLimit-Reset-After", format!("{reset_after:.3}")),
+            ("X-RateLimit-Bucket", "channel-messages".to_string()),
+        ])
+    }
+}
+
+/// The JSON of the bot user with this ID.
+fn user(id: u64) -> Value {
+    json!({
+        "id": id.to_string(),
+        "username": format!("bot-{id}"),
+        "discriminator": "0",
+        "global_name": null,
+        "avatar": null,
+        "bot": true,
+    })
+}
+
+fn not_found() -> Reply {
+    Reply::new(404, json!({ "message": "404: Not Found", "code": 0 }))
+}
+
+/// Accepts connections until aborted, each handled in its own task.
+async fn serve<F, Fut>(listener: TcpListener, state: Arc<State>, handle: F)
+where
+    F: Fn(TcpStream, Arc<State>) -> Fut,
+    Fut: std::future::Future<Output = Result<(), Box<dyn std::error::Error + Send + Sync>>>
+        + Send
+        + 'static,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Mock Discord failed to accept a connection: {err}");
+                continue;
+            }
+        };
+        let connection = handle(stream, Arc::clone(&state));
+        tokio::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("Mock Discord connection closed: {err}");
+            }
+        });
+    }
+}
+
+/// Answers the HTTP/1.1 requests of a connection, until it is closed.
+async fn handle_http(
+    stream: TcpStream,
+    state: Arc<State>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).await? == 0 {
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let method: String = parts.next().unwrap_or_default().to_string();
+        let path: String = parts.next().unwrap_or_default().to_string();
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let Some((name, value)) = line.split_once(':') else {
+                break;
+            };
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let length: usize = headers
+            .get("content-length")
+            .and_then(|length| length.parse().ok())
+            .unwrap_or(0);
+        let mut body: Vec<u8> = vec![0; length];
+        reader.read_exact(&mut body).await?;
+
+        let token: Option<&str> = headers.get("authorization").map(String::as_str);
+        let reply = state.respond(&method, &path, token, &body);
+        let status: u16 = reply.status;
+        debug!("Mock Discord: {method} {path} -> {status}");
+
+        let body: String = reply.body.to_string();
+        let reason: &str = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            429 => "Too Many Requests",
+            _ => "Not Found",
+        };
+        let headers: String = reply
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
+        let response = format!(
+            "HTTP/1.1 {status} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{headers}\r\n{body}",
+            body.len()
+        );
+        reader.get_mut().write_all(response.as_bytes()).await?;
+    }
+}
+
+/// Runs a gateway session: HELLO, then READY once identified, then the dispatches.
+async fn handle_gateway(
+    stream: TcpStream,
+    state: Arc<State>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    let mut dispatches = state.dispatches.subscribe();
+    // The sequence number of the last dispatch.
+    let mut seq: u64 = 0;
+    let mut identified: bool = false;
+
+    let hello = json!({ "op": 10, "d": { "heartbeat_interval": HEARTBEAT_INTERVAL_MS } });
+    ws.send(WsMessage::text(hello.to_string())).await?;
+
+    loop {
+        tokio::select! {
+            message = ws.next() => {
+                let payload: Value = match message {
+                    Some(Ok(WsMessage::Text(text))) => serde_json::from_str(&text)?,
+                    Some(Ok(WsMessage::Close(_))) | None => break,
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err.into()),
+                };
+                match payload["op"].as_u64() {
+                    // HEARTBEAT
+                    Some(1) => ws.send(WsMessage::text(json!({ "op": 11 }).to_string())).await?,
+                    // IDENTIFY
+                    Some(2) => {
+                        let user_id: u64 = state.user_id(payload["d"]["token"].as_str().unwrap_or_default());
+                        seq += 1;
+                        let ready = json!({
+                            "op": 0,
+                            "s": seq,
+                            "t": "READY",
+                            "d": {
+                                "v": 10,
+                                "user": user(user_id),
+                                "guilds": [{ "id": state.guild_id.to_string(), "unavailable": true }],
+                                "session_id": format!("mock-{user_id}-{seq}"),
+                                "resume_gateway_url": state.gateway_url,
+                                "application": { "id": user_id.to_string(), "flags": 0 },
+                            },
+                        });
+                        ws.send(WsMessage::text(ready.to_string())).await?;
+                        if !identified {
+                            identified = true;
+                            state.sessions.send_modify(|sessions| *sessions += 1);
+                        }
+                    }
+                    // The presence updates and such are ignored.
+                    _ => {}
+                }
+            }
+            dispatch = dispatches.recv() => match dispatch {
+                Ok(message) if identified => {
+                    seq += 1;
+                    let event = json!({ "op": 0, "s": seq, "t": "MESSAGE_CREATE", "d": message });
+                    ws.send(WsMessage::text(event.to_string())).await?;
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Mock Discord: a bot missed {missed} messages");
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    if identified {
+        state.sessions.send_modify(|sessions| *sessions -= 1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use tokio::sync::mpsc;
+
+    use crate::codec::Codec;
+    use crate::discord::{self, DiscordBot};
+    use crate::message::{Message, MessageDirection};
+    use crate::session::SessionId;
+    use crate::transport::{Bucket, Frame, Transport, TransportError};
+    use crate::{cli, crypto};
+
+    const GUILD_ID: u64 = 42;
+
+    /// Starts the bot of a side, logged in to the mock with the token.
+    async fn start_bot(mock: &MockDiscord, side: &str, token: &str, peer: &str) -> Arc<DiscordBot> {
+        let peer_bot: String = mock.user_id(peer).to_string();
+        let api_url: String = mock.api_url();
+        let (args, _) = cli::parse_from([
+            "discraft",
+            side,
+            "--token",
+            token,
+            "--guild-id",
+            &GUILD_ID.to_string(),
+            "--channel",
+            "10",
+            "--peer-bot",
+            &peer_bot,
+            "--api-url",
+            &api_url,
+        ])
+        .unwrap();
+        let cli::Command::Run(mode) = args.command else {
+            panic!("not a side");
+        };
+
+        let bot = Arc::new(DiscordBot::new(mode).await);
+        let bot_clone = Arc::clone(&bot);
+        tokio::spawn(async move { bot_clone.start().await });
+        bot
+    }
+
+    #[tokio::test]
+    async fn test_bots_talk_through_mock() {
+        let mock = MockDiscord::start(GUILD_ID).await.unwrap();
+        let server = start_bot(&mock, "server", "server-token", "client-token").await;
+        let client = start_bot(&mock, "client", "client-token", "server-token").await;
+        tokio::time::timeout(Duration::from_secs(10), mock.wait_for_sessions(2))
+            .await
+            .unwrap();
+
+        client.send(10, "serverbound".to_string()).await.unwrap();
+        server.send(11, "clientbound".to_string()).await.unwrap();
+
+        let receive = |bot: Arc<DiscordBot>| async move {
+            tokio::time::timeout(Duration::from_secs(10), bot.receive())
+                .await
+                .unwrap()
+                .unwrap()
+        };
+        // Neither bot reads its own messages.
+        assert_eq!(
+            receive(server).await,
+            Frame {
+                channel: 10,
+                content: "serverbound".to_string()
+            }
+        );
+        assert_eq!(receive(client).await.content, "clientbound");
+
+        let messages = mock.messages();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].author, mock.user_id("client-token"));
+        assert_eq!(messages[1].channel, 11);
+    }
+
+    #[tokio::test]
+    async fn test_writer_skips_empty_bucket() {
+        let per = Duration::from_secs(60);
+        let mock = MockDiscord::start_rate_limited(GUILD_ID, RateLimit { messages: 3, per })
+            .await
+            .unwrap();
+        let bot = start_bot(&mock, "server", "server-token", "client-token").await;
+
+        // The bucket is told by the headers.
+        for remaining in (0..3).rev() {
+            let bucket: Bucket = bot.send(10, "filler".to_string()).await.unwrap().unwrap();
+            assert_eq!(bucket.remaining, remaining);
+            assert!(bucket.reset_after <= per);
+        }
+        match bot.send(10, "filler".to_string()).await {
+            Err(TransportError::RateLimited {
+                retry_after,
+                global: false,
+            }) => assert!(retry_after > Duration::ZERO && retry_after <= per),
+            other => panic!("not rate limited: {other:?}"),
+        }
+        assert_eq!(mock.rate_limited(), 1);
+
+        let (tx, rx) = mpsc::channel(8);
+        let (stop_tx, _) = broadcast::channel(1);
+        let writer = Arc::clone(&bot);
+        tokio::spawn(async move {
+            let link = crypto::Link::new(None);
+            discord::handle_write_discord(
+                writer.as_ref(),
+                rx,
+                stop_tx,
+                &[10, 11],
+                &link,
+                Codec::default(),
+            )
+            .await
+        });
+
+        for sent in 1..=3 {
+            let message =
+                Message::from_bytes([sent], MessageDirection::Clientbound, SessionId::new(1));
+            tx.send(message).await.unwrap();
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while mock.messages().len() < 3 + usize::from(sent) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        // Channel 10 was tried first, knowing nothing yet: its 429 sent the message to channel
+        // 11, and channel 10 was left alone since.
+        let messages = mock.messages();
+        assert!(messages[3..].iter().all(|message| message.channel == 11));
+        assert_eq!(mock.rate_limited(), 2);
+    }
+
+    #[test]
+    fn test_rest_routes() {
+        let (dispatches, _) = broadcast::channel(1);
+        let state = State {
+            guild_id: GUILD_ID,
+            gateway_url: "ws://127.0.0.1:1".to_string(),
+            users: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(FIRST_ID),
+            messages: Mutex::new(Vec::new()),
+            dispatches,
+            sessions: watch::channel(0).0,
+            rate_limit: None,
+            buckets: Mutex::new(HashMap::new()),
+            rate_limited: AtomicU64::new(0),
+        };
+
+        let reply = state.respond("GET", "/api/v10/gateway", None, b"");
+        assert_eq!(reply.status, 200);
+        assert_eq!(reply.body["url"], "ws://127.0.0.1:1");
+
+        assert_eq!(
+            state.respond("GET", "/api/v10/users/@me", None, b"").status,
+            401
+        );
+        let me = state.respond("GET", "/api/v10/users/@me", Some("Bot a"), b"");
+        assert_eq!(me.body["id"], FIRST_ID.to_string());
+        // The prefix does not matter.
+        assert_eq!(state.user_id("a"), FIRST_ID);
+
+        let reply = state.respond(
+            "POST",
+            "/api/v10/channels/7/messages",
+            Some("Bot b"),
+            br#"{"content":"hi"}"#,
+        );
+        assert_eq!(reply.status, 200);
+        // Not rate limited.
+        assert!(reply.headers.is_empty());
+        assert_eq!(reply.body["author"]["id"], (FIRST_ID + 1).to_string());
+        let message: serenity::all::Message = serde_json::from_value(reply.body).unwrap();
+        assert_eq!(message.content, "hi");
+
+        assert_eq!(
+            state
+                .respond("POST", "/api/v10/channels/7/messages", Some("b"), b"{}")
+                .status,
+            400
+        );
+        assert_eq!(
+            state
+                .respond("GET", "/api/v10/guilds/1", Some("b"), b"")
+                .status,
+            404
+        );
+        assert_eq!(state.messages.lock().unwrap().len(), 1);
+    }
+}
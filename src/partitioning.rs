@@ -7,23 +7,31 @@
 //! - aggregation is taking multiple "small" `Message`s and transformaing them into a single, or
 //!   multiple, "big" compound `AggregateMessage`.
 
+use std::fmt;
+
 use once_cell::sync::Lazy;
 
 use crate::{
+    codec::Codec,
     discord::DiscordBot,
-    message::{Message, MessageDirection, MessageError},
+    message::{Message, MessageDirection, MessageError, MessageKind},
+    session::SessionId,
 };
 
 // Functions to partition and merge `Message`s.
 pub struct Partitioner {}
 
-// TODO: Make this mess into smaller digestible functions.
 impl Partitioner {
     /// Check if the length limit and the Message are compatible.
     /// (I.e., no, if the former is 0 or the latter's header size is less than the former.)
-    fn check_is_partitionable(message: &Message, limit: usize) -> Result<String, MessageError> {
-        println!("partition() input msg: {message:?}");
-        println!("partition() input limit: {limit:?}");
+    ///
+    /// Returns the number of payload bytes each part can hold, and the size of the header of a
+    /// part.
+    fn check_is_partitionable(
+        message: &Message,
+        limit: usize,
+        overhead: usize,
+    ) -> Result<(usize, usize), MessageError> {
         // Check for invalid `max` values
         if limit == 0 {
             return Err(MessageError::Partitioning(
@@ -31,225 +39,86 @@ impl Partitioner {
             ));
         }
 
-        // fn is_partitionable()
-        // fn check_is_partitionable()
-
-        // ----- COMPUTE HEADER SIZE && CHECK
         // Potentially unoptimized doing this every time.
-        let payload: String = Message::payload_bytes_to_string(message.payload());
-        println!("payload: {payload:?}");
-        // Size of the payload (STRING)
-        let payload_len: usize = payload.len();
-        println!("payload_len (string): {payload_len:?}");
-
-        let header_size: usize = message.get_header_size();
-        if limit <= header_size {
+        let mut header_size: usize = message.get_header_size();
+        if overhead > 0 {
+            // A part grows once the overhead is added: its length field may get wider than the
+            // message's, up to the limit's.
+            let widest_length: usize =
+                limit.to_string().len() + Message::LENGTH_DELIMITER.len_utf8();
+            header_size =
+                header_size - message.length.len() + message.length.len().max(widest_length);
+        }
+
+        let bytes_per_part: usize = limit
+            .checked_sub(header_size)
+            .map(|chars| message.codec().get().max_decoded_len(chars))
+            .and_then(|bytes| bytes.checked_sub(overhead))
+            .unwrap_or(0);
+        if bytes_per_part == 0 {
             return Err(MessageError::Partitioning(
                 "length limit is too small to accommodate the header",
             ));
         }
 
-        Ok(payload)
+        Ok((bytes_per_part, header_size))
     }
 
-    /// Computes the number of total parts the message will be split.
-    /// Returns the number of total parts AND the size of the parts.
-    fn compute_total_parts(limit: usize, header_size: usize, payload_len: usize) -> (usize, usize) {
-        let payload_slice_size: usize = limit - header_size;
-        // Compute the number of partitions we will need to create
-        let whole_parts: usize = payload_len / payload_slice_size;
-        let remainder: usize = payload_len % payload_slice_size;
-        let total_parts = if remainder > 0 {
-            whole_parts + 1
-        } else {
-            whole_parts
-        };
-
-        println!("There are {total_parts} parts");
-        println!("The payload slice size {payload_slice_size}");
-        println!("The remainder is {remainder}");
-
-        (total_parts, payload_slice_size)
-    }
-    /// This function partitions BY TEXT, and not bytewise!
     /// Takes a message and returns smaller messages that all fit within the character limit.
     ///
-    /// If the input message is already smaller than the max chars, it is returned.
-    ///
-    /// # ! IMPORTANT !
+    /// The payload is split bytewise, then each part is encoded on its own: the codec never has to
+    /// decode a slice of its output. (e.g. a base64 group cut in half)
     ///
-    /// IMPORTANT!!: Everything might just blow up if the message encoding is done with UTF-8 characters (non-ASCII).
+    /// If the input message is already smaller than the max chars, it is returned.
     ///
-    /// * The `limit` is a size in number of characters.
+    /// * The `limit` is a size in number of characters. (Unicode scalar values, not bytes)
     pub fn partition(message: Message, limit: usize) -> Result<Vec<Message>, MessageError> {
-        // Check: can the limit accommodate the message.
-        let payload: String = Self::check_is_partitionable(&message, limit)?;
-
-        // I call this function twice...
-        let header_size: usize = message.get_header_size();
-        let payload_len: usize = payload.len();
-
-        println!("FLAG I");
-
-        // The number of payload characters we can put while still being able to put the header.
-        let (total_parts, payload_slice_size) =
-            Self::compute_total_parts(limit, header_size, payload_len);
-
-        // testing2 begin--
-
-        // Where the partition will be stored each loop iteration
-        let mut part_buffer: String = String::with_capacity(limit);
-
-        // All the parts that make up the inputted message
-        let mut parts: Vec<Message> = Vec::with_capacity(total_parts);
-
-        let mut offset: usize = 0;
-        let mut neg_offset: usize = 0;
-
-
-        for i in 1..=total_parts {
-            let part: String = Part::new(i, total_parts)?.to_string();
-            println!("[FOR LOOP] payload.len()={}", payload.len());
-
-            let start = (i - 1) * payload_slice_size;
-            let end = if i != total_parts {
-                (i * payload_slice_size) - neg_offset
-            } else {
-                payload.len()
-            };
-            let mut slice = payload[start..end].to_owned();
-
-            // let mut slice: String = if i != total_parts {
-            //     // Get whole parts
-            //     payload[offset..((i - 1) * payload_slice_size)].to_owned()
-            // } else {
-            //     // Get the remainder
-            //     payload[offset..].to_owned()
-            // };
-
-            //let mut slice = payload[range].to_owned();
-
-            // if hex is len odd (badly cut). EXCEPT the last part.
-            if slice.len() % 2 != 0 && i != total_parts {
-                slice = payload[offset..(i * payload_slice_size - 1)].to_owned();
-                // So that the next iteration will contain the removed hex nibble.
-                //offset += payload_slice_size - 1;
-                neg_offset += 1;
-            }
-            // if hex len is even (OK). EXCEPT the last part.
-            if slice.len() % 2 == 0 && i != total_parts {
-                // not total parts.
-                // CHECK THIS: (original)
-                //offset += payload_slice_size;
-                offset += slice.len();
-            }
-
-            // if hex is odd ON THE LAST PART
-            if slice.len() % 2 != 0 && i == total_parts {
-                // Last part is not good:
-                slice += "0";
-                // Add 0 at the end to make it even.
-            }
-
-            // Construct the full partition string
-            // That's dirty, no constructor?
-            part_buffer.clear();
-            part_buffer.push_str(&message.direction.to_string());
-            part_buffer.push_str(&part);
-            part_buffer.push_str(&slice);
+        Self::partition_with_overhead(message, limit, 0)
+    }
 
-            let length: String =
-                part_buffer.len().to_string() + &Message::LENGTH_DELIMITER.to_string();
+    /// Same as `partition`, but leaves room for `overhead` more bytes in the payload of each part.
+    /// (e.g. to seal it, see `crypto`)
+    pub fn partition_with_overhead(
+        message: Message,
+        limit: usize,
+        overhead: usize,
+    ) -> Result<Vec<Message>, MessageError> {
+        // Check: can the limit accommodate the message.
+        let (bytes_per_part, header_size) =
+            Self::check_is_partitionable(&message, limit, overhead)?;
 
-            // A whole message is [Length, Direction, Part, Payload]
-            let part = Message::from_string(length + &part_buffer)?;
-            parts.extend_from_slice(&part);
+        let fits: bool = if overhead == 0 {
+            message.char_count() <= limit
+        } else {
+            let encoded_len = message
+                .codec()
+                .get()
+                .encoded_len(message.payload().len() + overhead);
+            header_size + encoded_len <= limit
+        };
+        if fits {
+            return Ok(vec![message]);
         }
 
-        // testing2 end--
-        return Ok(parts);
-
-        println!("FLAG II");
+        let chunks = message.payload().chunks(bytes_per_part);
+        let total_parts: usize = chunks.len();
 
-        // The number of payload characters we have partitioned
-        let mut put_payload_chars: usize = 0;
-        // The current part number. Like 1/2 (current/total).
-        let mut current_part: usize = 1;
         // All the parts that make up the inputted message
         let mut parts: Vec<Message> = Vec::with_capacity(total_parts);
 
-        // Where the partition will be stored each loop iteration
-        let mut part_buffer: String = String::with_capacity(limit);
-
-        println!("FLAG III");
-
-        // Exits when all the payload has been partitioned
-        // Also, we have computed the number of parts, surely there's a way to not use a while
-        // loop.
-
-        // On the second and other round of the while loop,
-        // this offset has to be added to the start idx in the slicing of the string payload.
-        // Because we make sure the sliced string payload is always valid (no nibbles at the end).
-        let mut hex_validity_offset: usize = 0;
-        while put_payload_chars < payload.len() {
-            println!("FLAG IV");
-            let part: String = Part::new(current_part, total_parts)?.to_string();
-            current_part += 1;
-
-            let start: usize = put_payload_chars;
-            // What? (my future me is having trouble here, start + payload_slice_size is always
-            // greater than payload_len, right...?)
-            let stop = usize::min(start + payload_slice_size, payload_len); // Prevent out-of-bounds slicing
-
-            // --- dev in progress BEGIN ---
-
-            // PROBLEM: THE SLICED PAYLOAD CANNOT CUT ANYWHERE, IT MUST CONTAIN A SEQUENCE OF BYTES
-            // IN HEX, NO PARTIAL-BYTE NIBBLE THINGGY.
-
-            // TODO: Ok, so we need to make a function that slices the payload string into parts,
-            // the function needs to slice the hex in a valid manner, no nybbles.
-            // However, it seems quite long and tedious to do with my tiny head, so I'm off...
-
-            // Not even == partial hex.
-            if &payload[start..stop].replace(" ", "").len() % 2 != 0 {
-                hex_validity_offset += 1;
-            }
-
-            let sliced_payload: &str = &payload[start - hex_validity_offset..stop];
-
-            // --- dev in progress END ---
-
-            put_payload_chars = stop; // Update position
-            println!("FLAG V");
-
-            // Construct the full partition string
-            part_buffer.clear();
-            //part_buffer.push_str(&direction);
-            part_buffer.push_str(&part);
-            part_buffer.push_str(sliced_payload);
-
-            let length: String =
-                part_buffer.len().to_string() + &Message::LENGTH_DELIMITER.to_string();
-            println!("FLAG VI");
+        for (i, chunk) in chunks.enumerate() {
+            let part = Part::new(i + 1, total_parts)?;
 
-            println!(
-                "length.clone() + &part_buffer: {:?}",
-                length.clone() + &part_buffer
-            );
-
-            // A whole message is [Lenght, Direction, Part, Payload]
-            // TODO: IS THIS WHERE "FAILED TO DECODE HEX"?
-            // TODO: IS THIS WHERE "FAILED TO DECODE HEX"?
-            // TODO: IS THIS WHERE "FAILED TO DECODE HEX"?
-            let part = Message::from_string(length + &part_buffer)?;
-            parts.extend_from_slice(&part);
-            println!("FLAG VII");
+            // A whole message is [Length, Direction, Session, Kind, Seq, Part, Payload]
+            parts.push(message.make_part(chunk, part));
         }
 
         Ok(parts)
     }
 
     /// Merges all the `Message`s into a single `Message`.
+    ///
+    /// The header (kind, seq, ...) is taken from the first part.
     pub fn merge<T: AsRef<[Message]>>(parts: T) -> Result<Message, MessageError> {
         let parts: &[Message] = parts.as_ref();
 
@@ -258,11 +127,11 @@ impl Partitioner {
             return Err(MessageError::Partitioning("No parts to merge"));
         }
 
-        // Extract direction from the first part
-        let direction = parts[0].direction;
+        // Extract the header from the first part
+        let first: &Message = &parts[0];
 
-        let max_message_length: usize = parts.len() * DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED;
-        let mut payload_buffer: Vec<u8> = Vec::with_capacity(max_message_length);
+        let payload_length: usize = parts.iter().map(|part| part.payload().len()).sum();
+        let mut payload_buffer: Vec<u8> = Vec::with_capacity(payload_length);
 
         // Merge all parts
         for part in parts {
@@ -270,7 +139,14 @@ impl Partitioner {
         }
 
         // Create and return the merged Message
-        Ok(Message::from_bytes(payload_buffer, direction))
+        Ok(Message::new(
+            first.kind,
+            first.direction,
+            first.session,
+            first.seq,
+            Part::new(1, 1)?,
+            payload_buffer,
+        ))
     }
 }
 
@@ -315,13 +191,6 @@ impl Part {
         self.total
     }
 
-    /// Encodes the partitioning into 2 hex digits.
-    /// Max is 0xFF which is 255, and Discord supports messages of 2000 characters.
-    /// 2000 * 255 = 510,000 which is larger than the max lenght of a TCP packet (65,535)
-    pub fn to_string(&self) -> String {
-        format!("{:02X}/{:02X} ", self.current, self.total)
-    }
-
     /// Decodes a partitioning string into a `Part`.
     /// The first section of the string must represent the partitioning format (`current/total`),
     /// and additional content is disallowed.
@@ -339,23 +208,40 @@ impl Part {
             ));
         }
 
-        // Slice to the expected length
-        let text = &text[..expected_len].trim();
+        // Slice to the expected length, which must end with the space.
+        let text = text
+            .get(..expected_len)
+            .filter(|text| text.ends_with(' '))
+            .ok_or(MessageError::Partitioning(
+                "Partitioning string malformed: not delimited",
+            ))?
+            .trim_end();
         let mut tokens = text.split('/');
-        println!("tokens: {tokens:?}");
+
+        // Exactly 2 hex digits. (`from_str_radix` alone would accept a sign)
+        let parse_hex = |token: &str| {
+            if token.len() == 2 && token.bytes().all(|b| b.is_ascii_hexdigit()) {
+                usize::from_str_radix(token, 16).ok()
+            } else {
+                None
+            }
+        };
+
         // Parse current value
-        let current_str = tokens.next().ok_or_else(|| {
-            MessageError::Partitioning("Missing 'current' part in partitioning string")
-        })?;
-        let current: usize = usize::from_str_radix(current_str, 16)
-            .map_err(|_| MessageError::Partitioning("Failed to parse 'current' as a hex number"))?;
+        let current_str = tokens.next().ok_or(MessageError::Partitioning(
+            "Missing 'current' part in partitioning string",
+        ))?;
+        let current: usize = parse_hex(current_str).ok_or(MessageError::Partitioning(
+            "Failed to parse 'current' as a hex number",
+        ))?;
 
         // Parse total value
-        let total_str = tokens.next().ok_or_else(|| {
-            MessageError::Partitioning("Missing 'total' part in partitioning string")
-        })?;
-        let total: usize = usize::from_str_radix(total_str, 16)
-            .map_err(|_| MessageError::Partitioning("Failed to parse 'total' as a hex number"))?;
+        let total_str = tokens.next().ok_or(MessageError::Partitioning(
+            "Missing 'total' part in partitioning string",
+        ))?;
+        let total: usize = parse_hex(total_str).ok_or(MessageError::Partitioning(
+            "Failed to parse 'total' as a hex number",
+        ))?;
 
         // Ensure no extra tokens exist
         if tokens.next().is_some() {
@@ -382,13 +268,23 @@ impl Part {
     }
 }
 
+impl fmt::Display for Part {
+    /// Encodes the partitioning into 2 hex digits.
+    /// Max is 0xFF which is 255, and Discord supports messages of 2000 characters.
+    /// 2000 * 255 = 510,000 which is larger than the max lenght of a TCP packet (65,535)
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02X}/{:02X} ", self.current, self.total)
+    }
+}
+
 /// Functions to aggregate and disaggregate `Messages`.
 ///
 /// Simply put: takes lots of small `Message`s and return the biggest messages we can build, while
 /// still being able to de-agregate those agregated messages into their smaller ones.
 ///
 ///
-/// (length = the length of the message's total string representation(sent to discord))
+/// (length = the length of the message's total string representation(sent to discord), in
+/// characters)
 ///
 /// In this context, aggregation is taking multiple "small" `Message`s and making fewer `Message`s
 /// packed with multiple sub-`Message`.
@@ -419,18 +315,22 @@ impl Aggregator {
 
         let mut aggregated: Vec<String> = Vec::new();
         let mut buffer = String::new();
+        let mut buffer_chars: usize = 0;
 
         // Process each part to form a segment.
         for part in parts {
             let segment: &str = part.to_string();
+            let segment_chars: usize = part.char_count();
 
             // If appending the segment would overflow the current buffer, flush it.
-            if buffer.len() + segment.len() > DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED {
+            if buffer_chars + segment_chars > DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED {
                 aggregated.push(buffer);
                 buffer = String::new();
+                buffer_chars = 0;
             }
 
-            buffer.push_str(&segment);
+            buffer.push_str(segment);
+            buffer_chars += segment_chars;
         }
 
         // Append any remaining data.
@@ -441,96 +341,140 @@ impl Aggregator {
         Ok(aggregated)
     }
 
+    /// Maximum number of messages in an aggregate. (Discord cannot carry more in a message, even
+    /// with empty payloads)
+    pub const MAX_MESSAGES_PER_AGGREGATE: usize = 64;
+
+    /// Maximum size of an aggregate, in bytes. (Discord messages are at most 2000 characters, of
+    /// at most 4 bytes each)
+    pub const MAX_AGGREGATE_LEN: usize = 2000 * 4;
+
+    /// Maximum number of digits of the length field. (the length is at most `MAX_AGGREGATE_LEN`)
+    const MAX_LENGTH_DIGITS: usize = 4;
+
     /// Disaggregates all aggregate parts from the current `&str` into multiple
-    /// `Message`s.
-    pub fn disaggregate(aggregate_message: &str) -> Result<Vec<Message>, MessageError> {
+    /// `Message`s, whose payloads are encoded with the codec.
+    ///
+    /// The text comes from Discord, so anyone able to post in the channels controls it: every
+    /// malformed input is an error, never a panic.
+    pub fn disaggregate(
+        aggregate_message: &str,
+        codec: Codec,
+    ) -> Result<Vec<Message>, MessageError> {
+        if aggregate_message.len() > Self::MAX_AGGREGATE_LEN {
+            return Err(MessageError::Aggregation("The aggregate is too long."));
+        }
+
         let mut messages: Vec<Message> = Vec::new();
-        let mut offset: usize = 0;
-        let total_len: usize = aggregate_message.len();
-        let mut messages_char_counter: usize = 0;
-
-        // Suspicious convoluted loop; bugs may be hidden.
-        while messages_char_counter < aggregate_message.len() {
-            // Parse the length field until the '*' delimiter is found.
-            let mut var_length = String::new();
-            while offset < total_len {
-                let c =
-                    aggregate_message
-                        .get(offset..offset + 1)
-                        .ok_or(MessageError::Aggregation(
-                            "Unexpected end of string while parsing length.",
-                        ))?;
-                offset += 1;
-                if c == Message::LENGTH_DELIMITER.to_string() {
-                    if var_length.is_empty() {
-                        return Err(MessageError::Aggregation(
-                            "No digits found for variable length.",
-                        ));
-                    }
-                    break;
-                }
-                var_length.push_str(c);
+        // What is left to parse.
+        let mut rest: &str = aggregate_message;
+
+        while !rest.is_empty() {
+            if messages.len() == Self::MAX_MESSAGES_PER_AGGREGATE {
+                return Err(MessageError::Aggregation(
+                    "The aggregate holds too many messages.",
+                ));
             }
 
-            // Add length of the length.
-            messages_char_counter += var_length.len() + Message::LENGTH_DELIMITER.len_utf8();
+            // Parse the length field until the delimiter is found.
+            let (var_length, after_length) =
+                rest.split_once(Message::LENGTH_DELIMITER)
+                    .ok_or(MessageError::Aggregation(
+                        "Unexpected end of string while parsing length.",
+                    ))?;
+            if var_length.is_empty() {
+                return Err(MessageError::Aggregation(
+                    "No digits found for variable length.",
+                ));
+            }
+            if var_length.len() > Self::MAX_LENGTH_DIGITS
+                || !var_length.bytes().all(|b| b.is_ascii_digit())
+            {
+                return Err(MessageError::Aggregation(
+                    "Failed to parse the message length.",
+                ));
+            }
             let message_length: usize = var_length
-                .trim()
                 .parse()
                 .map_err(|_| MessageError::Aggregation("Failed to parse the message length."))?;
+            rest = after_length;
 
-            // And add the length of the header(except the Length) + payload.
-            messages_char_counter += message_length;
+            // Each field was checked to be ASCII text, so skipping it stays on a char boundary.
 
             // Tries to read the first direction from the string.
-            let direction = MessageDirection::from_string(&aggregate_message[offset..])?;
-            offset += direction.to_string().len();
+            let direction = MessageDirection::from_string(rest)?;
+            rest = Self::skip(rest, direction.to_string().len())?;
+
+            let session = SessionId::from_string(rest)?;
+            rest = Self::skip(rest, SessionId::get_standard_string_length())?;
 
-            let part = Part::from_string(&aggregate_message[offset..])?;
-            offset += part.to_string().len();
+            let kind = MessageKind::from_string(rest)?;
+            rest = Self::skip(rest, kind.to_string().len())?;
+
+            let seq = Message::seq_from_string(rest)?;
+            rest = Self::skip(rest, Message::SEQ_STRING_LENGTH)?;
+
+            let part = Part::from_string(rest)?;
+            rest = Self::skip(rest, Part::get_standard_string_length())?;
 
             // Length_len - (direction_len + part_len) = payload_len
             // Because Length_len does not contain itself.
+            let header_len: usize = direction.to_string().len()
+                + SessionId::get_standard_string_length()
+                + kind.to_string().len()
+                + Message::SEQ_STRING_LENGTH
+                + Part::get_standard_string_length();
             let payload_len: usize =
-                message_length - (direction.to_string().len() + part.to_string().len());
-            let payload: &str = aggregate_message
-                .get(offset..offset + payload_len)
+                message_length
+                    .checked_sub(header_len)
+                    .ok_or(MessageError::Aggregation(
+                        "The message length is smaller than its header.",
+                    ))?;
+
+            // Takes `payload_len` characters.
+            let payload_end: usize = rest
+                .char_indices()
+                .map(|(index, _)| index)
+                .chain(std::iter::once(rest.len()))
+                .nth(payload_len)
                 .ok_or(MessageError::Aggregation("Failed to slice the payload."))?;
-            offset += payload.len();
+            let (payload, after_payload) = rest.split_at(payload_end);
+            rest = after_payload;
 
             // May be unoptimized, maybe use from_string().
-            messages.push(Message::from_bytes(
-                // TODO: STRING TO BYTES USED HERE !!!!!!!!!
-                // TODO: STRING TO BYTES USED HERE !!!!!!!!!
-                // TODO: STRING TO BYTES USED HERE !!!!!!!!!
-                // TODO: STRING TO BYTES USED HERE !!!!!!!!!
-                Message::payload_string_to_bytes(payload)?,
-                direction,
-            ));
+            messages.push(
+                Message::new(
+                    kind,
+                    direction,
+                    session,
+                    seq,
+                    part,
+                    codec.get().decode(payload)?,
+                )
+                .with_codec(codec),
+            );
         }
 
         Ok(messages)
     }
+
+    /// Returns the text after its first `len` bytes.
+    fn skip(text: &str, len: usize) -> Result<&str, MessageError> {
+        text.get(len..)
+            .ok_or(MessageError::Aggregation("Unexpected end of the header."))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    // For testing purposes, we define a dummy DiscordBot if one is not available.
-    // Remove or adjust this module if your crate already defines DiscordBot.
-    mod discord {
-        pub struct DiscordBot;
-        impl DiscordBot {
-            // A small limit to force partitioning/aggregation in tests.
-            pub const MAX_MESSAGE_LENGTH_ALLOWED: usize = 100;
-        }
-    }
     use crate::message::{Message, MessageDirection};
-    use rand::{seq::IndexedRandom, Rng, RngCore};
+    use crate::session::SessionId;
+    use rand::{Rng, RngCore};
 
     // Helper function to create a Message from a given payload string.
     fn create_message(payload: &str, direction: MessageDirection) -> Message {
-        Message::from_bytes(payload.as_bytes(), direction)
+        Message::from_bytes(payload.as_bytes(), direction, SessionId::new(1))
     }
 
     #[test]
@@ -544,9 +488,11 @@ mod tests {
         assert_eq!(parts.len(), 1);
 
         // Verify that the payload (after decoding) equals the original.
-        let encoded_payload = Message::payload_bytes_to_string(parts[0].payload());
-        let decoded_bytes =
-            Message::payload_string_to_bytes(&encoded_payload).expect("Decoding failed");
+        let encoded_payload = Codec::Hex.get().encode(parts[0].payload());
+        let decoded_bytes = Codec::Hex
+            .get()
+            .decode(&encoded_payload)
+            .expect("Decoding failed");
         assert_eq!(decoded_bytes, payload.as_bytes());
     }
 
@@ -581,12 +527,12 @@ mod tests {
     fn test_partition_message_split2() {
         for _ in 0..100 {
             let byte_count: usize = rand::rng().random_range(2001..17_000);
-            let mut data = Vec::with_capacity(byte_count);
-            data.resize(byte_count, 0);
+            let mut data = vec![0; byte_count];
             rand::rng().fill_bytes(&mut data);
             // let rnd_hex: String = Message::payload_bytes_to_string(&data);
 
-            let message = Message::from_bytes(data, MessageDirection::Serverbound);
+            let message =
+                Message::from_bytes(data, MessageDirection::Serverbound, SessionId::new(1));
             let messages = Partitioner::partition(message, 2000);
             assert!(
                 messages.is_ok(),
@@ -623,9 +569,11 @@ mod tests {
         let merged = Partitioner::merge(vec![msg1, msg2]).expect("Merge failed");
 
         // Decode the merged payload.
-        let merged_encoded = Message::payload_bytes_to_string(merged.payload());
-        let merged_bytes =
-            Message::payload_string_to_bytes(&merged_encoded).expect("Decoding failed");
+        let merged_encoded = Codec::Hex.get().encode(merged.payload());
+        let merged_bytes = Codec::Hex
+            .get()
+            .decode(&merged_encoded)
+            .expect("Decoding failed");
         let expected: Vec<u8> = [payload1.as_bytes(), payload2.as_bytes()].concat();
         assert_eq!(merged_bytes, expected);
     }
@@ -656,14 +604,16 @@ mod tests {
     fn test_merge_messages2() {
         for _ in 0..300 {
             let mut messages = Vec::new();
+            let mut total_len: usize = 0;
             for _ in 0..100 {
                 let byte_count: usize = rand::rng().random_range(1..324);
-                let mut data = Vec::with_capacity(byte_count);
-                data.resize(byte_count, 0);
+                total_len += byte_count;
+                let mut data = vec![0; byte_count];
                 rand::rng().fill_bytes(&mut data);
 
                 // Make a message with random payload.
-                let random_msg = Message::from_bytes(&data, MessageDirection::Clientbound);
+                let random_msg =
+                    Message::from_bytes(&data, MessageDirection::Clientbound, SessionId::new(1));
 
                 let msg_hex = random_msg.to_string();
 
@@ -673,11 +623,12 @@ mod tests {
                     // Get the first
                     messages.push(msg.clone());
                 } else {
-                    assert!(false, "Message is None. str: {msg_hex:?} / byte_count: {byte_count:?} / data: {data:?}");
+                    panic!("Message is None. str: {msg_hex:?} / byte_count: {byte_count:?} / data: {data:?}");
                 }
             }
 
             let messages_merged = Partitioner::merge(&messages).unwrap();
+            assert_eq!(messages_merged.payload().len(), total_len);
         }
     }
 
@@ -690,7 +641,7 @@ mod tests {
     #[test]
     fn test_aggregate_and_disaggregate() {
         // Create several messages.
-        let payloads = vec!["Part one.", "Part two.", "Part three."];
+        let payloads = ["Part one.", "Part two.", "Part three."];
         let messages: Vec<Message> = payloads
             .iter()
             .map(|p| create_message(p, MessageDirection::Serverbound))
@@ -704,7 +655,7 @@ mod tests {
         // Disaggregate each aggregated string.
         let mut disaggregated_messages = Vec::new();
         for agg in aggregated_strings {
-            let parts = Aggregator::disaggregate(&agg).expect("Disaggregation failed");
+            let parts = Aggregator::disaggregate(&agg, Codec::Hex).expect("Disaggregation failed");
             disaggregated_messages.extend(parts);
         }
 
@@ -720,14 +671,205 @@ mod tests {
         assert_eq!(reconstructed, expected);
     }
 
+    #[test]
+    fn test_disaggregate_keeps_parts_and_seq() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let message =
+            Message::from_bytes(&payload, MessageDirection::Serverbound, SessionId::new(1))
+                .with_seq(9);
+        let parts = Partitioner::partition(message, 200).expect("Partitioning failed");
+        assert!(parts.len() > 1);
+
+        let aggregated = Aggregator::aggregate(&parts).expect("Aggregation failed");
+        let disaggregated: Vec<Message> = aggregated
+            .iter()
+            .flat_map(|agg| {
+                Aggregator::disaggregate(agg, Codec::Hex).expect("Disaggregation failed")
+            })
+            .collect();
+
+        for (i, part) in disaggregated.iter().enumerate() {
+            assert_eq!(part.part.current(), i + 1);
+            assert_eq!(part.part.total(), parts.len());
+            assert_eq!(part.seq, 9);
+        }
+        let merged = Partitioner::merge(&disaggregated).expect("Merge failed");
+        assert_eq!(merged.payload(), &payload[..]);
+        assert_eq!(merged.seq, 9);
+    }
+
+    #[test]
+    fn test_partition_with_every_codec() {
+        for codec in [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768] {
+            // Limits that do not fall on a group boundary of the codec.
+            for extra_chars in [2, 3, 5, 7, 11] {
+                let mut payload = vec![0; 200];
+                rand::rng().fill_bytes(&mut payload);
+                let message =
+                    Message::from_bytes(&payload, MessageDirection::Clientbound, SessionId::new(1))
+                        .with_codec(codec);
+                let limit = message.get_header_size() + extra_chars;
+
+                let parts = Partitioner::partition(message, limit).expect("Partitioning failed");
+                assert!(parts.iter().all(|p| p.char_count() <= limit));
+
+                let aggregated = Aggregator::aggregate(&parts).expect("Aggregation failed");
+                let disaggregated: Vec<Message> = aggregated
+                    .iter()
+                    .flat_map(|agg| Aggregator::disaggregate(agg, codec).expect("Decoding failed"))
+                    .collect();
+                let merged = Partitioner::merge(&disaggregated).expect("Merge failed");
+                assert_eq!(merged.payload(), &payload[..], "{codec:?} / {extra_chars}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_aggregate_counts_chars() {
+        // 3 bytes per char in UTF-8, so a bytewise count would split into many more messages.
+        let parts: Vec<Message> = (0..8)
+            .map(|i| {
+                let payload = vec![i; 500];
+                Message::from_bytes(
+                    &payload,
+                    MessageDirection::Serverbound,
+                    SessionId::new(i as u32),
+                )
+                .with_codec(Codec::Base32768)
+            })
+            .collect();
+        assert!(parts.iter().all(|p| p.to_string().len() > p.char_count()));
+
+        let aggregated = Aggregator::aggregate(&parts).expect("Aggregation failed");
+        let total_chars: usize = parts.iter().map(Message::char_count).sum();
+        assert_eq!(
+            aggregated.len(),
+            total_chars.div_ceil(DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED)
+        );
+        assert!(aggregated
+            .iter()
+            .all(|agg| agg.chars().count() <= DiscordBot::MAX_MESSAGE_LENGTH_ALLOWED));
+
+        let disaggregated: Vec<Message> = aggregated
+            .iter()
+            .flat_map(|agg| {
+                Aggregator::disaggregate(agg, Codec::Base32768).expect("Decoding failed")
+            })
+            .collect();
+        assert_eq!(disaggregated.len(), parts.len());
+        for (a, b) in parts.iter().zip(&disaggregated) {
+            assert_eq!(a.payload(), b.payload());
+            assert_eq!(a.session, b.session);
+        }
+    }
+
+    #[test]
+    fn test_partition_with_overhead() {
+        for codec in [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768] {
+            for overhead in [1, 28] {
+                let mut payload = vec![0; 500];
+                rand::rng().fill_bytes(&mut payload);
+                let message =
+                    Message::from_bytes(&payload, MessageDirection::Clientbound, SessionId::new(1))
+                        .with_codec(codec);
+
+                // Around the width of the length field changing.
+                for limit in [150, 999, 1000, 1001, 1030] {
+                    let parts =
+                        Partitioner::partition_with_overhead(message.clone(), limit, overhead)
+                            .expect("Partitioning failed");
+                    for part in &parts {
+                        let grown =
+                            part.make_part(vec![0; part.payload().len() + overhead], part.part);
+                        assert!(
+                            grown.char_count() <= limit,
+                            "{codec:?} / {overhead} / {limit}"
+                        );
+                    }
+
+                    let merged = Partitioner::merge(&parts).expect("Merge failed");
+                    assert_eq!(merged.payload(), &payload[..]);
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_disaggregate_invalid_string() {
         // An aggregate string that does not follow the proper format should error.
         let invalid_aggregate = "invalid message without proper length delimiter";
-        let result = Aggregator::disaggregate(invalid_aggregate);
+        let result = Aggregator::disaggregate(invalid_aggregate, Codec::Hex);
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_disaggregate_hostile_input() {
+        let header = MessageDirection::Serverbound.to_string();
+        let invalid = [
+            // The length is smaller than the header. (used to underflow)
+            format!("1~{header}00000001 D 00000001 01/01 "),
+            // The length is bigger than what follows.
+            format!("999~{header}00000001 D 00000001 01/01 AB"),
+            // Too many digits, and not digits.
+            format!("00000000000000000064~{header}00000001 D 00000001 01/01 "),
+            format!("+64~{header}00000001 D 00000001 01/01 "),
+            // Multibyte characters where ASCII is expected.
+            format!("64~{header}0000000\u{e9} D 00000001 01/01 "),
+            format!("64~{header}00000001 D 00000001 0\u{e9}/01 "),
+            format!("6\u{e9}~{header}"),
+            // Too long.
+            "0".repeat(Aggregator::MAX_AGGREGATE_LEN + 1),
+        ];
+        for text in &invalid {
+            for codec in [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768] {
+                assert!(Aggregator::disaggregate(text, codec).is_err(), "{text}");
+            }
+        }
+
+        // Too many messages, even if each is valid.
+        let empty = Message::from_bytes([], MessageDirection::Serverbound, SessionId::new(1));
+        let many = empty
+            .to_string()
+            .repeat(Aggregator::MAX_MESSAGES_PER_AGGREGATE + 1);
+        assert!(Aggregator::disaggregate(&many, Codec::Hex).is_err());
+    }
+
+    #[test]
+    fn test_disaggregate_never_panics() {
+        let mut rng = rand::rng();
+        for codec in [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768] {
+            let mut payload = vec![0; 300];
+            rng.fill_bytes(&mut payload);
+            let message =
+                Message::from_bytes(&payload, MessageDirection::Clientbound, SessionId::new(1))
+                    .with_codec(codec);
+            let parts = Partitioner::partition(message, 150).expect("Partitioning failed");
+            let valid = Aggregator::aggregate(&parts)
+                .expect("Aggregation failed")
+                .remove(0);
+
+            // Random mutations of a valid aggregate: only errors, never panics.
+            for _ in 0..500 {
+                let mut bytes = valid.clone().into_bytes();
+                for _ in 0..rng.random_range(1..4) {
+                    let index = rng.random_range(0..bytes.len());
+                    match rng.random_range(0..3) {
+                        0 => bytes[index] = rng.random(),
+                        1 => bytes.truncate(index),
+                        _ => bytes
+                            .splice(index..index, "\u{e9}9~".bytes())
+                            .for_each(drop),
+                    }
+                    if bytes.is_empty() {
+                        break;
+                    }
+                }
+                let text = String::from_utf8_lossy(&bytes);
+                let _ = Aggregator::disaggregate(&text, codec);
+            }
+        }
+    }
+
     #[test]
     fn test_part_from_string_and_to_string() {
         // Verify that converting a Part to a string and back works correctly.
@@ -747,6 +889,12 @@ mod tests {
             "0110",        // Missing delimiter.
             "01/",         // Missing total.
             "/10",         // Missing current.
+            "+1/10 ",      // Sign.
+            "01/+A ",      // Sign.
+            "01/10X",      // Not delimited.
+            "0\u{e9}/10 ", // Not ASCII.
+            "00/10 ",      // Part 0.
+            "02/01 ",      // Part after the total.
         ];
         for s in invalid_strs {
             assert!(Part::from_string(s).is_err());
@@ -0,0 +1,514 @@
+//! Everything to deliver the messages of a session even when Discord loses some.
+//!
+//! Sending a message to Discord may fail (rate limits, outages, ...). So the sender keeps every
+//! sequenced message in a `RetransmitQueue` until the peer acknowledges it, and sends it again
+//! if the acknowledgment takes too long. The peer acknowledges with cumulative ACK frames: "I
+//! received every message numbered below N".
+//!
+//! The retransmission timeout adapts to the round-trip time measured on the acknowledgments,
+//! the same way as TCP does. (RFC 6298)
+//!
+//! The ACK frames also carry the window of the receiver: how many bytes it can still buffer,
+//! which shrinks while its socket is slow to write. The sender never has more bytes in flight
+//! (see `SendWindow`), so that the queues of a session stay bounded when Discord or the socket is
+//! slower than the Minecraft connection. Bytes rather than messages, because a message may be a
+//! 64 KiB aggregate, many Discord messages long.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use log::debug;
+use tokio::sync::watch;
+
+use crate::message::Message;
+use crate::sequencing::FIRST_SEQ;
+
+/// How many messages the receiver of a session buffers ahead of the next one to deliver.
+///
+/// Kept below the queue size of a session, so that ACKs and retransmissions always fit.
+pub const RECEIVE_WINDOW: u16 = 32;
+
+/// How many bytes the receiver of a session buffers: the messages in flight, and the ones
+/// waiting to be written to its socket. (about 100 Discord messages)
+pub const RECEIVE_BUFFER: u32 = 128 * 1024;
+
+/// Estimates the round-trip time to the peer, and derives the retransmission timeout from it.
+pub struct RttEstimator {
+    /// The smoothed round-trip time. (None until the first sample)
+    srtt: Option<Duration>,
+    /// The round-trip time variation.
+    rttvar: Duration,
+    /// The current retransmission timeout.
+    rto: Duration,
+}
+
+impl RttEstimator {
+    /// Discord messages take a while to go through, so start conservative.
+    pub const INITIAL_RTO: Duration = Duration::from_secs(3);
+    pub const MIN_RTO: Duration = Duration::from_secs(1);
+    pub const MAX_RTO: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: Self::INITIAL_RTO,
+        }
+    }
+
+    /// Returns the current retransmission timeout.
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Returns the smoothed round-trip time, if measured yet.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Updates the estimation with a new round-trip time measure.
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let deviation = srtt.abs_diff(rtt);
+                self.rttvar = self.rttvar * 3 / 4 + deviation / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+
+        let srtt = self.srtt.unwrap_or(rtt);
+        self.rto = (srtt + self.rttvar * 4).clamp(Self::MIN_RTO, Self::MAX_RTO);
+    }
+
+    /// Doubles the retransmission timeout, after a retransmission.
+    pub fn back_off(&mut self) {
+        self.rto = (self.rto * 2).min(Self::MAX_RTO);
+    }
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The retransmissions of a message went unacknowledged for too long.
+#[derive(Debug, PartialEq)]
+pub struct GaveUp {
+    pub seq: u32,
+    pub attempts: u32,
+}
+
+/// A message sent, waiting to be acknowledged.
+struct Unacked {
+    message: Message,
+    sent_at: Instant,
+    /// How many times it was sent.
+    attempts: u32,
+}
+
+/// Keeps the sequenced messages sent until the peer acknowledges them.
+pub struct RetransmitQueue {
+    unacked: BTreeMap<u32, Unacked>,
+    /// The size of the payloads of the unacknowledged messages.
+    bytes: usize,
+    rtt: RttEstimator,
+}
+
+impl RetransmitQueue {
+    /// A message is given up after being sent 8 times.
+    pub const MAX_ATTEMPTS: u32 = 8;
+
+    pub fn new() -> Self {
+        Self {
+            unacked: BTreeMap::new(),
+            bytes: 0,
+            rtt: RttEstimator::new(),
+        }
+    }
+
+    /// Returns the round-trip time estimation.
+    pub fn rtt(&self) -> &RttEstimator {
+        &self.rtt
+    }
+
+    /// Returns the number of messages waiting to be acknowledged.
+    pub fn len(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Returns the size of the payloads of the messages waiting to be acknowledged.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Returns true if every message sent was acknowledged.
+    pub fn is_empty(&self) -> bool {
+        self.unacked.is_empty()
+    }
+
+    /// Keeps a message that was just sent.
+    pub fn on_send(&mut self, message: Message) {
+        self.on_send_at(message, Instant::now());
+    }
+
+    fn on_send_at(&mut self, message: Message, now: Instant) {
+        self.bytes += message.payload().len();
+        let replaced = self.unacked.insert(
+            message.seq,
+            Unacked {
+                message,
+                sent_at: now,
+                attempts: 1,
+            },
+        );
+        if let Some(replaced) = replaced {
+            self.bytes -= replaced.message.payload().len();
+        }
+    }
+
+    /// Forgets the messages numbered below `next_seq`. Returns how many were acknowledged.
+    pub fn on_ack(&mut self, next_seq: u32) -> usize {
+        self.on_ack_at(next_seq, Instant::now())
+    }
+
+    fn on_ack_at(&mut self, next_seq: u32, now: Instant) -> usize {
+        let still_unacked = self.unacked.split_off(&next_seq);
+        let acked = std::mem::replace(&mut self.unacked, still_unacked);
+        self.bytes -= acked
+            .values()
+            .map(|acked| acked.message.payload().len())
+            .sum::<usize>();
+
+        // Only a message sent once tells the round-trip time: the ACK of a retransmitted message
+        // may answer any of its copies. (Karn's algorithm)
+        if let Some((_, newest)) = acked.last_key_value() {
+            if newest.attempts == 1 {
+                self.rtt.sample(now.duration_since(newest.sent_at));
+            }
+        }
+
+        acked.len()
+    }
+
+    /// Returns the oldest unacknowledged message if its timeout expired, to send it again.
+    ///
+    /// Only the oldest message is retransmitted: the peer holds the later ones it received until
+    /// the gap is filled, and then acknowledges them all at once.
+    pub fn take_due(&mut self) -> Result<Option<Message>, GaveUp> {
+        self.take_due_at(Instant::now())
+    }
+
+    fn take_due_at(&mut self, now: Instant) -> Result<Option<Message>, GaveUp> {
+        let rto: Duration = self.rtt.rto();
+        let Some((&seq, unacked)) = self.unacked.iter_mut().next() else {
+            return Ok(None);
+        };
+
+        if now.duration_since(unacked.sent_at) < rto {
+            return Ok(None);
+        }
+        if unacked.attempts >= Self::MAX_ATTEMPTS {
+            return Err(GaveUp {
+                seq,
+                attempts: unacked.attempts,
+            });
+        }
+
+        unacked.sent_at = now;
+        unacked.attempts += 1;
+        debug!(
+            "Retransmitting message #{seq} of session {} (attempt {}, timeout {rto:?})",
+            unacked.message.session, unacked.attempts
+        );
+        let message = unacked.message.clone();
+        self.rtt.back_off();
+
+        Ok(Some(message))
+    }
+}
+
+impl Default for RetransmitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the sender of a session may send, from the last ACK of the peer.
+#[derive(Clone, Copy, Debug)]
+struct Credit {
+    /// The next sequence number expected by the peer.
+    next_seq: u32,
+    /// How many bytes the peer takes in flight.
+    window: u32,
+    /// How many bytes are in flight: sent, and not acknowledged yet.
+    in_flight: usize,
+}
+
+impl Credit {
+    fn allows(&self, seq: u32, bytes: usize) -> bool {
+        let within_window: bool =
+            u64::from(seq) < u64::from(self.next_seq) + u64::from(RECEIVE_WINDOW);
+        // A message bigger than the whole window still goes, alone.
+        let fits: bool = self.in_flight == 0 || self.in_flight + bytes <= self.window as usize;
+        within_window && fits
+    }
+}
+
+/// The credit of the sender of a session: what it may send without overflowing the peer.
+///
+/// Both the number of messages ahead of the last one acknowledged (the reorder buffer of the
+/// peer holds `RECEIVE_WINDOW`), and the bytes in flight (the window of the last ACK) are
+/// limited.
+pub struct SendWindow {
+    credit: watch::Sender<Credit>,
+}
+
+impl SendWindow {
+    /// Until the first ACK, the peer is assumed to have empty buffers.
+    pub fn new() -> Self {
+        Self {
+            credit: watch::Sender::new(Credit {
+                next_seq: FIRST_SEQ,
+                window: RECEIVE_BUFFER,
+                in_flight: 0,
+            }),
+        }
+    }
+
+    /// Returns true if the message `seq`, with a payload of `bytes`, can be sent now.
+    pub fn allows(&self, seq: u32, bytes: usize) -> bool {
+        self.credit.borrow().allows(seq, bytes)
+    }
+
+    /// Updates the credit from an ACK.
+    ///
+    /// An ACK received late (older than the last one) is ignored: it must not give back the
+    /// credit taken by a more recent one.
+    pub fn on_ack(&self, next_seq: u32, window: u32) {
+        self.credit.send_if_modified(|credit| {
+            if next_seq < credit.next_seq {
+                return false;
+            }
+            credit.next_seq = next_seq;
+            credit.window = window;
+            true
+        });
+    }
+
+    /// Updates the bytes in flight, after sending or acknowledging messages.
+    pub fn set_in_flight(&self, bytes: usize) {
+        self.credit.send_if_modified(|credit| {
+            let changed = credit.in_flight != bytes;
+            credit.in_flight = bytes;
+            changed
+        });
+    }
+
+    /// Waits until the message `seq`, with a payload of `bytes`, can be sent.
+    pub async fn wait_for_credit(&self, seq: u32, bytes: usize) {
+        let mut credit = self.credit.subscribe();
+        // The sender lives as long as self, so waiting cannot fail.
+        let _ = credit.wait_for(|credit| credit.allows(seq, bytes)).await;
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageDirection;
+    use crate::session::SessionId;
+
+    fn data(seq: u32) -> Message {
+        Message::from_bytes(
+            [seq as u8],
+            MessageDirection::Serverbound,
+            SessionId::new(1),
+        )
+        .with_seq(seq)
+    }
+
+    #[test]
+    fn test_rtt_first_sample() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.rto(), RttEstimator::INITIAL_RTO);
+
+        rtt.sample(Duration::from_secs(2));
+        assert_eq!(rtt.srtt(), Some(Duration::from_secs(2)));
+        // 2s + 4 * 1s
+        assert_eq!(rtt.rto(), Duration::from_secs(6));
+    }
+
+    #[test]
+    fn test_rtt_converges_and_is_clamped() {
+        let mut rtt = RttEstimator::new();
+        for _ in 0..100 {
+            rtt.sample(Duration::from_millis(100));
+        }
+        assert!(
+            rtt.srtt().unwrap().abs_diff(Duration::from_millis(100)) < Duration::from_millis(5)
+        );
+        assert_eq!(rtt.rto(), RttEstimator::MIN_RTO);
+
+        for _ in 0..10 {
+            rtt.back_off();
+        }
+        assert_eq!(rtt.rto(), RttEstimator::MAX_RTO);
+    }
+
+    #[test]
+    fn test_cumulative_ack() {
+        let mut queue = RetransmitQueue::new();
+        let start = Instant::now();
+        for seq in 1..=5 {
+            queue.on_send_at(data(seq), start);
+        }
+
+        assert_eq!(queue.on_ack_at(4, start + Duration::from_millis(500)), 3);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.rtt().srtt(), Some(Duration::from_millis(500)));
+        // An old ACK acknowledges nothing more.
+        assert_eq!(queue.on_ack_at(2, start + Duration::from_millis(600)), 0);
+        assert_eq!(queue.on_ack_at(6, start + Duration::from_millis(700)), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_retransmits_oldest_after_timeout() {
+        let mut queue = RetransmitQueue::new();
+        let start = Instant::now();
+        queue.on_send_at(data(1), start);
+        queue.on_send_at(data(2), start);
+
+        let rto = queue.rtt().rto();
+        assert_eq!(queue.take_due_at(start + rto / 2), Ok(None));
+
+        let retransmitted = queue.take_due_at(start + rto).unwrap().unwrap();
+        assert_eq!(retransmitted.seq, 1);
+        // The timeout was doubled, and restarted for the retransmitted message.
+        assert_eq!(queue.rtt().rto(), rto * 2);
+        assert_eq!(queue.take_due_at(start + rto + rto), Ok(None));
+
+        // The ACK of a retransmitted message does not tell the round-trip time.
+        queue.on_ack_at(2, start + rto * 2);
+        assert_eq!(queue.rtt().srtt(), None);
+
+        // 2 was sent long ago, it is due right away.
+        assert_eq!(queue.take_due_at(start + rto * 2).unwrap().unwrap().seq, 2);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let mut queue = RetransmitQueue::new();
+        let mut now = Instant::now();
+        queue.on_send_at(data(1), now);
+
+        for _ in 1..RetransmitQueue::MAX_ATTEMPTS {
+            now += RttEstimator::MAX_RTO;
+            assert!(queue.take_due_at(now).unwrap().is_some());
+        }
+
+        now += RttEstimator::MAX_RTO;
+        assert_eq!(
+            queue.take_due_at(now),
+            Err(GaveUp {
+                seq: 1,
+                attempts: RetransmitQueue::MAX_ATTEMPTS
+            })
+        );
+    }
+
+    #[test]
+    fn test_unacked_bytes() {
+        let mut queue = RetransmitQueue::new();
+        for seq in 1..=3 {
+            queue.on_send(data(seq));
+        }
+        assert_eq!(queue.bytes(), 3);
+        queue.on_ack(3);
+        assert_eq!(queue.bytes(), 1);
+        queue.on_ack(4);
+        assert_eq!(queue.bytes(), 0);
+    }
+
+    #[test]
+    fn test_window_counts_messages_and_bytes() {
+        let window = SendWindow::new();
+        let past_window: u32 = FIRST_SEQ + u32::from(RECEIVE_WINDOW);
+        assert!(window.allows(past_window - 1, 1000));
+        assert!(!window.allows(past_window, 1000));
+
+        // Alone, a message goes whatever its size.
+        assert!(window.allows(FIRST_SEQ, RECEIVE_BUFFER as usize * 2));
+        window.set_in_flight(RECEIVE_BUFFER as usize - 100);
+        assert!(window.allows(FIRST_SEQ, 100));
+        assert!(!window.allows(FIRST_SEQ, 101));
+
+        // The receiver is slow to write: its window shrinks.
+        window.on_ack(10, 1000);
+        window.set_in_flight(900);
+        assert!(window.allows(10, 100));
+        assert!(!window.allows(10, 101));
+    }
+
+    #[test]
+    fn test_late_ack_is_ignored() {
+        let window = SendWindow::new();
+        window.set_in_flight(100);
+        window.on_ack(10, 100);
+        assert!(window.allows(41, 0));
+        assert!(!window.allows(10, 1));
+
+        // Late ACK
+        window.on_ack(5, RECEIVE_BUFFER);
+        assert!(window.allows(41, 0));
+        assert!(!window.allows(10, 1));
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_credit() {
+        let window = std::sync::Arc::new(SendWindow::new());
+        let limit: u32 = FIRST_SEQ + u32::from(RECEIVE_WINDOW);
+
+        // Within the window: no wait.
+        window.wait_for_credit(limit - 1, 1).await;
+
+        let waiting = {
+            let window = window.clone();
+            tokio::spawn(async move { window.wait_for_credit(limit, 1).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        window.on_ack(2, RECEIVE_BUFFER);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Out of bytes, until they are acknowledged.
+        window.set_in_flight(RECEIVE_BUFFER as usize);
+        let waiting = {
+            let window = window.clone();
+            tokio::spawn(async move { window.wait_for_credit(2, 1).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        window.set_in_flight(0);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
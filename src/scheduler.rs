@@ -0,0 +1,292 @@
+//! Everything to choose the Discord channel each message is sent to.
+//!
+//! Discord limits how fast a bot sends messages: about 5 every 5 seconds in each channel (its
+//! bucket), and a global limit over all of them. Sending to a channel whose bucket is empty only
+//! waits for it to refill, or gets a 429. So the `Scheduler` remembers the bucket of every
+//! channel, as told after each message sent to it, and gives each message to the channel that
+//! takes it soonest. While nothing is known about the buckets, the channels take turns.
+//!
+//! Each channel sends one message at a time, so a slow channel only holds back its own message.
+//! Channels that keep failing (deleted, missing permissions, ...) are skipped for a cooldown.
+
+use std::time::{Duration, Instant};
+
+use log::warn;
+
+use crate::transport::{Bucket, TransportError};
+
+/// What is known of a channel.
+struct Channel {
+    id: u64,
+    /// The messages the channel still takes before `reset_at`. (None until Discord tells)
+    remaining: Option<u32>,
+    reset_at: Instant,
+    /// How many sends failed in a row.
+    failures: u32,
+    /// The channel is skipped until then, after failing too much.
+    cooldown_until: Option<Instant>,
+    /// When the channel was last picked, in number of sends. (0 if never)
+    last_used: u64,
+    /// Whether a message is being sent to the channel.
+    sending: bool,
+}
+
+/// Chooses the channel each message is sent to, from the rate limits told by Discord.
+pub struct Scheduler {
+    channels: Vec<Channel>,
+    /// No channel takes messages until then, after hitting the global rate limit.
+    global_until: Option<Instant>,
+    /// How many sends were attempted.
+    sends: u64,
+}
+
+impl Scheduler {
+    /// How many sends to a channel fail in a row before it cools down.
+    pub const MAX_FAILURES: u32 = 3;
+    pub const COOLDOWN: Duration = Duration::from_secs(10);
+
+    pub fn new(channel_ids: &[u64]) -> Self {
+        assert!(!channel_ids.is_empty(), "No channel to schedule");
+        let now = Instant::now();
+        Self {
+            channels: channel_ids
+                .iter()
+                .map(|&id| Channel {
+                    id,
+                    remaining: None,
+                    reset_at: now,
+                    failures: 0,
+                    cooldown_until: None,
+                    last_used: 0,
+                    sending: false,
+                })
+                .collect(),
+            global_until: None,
+            sends: 0,
+        }
+    }
+
+    /// Returns the channel that takes a message soonest, and when it does.
+    /// (None if every channel is sending)
+    ///
+    /// Between channels ready at the same time, the least recently used one is picked.
+    pub fn next(&self, now: Instant) -> Option<(u64, Instant)> {
+        self.channels
+            .iter()
+            .filter(|channel| !channel.sending)
+            .map(|channel| (self.ready_at(channel, now), channel))
+            .min_by_key(|(ready_at, channel)| (*ready_at, channel.last_used))
+            .map(|(ready_at, channel)| (channel.id, ready_at))
+    }
+
+    /// Records a message being sent to the channel, until `on_sent` or `on_failed`.
+    pub fn on_start(&mut self, channel_id: u64) {
+        self.sends += 1;
+        let sends = self.sends;
+        let channel = self.channel(channel_id);
+        channel.last_used = sends;
+        channel.sending = true;
+    }
+
+    /// Records a message sent to the channel, with its bucket if Discord told it.
+    pub fn on_sent(&mut self, channel_id: u64, bucket: Option<Bucket>, now: Instant) {
+        let channel = self.channel(channel_id);
+        channel.sending = false;
+        channel.failures = 0;
+        channel.cooldown_until = None;
+        match bucket {
+            Some(bucket) => {
+                channel.remaining = Some(bucket.remaining);
+                channel.reset_at = now + bucket.reset_after;
+            }
+            // Not told, but one less message is left anyway.
+            None => {
+                if let Some(remaining) = channel.remaining.as_mut() {
+                    *remaining = remaining.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Records a message that could not be sent to the channel.
+    ///
+    /// Rate limits only delay the channel (or every channel if global): they do not count as
+    /// failures.
+    pub fn on_failed(&mut self, channel_id: u64, err: &TransportError, now: Instant) {
+        let channel = self.channel(channel_id);
+        channel.sending = false;
+        match *err {
+            TransportError::RateLimited {
+                retry_after,
+                global: true,
+            } => {
+                self.global_until = Some(now + retry_after);
+            }
+            TransportError::RateLimited { retry_after, .. } => {
+                channel.remaining = Some(0);
+                channel.reset_at = now + retry_after;
+            }
+            _ => {
+                channel.failures += 1;
+                if channel.failures >= Self::MAX_FAILURES {
+                    warn!(
+                        "Discord channel {channel_id} failed {} times in a row: skipping it for {:?}",
+                        channel.failures,
+                        Self::COOLDOWN
+                    );
+                    channel.failures = 0;
+                    channel.cooldown_until = Some(now + Self::COOLDOWN);
+                }
+            }
+        }
+    }
+
+    fn ready_at(&self, channel: &Channel, now: Instant) -> Instant {
+        let mut ready_at = now;
+        if let Some(global_until) = self.global_until {
+            ready_at = ready_at.max(global_until);
+        }
+        if channel.remaining == Some(0) {
+            ready_at = ready_at.max(channel.reset_at);
+        }
+        if let Some(cooldown_until) = channel.cooldown_until {
+            ready_at = ready_at.max(cooldown_until);
+        }
+        ready_at
+    }
+
+    fn channel(&mut self, channel_id: u64) -> &mut Channel {
+        self.channels
+            .iter_mut()
+            .find(|channel| channel.id == channel_id)
+            .expect("The channel is scheduled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `count` messages as the scheduler says, Discord telling nothing.
+    fn send(scheduler: &mut Scheduler, count: usize, now: Instant) -> Vec<u64> {
+        (0..count)
+            .map(|_| {
+                let (channel, ready_at) = scheduler.next(now).unwrap();
+                assert_eq!(ready_at, now);
+                scheduler.on_start(channel);
+                scheduler.on_sent(channel, None, now);
+                channel
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin_when_unknown() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(&[1, 2, 3]);
+        assert_eq!(send(&mut scheduler, 7, now), [1, 2, 3, 1, 2, 3, 1]);
+    }
+
+    #[test]
+    fn test_one_send_per_channel() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(&[1, 2]);
+        scheduler.on_start(1);
+        assert_eq!(scheduler.next(now), Some((2, now)));
+        scheduler.on_start(2);
+        assert_eq!(scheduler.next(now), None);
+
+        scheduler.on_sent(2, None, now);
+        assert_eq!(scheduler.next(now), Some((2, now)));
+        scheduler.on_failed(1, &TransportError::Send("Timeout".to_string()), now);
+        assert_eq!(scheduler.next(now), Some((1, now)));
+    }
+
+    #[test]
+    fn test_empty_bucket_is_skipped() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(&[1, 2]);
+        let empty = Bucket {
+            remaining: 0,
+            reset_after: Duration::from_secs(4),
+        };
+        scheduler.on_sent(1, Some(empty), now);
+        assert_eq!(send(&mut scheduler, 3, now), [2, 2, 2]);
+
+        // Every bucket empty: the one refilled first.
+        scheduler.on_sent(
+            2,
+            Some(Bucket {
+                remaining: 0,
+                reset_after: Duration::from_secs(2),
+            }),
+            now,
+        );
+        assert_eq!(scheduler.next(now), Some((2, now + Duration::from_secs(2))));
+
+        // Refilled.
+        let later = now + Duration::from_secs(5);
+        assert_eq!(scheduler.next(later).unwrap().1, later);
+    }
+
+    #[test]
+    fn test_remaining_counts_down() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(&[1]);
+        let bucket = Bucket {
+            remaining: 1,
+            reset_after: Duration::from_secs(3),
+        };
+        scheduler.on_sent(1, Some(bucket), now);
+        assert_eq!(scheduler.next(now), Some((1, now)));
+        scheduler.on_sent(1, None, now);
+        assert_eq!(scheduler.next(now), Some((1, now + Duration::from_secs(3))));
+    }
+
+    #[test]
+    fn test_rate_limited() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new(&[1, 2]);
+        let limited = TransportError::RateLimited {
+            retry_after: Duration::from_secs(1),
+            global: false,
+        };
+        scheduler.on_failed(1, &limited, now);
+        assert_eq!(scheduler.next(now), Some((2, now)));
+
+        let global = TransportError::RateLimited {
+            retry_after: Duration::from_secs(2),
+            global: true,
+        };
+        scheduler.on_failed(2, &global, now);
+        assert_eq!(scheduler.next(now), Some((1, now + Duration::from_secs(2))));
+    }
+
+    #[test]
+    fn test_failing_channel_cools_down() {
+        let now = Instant::now();
+        let failed = TransportError::Send("Missing Access".to_string());
+
+        let mut scheduler = Scheduler::new(&[1]);
+        for _ in 1..Scheduler::MAX_FAILURES {
+            scheduler.on_failed(1, &failed, now);
+        }
+        // A success forgets the failures.
+        scheduler.on_sent(1, None, now);
+        for _ in 1..Scheduler::MAX_FAILURES {
+            scheduler.on_failed(1, &failed, now);
+        }
+        assert_eq!(scheduler.next(now), Some((1, now)));
+        scheduler.on_failed(1, &failed, now);
+        assert_eq!(scheduler.next(now), Some((1, now + Scheduler::COOLDOWN)));
+
+        // Skipped while another channel works.
+        let mut scheduler = Scheduler::new(&[1, 2]);
+        for _ in 0..Scheduler::MAX_FAILURES {
+            scheduler.on_failed(1, &failed, now);
+        }
+        assert_eq!(send(&mut scheduler, 3, now), [2, 2, 2]);
+        let later = now + Scheduler::COOLDOWN;
+        assert_eq!(scheduler.next(later), Some((1, later)));
+    }
+}
@@ -0,0 +1,268 @@
+//! Everything to deliver the messages of a session in the order they were sent.
+//!
+//! Messages are sent to several Discord channels (see `scheduler`), and Discord does not promise
+//! any ordering across channels. So every sequenced message is numbered by its sender (see
+//! `SessionSender`), and the receiver holds the messages that arrive early in a `ReorderBuffer`
+//! until the gap before them is filled.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::message::Message;
+use crate::reliability::RECEIVE_WINDOW;
+
+/// The first sequence number of a session, in each direction.
+/// (0 means the message is not sequenced)
+pub const FIRST_SEQ: u32 = 1;
+
+/// A hole in the sequence of received messages.
+#[derive(Debug, PartialEq)]
+pub struct Gap {
+    /// The sequence numbers that are missing.
+    pub missing: Range<u32>,
+    /// For how long the gap has been open.
+    pub open_for: Duration,
+}
+
+/// Holds the messages received out of order until they can be delivered in order.
+pub struct ReorderBuffer {
+    /// The sequence number of the next message to deliver.
+    next_seq: u32,
+    /// The messages received ahead of `next_seq`.
+    pending: BTreeMap<u32, Message>,
+    /// Since when we are waiting for `next_seq` while later messages are pending.
+    gap_since: Option<Instant>,
+    /// Whether the current gap has already been reported.
+    gap_reported: bool,
+    /// How long a gap may stay open before being reported.
+    gap_timeout: Duration,
+}
+
+impl ReorderBuffer {
+    /// Gaps are reported after 10 seconds.
+    pub const GAP_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new(gap_timeout: Duration) -> Self {
+        Self {
+            next_seq: FIRST_SEQ,
+            pending: BTreeMap::new(),
+            gap_since: None,
+            gap_reported: false,
+            gap_timeout,
+        }
+    }
+
+    /// Returns the sequence number of the next message to deliver.
+    /// Every message numbered below was delivered.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+
+    /// Takes a received message and returns the messages that can now be delivered, in order.
+    ///
+    /// Messages that are not sequenced are delivered right away.
+    /// Duplicates and messages that were already delivered are dropped, and so are the messages
+    /// past the window: a sender respecting it never sends them, and they would pile up.
+    pub fn push(&mut self, message: Message) -> Vec<Message> {
+        self.push_at(message, Instant::now())
+    }
+
+    fn push_at(&mut self, message: Message, now: Instant) -> Vec<Message> {
+        if !message.kind.is_sequenced() {
+            return vec![message];
+        }
+
+        let seq: u32 = message.seq;
+        if seq < self.next_seq {
+            debug!(
+                "Dropped already delivered message #{seq} of session {}",
+                message.session
+            );
+            return Vec::new();
+        }
+        if u64::from(seq) >= u64::from(self.next_seq) + u64::from(RECEIVE_WINDOW) {
+            warn!(
+                "Dropped message #{seq} of session {}: past the window (next is #{})",
+                message.session, self.next_seq
+            );
+            return Vec::new();
+        }
+        if self.pending.contains_key(&seq) {
+            debug!(
+                "Dropped duplicate message #{seq} of session {}",
+                message.session
+            );
+            return Vec::new();
+        }
+
+        self.pending.insert(seq, message);
+
+        // Deliver everything that is now contiguous.
+        let mut ready: Vec<Message> = Vec::new();
+        while let Some(message) = self.pending.remove(&self.next_seq) {
+            ready.push(message);
+            self.next_seq += 1;
+        }
+
+        if self.pending.is_empty() {
+            self.gap_since = None;
+        } else if !ready.is_empty() || self.gap_since.is_none() {
+            // A new gap opens (or the previous one moved forward).
+            self.gap_since = Some(now);
+            self.gap_reported = false;
+        }
+
+        ready
+    }
+
+    /// Returns the current gap if it has been open for longer than the timeout.
+    ///
+    /// A gap is only returned once, so that it is reported once.
+    pub fn take_overdue_gap(&mut self) -> Option<Gap> {
+        self.take_overdue_gap_at(Instant::now())
+    }
+
+    fn take_overdue_gap_at(&mut self, now: Instant) -> Option<Gap> {
+        let open_for = now.duration_since(self.gap_since?);
+        if self.gap_reported || open_for < self.gap_timeout {
+            return None;
+        }
+
+        self.gap_reported = true;
+        let first_pending: u32 = *self.pending.keys().next()?;
+        Some(Gap {
+            missing: self.next_seq..first_pending,
+            open_for,
+        })
+    }
+}
+
+impl Default for ReorderBuffer {
+    fn default() -> Self {
+        Self::new(Self::GAP_TIMEOUT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{Control, MessageDirection};
+    use crate::session::SessionId;
+
+    fn data(seq: u32) -> Message {
+        Message::from_bytes(
+            [seq as u8],
+            MessageDirection::Clientbound,
+            SessionId::new(1),
+        )
+        .with_seq(seq)
+    }
+
+    fn seqs(messages: &[Message]) -> Vec<u32> {
+        messages.iter().map(|m| m.seq).collect()
+    }
+
+    #[test]
+    fn test_in_order_is_delivered_right_away() {
+        let mut buffer = ReorderBuffer::default();
+        for seq in 1..=5 {
+            assert_eq!(seqs(&buffer.push(data(seq))), vec![seq]);
+        }
+        assert_eq!(buffer.next_seq(), 6);
+    }
+
+    #[test]
+    fn test_out_of_order_is_held_until_gap_filled() {
+        let mut buffer = ReorderBuffer::default();
+        assert!(buffer.push(data(3)).is_empty());
+        assert!(buffer.push(data(2)).is_empty());
+        assert_eq!(seqs(&buffer.push(data(1))), vec![1, 2, 3]);
+        assert!(buffer.push(data(5)).is_empty());
+        assert_eq!(seqs(&buffer.push(data(4))), vec![4, 5]);
+    }
+
+    #[test]
+    fn test_duplicates_are_dropped() {
+        let mut buffer = ReorderBuffer::default();
+        assert_eq!(seqs(&buffer.push(data(1))), vec![1]);
+        assert!(buffer.push(data(1)).is_empty());
+        assert!(buffer.push(data(3)).is_empty());
+        assert!(buffer.push(data(3)).is_empty());
+        assert_eq!(seqs(&buffer.push(data(2))), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_past_the_window_is_dropped() {
+        let mut buffer = ReorderBuffer::default();
+        let last: u32 = FIRST_SEQ + u32::from(RECEIVE_WINDOW) - 1;
+        assert!(buffer.push(data(last)).is_empty());
+        assert!(buffer.push(data(last + 1)).is_empty());
+        assert!(buffer.push(data(u32::MAX)).is_empty());
+        assert_eq!(buffer.pending.len(), 1);
+
+        // The window slides with the delivered messages.
+        for seq in FIRST_SEQ..last - 1 {
+            assert_eq!(seqs(&buffer.push(data(seq))), vec![seq]);
+        }
+        assert_eq!(seqs(&buffer.push(data(last - 1))), vec![last - 1, last]);
+        assert!(buffer.push(data(last + 2)).is_empty());
+        assert_eq!(buffer.pending.len(), 1);
+    }
+
+    #[test]
+    fn test_unsequenced_bypass_the_buffer() {
+        let mut buffer = ReorderBuffer::default();
+        assert!(buffer.push(data(2)).is_empty());
+
+        let ping = Message::from_control(
+            &Control::Ping(7),
+            MessageDirection::Clientbound,
+            SessionId::new(1),
+        );
+        assert_eq!(buffer.push(ping.clone()), vec![ping]);
+    }
+
+    #[test]
+    fn test_gap_reported_once_after_timeout() {
+        let timeout = Duration::from_secs(10);
+        let mut buffer = ReorderBuffer::new(timeout);
+        let start = Instant::now();
+
+        assert!(buffer.push_at(data(3), start).is_empty());
+        assert_eq!(buffer.take_overdue_gap_at(start + timeout / 2), None);
+
+        let gap = buffer.take_overdue_gap_at(start + timeout).unwrap();
+        assert_eq!(gap.missing, 1..3);
+        assert_eq!(buffer.take_overdue_gap_at(start + timeout * 2), None);
+
+        // Filling the gap closes it.
+        assert_eq!(seqs(&buffer.push_at(data(1), start + timeout * 2)), vec![1]);
+        assert_eq!(
+            seqs(&buffer.push_at(data(2), start + timeout * 2)),
+            vec![2, 3]
+        );
+        assert_eq!(buffer.take_overdue_gap_at(start + timeout * 10), None);
+    }
+
+    #[test]
+    fn test_gap_timer_restarts_when_it_moves_forward() {
+        let timeout = Duration::from_secs(10);
+        let mut buffer = ReorderBuffer::new(timeout);
+        let start = Instant::now();
+
+        assert!(buffer.push_at(data(2), start).is_empty());
+        assert!(buffer.push_at(data(4), start).is_empty());
+
+        // 1 arrives late: 1 and 2 are delivered, 3 is now missing.
+        let later = start + timeout;
+        assert_eq!(seqs(&buffer.push_at(data(1), later)), vec![1, 2]);
+        assert_eq!(buffer.take_overdue_gap_at(later + timeout / 2), None);
+        assert_eq!(
+            buffer.take_overdue_gap_at(later + timeout).unwrap().missing,
+            3..4
+        );
+    }
+}
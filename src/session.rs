@@ -0,0 +1,428 @@
+//! Everything to multiplex many TCP connections over the same pair of Discord bots.
+//!
+//! Each TCP connection accepted on the client side is given a `SessionId`. That ID travels in
+//! the header of every `Message` so that both sides can route the frames to the right socket.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use log::{debug, warn};
+use tokio::sync::mpsc;
+
+use crate::compression::Compression;
+use crate::message::{Control, Message, MessageDirection, MessageError};
+use crate::reliability::{GaveUp, RetransmitQueue, SendWindow};
+use crate::sequencing::FIRST_SEQ;
+
+/// Identifies one tunneled TCP connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(u32);
+
+impl SessionId {
+    /// Not a session: carries what concerns a whole forward. (e.g. the status of the Minecraft
+    /// server)
+    pub const FORWARD: SessionId = SessionId(0);
+
+    /// Constructs a `SessionId` from its raw value.
+    pub fn new(id: u32) -> Self {
+        Self(id)
+    }
+
+    /// Returns a random `SessionId`.
+    ///
+    /// Random so that a restarted client side does not reuse the IDs of its previous run.
+    pub fn random() -> Self {
+        match Self::new(rand::random()) {
+            Self::FORWARD => Self(1),
+            id => id,
+        }
+    }
+
+    /// Returns the `SessionId` following this one. (`FORWARD` is skipped)
+    pub fn next(self) -> Self {
+        match Self(self.0.wrapping_add(1)) {
+            Self::FORWARD => Self(1),
+            next => next,
+        }
+    }
+
+    /// Decodes a session string into a `SessionId`.
+    /// The first section of the string must be the session in 8 hex digits followed by a space.
+    ///
+    /// Example:
+    /// "0000BEEF " -> SessionId(0xBEEF)
+    pub fn from_string<T: AsRef<str>>(text: T) -> Result<Self, MessageError> {
+        let text: &str = text.as_ref();
+
+        let expected_len = Self::get_standard_string_length();
+        let text = text
+            .get(..expected_len)
+            .ok_or(MessageError::Session("session string too small"))?;
+
+        if !text.ends_with(' ') {
+            return Err(MessageError::Session("session string is not delimited"));
+        }
+
+        u32::from_str_radix(text.trim_end(), 16)
+            .map(Self)
+            .map_err(|_| MessageError::Session("failed to parse the session as a hex number"))
+    }
+
+    /// Returns the length of the encoded (to String) `SessionId`.
+    pub const fn get_standard_string_length() -> usize {
+        // 8 hex digits and a space
+        9
+    }
+}
+
+impl fmt::Display for SessionId {
+    /// Encodes the session into 8 hex digits followed by a space.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08X} ", self.0)
+    }
+}
+
+/// Sends the messages of a single session to Discord.
+///
+/// Every sequenced message is numbered with the next sequence number of the session, so that
+/// the peer can put them back in order. It is also kept until the peer acknowledges it, so
+/// that it can be sent again if it got lost.
+///
+/// The data must wait for the credit given by the peer before being sent. (see `SendWindow`)
+#[derive(Clone)]
+pub struct SessionSender {
+    tx: mpsc::Sender<Message>,
+    direction: MessageDirection,
+    session: SessionId,
+    compression: Compression,
+    next_seq: Arc<AtomicU32>,
+    unacked: Arc<Mutex<RetransmitQueue>>,
+    window: Arc<SendWindow>,
+}
+
+impl SessionSender {
+    pub fn new(tx: mpsc::Sender<Message>, direction: MessageDirection, session: SessionId) -> Self {
+        Self {
+            tx,
+            direction,
+            session,
+            compression: Compression::None,
+            next_seq: Arc::new(AtomicU32::new(FIRST_SEQ)),
+            unacked: Arc::new(Mutex::new(RetransmitQueue::new())),
+            window: Arc::new(SendWindow::new()),
+        }
+    }
+
+    /// Returns the same SessionSender, compressing the data of the session with the compression.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Returns the compression of the data of the session.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns the session the messages are sent for.
+    pub fn session(&self) -> SessionId {
+        self.session
+    }
+
+    /// Returns the direction of the messages sent.
+    pub fn direction(&self) -> MessageDirection {
+        self.direction
+    }
+
+    /// Numbers the message if it is sequenced and sends it.
+    pub async fn send(&self, message: Message) -> Result<(), mpsc::error::SendError<Message>> {
+        let message = if message.kind.is_sequenced() {
+            let seq: u32 = self.next_seq.fetch_add(1, Ordering::Relaxed);
+            let message = message.with_seq(seq);
+            let mut unacked = self.unacked();
+            unacked.on_send(message.clone());
+            self.window.set_in_flight(unacked.bytes());
+            message
+        } else {
+            message
+        };
+
+        self.tx.send(message).await
+    }
+
+    /// Waits until the peer can buffer the next sequenced message.
+    pub async fn wait_for_credit(&self, message: &Message) {
+        let seq: u32 = self.next_seq.load(Ordering::Relaxed);
+        let bytes: usize = message.payload().len();
+        if !self.window.allows(seq, bytes) {
+            debug!(
+                "Session {} is out of credit (#{seq}, {bytes}B). Waiting for the peer.",
+                self.session
+            );
+        }
+        self.window.wait_for_credit(seq, bytes).await;
+    }
+
+    /// Acknowledges every message numbered below `next_seq`, and updates the credit.
+    pub fn on_ack(&self, next_seq: u32, window: u32) {
+        self.window.on_ack(next_seq, window);
+
+        let mut unacked = self.unacked();
+        let acked: usize = unacked.on_ack(next_seq);
+        self.window.set_in_flight(unacked.bytes());
+        if acked > 0 {
+            debug!(
+                "Peer acknowledged {acked} messages of session {} ({} left, rtt {:?}, rto {:?})",
+                self.session,
+                unacked.len(),
+                unacked.rtt().srtt(),
+                unacked.rtt().rto()
+            );
+        }
+    }
+
+    /// Returns true if the peer acknowledged every message sent.
+    pub fn is_all_acked(&self) -> bool {
+        self.unacked().is_empty()
+    }
+
+    /// Sends again the messages whose acknowledgment is overdue.
+    pub async fn retransmit_due(&self) -> Result<(), GaveUp> {
+        loop {
+            // The lock must not be held while awaiting.
+            let Some(message) = self.unacked().take_due()? else {
+                return Ok(());
+            };
+            if let Err(err) = self.tx.send(message).await {
+                warn!(
+                    "Failed to retransmit a message of session {}: {err}",
+                    self.session
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    /// Tells the peer that every message numbered below `next_seq` was received, and how many
+    /// bytes it may have in flight.
+    pub async fn send_ack(
+        &self,
+        next_seq: u32,
+        window: u32,
+    ) -> Result<(), mpsc::error::SendError<Message>> {
+        self.send_control(&Control::Ack { next_seq, window }).await
+    }
+
+    fn unacked(&self) -> std::sync::MutexGuard<'_, RetransmitQueue> {
+        // The queue stays consistent even if a holder panicked.
+        self.unacked
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sends a control frame of the session.
+    pub async fn send_control(
+        &self,
+        control: &Control,
+    ) -> Result<(), mpsc::error::SendError<Message>> {
+        self.send(Message::from_control(control, self.direction, self.session))
+            .await
+    }
+}
+
+/// Dispatches the messages received from Discord to the session they belong to.
+#[derive(Default)]
+pub struct SessionRouter {
+    sessions: DashMap<SessionId, mpsc::Sender<Message>>,
+    /// The sessions that were closed recently, with when they were closed.
+    /// Their late messages must not be mistaken for new sessions.
+    closed: DashMap<SessionId, Instant>,
+}
+
+impl SessionRouter {
+    /// Number of messages that can be queued for a single session.
+    const SESSION_QUEUE_SIZE: usize = 64;
+
+    /// Closed sessions are remembered for 5 minutes.
+    const CLOSED_SESSION_MEMORY: Duration = Duration::from_secs(5 * 60);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new session and returns the receiving end of its messages.
+    pub fn register(&self, session: SessionId) -> mpsc::Receiver<Message> {
+        let (tx, rx) = mpsc::channel::<Message>(Self::SESSION_QUEUE_SIZE);
+        if self.sessions.insert(session, tx).is_some() {
+            warn!("Session {session} was already registered. Replaced it.");
+        }
+        debug!("Registered session {session}");
+        rx
+    }
+
+    /// Forgets about a session. Its messages will not be dispatched anymore.
+    pub fn unregister(&self, session: SessionId) {
+        if self.sessions.remove(&session).is_some() {
+            debug!("Unregistered session {session}");
+        }
+
+        let now = Instant::now();
+        self.closed
+            .retain(|_, closed_at| now.duration_since(*closed_at) < Self::CLOSED_SESSION_MEMORY);
+        self.closed.insert(session, now);
+    }
+
+    /// Returns true if the session was closed recently.
+    pub fn is_closed(&self, session: SessionId) -> bool {
+        self.closed.contains_key(&session)
+    }
+
+    /// Sends the message to the session it belongs to.
+    ///
+    /// Gives the message back if its session is unknown or closed.
+    ///
+    /// The message is dropped if the queue of the session is full, rather than blocking the
+    /// other sessions: the peer retransmits it. (A session respecting the window never fills it)
+    pub async fn dispatch(&self, message: Message) -> Result<(), Message> {
+        let tx = match self.sessions.get(&message.session) {
+            Some(tx) => tx.clone(),
+            None => return Err(message),
+        };
+
+        match tx.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                warn!(
+                    "Queue of session {} is full. Dropped message #{}.",
+                    message.session, message.seq
+                );
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(message)) => Err(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::MessageDirection;
+
+    #[test]
+    fn test_session_to_string_and_from_string() {
+        let session = SessionId::new(0xBEEF);
+        let session_str = session.to_string();
+        assert_eq!(session_str, "0000BEEF ");
+        assert_eq!(session_str.len(), SessionId::get_standard_string_length());
+        assert_eq!(SessionId::from_string(&session_str).unwrap(), session);
+    }
+
+    #[test]
+    fn test_session_from_string_invalid() {
+        let invalid_strs = vec![
+            "BEEF ",     // Too small.
+            "0000BEEF_", // Missing delimiter.
+            "0000BEEG ", // Not hex.
+        ];
+        for s in invalid_strs {
+            assert!(SessionId::from_string(s).is_err());
+        }
+    }
+
+    #[test]
+    fn test_session_next_wraps() {
+        // 0 is `SessionId::FORWARD`, never a session.
+        assert_eq!(SessionId::new(u32::MAX).next(), SessionId::new(1));
+    }
+
+    #[tokio::test]
+    async fn test_router_dispatches_to_the_right_session() {
+        let router = SessionRouter::new();
+        let mut rx_a = router.register(SessionId::new(1));
+        let mut rx_b = router.register(SessionId::new(2));
+
+        let msg_a = Message::from_bytes(b"a", MessageDirection::Serverbound, SessionId::new(1));
+        let msg_b = Message::from_bytes(b"b", MessageDirection::Serverbound, SessionId::new(2));
+        router.dispatch(msg_b.clone()).await.unwrap();
+        router.dispatch(msg_a.clone()).await.unwrap();
+
+        assert_eq!(rx_a.recv().await.unwrap(), msg_a);
+        assert_eq!(rx_b.recv().await.unwrap(), msg_b);
+    }
+
+    #[tokio::test]
+    async fn test_router_gives_back_unknown_session() {
+        let router = SessionRouter::new();
+        let msg = Message::from_bytes(b"a", MessageDirection::Serverbound, SessionId::new(3));
+        assert!(router.dispatch(msg).await.is_err());
+
+        let _rx = router.register(SessionId::new(3));
+        let msg = Message::from_bytes(b"a", MessageDirection::Serverbound, SessionId::new(3));
+        assert!(router.dispatch(msg.clone()).await.is_ok());
+
+        router.unregister(SessionId::new(3));
+        assert!(router.dispatch(msg).await.is_err());
+        assert!(router.is_closed(SessionId::new(3)));
+        assert!(!router.is_closed(SessionId::new(4)));
+    }
+
+    #[tokio::test]
+    async fn test_router_drops_when_session_queue_is_full() {
+        let router = SessionRouter::new();
+        let mut rx = router.register(SessionId::new(6));
+        let msg = Message::from_bytes(b"a", MessageDirection::Serverbound, SessionId::new(6));
+
+        for _ in 0..SessionRouter::SESSION_QUEUE_SIZE + 1 {
+            // Never blocks, even once full.
+            router.dispatch(msg.clone()).await.unwrap();
+        }
+
+        let mut received: usize = 0;
+        while rx.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, SessionRouter::SESSION_QUEUE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn test_sender_numbers_sequenced_messages() {
+        let (tx, mut rx) = mpsc::channel::<Message>(8);
+        let sender = SessionSender::new(tx, MessageDirection::Serverbound, SessionId::new(5));
+
+        let data = |bytes: &[u8]| {
+            Message::from_bytes(bytes, MessageDirection::Serverbound, SessionId::new(5))
+        };
+        sender.send_control(&Control::Open).await.unwrap();
+        sender.send(data(b"data")).await.unwrap();
+        sender.send_control(&Control::Ping(1)).await.unwrap();
+        sender.send(data(b"more data")).await.unwrap();
+
+        let seqs: Vec<u32> = [
+            rx.recv().await.unwrap(),
+            rx.recv().await.unwrap(),
+            rx.recv().await.unwrap(),
+            rx.recv().await.unwrap(),
+        ]
+        .iter()
+        .map(|m| m.seq)
+        .collect();
+        assert_eq!(seqs, vec![1, 2, 0, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_sender_keeps_sequenced_messages_until_acked() {
+        let (tx, _rx) = mpsc::channel::<Message>(8);
+        let sender = SessionSender::new(tx, MessageDirection::Serverbound, SessionId::new(5));
+
+        sender.send_control(&Control::Open).await.unwrap();
+        sender.send_control(&Control::Ping(1)).await.unwrap();
+        sender.send_ack(3, 1024).await.unwrap();
+        assert!(!sender.is_all_acked());
+
+        sender.on_ack(2, 1024);
+        assert!(sender.is_all_acked());
+    }
+}
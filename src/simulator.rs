@@ -0,0 +1,764 @@
+//! Everything to reproduce the misbehaviors of Discord, to test the tunnel against them.
+//!
+//! Discord does not deliver the frames like a socket does. They take a variable time to go
+//! through, with the occasional hiccup. A frame posted in one channel may overtake the frames
+//! posted earlier in the others. Some sends are rejected as rate limited (429), and some frames
+//! are lost, or delivered twice (e.g. a retried send that went through the first time).
+//!
+//! `Simulator` wraps a transport and inflicts all of this on the frames sent through it, as
+//! configured by `Conditions`. The faults are drawn from a seeded RNG: the same frames sent in
+//! the same order meet the same faults, so a failing run can be replayed.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::debug;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::transport::{Bucket, Frame, Transport, TransportError};
+
+/// How long the frames take to go through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Anything between `min` and `max`, evenly.
+    Uniform {
+        min: Duration,
+        max: Duration,
+    },
+    /// Like `Uniform`, but `spike_rate` of the frames take `spike` more.
+    Spiky {
+        min: Duration,
+        max: Duration,
+        spike: Duration,
+        spike_rate: f64,
+    },
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut StdRng) -> Duration {
+        match *self {
+            Latency::Fixed(latency) => latency,
+            Latency::Uniform { min, max } => uniform(rng, min, max),
+            Latency::Spiky {
+                min,
+                max,
+                spike,
+                spike_rate,
+            } => {
+                let latency = uniform(rng, min, max);
+                if rng.random_bool(spike_rate) {
+                    latency + spike
+                } else {
+                    latency
+                }
+            }
+        }
+    }
+}
+
+fn uniform(rng: &mut StdRng, min: Duration, max: Duration) -> Duration {
+    if max <= min {
+        return min;
+    }
+    rng.random_range(min..=max)
+}
+
+/// What the frames sent through a `Simulator` go through.
+///
+/// The rates are probabilities, between 0 and 1. The default is a perfect transport.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    pub latency: Latency,
+    /// How often a frame is lost. (the send still succeeds)
+    pub drop_rate: f64,
+    /// How often a frame is delivered twice.
+    pub duplicate_rate: f64,
+    /// How many of the frames still in flight a frame may overtake.
+    pub reorder_window: usize,
+    /// How often a send is rejected as rate limited. (nothing is delivered)
+    pub rate_limit_rate: f64,
+    /// How long the rate limited sends are told to wait.
+    pub retry_after: Duration,
+    /// How many of the first frames are lost, whatever the seed. (e.g. the HELLOs sent before the
+    /// peer is up)
+    pub drop_first: u64,
+    pub seed: u64,
+}
+
+impl Default for Conditions {
+    fn default() -> Self {
+        Self {
+            latency: Latency::Fixed(Duration::ZERO),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_window: 0,
+            rate_limit_rate: 0.0,
+            retry_after: Duration::from_secs(1),
+            drop_first: 0,
+            seed: 0,
+        }
+    }
+}
+
+/// The faults inflicted so far.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The frames given to `send`.
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    /// The frames that overtook at least one other.
+    pub reordered: u64,
+    pub rate_limited: u64,
+}
+
+/// What happens to a frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Fate {
+    RateLimited,
+    Dropped,
+    Delivered {
+        copies: usize,
+        latency: Duration,
+        /// How many frames in flight it overtakes, at most.
+        overtakes: usize,
+    },
+}
+
+/// Draws the fate of each frame.
+struct Faults {
+    conditions: Conditions,
+    rng: StdRng,
+    /// The fates drawn so far.
+    drawn: u64,
+}
+
+impl Faults {
+    fn new(conditions: Conditions) -> Self {
+        Self {
+            conditions,
+            rng: StdRng::seed_from_u64(conditions.seed),
+            drawn: 0,
+        }
+    }
+
+    fn next_fate(&mut self) -> Fate {
+        let conditions: &Conditions = &self.conditions;
+        // Everything is drawn every time, so that a fault does not shift the next ones.
+        let rate_limited: bool = self.rng.random_bool(conditions.rate_limit_rate);
+        let dropped: bool = self.rng.random_bool(conditions.drop_rate);
+        let duplicated: bool = self.rng.random_bool(conditions.duplicate_rate);
+        let latency: Duration = conditions.latency.sample(&mut self.rng);
+        let overtakes: usize = self.rng.random_range(0..=conditions.reorder_window);
+        self.drawn += 1;
+
+        if self.drawn <= conditions.drop_first {
+            Fate::Dropped
+        } else if rate_limited {
+            Fate::RateLimited
+        } else if dropped {
+            Fate::Dropped
+        } else {
+            Fate::Delivered {
+                copies: if duplicated { 2 } else { 1 },
+                latency,
+                overtakes,
+            }
+        }
+    }
+}
+
+/// A frame on its way to the inner transport.
+struct InFlight {
+    deliver_at: Instant,
+    channel: u64,
+    content: String,
+}
+
+/// The frames in flight, in the order they will be delivered.
+#[derive(Default)]
+struct Queue {
+    frames: Mutex<VecDeque<InFlight>>,
+    /// Wakes the delivery up when a frame is queued.
+    queued: Notify,
+}
+
+/// A transport going through the `Conditions`, in the direction of the peer.
+///
+/// The frames are delivered to the inner transport in order, each once its latency has passed,
+/// unless they overtook some others. What the peer sends is received as is: wrap both ends to
+/// have faults in both directions.
+pub struct Simulator<T: Transport> {
+    inner: Arc<T>,
+    conditions: Conditions,
+    state: Mutex<(Faults, Stats)>,
+    queue: Arc<Queue>,
+    delivery: JoinHandle<()>,
+}
+
+impl<T: Transport> Simulator<T> {
+    pub fn new(inner: T, conditions: Conditions) -> Self {
+        let inner = Arc::new(inner);
+        let queue = Arc::new(Queue::default());
+        let delivery = tokio::spawn(deliver(Arc::clone(&inner), Arc::clone(&queue)));
+        Self {
+            inner,
+            conditions,
+            state: Mutex::new((Faults::new(conditions), Stats::default())),
+            queue,
+            delivery,
+        }
+    }
+
+    /// Returns the faults inflicted so far.
+    pub fn stats(&self) -> Stats {
+        self.state.lock().unwrap().1
+    }
+}
+
+impl<T: Transport> Drop for Simulator<T> {
+    fn drop(&mut self) {
+        // The frames still in flight are lost.
+        self.delivery.abort();
+    }
+}
+
+impl<T: Transport> Transport for Simulator<T> {
+    async fn send(&self, channel: u64, content: String) -> Result<Option<Bucket>, TransportError> {
+        let mut state = self.state.lock().unwrap();
+        let (faults, stats) = &mut *state;
+        stats.sent += 1;
+
+        let (copies, latency, overtakes) = match faults.next_fate() {
+            Fate::RateLimited => {
+                stats.rate_limited += 1;
+                return Err(TransportError::RateLimited {
+                    retry_after: self.conditions.retry_after,
+                    global: false,
+                });
+            }
+            Fate::Dropped => {
+                stats.dropped += 1;
+                return Ok(None);
+            }
+            Fate::Delivered {
+                copies,
+                latency,
+                overtakes,
+            } => (copies, latency, overtakes),
+        };
+
+        let deliver_at: Instant = Instant::now() + latency;
+        let mut frames = self.queue.frames.lock().unwrap();
+        let index: usize = frames.len().saturating_sub(overtakes);
+        if index < frames.len() {
+            stats.reordered += 1;
+        }
+        if copies > 1 {
+            stats.duplicated += 1;
+        }
+        for _ in 0..copies {
+            frames.insert(
+                index,
+                InFlight {
+                    deliver_at,
+                    channel,
+                    content: content.clone(),
+                },
+            );
+        }
+        self.queue.queued.notify_one();
+        Ok(None)
+    }
+
+    async fn receive(&self) -> Option<Frame> {
+        self.inner.receive().await
+    }
+}
+
+/// Delivers the frames in flight to the inner transport, until aborted.
+async fn deliver<T: Transport>(inner: Arc<T>, queue: Arc<Queue>) {
+    loop {
+        // The lock must not be held while awaiting.
+        let next: Option<Instant> = queue
+            .frames
+            .lock()
+            .unwrap()
+            .front()
+            .map(|frame| frame.deliver_at);
+
+        match next {
+            None => queue.queued.notified().await,
+            Some(deliver_at) if deliver_at > Instant::now() => {
+                // A frame may be queued in front meanwhile.
+                tokio::select! {
+                    _ = tokio::time::sleep_until(deliver_at) => {}
+                    _ = queue.queued.notified() => {}
+                }
+            }
+            Some(_) => {
+                let Some(frame) = queue.frames.lock().unwrap().pop_front() else {
+                    continue;
+                };
+                if let Err(err) = inner.send(frame.channel, frame.content).await {
+                    debug!("Simulator failed to deliver a frame: {err}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::broadcast;
+
+    use crate::codec::Codec;
+    use crate::compression::Compression;
+    use crate::crypto::{FrameCipher, FrameMode};
+    use crate::forward::{Forward, Framing, Protocol, Target};
+    use crate::minecraft::{write_string, Handshake, Packet, State};
+    use crate::transport::Loopback;
+    use crate::tunnel;
+    use futures::future::join_all;
+
+    /// Receives the next frame, or None if none comes soon.
+    async fn next_frame<T: Transport>(transport: &T) -> Option<Frame> {
+        tokio::time::timeout(Duration::from_millis(200), transport.receive())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    #[test]
+    fn test_same_seed_same_faults() {
+        let conditions = Conditions {
+            latency: Latency::Spiky {
+                min: Duration::from_millis(10),
+                max: Duration::from_millis(50),
+                spike: Duration::from_secs(1),
+                spike_rate: 0.1,
+            },
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            reorder_window: 3,
+            rate_limit_rate: 0.1,
+            seed: 7,
+            ..Conditions::default()
+        };
+        let fates = |conditions: Conditions| {
+            let mut faults = Faults::new(conditions);
+            (0..200).map(|_| faults.next_fate()).collect::<Vec<Fate>>()
+        };
+
+        let first = fates(conditions);
+        assert_eq!(first, fates(conditions));
+        assert_ne!(
+            first,
+            fates(Conditions {
+                seed: 8,
+                ..conditions
+            })
+        );
+        assert!(first.contains(&Fate::Dropped));
+        assert!(first.contains(&Fate::RateLimited));
+    }
+
+    #[tokio::test]
+    async fn test_perfect_conditions() {
+        let (a, b) = Loopback::pair();
+        let a = Simulator::new(a, Conditions::default());
+        for i in 0..10 {
+            a.send(i, i.to_string()).await.unwrap();
+        }
+        for i in 0..10 {
+            assert_eq!(next_frame(&b).await.unwrap().channel, i);
+        }
+        assert_eq!(next_frame(&b).await, None);
+
+        // The frames of the peer are untouched.
+        b.send(1, "back".to_string()).await.unwrap();
+        assert_eq!(next_frame(&a).await.unwrap().content, "back");
+        assert_eq!(
+            a.stats(),
+            Stats {
+                sent: 10,
+                ..Stats::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_faults() {
+        let (a, b) = Loopback::pair();
+        let dropping = Simulator::new(
+            a,
+            Conditions {
+                drop_rate: 1.0,
+                ..Conditions::default()
+            },
+        );
+        dropping.send(1, "lost".to_string()).await.unwrap();
+        assert_eq!(next_frame(&b).await, None);
+        assert_eq!(dropping.stats().dropped, 1);
+
+        let (a, b) = Loopback::pair();
+        let limited = Simulator::new(
+            a,
+            Conditions {
+                rate_limit_rate: 1.0,
+                retry_after: Duration::from_secs(5),
+                ..Conditions::default()
+            },
+        );
+        assert!(matches!(
+            limited.send(1, "429".to_string()).await,
+            Err(TransportError::RateLimited { retry_after, .. }) if retry_after == Duration::from_secs(5)
+        ));
+        assert_eq!(next_frame(&b).await, None);
+
+        let (a, b) = Loopback::pair();
+        let duplicating = Simulator::new(
+            a,
+            Conditions {
+                duplicate_rate: 1.0,
+                latency: Latency::Fixed(Duration::from_millis(20)),
+                ..Conditions::default()
+            },
+        );
+        duplicating.send(1, "twice".to_string()).await.unwrap();
+        duplicating.send(2, "too".to_string()).await.unwrap();
+        let contents: Vec<String> = [
+            next_frame(&b).await,
+            next_frame(&b).await,
+            next_frame(&b).await,
+            next_frame(&b).await,
+        ]
+        .into_iter()
+        .map(|frame| frame.unwrap().content)
+        .collect();
+        assert_eq!(contents, ["twice", "twice", "too", "too"]);
+        assert_eq!(next_frame(&b).await, None);
+
+        let (a, b) = Loopback::pair();
+        let late = Simulator::new(
+            a,
+            Conditions {
+                drop_first: 2,
+                ..Conditions::default()
+            },
+        );
+        for content in ["lost", "lost too", "first"] {
+            late.send(1, content.to_string()).await.unwrap();
+        }
+        assert_eq!(next_frame(&b).await.unwrap().content, "first");
+        assert_eq!(next_frame(&b).await, None);
+        assert_eq!(late.stats().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_reorder_window() {
+        let (a, b) = Loopback::pair();
+        let reordering = Simulator::new(
+            a,
+            Conditions {
+                // Long enough for every frame to be in flight at once.
+                latency: Latency::Fixed(Duration::from_millis(50)),
+                reorder_window: 2,
+                seed: 3,
+                ..Conditions::default()
+            },
+        );
+        for i in 0..20 {
+            reordering.send(1, i.to_string()).await.unwrap();
+        }
+
+        let mut received: Vec<usize> = Vec::new();
+        while let Some(frame) = next_frame(&b).await {
+            received.push(frame.content.parse().unwrap());
+        }
+        assert_ne!(received, (0..20).collect::<Vec<usize>>());
+        assert!(reordering.stats().reordered > 0);
+        // Nothing is lost.
+        let mut sorted = received.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..20).collect::<Vec<usize>>());
+    }
+
+    /// Returns an address nothing listens on yet.
+    async fn free_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// What a client sends to join: the handshake, then Login Start.
+    fn joining() -> Vec<u8> {
+        let mut data: Vec<u8> = Handshake {
+            protocol: 767,
+            address: "localhost".to_string(),
+            port: 25565,
+            next_state: State::Login,
+        }
+        .to_packet()
+        .to_frame();
+        let mut login_start: Vec<u8> = Vec::new();
+        write_string("Steve", &mut login_start);
+        login_start.extend_from_slice(&[0; 16]);
+        data.extend(Packet::new(0x00, login_start).to_frame());
+        data
+    }
+
+    /// What the server sends a joining player: the chunks around them, about 2 KB each.
+    fn chunks(seed: u64) -> Vec<u8> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut data: Vec<u8> = Vec::new();
+        for _ in 0..16 {
+            // Few distinct bytes: chunks compress well.
+            let body: Vec<u8> = (0..rng.random_range(1500..2500))
+                .map(|_| rng.random_range(0..16))
+                .collect();
+            data.extend(Packet::new(0x27, body).to_frame());
+        }
+        data
+    }
+
+    /// Accepts a single player: checks what it sends, then sends it the chunks.
+    async fn minecraft_server(seed: u64) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut joined = vec![0; joining().len()];
+            socket.read_exact(&mut joined).await.unwrap();
+            assert_eq!(joined, joining());
+            socket.write_all(&chunks(seed)).await.unwrap();
+            // Keeps the connection open until the player leaves.
+            let _ = socket.read(&mut [0; 1]).await;
+        });
+        addr
+    }
+
+    fn forward(listen: SocketAddr, target: Option<SocketAddr>) -> Forward {
+        Forward {
+            name: "minecraft".to_string(),
+            protocol: Protocol::Tcp,
+            framing: Framing::Raw,
+            listen: vec![listen],
+            target: target.map(|addr| Target {
+                host: addr.ip().to_string(),
+                port: Some(addr.port()),
+            }),
+            channels: vec![1, 2, 3],
+        }
+    }
+
+    /// How both sides write their frames.
+    #[derive(Clone, Copy, Debug, Default)]
+    struct Writing {
+        codec: Codec,
+        compression: Compression,
+        frame_mode: Option<FrameMode>,
+    }
+
+    impl Writing {
+        /// Every combination of codec, compression and frame mode.
+        fn grid() -> Vec<Writing> {
+            let mut grid: Vec<Writing> = Vec::new();
+            for codec in [Codec::Hex, Codec::Base64, Codec::Base85, Codec::Base32768] {
+                for compression in [Compression::None, Compression::Deflate] {
+                    for frame_mode in [None, Some(FrameMode::Encrypt), Some(FrameMode::Sign)] {
+                        grid.push(Writing {
+                            codec,
+                            compression,
+                            frame_mode,
+                        });
+                    }
+                }
+            }
+            grid
+        }
+
+        /// The settings of one side. (each side has its own run)
+        fn settings(self) -> tunnel::Settings {
+            tunnel::Settings {
+                codec: self.codec,
+                compression: self.compression,
+                cipher: self
+                    .frame_mode
+                    .map(|mode| Arc::new(FrameCipher::new("hunter2", mode))),
+            }
+        }
+    }
+
+    /// Runs a transfer for each way of writing the frames, all at once.
+    async fn transfer_grid(conditions: Conditions) -> Vec<(Stats, Stats)> {
+        join_all(
+            Writing::grid()
+                .into_iter()
+                .map(|writing| transfer(conditions, writing)),
+        )
+        .await
+    }
+
+    /// Joins a Minecraft server through a tunnel going through the conditions in both
+    /// directions, and checks that every byte of the chunks arrives.
+    ///
+    /// Returns the faults inflicted to the serverbound frames, then to the clientbound ones.
+    async fn transfer(conditions: Conditions, writing: Writing) -> (Stats, Stats) {
+        let target = minecraft_server(conditions.seed).await;
+        let listen = free_addr().await;
+        let (client_end, server_end) = Loopback::pair();
+        let client_end = Arc::new(Simulator::new(client_end, conditions));
+        let server_end = Arc::new(Simulator::new(
+            server_end,
+            Conditions {
+                seed: conditions.seed + 1,
+                ..conditions
+            },
+        ));
+        let (stop_tx, _) = broadcast::channel::<()>(16);
+
+        tokio::spawn(tunnel::run(
+            Arc::clone(&server_end),
+            vec![forward(listen, Some(target))],
+            true,
+            stop_tx.clone(),
+            writing.settings(),
+        ));
+        tokio::spawn(tunnel::run(
+            Arc::clone(&client_end),
+            vec![forward(listen, None)],
+            false,
+            stop_tx,
+            writing.settings(),
+        ));
+
+        // Waits for the client side to listen.
+        let mut socket = loop {
+            match TcpStream::connect(listen).await {
+                Ok(socket) => break socket,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        socket.write_all(&joining()).await.unwrap();
+        let expected: Vec<u8> = chunks(conditions.seed);
+        let mut received = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(60), socket.read_exact(&mut received))
+            .await
+            .unwrap_or_else(|_| {
+                panic!(
+                    "the chunks did not arrive under {conditions:?} ({writing:?}): {:?} then {:?}",
+                    client_end.stats(),
+                    server_end.stats()
+                )
+            })
+            .unwrap();
+        let corrupted: Option<usize> = (0..expected.len()).find(|&i| received[i] != expected[i]);
+        assert_eq!(
+            corrupted, None,
+            "corrupted under {conditions:?} ({writing:?})"
+        );
+        (client_end.stats(), server_end.stats())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_latency_and_reordering() {
+        let stats = transfer_grid(Conditions {
+            latency: Latency::Spiky {
+                min: Duration::from_millis(5),
+                max: Duration::from_millis(40),
+                spike: Duration::from_millis(300),
+                spike_rate: 0.05,
+            },
+            reorder_window: 4,
+            seed: 1,
+            ..Conditions::default()
+        })
+        .await;
+        assert!(stats
+            .iter()
+            .all(|(_, clientbound)| clientbound.reordered > 0));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_losses() {
+        let stats = transfer_grid(Conditions {
+            latency: Latency::Uniform {
+                min: Duration::from_millis(5),
+                max: Duration::from_millis(20),
+            },
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            rate_limit_rate: 0.05,
+            seed: 2,
+            ..Conditions::default()
+        })
+        .await;
+        let faults = |stats: Stats| stats.dropped + stats.duplicated + stats.rate_limited;
+        assert!(stats
+            .iter()
+            .any(|&(serverbound, clientbound)| faults(serverbound) + faults(clientbound) > 0));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_under_everything() {
+        let stats = transfer_grid(Conditions {
+            latency: Latency::Spiky {
+                min: Duration::from_millis(5),
+                max: Duration::from_millis(30),
+                spike: Duration::from_millis(200),
+                spike_rate: 0.05,
+            },
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            reorder_window: 3,
+            rate_limit_rate: 0.05,
+            seed: 3,
+            ..Conditions::default()
+        })
+        .await;
+        assert!(stats
+            .iter()
+            .any(|(_, clientbound)| clientbound.dropped + clientbound.rate_limited > 0));
+    }
+
+    #[tokio::test]
+    async fn test_sealed_transfer_with_lost_hellos() {
+        // The first HELLO of each side is lost: nothing can be sealed for the peer until the next
+        // ones, and the sessions only go through once retransmitted.
+        let conditions = |seed: u64| Conditions {
+            latency: Latency::Uniform {
+                min: Duration::from_millis(5),
+                max: Duration::from_millis(20),
+            },
+            duplicate_rate: 0.05,
+            reorder_window: 3,
+            drop_first: 1,
+            seed,
+            ..Conditions::default()
+        };
+        let sealed = |frame_mode: FrameMode| Writing {
+            frame_mode: Some(frame_mode),
+            ..Writing::default()
+        };
+        let ((encrypted_serverbound, encrypted_clientbound), (signed_serverbound, _)) = tokio::join!(
+            transfer(conditions(4), sealed(FrameMode::Encrypt)),
+            transfer(conditions(6), sealed(FrameMode::Sign)),
+        );
+        assert_eq!(encrypted_serverbound.dropped, 1);
+        assert_eq!(encrypted_clientbound.dropped, 1);
+        assert!(encrypted_clientbound.duplicated + signed_serverbound.duplicated > 0);
+    }
+}
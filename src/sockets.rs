@@ -1,64 +1,261 @@
-use std::sync::Arc;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use crate::compression;
+use crate::config::Timeouts;
+use crate::message::{Control, MessageDirection};
+use crate::minecraft::Tracker;
+use crate::reliability::RECEIVE_BUFFER;
+use crate::sequencing::ReorderBuffer;
+use crate::session::SessionSender;
 use crate::{message, partitioning};
-use log::{debug, error, warn};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::sync::Mutex;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::{broadcast, mpsc};
 
-/// Sends a Discord halt message if a stop signal is received.
-async fn stop_signal_listener(
-    stop_tx: broadcast::Sender<()>,
-    tx: mpsc::Sender<message::Message>,
-    message_direction: message::MessageDirection,
-) {
+/// Why a session was stopped.
+#[derive(Clone, Debug)]
+pub enum StopReason {
+    /// We stopped the session. The control frame (CLOSE or ERROR) must be sent to the peer.
+    Local(Control),
+    /// The peer stopped the session with this control frame. Nothing has to be sent back.
+    Peer(Control),
+}
+
+impl StopReason {
+    /// Stop reason for a session closed on our side without any error.
+    pub fn close<T: Into<String>>(reason: T) -> Self {
+        StopReason::Local(Control::Close {
+            reason: reason.into(),
+        })
+    }
+
+    /// Stop reason for a session that failed on our side.
+    pub fn error<T: Into<String>>(code: u16, text: T) -> Self {
+        StopReason::Local(Control::Error {
+            code,
+            text: text.into(),
+        })
+    }
+
+    /// What the player is told when the session stops, if it failed.
+    pub fn disconnect_reason(&self) -> Option<String> {
+        let (StopReason::Local(Control::Error { code, text })
+        | StopReason::Peer(Control::Error { code, text })) = self
+        else {
+            return None;
+        };
+        let title: &str = match *code {
+            Control::ERROR_UNREACHABLE => "Tunnel server unreachable",
+            Control::ERROR_SOCKET => "Tunnel connection failed",
+            Control::ERROR_DISCORD => "Discord side of the tunnel failed",
+            Control::ERROR_DECODE => "Tunnel data corrupted",
+            _ => "Tunnel failed",
+        };
+        Some(format!("{title}\n\n{text}"))
+    }
+}
+
+/// Sends the control frame closing the session to Discord if we stopped it.
+fn stop_signal_listener(
+    stop_tx: broadcast::Sender<StopReason>,
+    tx: SessionSender,
+) -> tokio::task::JoinHandle<()> {
     let mut stop_rx = stop_tx.subscribe();
+    let session = tx.session();
     tokio::spawn(async move {
-        loop {
-            if let Err(err) = stop_rx.recv().await {
-                warn!("Failed to receive from stop signal tx: {err}");
-            } else {
-                let halt_message = message::Message::make_halt_message(message_direction);
-                if let Err(err) = tx.send(halt_message).await {
-                    warn!("Failed to send halt message to tx: {err}");
+        match stop_rx.recv().await {
+            Ok(StopReason::Local(control)) => {
+                debug!("Session {session} stopped: {control:?}. Telling the peer.");
+                if let Err(err) = tx.send_control(&control).await {
+                    warn!("Failed to send {:?} frame to tx: {err}", control.kind());
                 }
             }
+            Ok(StopReason::Peer(control)) => {
+                debug!("Session {session} stopped by the peer: {control:?}");
+            }
+            Err(RecvError::Lagged(_)) => {
+                warn!("Missed the stop reason of session {session}. Closing it anyway.");
+                let control = Control::Close {
+                    reason: String::from("session stopped"),
+                };
+                let _ = tx.send_control(&control).await;
+            }
+            Err(RecvError::Closed) => {
+                debug!("Stop signal channel closed for session {session}");
+            }
+        }
+    })
+}
+
+/// Where the data received from the peer is written. (a TCP socket, or a UDP peer)
+pub trait PayloadWriter: Send + 'static {
+    /// Writes the payload of a data message.
+    fn write_payload(&mut self, payload: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Tells the other end why the session stopped, before the socket is closed. (nothing by
+    /// default)
+    fn write_stop(&mut self, _reason: &StopReason) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+impl PayloadWriter for OwnedWriteHalf {
+    async fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        self.write_all(payload).await
+    }
+}
+
+/// Writes to a socket the other direction of a Minecraft connection followed by a `Tracker`.
+///
+/// The clones write to the same socket: the task reading the socket writes the packets made up by
+/// the tracker with one. (e.g. the answers to the Keep Alives)
+pub struct TrackedWriter<W> {
+    inner: Arc<tokio::sync::Mutex<W>>,
+    tracker: Arc<Mutex<Tracker>>,
+    /// The direction of what is written. (the opposite of what is read from the socket)
+    direction: MessageDirection,
+}
+
+impl<W> Clone for TrackedWriter<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            tracker: Arc::clone(&self.tracker),
+            direction: self.direction,
+        }
+    }
+}
+
+impl<W: PayloadWriter> TrackedWriter<W> {
+    pub fn new(inner: W, tracker: Arc<Mutex<Tracker>>, direction: MessageDirection) -> Self {
+        Self {
+            inner: Arc::new(tokio::sync::Mutex::new(inner)),
+            tracker,
+            direction,
+        }
+    }
+
+    pub fn tracker(&self) -> &Mutex<Tracker> {
+        &self.tracker
+    }
+
+    /// Writes the packets made up by the tracker, if the socket is between two packets.
+    pub async fn write_local(&self) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        self.write_local_to(&mut inner).await
+    }
+
+    async fn write_local_to(&self, inner: &mut W) -> io::Result<()> {
+        let local: Vec<u8> = self
+            .tracker
+            .lock()
+            .map(|mut tracker| tracker.take_local(self.direction))
+            .unwrap_or_default();
+        if local.is_empty() {
+            return Ok(());
+        }
+        inner.write_payload(&local).await
+    }
+}
+
+impl<W: PayloadWriter> PayloadWriter for TrackedWriter<W> {
+    async fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut inner = self.inner.lock().await;
+        // The tracker must know about it before the peer can answer.
+        if let Ok(mut tracker) = self.tracker.lock() {
+            tracker.observe_stream(self.direction, payload);
+        }
+        inner.write_payload(payload).await?;
+        self.write_local_to(&mut inner).await
+    }
+
+    /// Shows the reason of a failure to the player, with a disconnect screen.
+    async fn write_stop(&mut self, reason: &StopReason) -> io::Result<()> {
+        if self.direction != MessageDirection::Clientbound {
+            return Ok(());
+        }
+        let Some(reason) = reason.disconnect_reason() else {
+            return Ok(());
+        };
+
+        let mut inner = self.inner.lock().await;
+        let disconnect: Option<Vec<u8>> = self
+            .tracker
+            .lock()
+            .ok()
+            .and_then(|tracker| tracker.disconnect(&reason));
+        match disconnect {
+            Some(disconnect) => {
+                info!("Disconnecting the player: {reason}");
+                inner.write_payload(&disconnect).await
+            }
+            None => Ok(()),
         }
-    });
+    }
 }
 
-/// Received TCP packets from a OwnedReadHalf socket and then sends them through a Sender channel.
-pub async fn handle_receive_socket(
-    socket: OwnedReadHalf,
-    tx: mpsc::Sender<message::Message>,
-    stop_tx: broadcast::Sender<()>,
-    messages_direction: message::MessageDirection,
+/// Received TCP packets from a socket (e.g. a OwnedReadHalf) and then sends them through a Sender
+/// channel.
+///
+/// The messages are numbered by the `SessionSender`, so that the peer can put them back in order.
+///
+/// With the `TrackedWriter` of the socket, the messages only carry whole Minecraft packets, as long
+/// as the packets can be read. The Keep Alives are proxied too: the packets made up by the tracker
+/// are written back to the socket.
+pub async fn handle_receive_socket<R: AsyncRead + Unpin>(
+    socket: R,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
+    tracked: Option<TrackedWriter<OwnedWriteHalf>>,
+) {
+    let pump = handle_receive_socket_offload(socket, tx.clone(), stop_tx.clone(), tracked);
+    handle_receive(pump, tx, stop_tx).await;
+}
+
+/// Runs the task sending the data of a session to the peer, until it ends or the session stops.
+///
+/// Sends the CLOSE or ERROR frame to the peer if we stop the session.
+pub async fn handle_receive<F: Future<Output = ()>>(
+    pump: F,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
 ) {
     let mut stop_rx = stop_tx.subscribe();
 
-    // Sends a Discord halt message if stop signal received.
-    stop_signal_listener(stop_tx.clone(), tx.clone(), messages_direction).await;
+    // Sends a CLOSE or ERROR frame if we stop the session.
+    let stop_listener = stop_signal_listener(stop_tx, tx);
 
     tokio::select! {
-        _ = handle_receive_socket_offload(socket, tx, stop_tx, messages_direction) => { debug!("Socket receiving handling task finished.") }
+        _ = pump => { debug!("Socket receiving handling task finished.") }
         _ = stop_rx.recv() => {
             debug!("Stop signal received. Terminating handler.");
-            return;
-            //info!("SENT DISCORD HALT MESSAGE");
         }
     }
+
+    // The CLOSE or ERROR frame is sent before the session is considered stopped.
+    let _ = stop_listener.await;
 }
 
 use tokio::time::interval;
 
-async fn handle_receive_socket_offload(
-    mut socket: OwnedReadHalf,
-    tx: mpsc::Sender<message::Message>,
-    stop_tx: broadcast::Sender<()>,
-    messages_direction: message::MessageDirection,
+/// The buffered reads are flushed right away once they reach 64 KiB, so that a flushed message
+/// never needs more parts than allowed.
+const MAX_BUFFERED_BYTES: usize = 64 * 1024;
+
+async fn handle_receive_socket_offload<R: AsyncRead + Unpin>(
+    mut socket: R,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
+    tracked: Option<TrackedWriter<OwnedWriteHalf>>,
 ) {
+    let session = tx.session();
+    let tracker: Option<&Mutex<Tracker>> = tracked.as_ref().map(TrackedWriter::tracker);
     let mut buffer = Vec::with_capacity(8192);
     let mut buffer_aggregate = Vec::with_capacity(100);
 
@@ -71,96 +268,425 @@ async fn handle_receive_socket_offload(
                 match result {
                     Ok(0) => {
                         warn!("Socket closed by the peer.");
-                        let _ = stop_tx.send(());
-                        debug!("Socket error, broadcast stop signal.");
+                        // The data read before the socket was closed must reach the peer
+                        // before the CLOSE frame, even an incomplete packet.
+                        if let Some(tracker) = tracker {
+                            let pending = take_pending(tracker, tx.direction());
+                            if !pending.is_empty() {
+                                buffer_aggregate.push(message::Message::from_bytes(&pending, tx.direction(), session));
+                            }
+                        }
+                        if flush_aggregate(&mut buffer_aggregate, &tx).await.is_err() {
+                            error!("Failed sending the last messages of session {session} through channel");
+                        }
+                        let _ = stop_tx.send(StopReason::close("socket closed by the peer"));
+                        debug!("Socket closed, broadcast stop signal.");
                         return;
                     }
                     Ok(read) => {
-                        debug!("Received TCP packet from MINECRAFT [{read}B]");
-                        let message = message::Message::from_bytes(&buffer, messages_direction);
-                        buffer_aggregate.push(message.clone());
+                        debug!("Received TCP packet from MINECRAFT [{read}B] (session {session})");
+                        let ready: Vec<u8> = match tracker {
+                            Some(tracker) => frame_packets(tracker, tx.direction(), &buffer),
+                            None => buffer.clone(),
+                        };
+                        if let Err(e) = write_local(tracked.as_ref()).await {
+                            error!("Failed writing to the TCP socket: {e}");
+                            let _ = stop_tx.send(StopReason::error(Control::ERROR_SOCKET, e.to_string()));
+                            return;
+                        }
+                        if !ready.is_empty() {
+                            let message = message::Message::from_bytes(&ready, tx.direction(), session);
+                            buffer_aggregate.push(message);
+                        }
                         buffer.clear();
+
+                        let buffered: usize = buffer_aggregate.iter().map(|m| m.payload().len()).sum();
+                        if buffered >= MAX_BUFFERED_BYTES {
+                            if let Err(e) = flush_aggregate(&mut buffer_aggregate, &tx).await {
+                                error!("Failed sending message through channel: {e}");
+                                let _ = stop_tx.send(StopReason::error(Control::ERROR_DISCORD, e.to_string()));
+                                return;
+                            }
+                        }
                     }
                     Err(e) => {
                         error!("Failed reading the TCP socket: {e}");
-                        let _ = stop_tx.send(());
+                        let _ = stop_tx.send(StopReason::error(Control::ERROR_SOCKET, e.to_string()));
                         debug!("Socket error, broadcast stop signal.");
                         return;
                     }
                 }
             }
-            // 500ms tick event
+            // 100ms tick event
             _ = tick.tick() => {
-                if !buffer_aggregate.is_empty() {
-                    for msg_str in partitioning::Aggregator::aggregate(&buffer_aggregate)
-                        .expect("Error in aggregation")
-                    {
-                        for msg in message::Message::from_string(msg_str)
-                            .expect("Error in message from string")
-                        {
-                            if let Err(e) = tx.send(msg).await {
-                                error!("Failed sending message through channel: {e}");
-                                let _ = stop_tx.send(());
-                                debug!("mpsc channel error, broadcast stop signal");
-                                return;
-                            } else {
-                                debug!("Sent TCP packet message through the mpsc channel");
-                            }
-                        }
+                // The side of the player sends it Keep Alives.
+                if let (Some(tracker), MessageDirection::Serverbound) = (tracker, tx.direction()) {
+                    if let Ok(mut tracker) = tracker.lock() {
+                        tracker.poll_keep_alive(Instant::now());
                     }
-                    buffer_aggregate.clear();
+                }
+                if let Err(e) = write_local(tracked.as_ref()).await {
+                    error!("Failed writing to the TCP socket: {e}");
+                    let _ = stop_tx.send(StopReason::error(Control::ERROR_SOCKET, e.to_string()));
+                    return;
+                }
+                if let Err(e) = flush_aggregate(&mut buffer_aggregate, &tx).await {
+                    error!("Failed sending message through channel: {e}");
+                    let _ = stop_tx.send(StopReason::error(Control::ERROR_DISCORD, e.to_string()));
+                    debug!("mpsc channel error, broadcast stop signal");
+                    return;
                 }
             }
         }
     }
 }
 
-/// Receives messages from a Receiver channel and then sends them through a OwnedWriteHalf TCP socket.
-pub async fn handle_channel_to_socket(
-    socket: OwnedWriteHalf,
-    rx: Arc<Mutex<mpsc::Receiver<message::Message>>>,
-    stop_tx: broadcast::Sender<()>,
+/// Feeds the data read from the socket to the tracker, and returns the whole packets.
+///
+/// A packet too big for a message is sent in pieces, as if it was not framed.
+fn frame_packets(tracker: &Mutex<Tracker>, direction: MessageDirection, data: &[u8]) -> Vec<u8> {
+    let Ok(mut tracker) = tracker.lock() else {
+        return data.to_vec();
+    };
+    let mut ready: Vec<u8> = tracker.feed(direction, data);
+    if tracker.pending(direction) >= MAX_BUFFERED_BYTES {
+        ready.extend_from_slice(&tracker.take_pending(direction));
+    }
+    ready
+}
+
+/// Writes the packets made up by the tracker back to the socket.
+async fn write_local(tracked: Option<&TrackedWriter<OwnedWriteHalf>>) -> io::Result<()> {
+    match tracked {
+        Some(tracked) => tracked.write_local().await,
+        None => Ok(()),
+    }
+}
+
+/// Takes the start of the incomplete packet left in the tracker.
+fn take_pending(tracker: &Mutex<Tracker>, direction: MessageDirection) -> Vec<u8> {
+    tracker
+        .lock()
+        .map(|mut tracker| tracker.take_pending(direction))
+        .unwrap_or_default()
+}
+
+/// Merges the buffered reads into a single message and sends it through the Sender channel.
+///
+/// The message gets a single sequence number: if it is too big for Discord, it is partitioned
+/// (and later reassembled) as a whole.
+///
+/// Waits for the peer to give credit first. Meanwhile, the socket is not read: the backpressure
+/// goes up to the Minecraft client/server through TCP.
+async fn flush_aggregate(
+    buffer_aggregate: &mut Vec<message::Message>,
+    tx: &SessionSender,
+) -> Result<(), mpsc::error::SendError<message::Message>> {
+    if buffer_aggregate.is_empty() {
+        return Ok(());
+    }
+
+    match partitioning::Partitioner::merge(&buffer_aggregate[..]) {
+        Ok(message) => {
+            let message = tx.compression().compress(message);
+            tx.wait_for_credit(&message).await;
+            tx.send(message).await?;
+            debug!("Sent TCP packet message through the mpsc channel");
+        }
+        Err(err) => error!("Failed merging the buffered reads: {err}"),
+    }
+    buffer_aggregate.clear();
+
+    Ok(())
+}
+
+/// Receives messages from a Receiver channel and then writes them to the socket. (e.g. a
+/// OwnedWriteHalf TCP socket)
+///
+/// The receiver only yields the messages of the session the socket belongs to.
+/// They are put back in the order they were sent before being written to the socket.
+///
+/// The `SessionSender` of the session is used to acknowledge the received messages, and to send
+/// again the messages the peer did not acknowledge.
+pub async fn handle_channel_to_socket<W: PayloadWriter>(
+    mut socket: W,
+    rx: &mut mpsc::Receiver<message::Message>,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
 ) {
     let mut stop_rx = stop_tx.subscribe();
+    let mut reason_rx = stop_tx.subscribe();
 
     tokio::select! {
-        _ = handle_channel_to_socket_offload(socket, rx, stop_tx) => { debug!("task finished: handle_channel_to_socket") }
+        _ = handle_channel_to_socket_offload(&mut socket, rx, tx, stop_tx) => { debug!("task finished: handle_channel_to_socket") }
         _ = stop_rx.recv() => { debug!("Stop signal received. Terminating handler.") }
     }
+
+    // The first reason is the one the session stopped for.
+    if let Ok(reason) = reason_rx.try_recv() {
+        if let Err(err) = socket.write_stop(&reason).await {
+            debug!("Failed to tell the socket why the session stopped: {err}");
+        }
+    }
 }
 
-async fn handle_channel_to_socket_offload(
-    mut socket: OwnedWriteHalf,
-    rx: Arc<Mutex<mpsc::Receiver<message::Message>>>,
-    stop_tx: broadcast::Sender<()>,
+/// How often acknowledgments are sent and retransmissions are checked.
+const RELIABILITY_TICK: Duration = Duration::from_millis(200);
+
+async fn handle_channel_to_socket_offload<W: PayloadWriter>(
+    socket: &mut W,
+    rx: &mut mpsc::Receiver<message::Message>,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
 ) {
     debug!("Inside handle_channel_to_socket_offload");
 
+    // The messages put back in order wait in a queue to be written to the socket, so that a slow
+    // socket shrinks the window advertised to the peer instead of holding up the ACKs.
+    let (delivered_tx, delivered_rx) = mpsc::unbounded_channel::<message::Message>();
+    let queued = AtomicUsize::new(0);
+
+    let reason: StopReason = tokio::select! {
+        reason = receive_in_order(rx, &tx, delivered_tx, &queued) => reason,
+        reason = write_delivered(socket, delivered_rx, &tx, &queued) => reason,
+    };
+    let _ = stop_tx.send(reason);
+}
+
+/// Puts the received messages back in order and queues them to be written, acknowledging them
+/// and retransmitting ours meanwhile.
+///
+/// Returns why the session must stop.
+async fn receive_in_order(
+    rx: &mut mpsc::Receiver<message::Message>,
+    tx: &SessionSender,
+    delivered_tx: mpsc::UnboundedSender<message::Message>,
+    queued: &AtomicUsize,
+) -> StopReason {
+    let session = tx.session();
+    let mut reorder_buffer = ReorderBuffer::default();
+    // Acknowledgments are delayed until the next tick, so that one ACK covers many messages.
+    let mut ack_pending = false;
+    let mut advertised: u32 = RECEIVE_BUFFER;
+    let mut tick = interval(RELIABILITY_TICK);
+
     loop {
-        let packet = {
-            debug!("Getting the mutex guard");
-            let mut rx_guard = rx.lock().await;
-            debug!("Acquired the mutex guard");
-            rx_guard.recv().await
-        };
+        tokio::select! {
+            // Once the write queue is full, the messages wait in the queue of the session.
+            received = rx.recv(), if window(queued) > 0 => {
+                let Some(packet) = received else {
+                    error!("Failed receiving message, channel closed, got None");
+                    debug!("Error receiving message from closed channel (None). Broacast stop signal");
+                    return StopReason::close("session channel closed");
+                };
+
+                if let Ok(Control::Ack { next_seq, window }) = Control::try_from(&packet) {
+                    tx.on_ack(next_seq, window);
+                    continue;
+                }
+                // Duplicates are acknowledged too: the previous ACK may have been lost.
+                ack_pending |= packet.kind.is_sequenced();
+
+                for packet in reorder_buffer.push(packet) {
+                    queued.fetch_add(packet.payload().len(), Ordering::Relaxed);
+                    // The writer only stops along with this loop.
+                    let _ = delivered_tx.send(packet);
+                }
+            }
+            _ = tick.tick() => {
+                // The window is advertised again once the socket caught up, even with nothing
+                // to acknowledge: the peer may be waiting for it.
+                let window: u32 = window(queued);
+                if ack_pending || window >= advertised.saturating_add(RECEIVE_BUFFER / 4) {
+                    ack_pending = false;
+                    advertised = window;
+                    if let Err(err) = tx.send_ack(reorder_buffer.next_seq(), window).await {
+                        warn!("Failed to send ACK frame of session {session}: {err}");
+                    }
+                }
 
-        match packet {
-            Some(packet) => {
-                if let Err(e) = socket.write_all(packet.payload()).await {
-                    error!("Failed to send message to socket: {e}");
-                    stop_tx.send(()).unwrap();
-                    debug!("Failed sending message to socket. Broadcast stop signal");
+                if let Err(gave_up) = tx.retransmit_due().await {
+                    error!("Session {session}: message #{} was never acknowledged ({} attempts)", gave_up.seq, gave_up.attempts);
+                    return StopReason::error(Control::ERROR_DISCORD, "the peer stopped acknowledging messages");
+                }
+
+                // Reports the messages that never arrived.
+                if let Some(gap) = reorder_buffer.take_overdue_gap() {
+                    warn!(
+                        "Messages #{}..#{} of session {session} are still missing after {:?}. Waiting for them.",
+                        gap.missing.start, gap.missing.end, gap.open_for
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Writes the messages put back in order to the socket, or handles their control frames.
+///
+/// Returns why the session must stop.
+async fn write_delivered<W: PayloadWriter>(
+    socket: &mut W,
+    mut delivered_rx: mpsc::UnboundedReceiver<message::Message>,
+    tx: &SessionSender,
+    queued: &AtomicUsize,
+) -> StopReason {
+    while let Some(packet) = delivered_rx.recv().await {
+        let seq: u32 = packet.seq;
+        let bytes: usize = packet.payload().len();
+        let result = handle_packet(socket, packet).await;
+        queued.fetch_sub(bytes, Ordering::Relaxed);
+
+        if let Err(reason) = result {
+            if matches!(reason, StopReason::Peer(_)) {
+                // The peer waits for its CLOSE or ERROR frame to be acknowledged.
+                let _ = tx.send_ack(seq + 1, window(queued)).await;
+            }
+            return reason;
+        }
+    }
+    StopReason::close("session channel closed")
+}
+
+/// Returns the window advertised to the peer: how many more bytes the write queue takes.
+fn window(queued: &AtomicUsize) -> u32 {
+    let queued: u32 = queued
+        .load(Ordering::Relaxed)
+        .try_into()
+        .unwrap_or(u32::MAX);
+    RECEIVE_BUFFER.saturating_sub(queued)
+}
+
+/// Writes a data message to the socket or handles a control frame.
+///
+/// Returns why the session must stop, if it must.
+async fn handle_packet<W: PayloadWriter>(
+    socket: &mut W,
+    packet: message::Message,
+) -> Result<(), StopReason> {
+    if !packet.is_control() {
+        let packet = compression::decompress(packet).map_err(|e| {
+            error!("Failed to decompress message: {e}");
+            StopReason::error(Control::ERROR_DECODE, e.to_string())
+        })?;
+
+        if let Err(e) = socket.write_payload(packet.payload()).await {
+            error!("Failed to send message to socket: {e}");
+            return Err(StopReason::error(Control::ERROR_SOCKET, e.to_string()));
+        }
+        debug!("Sent packet #{} to MC", packet.seq);
+        return Ok(());
+    }
+
+    match Control::try_from(&packet) {
+        Ok(control @ Control::Close { .. }) => {
+            info!("Session {} closed by the peer: {control:?}", packet.session);
+            Err(StopReason::Peer(control))
+        }
+        Ok(control @ Control::Error { .. }) => {
+            error!("Session {} failed on the peer: {control:?}", packet.session);
+            Err(StopReason::Peer(control))
+        }
+        Ok(control) => {
+            debug!("Ignored {control:?} frame of session {}", packet.session);
+            Ok(())
+        }
+        Err(err) => {
+            warn!("Invalid control frame of session {}: {err}", packet.session);
+            Ok(())
+        }
+    }
+}
+
+/// Keeps retransmitting the messages of a session we closed until the peer acknowledges them.
+///
+/// Without it, losing one of the last messages (or the CLOSE frame itself) would go unnoticed.
+pub async fn linger(rx: &mut mpsc::Receiver<message::Message>, tx: &SessionSender) {
+    let session = tx.session();
+    // Maximum time waiting for the peer to acknowledge the last messages.
+    let deadline = tokio::time::Instant::now() + Timeouts::current().linger();
+    let mut tick = interval(RELIABILITY_TICK);
+
+    while !tx.is_all_acked() {
+        tokio::select! {
+            received = rx.recv() => match received {
+                Some(packet) => {
+                    if let Ok(Control::Ack { next_seq, window }) = Control::try_from(&packet) {
+                        tx.on_ack(next_seq, window);
+                    }
+                }
+                None => return,
+            },
+            _ = tick.tick() => {
+                if tx.retransmit_due().await.is_err() {
+                    warn!("Session {session} closed before the peer acknowledged its last messages");
                     return;
-                } else {
-                    debug!("Sent packet to MC");
                 }
             }
-            None => {
-                error!("Failed receiving message, channel closed, got None");
-                stop_tx.send(()).unwrap();
-                debug!("Error receiving message from closed channel (None). Broacast stop signal");
+            _ = tokio::time::sleep_until(deadline) => {
+                warn!("Session {session} closed before the peer acknowledged its last messages");
                 return;
             }
         }
     }
+
+    debug!("The peer acknowledged all the messages of session {session}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionId;
+    use tokio::sync::Semaphore;
+
+    /// A socket that writes a payload for each permit added.
+    struct Stalled(Arc<Semaphore>);
+
+    impl PayloadWriter for Stalled {
+        async fn write_payload(&mut self, _payload: &[u8]) -> io::Result<()> {
+            self.0.acquire().await.unwrap().forget();
+            Ok(())
+        }
+    }
+
+    async fn next_ack(rx: &mut mpsc::Receiver<message::Message>) -> (u32, u32) {
+        let message = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        match Control::try_from(&message) {
+            Ok(Control::Ack { next_seq, window }) => (next_seq, window),
+            other => panic!("Expected an ACK, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_socket_shrinks_window() {
+        let session = SessionId::new(1);
+        let (peer_tx, mut peer_rx) = mpsc::channel(64);
+        let tx = SessionSender::new(peer_tx, MessageDirection::Clientbound, session);
+        let (session_tx, mut session_rx) = mpsc::channel(64);
+        let (stop_tx, _) = broadcast::channel(4);
+        let permits = Arc::new(Semaphore::new(0));
+
+        let socket = Stalled(Arc::clone(&permits));
+        tokio::spawn(async move {
+            handle_channel_to_socket(socket, &mut session_rx, tx, stop_tx).await;
+        });
+
+        for seq in 1..=4 {
+            let message = message::Message::from_bytes(
+                vec![0; 10_000],
+                MessageDirection::Serverbound,
+                session,
+            )
+            .with_seq(seq);
+            session_tx.send(message).await.unwrap();
+        }
+        assert_eq!(next_ack(&mut peer_rx).await, (5, RECEIVE_BUFFER - 40_000));
+
+        // Caught up: the window is advertised again.
+        permits.add_permits(4);
+        assert_eq!(next_ack(&mut peer_rx).await, (5, RECEIVE_BUFFER));
+    }
 }
@@ -0,0 +1,423 @@
+//! Everything to answer the server list pings on the client side, without going through Discord.
+//!
+//! Each refresh of the multiplayer menu pings every server of the list: the client asks for the
+//! status (MOTD, players, favicon), then sends a ping to measure the latency. Through the tunnel,
+//! that would be a whole session and several Discord round trips each time.
+//!
+//! Instead, the server side gets the status of the Minecraft server every
+//! `timeouts.status_refresh_secs` and sends it to the client side in a STATUS message. The client
+//! side pings the server side as often, and answers the server list pings with the cached status.
+//! Its pongs are delayed by the round trip through the tunnel, so that the menu shows the latency
+//! the players will get.
+//!
+//! Both sides must frame the Minecraft packets. (`framing = "minecraft"`)
+
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{interval, timeout};
+
+use crate::address;
+use crate::config::Timeouts;
+use crate::forward::Target;
+use crate::message::{Control, Message, MessageDirection, MessageKind};
+use crate::minecraft::{self, Chunk, Fields, Framer, Handshake, Packet, State};
+use crate::partitioning::Part;
+use crate::session::SessionId;
+
+/// The packets of the status state.
+mod ids {
+    pub const STATUS_REQUEST: i32 = 0x00;
+    pub const STATUS_RESPONSE: i32 = 0x00;
+    pub const PING_REQUEST: i32 = 0x01;
+    pub const PONG_RESPONSE: i32 = 0x01;
+}
+
+/// A status that missed this many refreshes is not used anymore: the server side stopped sending
+/// it, so the Minecraft server (or the server side) is probably down.
+const MAX_MISSED_REFRESHES: u32 = 3;
+
+/// How long the client side waits for each packet of a server list ping.
+const PACKET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The status of the Minecraft server as last sent by the server side, and the round trip through
+/// the tunnel. (the client side)
+pub struct StatusCache {
+    start: Instant,
+    /// The status in JSON, with when it was received.
+    status: Mutex<Option<(String, Instant)>>,
+    rtt: Mutex<Option<Duration>>,
+}
+
+impl StatusCache {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            status: Mutex::new(None),
+            rtt: Mutex::new(None),
+        }
+    }
+
+    fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+        // The values stay consistent even if a holder panicked.
+        mutex
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// The status in JSON, unless it was not refreshed for too long.
+    pub fn status(&self) -> Option<String> {
+        self.status_at(Instant::now(), Timeouts::current().status_refresh())
+    }
+
+    fn status_at(&self, now: Instant, refresh: Duration) -> Option<String> {
+        Self::lock(&self.status)
+            .as_ref()
+            .filter(|(_, received_at)| {
+                now.saturating_duration_since(*received_at) <= refresh * MAX_MISSED_REFRESHES
+            })
+            .map(|(status, _)| status.clone())
+    }
+
+    /// The last round trip through the tunnel.
+    pub fn rtt(&self) -> Option<Duration> {
+        *Self::lock(&self.rtt)
+    }
+
+    /// Makes the PING measuring the round trip through the tunnel. Its nonce is when it is sent.
+    pub fn ping(&self) -> Message {
+        let nonce: u64 = self.start.elapsed().as_millis() as u64;
+        Message::from_control(
+            &Control::Ping(nonce),
+            MessageDirection::Serverbound,
+            SessionId::FORWARD,
+        )
+    }
+
+    /// Handles a message of the `SessionId::FORWARD` pseudo-session: a status or a PONG.
+    pub fn handle(&self, message: &Message) {
+        self.handle_at(message, Instant::now());
+    }
+
+    fn handle_at(&self, message: &Message, now: Instant) {
+        if message.kind == MessageKind::Status {
+            match String::from_utf8(message.payload().to_vec()) {
+                Ok(status) => {
+                    debug!(
+                        "Received the status of the Minecraft server [{}B]",
+                        status.len()
+                    );
+                    *Self::lock(&self.status) = Some((status, now));
+                }
+                Err(_) => warn!("Ignored a status of the Minecraft server that is not UTF-8"),
+            }
+            return;
+        }
+
+        match Control::try_from(message) {
+            Ok(Control::Pong(nonce)) => {
+                let sent_at: Instant = self.start + Duration::from_millis(nonce);
+                let rtt: Duration = now.saturating_duration_since(sent_at);
+                debug!("Round trip through the tunnel: {rtt:?}");
+                *Self::lock(&self.rtt) = Some(rtt);
+            }
+            _ => debug!("Ignored a {:?} message of the forward", message.kind),
+        }
+    }
+}
+
+impl Default for StatusCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+/// Reads the next packet, before the compression is enabled.
+async fn read_packet<R: AsyncRead + Unpin>(
+    socket: &mut R,
+    framer: &mut Framer,
+) -> io::Result<Packet> {
+    let mut buffer: Vec<u8> = Vec::with_capacity(4096);
+    loop {
+        match framer.next_chunk().map_err(invalid_data)? {
+            Some(Chunk::Packet(frame)) => {
+                return Packet::parse(&frame, false).map_err(invalid_data)
+            }
+            Some(Chunk::Tail(_)) => return Err(invalid_data("incomplete packet")),
+            None => {}
+        }
+
+        buffer.clear();
+        if socket.read_buf(&mut buffer).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        framer.push(&buffer);
+    }
+}
+
+/// Gets the status of a Minecraft server, in JSON.
+pub async fn query(target: &Target) -> io::Result<String> {
+    let (mut socket, addr) = address::connect(&target.host, target.port).await?;
+
+    let handshake = Handshake {
+        protocol: Handshake::ANY_PROTOCOL,
+        address: target.host.clone(),
+        port: addr.port(),
+        next_state: State::Status,
+    };
+    let request: Vec<u8> = [
+        handshake.to_packet().to_frame(),
+        Packet::new(ids::STATUS_REQUEST, []).to_frame(),
+    ]
+    .concat();
+    socket.write_all(&request).await?;
+
+    let response: Packet = read_packet(&mut socket, &mut Framer::default()).await?;
+    if response.id != ids::STATUS_RESPONSE {
+        return Err(invalid_data(format!(
+            "unexpected packet {:#04X} instead of the status",
+            response.id
+        )));
+    }
+    Fields::new(&response.body).string().map_err(invalid_data)
+}
+
+/// Sends the status of the Minecraft server to the client side, every
+/// `timeouts.status_refresh_secs`. (the server side)
+///
+/// Stops once `tx` is closed.
+pub async fn refresh(target: Target, tx: mpsc::Sender<Message>) {
+    let mut tick = interval(Timeouts::current().status_refresh());
+    // Tells the parts of the successive statuses apart, as the sequence number of a session does.
+    let mut count: u32 = 0;
+
+    loop {
+        tick.tick().await;
+        let status = timeout(Timeouts::current().connect(), query(&target))
+            .await
+            .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()));
+        let status: String = match status {
+            Ok(status) => status,
+            Err(err) => {
+                debug!("Failed to get the status of {target}: {err}");
+                continue;
+            }
+        };
+
+        count = count.wrapping_add(1);
+        let message = Message::new(
+            MessageKind::Status,
+            MessageDirection::Clientbound,
+            SessionId::FORWARD,
+            count,
+            Part::new(1, 1).unwrap(),
+            status.into_bytes(),
+        );
+        if tx.send(message).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads the handshake of a new connection, and answers it with the cached status if it is a
+/// server list ping. (the client side)
+///
+/// Returns the bytes read if the connection must go through the tunnel instead.
+pub async fn intercept(socket: &mut TcpStream, cache: &StatusCache) -> io::Result<Option<Vec<u8>>> {
+    let mut read: Vec<u8> = Vec::new();
+    let mut framer = Framer::default();
+
+    let handshake: Option<Handshake> = timeout(
+        PACKET_TIMEOUT,
+        read_handshake(socket, &mut read, &mut framer),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    let status: Option<String> = match handshake {
+        Some(handshake) if handshake.next_state == State::Status => cache.status(),
+        _ => None,
+    };
+    let Some(status) = status else {
+        return Ok(Some(read));
+    };
+
+    debug!("Answering a server list ping with the cached status");
+    answer(socket, &mut framer, &status, cache.rtt()).await?;
+    Ok(None)
+}
+
+/// Reads the first packet of a connection. Returns `None` if it is not a handshake.
+///
+/// Everything read is kept in `read`, in case it has to go through the tunnel.
+async fn read_handshake(
+    socket: &mut TcpStream,
+    read: &mut Vec<u8>,
+    framer: &mut Framer,
+) -> io::Result<Option<Handshake>> {
+    loop {
+        // e.g. the server list ping of the clients older than 1.7: the server answers it.
+        if read.first() == Some(&minecraft::LEGACY_PING) {
+            return Ok(None);
+        }
+        match framer.next_chunk() {
+            Ok(Some(Chunk::Packet(frame))) => {
+                return Ok(Packet::parse(&frame, false)
+                    .and_then(|packet| Handshake::parse(&packet))
+                    .ok());
+            }
+            Ok(None) => {}
+            _ => return Ok(None),
+        }
+
+        let start: usize = read.len();
+        if socket.read_buf(read).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        framer.push(&read[start..]);
+    }
+}
+
+/// Answers the status request and the ping of a server list ping.
+async fn answer(
+    socket: &mut TcpStream,
+    framer: &mut Framer,
+    status: &str,
+    rtt: Option<Duration>,
+) -> io::Result<()> {
+    loop {
+        let packet: Packet = timeout(PACKET_TIMEOUT, read_packet(socket, framer))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        match packet.id {
+            ids::STATUS_REQUEST => {
+                let mut body: Vec<u8> = Vec::new();
+                minecraft::write_string(status, &mut body);
+                let response = Packet::new(ids::STATUS_RESPONSE, body);
+                socket.write_all(&response.to_frame()).await?;
+            }
+            ids::PING_REQUEST => {
+                // The latency shown is the one of the tunnel.
+                if let Some(rtt) = rtt {
+                    tokio::time::sleep(rtt).await;
+                }
+                let pong = Packet::new(ids::PONG_RESPONSE, packet.body);
+                socket.write_all(&pong.to_frame()).await?;
+                return Ok(());
+            }
+            id => {
+                return Err(invalid_data(format!(
+                    "unexpected packet {id:#04X} in a server list ping"
+                )))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn status_message(status: &str) -> Message {
+        Message::new(
+            MessageKind::Status,
+            MessageDirection::Clientbound,
+            SessionId::FORWARD,
+            1,
+            Part::new(1, 1).unwrap(),
+            status.as_bytes().to_vec(),
+        )
+    }
+
+    #[test]
+    fn test_status_cache() {
+        let cache = StatusCache::new();
+        let refresh = Duration::from_secs(30);
+        let now = cache.start + Duration::from_secs(10);
+        assert_eq!(cache.status_at(now, refresh), None);
+
+        cache.handle_at(&status_message("{}"), now);
+        assert_eq!(cache.status_at(now, refresh).as_deref(), Some("{}"));
+        // Too old once the server side stopped refreshing it.
+        assert_eq!(cache.status_at(now + refresh * 4, refresh), None);
+
+        let Control::Ping(nonce) = Control::try_from(&cache.ping()).unwrap() else {
+            panic!("not a ping");
+        };
+        let pong = Message::from_control(
+            &Control::Pong(nonce),
+            MessageDirection::Clientbound,
+            SessionId::FORWARD,
+        );
+        let sent_at = cache.start + Duration::from_millis(nonce);
+        cache.handle_at(&pong, sent_at + Duration::from_millis(1500));
+        assert_eq!(cache.rtt(), Some(Duration::from_millis(1500)));
+    }
+
+    #[tokio::test]
+    async fn test_intercept() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cache = StatusCache::new();
+        cache.handle(&status_message(r#"{"description":"tunnel"}"#));
+
+        // A server list ping is answered locally.
+        let player = tokio::spawn(async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            let handshake = Handshake {
+                protocol: 767,
+                address: "localhost".to_string(),
+                port: addr.port(),
+                next_state: State::Status,
+            };
+            let ping = Packet::new(ids::PING_REQUEST, 42u64.to_be_bytes());
+            let request = [
+                handshake.to_packet().to_frame(),
+                Packet::new(ids::STATUS_REQUEST, []).to_frame(),
+                ping.to_frame(),
+            ]
+            .concat();
+            socket.write_all(&request).await.unwrap();
+
+            let mut framer = Framer::default();
+            let response = read_packet(&mut socket, &mut framer).await.unwrap();
+            let status = Fields::new(&response.body).string().unwrap();
+            let pong = read_packet(&mut socket, &mut framer).await.unwrap();
+            (status, pong.body)
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert_eq!(intercept(&mut socket, &cache).await.unwrap(), None);
+        let (status, pong) = player.await.unwrap();
+        assert_eq!(status, r#"{"description":"tunnel"}"#);
+        assert_eq!(pong, 42u64.to_be_bytes());
+
+        // A login goes through the tunnel, with what was read.
+        let login = Handshake {
+            protocol: 767,
+            address: "localhost".to_string(),
+            port: addr.port(),
+            next_state: State::Login,
+        }
+        .to_packet()
+        .to_frame();
+        let sent = login.clone();
+        tokio::spawn(async move {
+            let mut socket = TcpStream::connect(addr).await.unwrap();
+            socket.write_all(&sent).await.unwrap();
+            // Keeps the connection open.
+            let _ = socket.read(&mut [0; 1]).await;
+        });
+        let (mut socket, _) = listener.accept().await.unwrap();
+        assert_eq!(intercept(&mut socket, &cache).await.unwrap(), Some(login));
+    }
+}
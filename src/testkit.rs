@@ -0,0 +1,678 @@
+//! Fake Minecraft endpoints, to check that a tunnel delivers every byte. (see the
+//! `discraft-testkit` binary)
+//!
+//! The fake server answers the status, and logs the players in (offline mode: no encryption)
+//! up to the play state. Then it streams `Settings::packets` synthetic packets of about the size
+//! of a chunk. Each carries its number, the time it was sent, and bytes derived from its number
+//! and the seed, so the fake client checks every byte of them and tells the offset of the first
+//! corrupted one in the connection.
+//!
+//! The fake client answers the Keep Alives, which the client side makes up for the players.
+
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{debug, info, warn};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde_json::json;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::minecraft::{
+    ids, write_string, write_varint, Chunk, Fields, Framer, Handshake, MinecraftError, Packet,
+    State,
+};
+
+/// The size of the number and of the send time, at the start of the streamed packets.
+const HEADER_LEN: usize = 4 + 8;
+
+/// The ID of the clientbound Status Response and of the serverbound Status Request.
+const STATUS: i32 = 0x00;
+
+/// The ID of the Ping Request and of the Pong Response.
+const PING: i32 = 0x01;
+
+/// The ID of Login Start.
+const LOGIN_START: i32 = 0x00;
+
+/// The name the fake client logs in with.
+const PLAYER_NAME: &str = "discraft_testkit";
+
+#[derive(Debug, Error)]
+pub enum TestkitError {
+    #[error("Connection failed: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Invalid packet: {0}")]
+    Minecraft(#[from] MinecraftError),
+
+    #[error("Unexpected {0}")]
+    Unexpected(String),
+
+    #[error("Disconnected: {0}")]
+    Disconnected(String),
+}
+
+/// What is streamed to the players. Both tools must be given the same.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Settings {
+    /// The protocol version spoken. (767 is 1.21.1)
+    pub protocol: i32,
+    /// How many packets the server streams.
+    pub packets: u32,
+    /// The length of the body of the streamed packets.
+    pub packet_size: usize,
+    /// The compression threshold, if the server enables the compression.
+    pub compression: Option<usize>,
+    /// What the streamed bytes are derived from.
+    pub seed: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            protocol: 767,
+            packets: 64,
+            packet_size: 4096,
+            compression: None,
+            seed: 0,
+        }
+    }
+}
+
+impl Settings {
+    /// The ID of the streamed packets: one the tunnel does not look at in the play state.
+    pub fn chunk_id(&self) -> i32 {
+        let mut id: i32 = 0x27;
+        let keep_alive: Option<i32> =
+            ids::keep_alive(self.protocol).map(|(clientbound, _)| clientbound);
+        while Some(id) == keep_alive || Some(id) == ids::play_disconnect(self.protocol) {
+            id += 1;
+        }
+        id
+    }
+
+    /// The body of the streamed packet numbered `seq`, sent at `sent_at`. (in microseconds since
+    /// the UNIX epoch)
+    fn chunk_body(&self, seq: u32, sent_at: u64) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::with_capacity(self.packet_size.max(HEADER_LEN));
+        body.extend_from_slice(&seq.to_be_bytes());
+        body.extend_from_slice(&sent_at.to_be_bytes());
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(seq as u64));
+        body.extend((HEADER_LEN..self.packet_size).map(|_| rng.random::<u8>()));
+        body
+    }
+
+    fn uses_configuration(&self) -> bool {
+        self.protocol >= ids::CONFIGURATION_PROTOCOL
+    }
+}
+
+/// The time now, in microseconds since the UNIX epoch.
+fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// A Minecraft connection, read and written packet by packet.
+struct Connection {
+    stream: TcpStream,
+    framer: Framer,
+    /// The compression threshold, once enabled.
+    compression: Option<usize>,
+    /// The bytes read from the connection before the buffered ones.
+    offset: u64,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            framer: Framer::default(),
+            compression: None,
+            offset: 0,
+        }
+    }
+
+    /// Reads the next packet, with its length prefix. Returns its offset in the connection too,
+    /// or `None` once the connection is closed.
+    async fn read_frame(&mut self) -> Result<Option<(u64, Vec<u8>)>, TestkitError> {
+        loop {
+            if let Some(Chunk::Packet(frame)) = self.framer.next_chunk()? {
+                let offset: u64 = self.offset;
+                self.offset += frame.len() as u64;
+                return Ok(Some((offset, frame)));
+            }
+            let mut buffer = [0; 16 * 1024];
+            let len: usize = self.stream.read(&mut buffer).await?;
+            if len == 0 {
+                return Ok(None);
+            }
+            self.framer.push(&buffer[..len]);
+        }
+    }
+
+    /// Reads the next packet, failing if the connection is closed.
+    async fn read_packet(&mut self) -> Result<Packet, TestkitError> {
+        match self.read_frame().await? {
+            Some((_, frame)) => Ok(Packet::parse(&frame, self.compression.is_some())?),
+            None => Err(TestkitError::Disconnected(
+                "the connection was closed".to_string(),
+            )),
+        }
+    }
+
+    /// Reads the next packet, failing if it is not `id`.
+    async fn expect(&mut self, id: i32, what: &str) -> Result<Packet, TestkitError> {
+        let packet: Packet = self.read_packet().await?;
+        if packet.id != id {
+            return Err(TestkitError::Unexpected(format!(
+                "packet {:#04X} instead of {what} ({id:#04X})",
+                packet.id
+            )));
+        }
+        Ok(packet)
+    }
+
+    async fn write_packet(&mut self, packet: &Packet) -> Result<(), TestkitError> {
+        let frame: Vec<u8> = match self.compression {
+            Some(threshold) => packet.to_compressed_frame(threshold),
+            None => packet.to_frame(),
+        };
+        self.stream.write_all(&frame).await?;
+        Ok(())
+    }
+}
+
+/// Serves the players connecting to the listener, until it fails.
+pub async fn serve(listener: TcpListener, settings: Settings) -> std::io::Result<()> {
+    loop {
+        let (stream, from) = listener.accept().await?;
+        tokio::spawn(async move {
+            match serve_player(stream, settings).await {
+                Ok(()) => info!("{from} left"),
+                Err(err) => warn!("{from} failed: {err}"),
+            }
+        });
+    }
+}
+
+/// Answers the status, or logs the player in and streams the packets to it.
+async fn serve_player(stream: TcpStream, settings: Settings) -> Result<(), TestkitError> {
+    let mut connection = Connection::new(stream);
+    let handshake: Handshake = Handshake::parse(&connection.read_packet().await?)?;
+    debug!("Handshake: {handshake:?}");
+
+    if handshake.next_state == State::Status {
+        connection.expect(STATUS, "Status Request").await?;
+        let status = json!({
+            "version": { "name": "discraft-testkit", "protocol": settings.protocol },
+            "players": { "max": 1, "online": 0 },
+            "description": { "text": "Discraft testkit" },
+        });
+        let mut body: Vec<u8> = Vec::new();
+        write_string(&status.to_string(), &mut body);
+        connection.write_packet(&Packet::new(STATUS, body)).await?;
+        let ping: Packet = connection.expect(PING, "Ping Request").await?;
+        connection.write_packet(&ping).await?;
+        return Ok(());
+    }
+
+    connection.expect(LOGIN_START, "Login Start").await?;
+    if let Some(threshold) = settings.compression {
+        let mut body: Vec<u8> = Vec::new();
+        write_varint(threshold as i32, &mut body);
+        connection
+            .write_packet(&Packet::new(ids::SET_COMPRESSION, body))
+            .await?;
+        connection.compression = Some(threshold);
+    }
+    connection
+        .write_packet(&Packet::new(
+            ids::LOGIN_SUCCESS,
+            login_success(settings.protocol),
+        ))
+        .await?;
+
+    if settings.uses_configuration() {
+        connection
+            .expect(ids::LOGIN_ACKNOWLEDGED, "Login Acknowledged")
+            .await?;
+        let finish: i32 = ids::finish_configuration(settings.protocol);
+        connection
+            .write_packet(&Packet::new(finish, Vec::new()))
+            .await?;
+        connection
+            .expect(finish, "Acknowledge Finish Configuration")
+            .await?;
+    }
+
+    info!("Streaming {} packets", settings.packets);
+    for seq in 0..settings.packets {
+        let body: Vec<u8> = settings.chunk_body(seq, now_micros());
+        connection
+            .write_packet(&Packet::new(settings.chunk_id(), body))
+            .await?;
+    }
+
+    // The player leaves once it has everything. (its answers to the Keep Alives of the client
+    // side never come)
+    while connection.read_frame().await?.is_some() {}
+    Ok(())
+}
+
+/// The body of Login Success. (the fake client does not read it)
+fn login_success(protocol: i32) -> Vec<u8> {
+    let mut body: Vec<u8> = Vec::new();
+    // The UUID is a string before 1.16.
+    if protocol < 735 {
+        write_string("00000000-0000-0000-0000-000000000000", &mut body);
+    } else {
+        body.extend_from_slice(&[0; 16]);
+    }
+    write_string(PLAYER_NAME, &mut body);
+    if protocol >= 759 {
+        // No properties.
+        write_varint(0, &mut body);
+    }
+    if (766..=767).contains(&protocol) {
+        // No strict error handling.
+        body.push(0);
+    }
+    body
+}
+
+/// The latencies of the streamed packets, from the server to the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Latencies {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+}
+
+/// What the fake client received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// The streamed packets received whole and intact.
+    pub packets: u32,
+    /// The bytes of their bodies.
+    pub bytes: u64,
+    /// From the end of the login to the last packet.
+    pub elapsed: Duration,
+    /// Only meaningful if both tools run on the same machine.
+    pub latencies: Option<Latencies>,
+    /// The Keep Alives answered.
+    pub keep_alives: u32,
+    /// The offset in the connection of the first corrupted byte, or of the packet it is in if it
+    /// was compressed.
+    pub first_corrupted: Option<u64>,
+    /// Why the connection ended before the last packet.
+    pub interrupted: Option<String>,
+}
+
+impl Report {
+    /// In bytes per second.
+    pub fn throughput(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Returns true if every packet was received intact.
+    pub fn is_ok(&self, settings: &Settings) -> bool {
+        self.first_corrupted.is_none()
+            && self.interrupted.is_none()
+            && self.packets == settings.packets
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Received {} packets ({} bytes) in {:.2?}: {:.1} KiB/s",
+            self.packets,
+            self.bytes,
+            self.elapsed,
+            self.throughput() / 1024.0
+        )?;
+        if let Some(latencies) = self.latencies {
+            writeln!(
+                f,
+                "Latency: min {:.2?}, avg {:.2?}, max {:.2?}",
+                latencies.min, latencies.avg, latencies.max
+            )?;
+        }
+        writeln!(f, "Keep Alives answered: {}", self.keep_alives)?;
+        if let Some(reason) = &self.interrupted {
+            writeln!(f, "Interrupted: {reason}")?;
+        }
+        match self.first_corrupted {
+            Some(offset) => write!(f, "First corrupted offset: {offset}"),
+            None => write!(f, "No corruption"),
+        }
+    }
+}
+
+/// Gets the status of the server, as the server list does. Returns it with the ping.
+pub async fn status(
+    addr: SocketAddr,
+    settings: Settings,
+) -> Result<(String, Duration), TestkitError> {
+    let mut connection = Connection::new(TcpStream::connect(addr).await?);
+    let handshake = Handshake {
+        protocol: settings.protocol,
+        address: addr.ip().to_string(),
+        port: addr.port(),
+        next_state: State::Status,
+    };
+    connection.write_packet(&handshake.to_packet()).await?;
+    connection
+        .write_packet(&Packet::new(STATUS, Vec::new()))
+        .await?;
+    let response: Packet = connection.expect(STATUS, "Status Response").await?;
+    let status: String = Fields::new(&response.body).string()?;
+
+    let sent_at = Instant::now();
+    let payload: Vec<u8> = now_micros().to_be_bytes().to_vec();
+    connection
+        .write_packet(&Packet::new(PING, payload.clone()))
+        .await?;
+    let pong: Packet = connection.expect(PING, "Pong Response").await?;
+    if pong.body != payload {
+        return Err(TestkitError::Unexpected("Pong payload".to_string()));
+    }
+    Ok((status, sent_at.elapsed()))
+}
+
+/// Joins the server, and checks every packet it streams.
+pub async fn join(addr: SocketAddr, settings: Settings) -> Result<Report, TestkitError> {
+    let mut connection = Connection::new(TcpStream::connect(addr).await?);
+    let handshake = Handshake {
+        protocol: settings.protocol,
+        address: addr.ip().to_string(),
+        port: addr.port(),
+        next_state: State::Login,
+    };
+    connection.write_packet(&handshake.to_packet()).await?;
+    let mut login_start: Vec<u8> = Vec::new();
+    write_string(PLAYER_NAME, &mut login_start);
+    if settings.uses_configuration() {
+        login_start.extend_from_slice(&[0; 16]);
+    }
+    connection
+        .write_packet(&Packet::new(LOGIN_START, login_start))
+        .await?;
+
+    loop {
+        let packet: Packet = connection.read_packet().await?;
+        match packet.id {
+            ids::SET_COMPRESSION => {
+                let threshold: i32 = Fields::new(&packet.body).varint()?;
+                connection.compression = Some(threshold.max(0) as usize);
+            }
+            ids::LOGIN_SUCCESS => break,
+            ids::LOGIN_DISCONNECT => {
+                let reason: String = Fields::new(&packet.body).string()?;
+                return Err(TestkitError::Disconnected(reason));
+            }
+            id => return Err(TestkitError::Unexpected(format!("login packet {id:#04X}"))),
+        }
+    }
+
+    if settings.uses_configuration() {
+        connection
+            .write_packet(&Packet::new(ids::LOGIN_ACKNOWLEDGED, Vec::new()))
+            .await?;
+        let finish: i32 = ids::finish_configuration(settings.protocol);
+        let packet: Packet = connection.read_packet().await?;
+        if packet.id != finish {
+            return Err(TestkitError::Disconnected(format!(
+                "packet {:#04X} during the configuration",
+                packet.id
+            )));
+        }
+        connection
+            .write_packet(&Packet::new(finish, Vec::new()))
+            .await?;
+    }
+
+    let started = Instant::now();
+    let mut report = Report {
+        packets: 0,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+        latencies: None,
+        keep_alives: 0,
+        first_corrupted: None,
+        interrupted: None,
+    };
+    let mut latencies: Vec<Duration> = Vec::with_capacity(settings.packets as usize);
+    let keep_alive: Option<(i32, i32)> = ids::keep_alive(settings.protocol);
+
+    while report.packets < settings.packets {
+        let Some((offset, frame)) = connection.read_frame().await? else {
+            report.interrupted = Some("the connection was closed".to_string());
+            break;
+        };
+        let packet: Packet = match Packet::parse(&frame, connection.compression.is_some()) {
+            Ok(packet) => packet,
+            Err(_) => {
+                report.first_corrupted = Some(offset);
+                break;
+            }
+        };
+
+        if let Some((_, serverbound)) = keep_alive.filter(|(id, _)| *id == packet.id) {
+            debug!("Answering Keep Alive {:02X?}", packet.body);
+            connection
+                .write_packet(&Packet::new(serverbound, packet.body))
+                .await?;
+            report.keep_alives += 1;
+            continue;
+        }
+        if Some(packet.id) == ids::play_disconnect(settings.protocol) {
+            report.interrupted = Some("disconnected by the tunnel".to_string());
+            break;
+        }
+
+        if let Some(corrupted) = check_chunk(
+            &settings,
+            report.packets,
+            offset,
+            &frame,
+            &packet,
+            connection.compression.is_some(),
+        ) {
+            report.first_corrupted = Some(corrupted);
+            break;
+        }
+
+        let sent_at: u64 =
+            u64::from_be_bytes(packet.body[4..HEADER_LEN].try_into().unwrap_or_default());
+        latencies.push(Duration::from_micros(now_micros().saturating_sub(sent_at)));
+        report.packets += 1;
+        report.bytes += packet.body.len() as u64;
+        report.elapsed = started.elapsed();
+    }
+
+    if !latencies.is_empty() {
+        let total: Duration = latencies.iter().sum();
+        report.latencies = Some(Latencies {
+            min: latencies.iter().min().copied().unwrap_or_default(),
+            avg: total / latencies.len() as u32,
+            max: latencies.iter().max().copied().unwrap_or_default(),
+        });
+    }
+    Ok(report)
+}
+
+/// Checks the streamed packet numbered `seq`, cut out of the connection at `offset`.
+///
+/// Returns the offset of its first corrupted byte, or of the packet itself if the byte cannot be
+/// told. (its ID is wrong, or it was compressed)
+fn check_chunk(
+    settings: &Settings,
+    seq: u32,
+    offset: u64,
+    frame: &[u8],
+    packet: &Packet,
+    compressed: bool,
+) -> Option<u64> {
+    if packet.id != settings.chunk_id() || packet.body.len() < HEADER_LEN {
+        return Some(offset);
+    }
+
+    // The send time cannot be known in advance.
+    let sent_at: u64 = u64::from_be_bytes(packet.body[4..HEADER_LEN].try_into().ok()?);
+    let expected: Vec<u8> = settings.chunk_body(seq, sent_at);
+    let index: usize = (0..expected.len().max(packet.body.len()))
+        .find(|&i| packet.body.get(i) != expected.get(i))?;
+    if compressed {
+        return Some(offset);
+    }
+    let body_offset: u64 = offset + (frame.len() - packet.body.len()) as u64;
+    Some(body_offset + index as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use tokio::sync::broadcast;
+
+    use crate::forward::{Forward, Framing, Protocol, Target};
+    use crate::transport::Loopback;
+    use crate::tunnel;
+
+    async fn fake_server(settings: Settings) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, settings));
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_join() {
+        for (protocol, compression) in [(340, None), (763, Some(256)), (767, None), (769, Some(64))]
+        {
+            let settings = Settings {
+                protocol,
+                packets: 8,
+                compression,
+                ..Settings::default()
+            };
+            let addr = fake_server(settings).await;
+
+            let (status, _) = status(addr, settings).await.unwrap();
+            assert!(status.contains("Discraft testkit"));
+            let report = join(addr, settings).await.unwrap();
+            assert!(report.is_ok(&settings), "protocol {protocol}: {report}");
+            assert_eq!(report.bytes, 8 * 4096);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_first_corrupted_offset() {
+        let settings = Settings {
+            packets: 4,
+            ..Settings::default()
+        };
+        let target = fake_server(settings).await;
+
+        // Flips a byte of the third packet, in the middle of the stream of the server.
+        const CORRUPTED: u64 = 10_000;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (client, _) = listener.accept().await.unwrap();
+            let server = TcpStream::connect(target).await.unwrap();
+            let (mut client_read, mut client_write) = client.into_split();
+            let (mut server_read, mut server_write) = server.into_split();
+            tokio::spawn(async move {
+                let _ = tokio::io::copy(&mut client_read, &mut server_write).await;
+            });
+            let mut read: u64 = 0;
+            let mut buffer = [0; 4096];
+            while let Ok(len @ 1..) = server_read.read(&mut buffer).await {
+                if (read..read + len as u64).contains(&CORRUPTED) {
+                    buffer[(CORRUPTED - read) as usize] ^= 0xFF;
+                }
+                read += len as u64;
+                if client_write.write_all(&buffer[..len]).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let report = join(addr, settings).await.unwrap();
+        assert_eq!(report.first_corrupted, Some(CORRUPTED));
+        assert_eq!(report.packets, 2);
+        assert!(!report.is_ok(&settings));
+    }
+
+    fn forward(listen: SocketAddr, target: Option<SocketAddr>) -> Forward {
+        Forward {
+            name: "minecraft".to_string(),
+            protocol: Protocol::Tcp,
+            framing: Framing::Minecraft,
+            listen: vec![listen],
+            target: target.map(|addr| Target {
+                host: addr.ip().to_string(),
+                port: Some(addr.port()),
+            }),
+            channels: vec![1, 2],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_join_through_tunnel() {
+        let settings = Settings {
+            packets: 16,
+            compression: Some(256),
+            ..Settings::default()
+        };
+        let target = fake_server(settings).await;
+        let listen = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (client_end, server_end) = Loopback::pair();
+        let (stop_tx, _) = broadcast::channel::<()>(16);
+        tokio::spawn(tunnel::run(
+            Arc::new(server_end),
+            vec![forward(listen, Some(target))],
+            true,
+            stop_tx.clone(),
+            tunnel::Settings::default(),
+        ));
+        tokio::spawn(tunnel::run(
+            Arc::new(client_end),
+            vec![forward(listen, None)],
+            false,
+            stop_tx,
+            tunnel::Settings::default(),
+        ));
+
+        // Waits for the client side to listen.
+        let (status, _) = loop {
+            match super::status(listen, settings).await {
+                Ok(status) => break status,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        assert!(status.contains("Discraft testkit"));
+
+        let report = tokio::time::timeout(Duration::from_secs(30), join(listen, settings))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(report.is_ok(&settings), "{report}");
+    }
+}
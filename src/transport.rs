@@ -0,0 +1,125 @@
+//! Everything to carry the frames between both sides of the tunnel.
+//!
+//! A frame is the content of a Discord message, posted in one of the channels of a forward. The
+//! `Transport` trait is what the tunnel needs from Discord: `DiscordBot` implements it for real,
+//! and `Loopback` connects both sides in memory, so that they can run in a single process. (e.g.
+//! in the tests) `simulator::Simulator` makes any of them misbehave the way Discord does.
+
+use std::future::Future;
+use std::time::Duration;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, Mutex};
+
+/// The content of a message, received in a channel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub channel: u64,
+    pub content: String,
+}
+
+#[derive(Debug, Error)]
+pub enum TransportError {
+    #[error("The transport is closed")]
+    Closed,
+
+    #[error("Failed to send the frame: {0}")]
+    Send(String),
+
+    #[error("Rate limited for {retry_after:?}")]
+    RateLimited {
+        retry_after: Duration,
+        /// Whether every channel is, not only this one.
+        global: bool,
+    },
+}
+
+/// The rate limit of a channel, as told by Discord after each message sent to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bucket {
+    /// How many more messages the channel takes before `reset_after`.
+    pub remaining: u32,
+    /// How long until the channel takes messages again, if `remaining` is 0.
+    pub reset_after: Duration,
+}
+
+/// Sends frames to the peer, and receives its frames.
+pub trait Transport: Send + Sync + 'static {
+    /// Sends a frame to the channel. Returns the rate limit of the channel, if known.
+    fn send(
+        &self,
+        channel: u64,
+        content: String,
+    ) -> impl Future<Output = Result<Option<Bucket>, TransportError>> + Send;
+
+    /// Waits for the next frame of the peer. Returns `None` once the transport is closed.
+    ///
+    /// The frames are meant to be read by a single task.
+    fn receive(&self) -> impl Future<Output = Option<Frame>> + Send;
+}
+
+/// One end of an in-memory transport: what it sends is received by the other end.
+///
+/// Unlike Discord, the frames are never lost, and are received in the order they were sent.
+pub struct Loopback {
+    tx: mpsc::UnboundedSender<Frame>,
+    rx: Mutex<mpsc::UnboundedReceiver<Frame>>,
+}
+
+impl Loopback {
+    /// Makes both ends of a transport.
+    pub fn pair() -> (Loopback, Loopback) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        (
+            Loopback {
+                tx: b_tx,
+                rx: Mutex::new(a_rx),
+            },
+            Loopback {
+                tx: a_tx,
+                rx: Mutex::new(b_rx),
+            },
+        )
+    }
+}
+
+impl Transport for Loopback {
+    async fn send(&self, channel: u64, content: String) -> Result<Option<Bucket>, TransportError> {
+        self.tx
+            .send(Frame { channel, content })
+            .map(|_| None)
+            .map_err(|_| TransportError::Closed)
+    }
+
+    async fn receive(&self) -> Option<Frame> {
+        self.rx.lock().await.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_loopback() {
+        let (client, server) = Loopback::pair();
+        client.send(1, "hello".to_string()).await.unwrap();
+        client.send(2, "world".to_string()).await.unwrap();
+        server.send(1, "back".to_string()).await.unwrap();
+
+        assert_eq!(
+            server.receive().await,
+            Some(Frame {
+                channel: 1,
+                content: "hello".to_string()
+            })
+        );
+        assert_eq!(server.receive().await.unwrap().channel, 2);
+        assert_eq!(client.receive().await.unwrap().content, "back");
+
+        drop(server);
+        assert!(client.send(1, "lost".to_string()).await.is_err());
+        assert_eq!(client.receive().await, None);
+    }
+}
@@ -0,0 +1,836 @@
+//! Both ends of the forwards: the sockets on one side, the transport to the peer on the other.
+//!
+//! The client side (the entry end) listens, and opens a session for each connection. The server
+//! side (the exit end) connects to the target of the forward for each session the peer opens.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use log::{debug, error, info, warn};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::task::JoinSet;
+
+use crate::codec::Codec;
+use crate::compression::Compression;
+use crate::config::Timeouts;
+use crate::forward::{Forward, Framing, Protocol, Target};
+use crate::message::{self, Control};
+use crate::session::{SessionId, SessionRouter, SessionSender};
+use crate::sockets::{self, PayloadWriter, StopReason};
+use crate::status::{self, StatusCache};
+use crate::transport::Transport;
+use crate::{address, crypto, discord, minecraft, udp};
+
+/// How one side of the tunnel writes its frames, for a whole run.
+#[derive(Clone, Default)]
+pub struct Settings {
+    /// Encodes the payloads of the frames. (both sides must use the same)
+    pub codec: Codec,
+    /// Compresses the data sent. (the peer decompresses it, whatever its own)
+    pub compression: Compression,
+    /// Seals the frames, if a secret was given.
+    pub cipher: Option<Arc<crypto::FrameCipher>>,
+}
+
+/// Runs the forwards of one side of the tunnel over the transport, until one of them fails.
+///
+/// `stop_tx` stops every session. (e.g. when the Discord bot dies)
+pub async fn run<T: Transport>(
+    transport: Arc<T>,
+    forwards: Vec<Forward>,
+    is_server: bool,
+    stop_tx: broadcast::Sender<()>,
+    settings: Settings,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Each forward receives the messages of its own channels, and sends its messages (and the
+    // answers to the PINGs and HELLOs received in its channels) to them, sealed for the peer of
+    // its link.
+    let mut routes = discord::Routes::default();
+    let mut queues: Vec<(Sender<message::Message>, Receiver<message::Message>)> =
+        Vec::with_capacity(forwards.len());
+    for forward in &forwards {
+        let (discord_tx, discord_rx) = mpsc::channel::<message::Message>(64);
+        let link = Arc::new(crypto::Link::new(settings.cipher.clone()));
+        let tcp_tx = spawn_discord_writer(
+            Arc::clone(&transport),
+            forward.channels.clone(),
+            stop_tx.clone(),
+            Arc::clone(&link),
+            settings.codec,
+        );
+        routes.add(&forward.channels, discord_tx, tcp_tx.clone(), link);
+        queues.push((tcp_tx, discord_rx));
+    }
+
+    let mut tasks = JoinSet::new();
+    let reader = Arc::clone(&transport);
+    tasks.spawn(async move {
+        discord::handle_read_discord(reader, routes, is_server, settings.codec).await;
+        Err("The transport is closed".into())
+    });
+
+    let compression: Compression = settings.compression;
+    for (forward, (tcp_tx, discord_rx)) in forwards.into_iter().zip(queues) {
+        let stop_tx = stop_tx.clone();
+        tasks.spawn(async move {
+            if is_server {
+                server(forward, stop_tx, tcp_tx, discord_rx, compression).await
+            } else {
+                client(forward, stop_tx, tcp_tx, discord_rx, compression).await
+            }
+        });
+    }
+
+    // The first forward to fail stops them all.
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
+
+/// Spawns the task sending the messages of all sessions of a forward to its Discord channels,
+/// encoded with the codec and sealed for the peer of the link.
+///
+/// Returns where the sessions send their messages.
+fn spawn_discord_writer<T: Transport>(
+    transport: Arc<T>,
+    channel_ids: Vec<u64>,
+    stop_tx: broadcast::Sender<()>,
+    link: Arc<crypto::Link>,
+    codec: Codec,
+) -> Sender<message::Message> {
+    debug!("Discord channel IDs: {channel_ids:#?}");
+
+    let (tcp_tx, tcp_rx) = mpsc::channel::<message::Message>(64);
+    tokio::spawn(async move {
+        debug!("Inside the handle_write_discord async task");
+        discord::handle_write_discord(
+            transport.as_ref(),
+            tcp_rx,
+            stop_tx,
+            &channel_ids,
+            &link,
+            codec,
+        )
+        .await;
+    });
+    tcp_tx
+}
+
+/// Client-side logic (the entry end of a forward)
+///
+/// Every session of the forward sends its messages to Discord through `tcp_tx` (its data
+/// compressed with the compression), and `discord_rx` gets the messages received in the channels
+/// of the forward.
+pub async fn client(
+    forward: Forward,
+    stop_tx: broadcast::Sender<()>,
+    tcp_tx: Sender<message::Message>,
+    discord_rx: Receiver<message::Message>,
+    compression: Compression,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Dispatches the received Discord messages to their session.
+    let router = Arc::new(SessionRouter::new());
+
+    if forward.protocol == Protocol::Udp {
+        return udp_client(forward, stop_tx, tcp_tx, router, discord_rx, compression).await;
+    }
+    let name: String = forward.name;
+
+    // The server list pings are answered here, with the status sent by the server side.
+    let status_cache: Option<Arc<StatusCache>> = match forward.framing {
+        Framing::Raw => None,
+        Framing::Minecraft => Some(Arc::new(StatusCache::new())),
+    };
+    if let Some(cache) = &status_cache {
+        spawn_tunnel_pinger(Arc::clone(cache), tcp_tx.clone());
+    }
+
+    // The connections accepted on every address end up in this channel, with what was already
+    // read from them.
+    let (accept_tx, mut accept_rx) =
+        mpsc::channel::<io::Result<(TcpStream, SocketAddr, Vec<u8>)>>(16);
+    for addr in forward.listen {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Listening on {addr} (forward {name})...");
+
+        let accept_tx = accept_tx.clone();
+        let status_cache = status_cache.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        let _ = accept_tx.send(Err(err)).await;
+                        return;
+                    }
+                };
+                let Some(cache) = status_cache.clone() else {
+                    if accept_tx
+                        .send(Ok((socket, addr, Vec::new())))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                    continue;
+                };
+
+                // Waiting for the handshake must not hold back the next connections.
+                let accept_tx = accept_tx.clone();
+                tokio::spawn(async move {
+                    match status::intercept(&mut socket, &cache).await {
+                        Ok(Some(read)) => {
+                            let _ = accept_tx.send(Ok((socket, addr, read))).await;
+                        }
+                        Ok(None) => debug!("Answered the server list ping of {addr}"),
+                        Err(err) => debug!("Failed to read the handshake of {addr}: {err}"),
+                    }
+                });
+            }
+        });
+    }
+    drop(accept_tx);
+
+    spawn_dispatcher(Arc::clone(&router), discord_rx, status_cache);
+
+    let mut conn_counter: u64 = 0;
+    // Random start so that sessions of a previous run are not mistaken for ours.
+    let mut session = SessionId::random();
+
+    loop {
+        let Some(accepted) = accept_rx.recv().await else {
+            return Err(format!("No address to listen on for forward {name}").into());
+        };
+        let (socket, addr, read) = accepted?;
+        session = session.next();
+        info!("Connected to client #{conn_counter} of forward {name} (session {session}): {addr}");
+        conn_counter += 1;
+
+        let session_rx = router.register(session);
+        let session_tx = SessionSender::new(
+            tcp_tx.clone(),
+            message::MessageDirection::Serverbound,
+            session,
+        )
+        .with_compression(compression);
+
+        // Tells the server side to connect to the MC Server for this session.
+        // It is the first message of the session.
+        if let Err(err) = session_tx.send_control(&Control::Open).await {
+            error!("Failed to send OPEN frame of session {session}: {err}");
+            router.unregister(session);
+            continue;
+        }
+
+        spawn_tcp_session(
+            socket,
+            read,
+            forward.framing,
+            session_rx,
+            session_tx,
+            stop_tx.clone(),
+            Arc::clone(&router),
+        );
+    }
+}
+
+/// Client-side logic of a UDP forward: each source address gets a session of its own.
+async fn udp_client(
+    forward: Forward,
+    stop_tx: broadcast::Sender<()>,
+    tcp_tx: Sender<message::Message>,
+    router: Arc<SessionRouter>,
+    discord_rx: Receiver<message::Message>,
+    compression: Compression,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let name: String = forward.name;
+
+    // The datagrams received on every address end up in this channel.
+    let (datagram_tx, mut datagram_rx) =
+        mpsc::channel::<(Arc<UdpSocket>, Vec<u8>, SocketAddr)>(udp::DATAGRAM_QUEUE_SIZE);
+    for addr in forward.listen {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        info!("Listening on {addr}/udp (forward {name})...");
+
+        let datagram_tx = datagram_tx.clone();
+        tokio::spawn(async move {
+            let mut buffer = vec![0; udp::MAX_DATAGRAM_LEN];
+            loop {
+                match socket.recv_from(&mut buffer).await {
+                    Ok((len, from)) => {
+                        let datagram = (Arc::clone(&socket), buffer[..len].to_vec(), from);
+                        if datagram_tx.send(datagram).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(err) => debug!("Failed to receive a datagram on {addr}: {err}"),
+                }
+            }
+        });
+    }
+    drop(datagram_tx);
+
+    spawn_dispatcher(Arc::clone(&router), discord_rx, None);
+
+    // The datagrams of each source address go to its session.
+    let mut sessions: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+    // Random start so that sessions of a previous run are not mistaken for ours.
+    let mut session = SessionId::random();
+
+    loop {
+        let Some((socket, datagram, from)) = datagram_rx.recv().await else {
+            return Err(format!("No address to listen on for forward {name}").into());
+        };
+
+        let datagram = match sessions.get(&from) {
+            Some(tx) => match tx.try_send(datagram) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropped a datagram from {from}: its session is behind");
+                    continue;
+                }
+                // The session expired: the source gets a new one.
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+        sessions.retain(|_, tx| !tx.is_closed());
+
+        session = session.next();
+        info!("New UDP session {session} of forward {name}: {from}");
+
+        let session_rx = router.register(session);
+        let session_tx = SessionSender::new(
+            tcp_tx.clone(),
+            message::MessageDirection::Serverbound,
+            session,
+        )
+        .with_compression(compression);
+
+        // Tells the server side to open the session. It is the first message of the session.
+        if let Err(err) = session_tx.send_control(&Control::Open).await {
+            error!("Failed to send OPEN frame of session {session}: {err}");
+            router.unregister(session);
+            continue;
+        }
+
+        let (session_datagram_tx, session_datagram_rx) = mpsc::channel(udp::DATAGRAM_QUEUE_SIZE);
+        let _ = session_datagram_tx.try_send(datagram);
+        sessions.insert(from, session_datagram_tx);
+
+        let activity = Arc::new(udp::Activity::new());
+        let writer = udp::UdpPeer::shared(socket, from, Arc::clone(&activity));
+        spawn_udp_session(
+            session_datagram_rx,
+            writer,
+            activity,
+            session_rx,
+            session_tx,
+            stop_tx.clone(),
+            Arc::clone(&router),
+        );
+    }
+}
+
+/// Spawns the task sending the received Discord messages to the session they belong to.
+///
+/// The messages about the whole forward go to the status cache.
+fn spawn_dispatcher(
+    router: Arc<SessionRouter>,
+    mut discord_rx: Receiver<message::Message>,
+    status_cache: Option<Arc<StatusCache>>,
+) {
+    tokio::spawn(async move {
+        while let Some(discord_msg) = discord_rx.recv().await {
+            if discord_msg.session == SessionId::FORWARD {
+                match &status_cache {
+                    Some(cache) => cache.handle(&discord_msg),
+                    None => debug!("Ignored {:?} message of the forward", discord_msg.kind),
+                }
+                continue;
+            }
+            if let Err(msg) = router.dispatch(discord_msg).await {
+                debug!("Dropped Discord message of unknown session {}", msg.session);
+            }
+        }
+        warn!("Discord message channel closed. Stopped dispatching to sessions.");
+    });
+}
+
+/// Spawns the task measuring the round trip through the tunnel, for the server list pings.
+fn spawn_tunnel_pinger(status_cache: Arc<StatusCache>, tcp_tx: Sender<message::Message>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Timeouts::current().status_refresh());
+        loop {
+            tick.tick().await;
+            if tcp_tx.send(status_cache.ping()).await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+/// Server-side logic (the exit end of a forward)
+///
+/// Every session of the forward sends its messages to Discord through `tcp_tx` (its data
+/// compressed with the compression), and `discord_rx` gets the messages received in the channels
+/// of the forward.
+pub async fn server(
+    forward: Forward,
+    stop_tx: broadcast::Sender<()>,
+    tcp_tx: Sender<message::Message>,
+    mut discord_rx: Receiver<message::Message>,
+    compression: Compression,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    // Dispatches the received Discord messages to their session.
+    let router = Arc::new(SessionRouter::new());
+
+    let name: String = forward.name;
+    let Some(target) = forward.target else {
+        return Err(format!("Forward {name} has no target").into());
+    };
+    let mut conn_counter: u64 = 0;
+
+    // The client side answers the server list pings with this status.
+    if forward.framing == Framing::Minecraft {
+        tokio::spawn(status::refresh(target.clone(), tcp_tx.clone()));
+    }
+
+    loop {
+        // Listen for a message that's serverbound (directed to us)
+        let Some(discord_msg) = discord_rx.recv().await else {
+            error!("Error receiving discord message from closed mpsc channel, got None");
+            return Err("Discord message channel closed".into());
+        };
+
+        // Messages of known sessions go straight to their socket.
+        let discord_msg = match router.dispatch(discord_msg).await {
+            Ok(()) => continue,
+            Err(msg) => msg,
+        };
+
+        // The messages of a closed session arrive late, they must not open it again.
+        // Messages that are not sequenced (PING, PONG) never open a session.
+        let session = discord_msg.session;
+        if router.is_closed(session)
+            || !discord_msg.kind.is_sequenced()
+            || session == SessionId::FORWARD
+        {
+            debug!(
+                "Ignored {:?} message of unknown session {session}",
+                discord_msg.kind
+            );
+            continue;
+        }
+
+        // From here, the message is the first one received of a new session.
+        // It is usually the OPEN frame, but it may be overtaken by the first data messages:
+        // they wait for it in the reorder buffer of the session.
+        let session_rx = router.register(session);
+        if let Err(msg) = router.dispatch(discord_msg).await {
+            error!(
+                "Failed to dispatch the first message of session {}",
+                msg.session
+            );
+            router.unregister(session);
+            continue;
+        }
+
+        let conn_id = conn_counter;
+        conn_counter += 1;
+
+        let session_tx = SessionSender::new(
+            tcp_tx.clone(),
+            message::MessageDirection::Clientbound,
+            session,
+        )
+        .with_compression(compression);
+        let stop_tx_clone = stop_tx.clone();
+        let router_clone = Arc::clone(&router);
+        let target: Target = target.clone();
+        let name: String = name.clone();
+        let protocol: Protocol = forward.protocol;
+        let framing: Framing = forward.framing;
+        tokio::spawn(async move {
+            info!("Connecting to {target} (forward {name}, session {session})...");
+            // Connect to the server
+            let connect_timeout = Timeouts::current().connect();
+            let connected = tokio::time::timeout(connect_timeout, connect(protocol, &target))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+            let (connection, addr) = match connected {
+                Ok(connected) => connected,
+                Err(err) => {
+                    error!("Failed to connect to {target} (session {session}): {err}");
+                    router_clone.unregister(session);
+                    let error = Control::Error {
+                        code: Control::ERROR_UNREACHABLE,
+                        text: format!("Tunnel server could not reach {target}: {err}"),
+                    };
+                    if let Err(err) = session_tx.send_control(&error).await {
+                        warn!("Failed to send ERROR frame to tx: {err}");
+                    }
+                    return;
+                }
+            };
+            info!("Connection #{conn_id} of forward {name} established with {addr} (session {session})");
+
+            match connection {
+                Connection::Tcp(socket) => spawn_tcp_session(
+                    socket,
+                    Vec::new(),
+                    framing,
+                    session_rx,
+                    session_tx,
+                    stop_tx_clone,
+                    router_clone,
+                ),
+                Connection::Udp(socket) => {
+                    let socket = Arc::new(socket);
+                    let (datagram_tx, datagram_rx) = mpsc::channel(udp::DATAGRAM_QUEUE_SIZE);
+                    tokio::spawn(udp::read_connected(Arc::clone(&socket), datagram_tx));
+
+                    let activity = Arc::new(udp::Activity::new());
+                    let writer = udp::UdpPeer::connected(socket, Arc::clone(&activity));
+                    spawn_udp_session(
+                        datagram_rx,
+                        writer,
+                        activity,
+                        session_rx,
+                        session_tx,
+                        stop_tx_clone,
+                        router_clone,
+                    );
+                }
+            }
+        });
+    }
+}
+
+/// A connection to the target of a forward.
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+/// Connects to the target of a forward.
+async fn connect(protocol: Protocol, target: &Target) -> io::Result<(Connection, SocketAddr)> {
+    match protocol {
+        Protocol::Tcp => {
+            let (socket, addr) = address::connect(&target.host, target.port).await?;
+            Ok((Connection::Tcp(socket), addr))
+        }
+        Protocol::Udp => {
+            let addr: SocketAddr = address::resolve(&target.host, target.port).await?[0];
+            Ok((Connection::Udp(udp::connect(addr).await?), addr))
+        }
+    }
+}
+
+/// Spawns the tasks pumping data between a TCP socket and Discord for a single session.
+///
+/// `read` is what was already read from the socket.
+fn spawn_tcp_session(
+    socket: TcpStream,
+    read: Vec<u8>,
+    framing: Framing,
+    session_rx: Receiver<message::Message>,
+    session_tx: SessionSender,
+    stop_tx: broadcast::Sender<()>,
+    router: Arc<SessionRouter>,
+) {
+    // Split to socket in two OWNED parts so that we can use the socket through two functions.
+    let (read_half, write_half) = socket.into_split();
+    let read_half = io::Cursor::new(read).chain(read_half);
+
+    match framing {
+        Framing::Raw => spawn_session(
+            move |session_tx, session_stop_tx| {
+                sockets::handle_receive_socket(read_half, session_tx, session_stop_tx, None)
+            },
+            write_half,
+            session_rx,
+            session_tx,
+            stop_tx,
+            router,
+        ),
+        Framing::Minecraft => {
+            // Both directions of the connection go through the same tracker.
+            let tracker = Arc::new(Mutex::new(minecraft::Tracker::default()));
+            let writer =
+                sockets::TrackedWriter::new(write_half, tracker, session_tx.direction().opposite());
+            let tracked = writer.clone();
+            spawn_session(
+                move |session_tx, session_stop_tx| {
+                    sockets::handle_receive_socket(
+                        read_half,
+                        session_tx,
+                        session_stop_tx,
+                        Some(tracked),
+                    )
+                },
+                writer,
+                session_rx,
+                session_tx,
+                stop_tx,
+                router,
+            )
+        }
+    }
+}
+
+/// Spawns the tasks pumping datagrams between a UDP peer and Discord for a single session.
+fn spawn_udp_session(
+    datagram_rx: Receiver<Vec<u8>>,
+    writer: udp::UdpPeer,
+    activity: Arc<udp::Activity>,
+    session_rx: Receiver<message::Message>,
+    session_tx: SessionSender,
+    stop_tx: broadcast::Sender<()>,
+    router: Arc<SessionRouter>,
+) {
+    let idle_timeout = Timeouts::current().udp_idle();
+    spawn_session(
+        move |session_tx, session_stop_tx| {
+            udp::handle_receive_datagrams(
+                datagram_rx,
+                session_tx,
+                session_stop_tx,
+                activity,
+                idle_timeout,
+            )
+        },
+        writer,
+        session_rx,
+        session_tx,
+        stop_tx,
+        router,
+    );
+}
+
+/// Spawns the tasks pumping data between the socket and Discord for a single session.
+///
+/// `receive` makes the task sending the data read from the socket to Discord. The data received
+/// from Discord is written to `writer`.
+///
+/// The session is unregistered from the router once the connection is closed.
+fn spawn_session<R, F, W>(
+    receive: R,
+    writer: W,
+    session_rx: Receiver<message::Message>,
+    session_tx: SessionSender,
+    stop_tx: broadcast::Sender<()>,
+    router: Arc<SessionRouter>,
+) where
+    R: FnOnce(SessionSender, broadcast::Sender<StopReason>) -> F + Send + 'static,
+    F: Future<Output = ()> + Send + 'static,
+    W: PayloadWriter,
+{
+    let session = session_tx.session();
+
+    // Each session can be stopped on its own, without stopping the others.
+    let (session_stop_tx, _) = broadcast::channel::<StopReason>(16);
+
+    // The global stop signal stops every session.
+    let mut global_stop_rx = stop_tx.subscribe();
+    let session_stop_tx_clone = session_stop_tx.clone();
+    let forward_stop = tokio::spawn(async move {
+        if global_stop_rx.recv().await.is_ok() {
+            let _ = session_stop_tx_clone.send(StopReason::error(
+                Control::ERROR_DISCORD,
+                "Discord side of the tunnel stopped",
+            ));
+        }
+    });
+
+    // Tells why the session stopped, once it is.
+    let mut stop_reason_rx = session_stop_tx.subscribe();
+
+    tokio::spawn(async move {
+        // Receives packets from the MC Client/Server.
+        let stop_tx_clone = session_stop_tx.clone();
+        let session_tx_clone = session_tx.clone();
+        let handle_receive_tcp = tokio::spawn(async move {
+            debug!("Inside the handle_receive_socket async task");
+
+            receive(session_tx_clone, stop_tx_clone).await;
+        });
+
+        // Sends received Discord messages to the MC Client/Server.
+        let stop_tx_clone2 = session_stop_tx.clone();
+        let session_tx_clone2 = session_tx.clone();
+        let handle_write_tcp = tokio::spawn(async move {
+            let mut session_rx = session_rx;
+            sockets::handle_channel_to_socket(
+                writer,
+                &mut session_rx,
+                session_tx_clone2,
+                stop_tx_clone2,
+            )
+            .await;
+            session_rx
+        });
+
+        match tokio::try_join!(handle_receive_tcp, handle_write_tcp) {
+            Ok(((), mut session_rx)) => {
+                // The peer only has to acknowledge our last messages if we closed the session.
+                if let Ok(StopReason::Local(_)) = stop_reason_rx.try_recv() {
+                    sockets::linger(&mut session_rx, &session_tx).await;
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Error in one of the connection tasks of session {session}: {:?}",
+                    err
+                );
+            }
+        }
+
+        forward_stop.abort();
+        router.unregister(session);
+        info!("--- CONNECTION CLOSED (session {session}) ---");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{FrameCipher, FrameMode};
+    use crate::simulator::{Conditions, Simulator};
+    use crate::transport::Loopback;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    /// Returns an address nothing listens on yet.
+    async fn free_addr() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    /// Accepts connections and writes back what they send.
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = socket.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn forward(listen: SocketAddr, target: Option<SocketAddr>) -> Forward {
+        Forward {
+            name: "echo".to_string(),
+            protocol: Protocol::Tcp,
+            framing: Framing::Raw,
+            listen: vec![listen],
+            target: target.map(|addr| Target {
+                host: addr.ip().to_string(),
+                port: Some(addr.port()),
+            }),
+            channels: vec![1, 2],
+        }
+    }
+
+    /// Runs an echo forward over the ends, each side sealing the frames with its own run in the
+    /// frame mode (if any), and checks that the data comes back within the timeout.
+    async fn assert_echoes<T: Transport>(
+        client_end: Arc<T>,
+        server_end: Arc<T>,
+        frame_mode: Option<FrameMode>,
+        timeout: Duration,
+    ) {
+        let target = echo_server().await;
+        let listen = free_addr().await;
+        let (stop_tx, _) = broadcast::channel::<()>(16);
+        // Each side has its own run.
+        let settings = || Settings {
+            cipher: frame_mode.map(|mode| Arc::new(FrameCipher::new("hunter2", mode))),
+            ..Settings::default()
+        };
+
+        tokio::spawn(run(
+            server_end,
+            vec![forward(listen, Some(target))],
+            true,
+            stop_tx.clone(),
+            settings(),
+        ));
+        tokio::spawn(run(
+            client_end,
+            vec![forward(listen, None)],
+            false,
+            stop_tx,
+            settings(),
+        ));
+
+        // Waits for the client side to listen.
+        let mut socket = loop {
+            match TcpStream::connect(listen).await {
+                Ok(socket) => break socket,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        // Big enough to be partitioned into many Discord messages.
+        let data: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        socket.write_all(&data).await.unwrap();
+        let mut echoed = vec![0; data.len()];
+        tokio::time::timeout(timeout, socket.read_exact(&mut echoed))
+            .await
+            .unwrap_or_else(|_| panic!("the data did not come back ({frame_mode:?})"))
+            .unwrap();
+        assert_eq!(echoed, data);
+    }
+
+    #[tokio::test]
+    async fn test_loopback_tunnel() {
+        let (client_end, server_end) = Loopback::pair();
+        assert_echoes(
+            Arc::new(client_end),
+            Arc::new(server_end),
+            None,
+            Duration::from_secs(10),
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_sealed_loopback_tunnel_with_lost_hellos() {
+        // The first frame of each side (its first HELLO) is lost: the next HELLOs come
+        // `HELLO_INTERVAL` later, and the sessions only go through once retransmitted.
+        let echoes = |frame_mode: FrameMode| async move {
+            let lost_hello = Conditions {
+                drop_first: 1,
+                ..Conditions::default()
+            };
+            let (client_end, server_end) = Loopback::pair();
+            let client_end = Arc::new(Simulator::new(client_end, lost_hello));
+            let server_end = Arc::new(Simulator::new(server_end, lost_hello));
+
+            assert_echoes(
+                Arc::clone(&client_end),
+                Arc::clone(&server_end),
+                Some(frame_mode),
+                Duration::from_secs(30),
+            )
+            .await;
+            assert_eq!(client_end.stats().dropped, 1);
+            assert_eq!(server_end.stats().dropped, 1);
+        };
+        tokio::join!(echoes(FrameMode::Encrypt), echoes(FrameMode::Sign));
+    }
+}
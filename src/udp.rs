@@ -0,0 +1,282 @@
+//! Everything to carry UDP datagrams through the tunnel.
+//!
+//! Each datagram is sent as a data `Message` of its own, so that the peer writes it back as one
+//! datagram: its boundaries are kept, even if the message is partitioned on the way.
+//!
+//! UDP has no connections, so the entry end makes up a session for each source address. A
+//! session is closed once no datagram went through it, in either direction, for a while.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, error};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::interval;
+
+use crate::message::{Control, Message};
+use crate::session::SessionSender;
+use crate::sockets::{self, PayloadWriter, StopReason};
+
+/// The biggest possible UDP datagram.
+pub const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// How many datagrams of a session can wait to be sent. The next ones are dropped, as the network
+/// would.
+pub const DATAGRAM_QUEUE_SIZE: usize = 64;
+
+/// When a session last carried a datagram, in either direction.
+#[derive(Debug)]
+pub struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`.
+    last: AtomicU64,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a datagram going through the session.
+    pub fn touch(&self) {
+        self.touch_at(Instant::now());
+    }
+
+    fn touch_at(&self, now: Instant) {
+        let elapsed = now.duration_since(self.start).as_millis() as u64;
+        self.last.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// Returns how long the session has been idle.
+    pub fn idle_for(&self) -> Duration {
+        self.idle_for_at(Instant::now())
+    }
+
+    fn idle_for_at(&self, now: Instant) -> Duration {
+        let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+        now.saturating_duration_since(last)
+    }
+}
+
+impl Default for Activity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Writes the datagrams received from the tunnel to a UDP peer.
+pub struct UdpPeer {
+    socket: Arc<UdpSocket>,
+    /// The source address of the session on the entry end. `None` if the socket is connected.
+    peer: Option<SocketAddr>,
+    activity: Arc<Activity>,
+}
+
+impl UdpPeer {
+    /// A peer sharing the socket with others. (the entry end)
+    pub fn shared(socket: Arc<UdpSocket>, peer: SocketAddr, activity: Arc<Activity>) -> Self {
+        Self {
+            socket,
+            peer: Some(peer),
+            activity,
+        }
+    }
+
+    /// The only peer of a connected socket. (the exit end)
+    pub fn connected(socket: Arc<UdpSocket>, activity: Arc<Activity>) -> Self {
+        Self {
+            socket,
+            peer: None,
+            activity,
+        }
+    }
+}
+
+impl PayloadWriter for UdpPeer {
+    async fn write_payload(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        self.activity.touch();
+        match self.peer {
+            Some(peer) => self.socket.send_to(payload, peer).await?,
+            None => self.socket.send(payload).await?,
+        };
+        Ok(())
+    }
+}
+
+/// Binds a socket to send datagrams to the target from. (the exit end)
+pub async fn connect(target: SocketAddr) -> std::io::Result<UdpSocket> {
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+/// Reads the datagrams of a connected socket into a channel, until the channel is closed.
+pub async fn read_connected(socket: Arc<UdpSocket>, datagram_tx: mpsc::Sender<Vec<u8>>) {
+    let mut buffer = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        tokio::select! {
+            received = socket.recv(&mut buffer) => match received {
+                Ok(len) => {
+                    if let Err(mpsc::error::TrySendError::Closed(_)) =
+                        datagram_tx.try_send(buffer[..len].to_vec())
+                    {
+                        return;
+                    }
+                }
+                // e.g. ICMP port unreachable: the target may come back.
+                Err(err) => debug!("Failed to receive a datagram: {err}"),
+            },
+            _ = datagram_tx.closed() => return,
+        }
+    }
+}
+
+/// Sends the datagrams of a session to the peer, one message each.
+///
+/// Stops the session once it has been idle for `idle_timeout`.
+pub async fn handle_receive_datagrams(
+    datagram_rx: mpsc::Receiver<Vec<u8>>,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
+    activity: Arc<Activity>,
+    idle_timeout: Duration,
+) {
+    let pump = handle_receive_datagrams_offload(
+        datagram_rx,
+        tx.clone(),
+        stop_tx.clone(),
+        activity,
+        idle_timeout,
+    );
+    sockets::handle_receive(pump, tx, stop_tx).await;
+}
+
+async fn handle_receive_datagrams_offload(
+    mut datagram_rx: mpsc::Receiver<Vec<u8>>,
+    tx: SessionSender,
+    stop_tx: broadcast::Sender<StopReason>,
+    activity: Arc<Activity>,
+    idle_timeout: Duration,
+) {
+    let session = tx.session();
+    let mut tick = interval(idle_timeout.min(Duration::from_secs(1)));
+
+    loop {
+        tokio::select! {
+            received = datagram_rx.recv() => {
+                let Some(datagram) = received else {
+                    let _ = stop_tx.send(StopReason::close("datagram channel closed"));
+                    return;
+                };
+                activity.touch();
+                debug!("Received UDP datagram [{}B] (session {session})", datagram.len());
+
+                let message = Message::from_bytes(&datagram, tx.direction(), session);
+                let message = tx.compression().compress(message);
+                tx.wait_for_credit(&message).await;
+                if let Err(e) = tx.send(message).await {
+                    error!("Failed sending datagram through channel: {e}");
+                    let _ = stop_tx.send(StopReason::error(Control::ERROR_DISCORD, e.to_string()));
+                    return;
+                }
+            }
+            _ = tick.tick() => {
+                if activity.idle_for() >= idle_timeout {
+                    debug!("Session {session} idle for {idle_timeout:?}. Closing it.");
+                    let _ = stop_tx.send(StopReason::close("idle UDP session"));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity() {
+        let activity = Activity::new();
+        let start = activity.start;
+
+        assert_eq!(
+            activity.idle_for_at(start + Duration::from_secs(5)),
+            Duration::from_secs(5)
+        );
+
+        activity.touch_at(start + Duration::from_secs(4));
+        assert_eq!(
+            activity.idle_for_at(start + Duration::from_secs(5)),
+            Duration::from_secs(1)
+        );
+
+        // A late update from another task does not go back in time.
+        activity.touch_at(start + Duration::from_secs(2));
+        assert_eq!(
+            activity.idle_for_at(start + Duration::from_secs(5)),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_datagram_boundaries() {
+        use crate::message::MessageDirection;
+        use crate::session::SessionId;
+
+        let (tx, mut rx) = mpsc::channel(16);
+        let session_tx = SessionSender::new(tx, MessageDirection::Serverbound, SessionId::new(7));
+        let (stop_tx, _) = broadcast::channel(4);
+        let (datagram_tx, datagram_rx) = mpsc::channel(4);
+
+        datagram_tx.send(b"first".to_vec()).await.unwrap();
+        datagram_tx.send(b"second".to_vec()).await.unwrap();
+        drop(datagram_tx);
+
+        handle_receive_datagrams(
+            datagram_rx,
+            session_tx,
+            stop_tx,
+            Arc::new(Activity::new()),
+            Duration::from_secs(60),
+        )
+        .await;
+
+        assert_eq!(rx.recv().await.unwrap().payload(), b"first");
+        assert_eq!(rx.recv().await.unwrap().payload(), b"second");
+    }
+
+    #[tokio::test]
+    async fn test_udp_peer_writes_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let sender = Arc::new(connect(receiver.local_addr().unwrap()).await.unwrap());
+        let activity = Arc::new(Activity::new());
+        let mut peer = UdpPeer::connected(Arc::clone(&sender), Arc::clone(&activity));
+
+        peer.write_payload(b"one").await.unwrap();
+        peer.write_payload(b"two").await.unwrap();
+
+        let mut buffer = [0; 16];
+        let (len, from) = receiver.recv_from(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"one");
+        let len = receiver.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"two");
+
+        // And back, through the shared socket of the other end.
+        let receiver = Arc::new(receiver);
+        let mut back = UdpPeer::shared(receiver, from, activity);
+        back.write_payload(b"three").await.unwrap();
+        let len = sender.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..len], b"three");
+    }
+}